@@ -3,8 +3,11 @@ extern crate nix;
 use crate::audiodevice::*;
 use crate::config;
 use crate::config::SampleFormat;
-use crate::conversions::{buffer_to_chunk_rawbytes, chunk_to_buffer_rawbytes};
+use crate::conversions::{
+    apply_int_conversion, buffer_to_chunk_rawbytes, chunk_to_buffer_rawbytes,
+};
 use crate::countertimer;
+use crate::filedevice::{start_capture_monitor, CaptureMonitor};
 use alsa::ctl::{ElemId, ElemIface};
 use alsa::ctl::{ElemType, ElemValue};
 use alsa::hctl::HCtl;
@@ -26,15 +29,13 @@ use crate::Res;
 use crate::StatusMessage;
 use crate::{CaptureStatus, PlaybackStatus};
 
+/// Length of the fade to silence that is played before the playback device is closed.
+const SHUTDOWN_FADE_MS: usize = 5;
+
 lazy_static! {
     static ref ALSA_MUTEX: Mutex<()> = Mutex::new(());
 }
 
-const STANDARD_RATES: [u32; 17] = [
-    5512, 8000, 11025, 16000, 22050, 32000, 44100, 48000, 64000, 88200, 96000, 176400, 192000,
-    352800, 384000, 705600, 768000,
-];
-
 #[derive(Debug)]
 enum SupportedValues {
     Range(u32, u32),
@@ -43,6 +44,7 @@ enum SupportedValues {
 
 pub struct AlsaPlaybackDevice {
     pub devname: String,
+    pub device_match: config::DeviceMatch,
     pub samplerate: usize,
     pub chunksize: usize,
     pub channels: usize,
@@ -50,10 +52,12 @@ pub struct AlsaPlaybackDevice {
     pub target_level: usize,
     pub adjust_period: f32,
     pub enable_rate_adjust: bool,
+    pub int_conversion: config::IntConversion,
 }
 
 pub struct AlsaCaptureDevice {
     pub devname: String,
+    pub device_match: config::DeviceMatch,
     pub samplerate: usize,
     pub enable_resampling: bool,
     pub capture_samplerate: usize,
@@ -65,6 +69,10 @@ pub struct AlsaCaptureDevice {
     pub silence_timeout: PrcFmt,
     pub stop_on_rate_change: bool,
     pub rate_measure_interval: f32,
+    pub clock_source: config::ClockSource,
+    pub open_timeout_ms: u64,
+    pub open_retry_ms: u64,
+    pub monitor_file: Option<String>,
 }
 
 struct CaptureChannels {
@@ -88,6 +96,7 @@ struct CaptureParams {
     samplerate: usize,
     capture_samplerate: usize,
     async_src: bool,
+    clock_source: config::ClockSource,
     capture_status: Arc<RwLock<CaptureStatus>>,
     stop_on_rate_change: bool,
     rate_measure_interval: f32,
@@ -99,6 +108,7 @@ struct PlaybackParams {
     adjust_period: f32,
     adjust_enabled: bool,
     sample_format: SampleFormat,
+    int_conversion: config::IntConversion,
     playback_status: Arc<RwLock<PlaybackStatus>>,
     chunksize: usize,
     samplerate: usize,
@@ -386,6 +396,106 @@ fn list_formats_as_text(hwp: &HwParams) -> String {
     }
 }
 
+/// Read the capabilities of a device by opening it without configuring it.
+fn get_device_info(name: &str, desc: &Option<String>, capture: bool) -> DeviceInfo {
+    let mut info = DeviceInfo {
+        backend: "Alsa".to_string(),
+        capture,
+        name: name.to_string(),
+        description: desc.clone(),
+        channels: Vec::new(),
+        samplerates: Vec::new(),
+        formats: Vec::new(),
+    };
+    let direction = if capture {
+        Direction::Capture
+    } else {
+        Direction::Playback
+    };
+    let _lock = ALSA_MUTEX.lock().unwrap();
+    let pcm = match PCM::new(name, direction, true) {
+        Ok(pcm) => pcm,
+        Err(err) => {
+            debug!("Unable to open {}: {}", name, err);
+            return info;
+        }
+    };
+    if let Ok(hwp) = HwParams::any(&pcm) {
+        if let Ok((_, _, channels)) = list_nbr_channels(&hwp) {
+            info.channels = channels.iter().map(|ch| *ch as usize).collect();
+        }
+        info.samplerates = match list_samplerates(&hwp) {
+            Ok(SupportedValues::Discrete(rates)) => rates.iter().map(|r| *r as usize).collect(),
+            Ok(SupportedValues::Range(min_rate, max_rate)) => STANDARD_RATES
+                .iter()
+                .filter(|rate| (min_rate..=max_rate).contains(rate))
+                .map(|r| *r as usize)
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        info.formats = list_formats(&hwp).unwrap_or_default();
+    }
+    info
+}
+
+/// List the Alsa pcm devices, with their capabilities.
+pub fn list_devices() -> Vec<DeviceInfo> {
+    let hints = match alsa::device_name::HintIter::new_str(None, "pcm") {
+        Ok(hints) => hints,
+        Err(err) => {
+            warn!("Unable to list Alsa devices: {}", err);
+            return Vec::new();
+        }
+    };
+    let mut devices = Vec::new();
+    for hint in hints {
+        if let Some(name) = &hint.name {
+            if hint.direction != Some(Direction::Playback) {
+                devices.push(get_device_info(name, &hint.desc, true));
+            }
+            if hint.direction != Some(Direction::Capture) {
+                devices.push(get_device_info(name, &hint.desc, false));
+            }
+        }
+    }
+    devices
+}
+
+/// Get the name of the Alsa PCM device to open, matching the configured name
+/// against the names of the available devices for the given direction.
+fn resolve_device_name(
+    devname: &str,
+    device_match: config::DeviceMatch,
+    capture: bool,
+) -> Res<String> {
+    if device_match == config::DeviceMatch::Exact {
+        return Ok(devname.to_string());
+    }
+    let other_direction = if capture {
+        Direction::Playback
+    } else {
+        Direction::Capture
+    };
+    // Each card description is shared by all the devices of the card.
+    // Only keep it for the hw devices, so that a match on a description gives a single device.
+    let devices: Vec<(String, Option<String>)> = alsa::device_name::HintIter::new_str(None, "pcm")?
+        .filter(|hint| hint.direction != Some(other_direction))
+        .filter_map(|hint| {
+            let name = hint.name?;
+            let desc = hint.desc.filter(|_| name.starts_with("hw:"));
+            Some((name, desc))
+        })
+        .collect();
+    match_device_name_or_description(devname, device_match, &devices)
+}
+
+/// Check if opening a device failed because it is in use by another application.
+fn is_device_busy(err: &(dyn std::error::Error + 'static)) -> bool {
+    err.downcast_ref::<alsa::Error>()
+        .map(|err| err.nix_error() == alsa::nix::errno::Errno::EBUSY)
+        .unwrap_or(false)
+}
+
 /// Open an Alsa PCM device
 fn open_pcm(
     devname: String,
@@ -481,9 +591,15 @@ fn playback_loop_bytes(
     let adjust = params.adjust_period > 0.0 && params.adjust_enabled;
     let target_delay = 1000 * (params.target_level as u64) / srate as u64;
     let millis_per_chunk = 1000 * params.chunksize / params.samplerate;
+    // The last value written on each channel, for fading out when stopping
+    let mut last_values: Vec<PrcFmt> = vec![0.0; params.channels];
     loop {
         match channels.audio.recv() {
-            Ok(AudioMessage::Audio(chunk)) => {
+            Ok(AudioMessage::Audio(mut chunk)) => {
+                apply_int_conversion(&mut chunk, &params.sample_format, params.int_conversion);
+                for (value, waveform) in last_values.iter_mut().zip(chunk.waveforms.iter()) {
+                    *value = waveform.last().copied().unwrap_or(0.0);
+                }
                 conversion_result =
                     chunk_to_buffer_rawbytes(&chunk, &mut buffer, &params.sample_format);
                 if conversion_result.1 > 0 {
@@ -532,6 +648,9 @@ fn playback_loop_bytes(
                 match playback_res {
                     Ok(_) => {}
                     Err(msg) => {
+                        // Try to end with a fade, in case the device is still able to play
+                        fade_out(&last_values, &mut buffer, pcmdevice, &io, &params);
+                        last_values.iter_mut().for_each(|value| *value = 0.0);
                         channels
                             .status
                             .send(StatusMessage::PlaybackError(msg.to_string()))
@@ -541,8 +660,11 @@ fn playback_loop_bytes(
             }
             Ok(AudioMessage::Pause) => {
                 trace!("Pause message received");
+                // The device runs out of data while paused, meaning that the output is already silent
+                last_values.iter_mut().for_each(|value| *value = 0.0);
             }
             Ok(AudioMessage::EndOfStream) => {
+                fade_out(&last_values, &mut buffer, pcmdevice, &io, &params);
                 channels
                     .status
                     .send(StatusMessage::PlaybackDone)
@@ -551,6 +673,7 @@ fn playback_loop_bytes(
             }
             Err(err) => {
                 error!("Message channel error: {}", err);
+                fade_out(&last_values, &mut buffer, pcmdevice, &io, &params);
                 channels
                     .status
                     .send(StatusMessage::PlaybackError(err.to_string()))
@@ -561,6 +684,60 @@ fn playback_loop_bytes(
     }
 }
 
+/// Write a last chunk that ramps the output from the last written values to silence.
+/// This avoids a pop when the device is closed with a signal still playing.
+/// Closing the device drops any audio still in its buffer, so the fade must be played before that.
+/// The wait is bounded, to not hang on a device that has stopped playing.
+fn fade_out(
+    last_values: &[PrcFmt],
+    buffer: &mut [u8],
+    pcmdevice: &alsa::PCM,
+    io: &alsa::pcm::IO<u8>,
+    params: &PlaybackParams,
+) {
+    if last_values.iter().all(|value| *value == 0.0) {
+        return;
+    }
+    debug!("Fading out playback before closing the device");
+    let fade_frames = SHUTDOWN_FADE_MS * params.samplerate / 1000;
+    let chunk = AudioChunk::fade_to_silence(last_values, fade_frames, params.chunksize);
+    chunk_to_buffer_rawbytes(&chunk, buffer, &params.sample_format);
+    let millis_per_chunk = 1000 * params.chunksize / params.samplerate;
+    let target_delay = 1000 * (params.target_level as u64) / params.samplerate as u64;
+    let result = play_buffer(
+        buffer,
+        pcmdevice,
+        io,
+        target_delay,
+        millis_per_chunk,
+        params.chunksize,
+    );
+    match result {
+        Ok(()) => wait_for_buffer_played(pcmdevice, params.samplerate, millis_per_chunk),
+        Err(err) => debug!("Could not fade out playback: {}", err),
+    }
+}
+
+/// Wait until the device has played the audio in its buffer.
+/// Gives up when this takes longer than the buffered audio plus a margin of one chunk.
+fn wait_for_buffer_played(pcmdevice: &alsa::PCM, samplerate: usize, margin_millis: usize) {
+    let buffered_frames = pcmdevice.delay().unwrap_or(0).max(0) as usize;
+    let timeout =
+        Duration::from_millis((1000 * buffered_frames / samplerate + margin_millis) as u64);
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if pcmdevice.state_raw() != alsa_sys::SND_PCM_STATE_RUNNING as i32 {
+            // Either played to the end, or the device has stopped
+            return;
+        }
+        match pcmdevice.delay() {
+            Ok(frames) if frames > 0 => thread::sleep(Duration::from_millis(2)),
+            _ => return,
+        }
+    }
+    debug!("Playback buffer was not played within {:?}", timeout);
+}
+
 fn capture_loop_bytes(
     channels: CaptureChannels,
     mut buffer: Vec<u8>,
@@ -568,6 +745,7 @@ fn capture_loop_bytes(
     io: alsa::pcm::IO<u8>,
     params: CaptureParams,
     mut resampler: Option<Box<dyn VecResampler<PrcFmt>>>,
+    mut monitor: Option<CaptureMonitor>,
 ) {
     let pcminfo = pcmdevice.info().unwrap();
     let card = pcminfo.get_card();
@@ -580,13 +758,23 @@ fn capture_loop_bytes(
     elid_loopback.set_device(device);
     elid_loopback.set_subdevice(subdevice);
     elid_loopback.set_name(&CString::new("PCM Rate Shift 100000").unwrap());
-    let element_loopback = h.find_elem(&elid_loopback);
+    let mut element_loopback = h.find_elem(&elid_loopback);
 
     let mut elid_uac2_gadget = ElemId::new(ElemIface::PCM);
     elid_uac2_gadget.set_device(device);
     elid_uac2_gadget.set_subdevice(subdevice);
     elid_uac2_gadget.set_name(&CString::new("Capture Pitch 1000000").unwrap());
-    let element_uac2_gadget = h.find_elem(&elid_uac2_gadget);
+    let mut element_uac2_gadget = h.find_elem(&elid_uac2_gadget);
+
+    // The capture device is the reference clock and must not be tuned,
+    // any rate adjust is then done by the resampler.
+    if params.clock_source == config::ClockSource::Capture
+        && (element_loopback.is_some() || element_uac2_gadget.is_some())
+    {
+        debug!("Capture device is the clock source, not using its rate adjust control");
+        element_loopback = None;
+        element_uac2_gadget = None;
+    }
 
     if element_loopback.is_some() || element_uac2_gadget.is_some() {
         info!("Capture device supports rate adjust");
@@ -688,6 +876,9 @@ fn capture_loop_bytes(
         match capture_res {
             Ok(CaptureResult::Normal) => {
                 //trace!("Captured {} bytes", capture_bytes);
+                if let Some(monitor) = &mut monitor {
+                    monitor.write_bytes(&buffer[0..capture_bytes]);
+                }
                 averager.add_value(capture_bytes);
                 if averager.larger_than_millis(
                     params.capture_status.read().unwrap().update_interval as u64,
@@ -698,7 +889,7 @@ fn capture_loop_bytes(
                         bytes_per_sec / (params.channels * params.store_bytes_per_sample) as f64;
                     trace!("Measured sample rate is {} Hz", measured_rate_f);
                     let mut capt_stat = params.capture_status.write().unwrap();
-                    capt_stat.measured_samplerate = measured_rate_f as usize;
+                    capt_stat.update_measured_samplerate(measured_rate_f);
                     capt_stat.signal_range = value_range as f32;
                     capt_stat.rate_adjust = rate_adjust as f32;
                     capt_stat.state = state;
@@ -829,6 +1020,7 @@ impl PlaybackDevice for AlsaPlaybackDevice {
         playback_status: Arc<RwLock<PlaybackStatus>>,
     ) -> Res<Box<thread::JoinHandle<()>>> {
         let devname = self.devname.clone();
+        let device_match = self.device_match;
         let target_level = if self.target_level > 0 {
             self.target_level
         } else {
@@ -841,18 +1033,21 @@ impl PlaybackDevice for AlsaPlaybackDevice {
         let channels = self.channels;
         let bytes_per_sample = self.sample_format.bytes_per_sample();
         let sample_format = self.sample_format.clone();
+        let int_conversion = self.int_conversion;
         let handle = thread::Builder::new()
             .name("AlsaPlayback".to_string())
             .spawn(move || {
-                match open_pcm(
-                    devname,
-                    samplerate as u32,
-                    chunksize as Frames,
-                    chunksize as Frames,
-                    channels as u32,
-                    &sample_format,
-                    false,
-                ) {
+                match resolve_device_name(&devname, device_match, false).and_then(|devname| {
+                    open_pcm(
+                        devname,
+                        samplerate as u32,
+                        chunksize as Frames,
+                        chunksize as Frames,
+                        channels as u32,
+                        &sample_format,
+                        false,
+                    )
+                }) {
                     Ok(pcmdevice) => {
                         match status_channel.send(StatusMessage::PlaybackReady) {
                             Ok(()) => {}
@@ -867,6 +1062,7 @@ impl PlaybackDevice for AlsaPlaybackDevice {
                             adjust_period,
                             adjust_enabled,
                             sample_format,
+                            int_conversion,
                             playback_status,
                             chunksize,
                             samplerate,
@@ -906,6 +1102,7 @@ impl CaptureDevice for AlsaCaptureDevice {
         capture_status: Arc<RwLock<CaptureStatus>>,
     ) -> Res<Box<thread::JoinHandle<()>>> {
         let devname = self.devname.clone();
+        let device_match = self.device_match;
         let samplerate = self.samplerate;
         let capture_samplerate = self.capture_samplerate;
         let chunksize = self.chunksize;
@@ -925,6 +1122,10 @@ impl CaptureDevice for AlsaCaptureDevice {
         let async_src = resampler_is_async(&resampler_conf);
         let stop_on_rate_change = self.stop_on_rate_change;
         let rate_measure_interval = self.rate_measure_interval;
+        let clock_source = self.clock_source;
+        let open_timeout = Duration::from_millis(self.open_timeout_ms);
+        let open_retry = Duration::from_millis(self.open_retry_ms);
+        let monitor_file = self.monitor_file.clone();
         let handle = thread::Builder::new()
             .name("AlsaCapture".to_string())
             .spawn(move || {
@@ -940,16 +1141,42 @@ impl CaptureDevice for AlsaCaptureDevice {
                 } else {
                     None
                 };
-                match open_pcm(
-                    devname,
-                    capture_samplerate as u32,
-                    chunksize as Frames,
-                    buffer_frames as Frames,
-                    channels as u32,
-                    &sample_format,
-                    true,
-                ) {
+                // Retry opening a device that is busy until the timeout has passed
+                let open_start = Instant::now();
+                let open_result = loop {
+                    match resolve_device_name(&devname, device_match, true).and_then(|devname| {
+                        open_pcm(
+                            devname,
+                            capture_samplerate as u32,
+                            chunksize as Frames,
+                            buffer_frames as Frames,
+                            channels as u32,
+                            &sample_format,
+                            true,
+                        )
+                    }) {
+                        Ok(pcmdevice) => break Ok(pcmdevice),
+                        Err(err)
+                            if is_device_busy(err.as_ref())
+                                && open_start.elapsed() < open_timeout =>
+                        {
+                            warn!(
+                                "Capture device {} is busy, retrying. Error: {}",
+                                devname, err
+                            );
+                            thread::sleep(open_retry);
+                        }
+                        Err(err) => break Err(err),
+                    }
+                };
+                match open_result {
                     Ok(pcmdevice) => {
+                        let monitor = start_capture_monitor(
+                            &monitor_file,
+                            channels,
+                            capture_samplerate,
+                            &sample_format,
+                        );
                         match status_channel.send(StatusMessage::CaptureReady) {
                             Ok(()) => {}
                             Err(_err) => {}
@@ -966,6 +1193,7 @@ impl CaptureDevice for AlsaCaptureDevice {
                             samplerate,
                             capture_samplerate,
                             async_src,
+                            clock_source,
                             capture_status,
                             stop_on_rate_change,
                             rate_measure_interval,
@@ -984,6 +1212,7 @@ impl CaptureDevice for AlsaCaptureDevice {
                             io,
                             cap_params,
                             resampler,
+                            monitor,
                         );
                     }
                     Err(err) => {
@@ -7,15 +7,19 @@ use crate::coreaudiodevice;
 #[cfg(feature = "cpal-backend")]
 use crate::cpaldevice;
 use crate::filedevice;
+use crate::multidevice;
 #[cfg(feature = "pulse-backend")]
 use crate::pulsedevice;
 #[cfg(target_os = "windows")]
 use crate::wasapidevice;
+use crate::wavfile;
+use clap::crate_version;
 use num_integer as integer;
 use rubato::{
     FftFixedOut, InterpolationParameters, InterpolationType, SincFixedOut, VecResampler,
     WindowFunction,
 };
+use serde::Serialize;
 use std::error;
 use std::fmt;
 use std::sync::mpsc;
@@ -32,6 +36,12 @@ use crate::{CaptureStatus, PlaybackStatus};
 pub const RATE_CHANGE_THRESHOLD_COUNT: usize = 3;
 pub const RATE_CHANGE_THRESHOLD_VALUE: f32 = 0.04;
 
+/// Samplerates to check for when listing the capabilities of a device.
+pub const STANDARD_RATES: [u32; 17] = [
+    5512, 8000, 11025, 16000, 22050, 32000, 44100, 48000, 64000, 88200, 96000, 176400, 192000,
+    352800, 384000, 705600, 768000,
+];
+
 #[derive(Debug)]
 pub struct DeviceError {
     desc: String,
@@ -65,6 +75,7 @@ pub enum AudioMessage {
 }
 
 /// Main container of audio data
+#[derive(Clone)]
 pub struct AudioChunk {
     pub frames: usize,
     pub channels: usize,
@@ -149,6 +160,92 @@ impl AudioChunk {
         }
     }
 
+    /// Replace all samples by zeros, keeping the length of each channel.
+    pub fn set_silent(&mut self) {
+        for waveform in self.waveforms.iter_mut() {
+            waveform.iter_mut().for_each(|value| *value = 0.0);
+        }
+        self.maxval = 0.0;
+        self.minval = 0.0;
+    }
+
+    /// Add the channels that are not used by the pipeline to the first used channel.
+    /// The unused channels are then cleared, so that they are skipped by the processing.
+    pub fn mix_unused_to_first(&mut self, used_channels: &[bool]) {
+        let first = match used_channels.iter().position(|used| *used) {
+            Some(first) => first,
+            None => return,
+        };
+        for (n, used) in used_channels.iter().enumerate() {
+            if *used || n >= self.waveforms.len() || self.waveforms[n].is_empty() {
+                continue;
+            }
+            let extra = std::mem::take(&mut self.waveforms[n]);
+            for (value, extra_value) in self.waveforms[first].iter_mut().zip(extra) {
+                *value += extra_value;
+            }
+        }
+    }
+
+    /// Create a chunk that ramps each channel from the given value to zero over `fade_frames`,
+    /// followed by silence for the rest of the chunk.
+    /// Playing this before closing a device avoids a pop from an abrupt stop of the signal.
+    pub fn fade_to_silence(last_values: &[PrcFmt], fade_frames: usize, frames: usize) -> Self {
+        let fade_frames = fade_frames.min(frames);
+        let waveforms: Vec<Vec<PrcFmt>> = last_values
+            .iter()
+            .map(|value| {
+                (0..frames)
+                    .map(|n| {
+                        if n < fade_frames {
+                            value * (fade_frames - n) as PrcFmt / (fade_frames + 1) as PrcFmt
+                        } else {
+                            0.0
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+        let maxval = last_values
+            .iter()
+            .fold(0.0, |acc: PrcFmt, value| acc.max(*value));
+        let minval = last_values
+            .iter()
+            .fold(0.0, |acc: PrcFmt, value| acc.min(*value));
+        AudioChunk::new(waveforms, maxval, minval, frames, frames)
+    }
+
+    /// Reduce the chunk to the given number of channels, by dropping the channels above it.
+    /// With `sum_to_last`, the dropped channels are first added to the last remaining channel.
+    pub fn remove_surplus(&mut self, channels: usize, sum_to_last: bool) {
+        if channels == 0 || self.waveforms.len() <= channels {
+            return;
+        }
+        let surplus = self.waveforms.split_off(channels);
+        if sum_to_last {
+            let last = &mut self.waveforms[channels - 1];
+            for extra in surplus.iter().filter(|wf| !wf.is_empty()) {
+                if last.is_empty() {
+                    *last = vec![0.0; extra.len()];
+                }
+                for (value, extra_value) in last.iter_mut().zip(extra) {
+                    *value += extra_value;
+                }
+            }
+        }
+        self.channels = channels;
+    }
+
+    /// Copy the first channel to all channels of a chunk with the given number of channels.
+    pub fn duplicate_first(&mut self, channels: usize) {
+        let first = self.waveforms.first().cloned().unwrap_or_default();
+        self.waveforms.resize(channels, Vec::new());
+        for waveform in self.waveforms.iter_mut().skip(1) {
+            *waveform = first.clone();
+        }
+        self.channels = channels;
+    }
+
     pub fn get_stats(&self) -> ChunkStats {
         let rms_peak: Vec<(PrcFmt, PrcFmt)> =
             self.waveforms.iter().map(|wf| rms_and_peak(wf)).collect();
@@ -212,16 +309,188 @@ pub trait CaptureDevice {
     ) -> Res<Box<thread::JoinHandle<()>>>;
 }
 
+/// Description of an available audio device, with the capabilities that could be determined.
+/// Empty lists mean that the capability could not be read, for example because the device is busy.
+#[derive(Clone, Debug, Serialize)]
+pub struct DeviceInfo {
+    pub backend: String,
+    pub capture: bool,
+    pub name: String,
+    pub description: Option<String>,
+    pub channels: Vec<usize>,
+    pub samplerates: Vec<usize>,
+    pub formats: Vec<config::SampleFormat>,
+}
+
+/// List the capture and playback devices of all backends that support device enumeration.
+pub fn list_devices() -> Vec<DeviceInfo> {
+    #[allow(unused_mut)]
+    let mut devices = Vec::new();
+    #[cfg(target_os = "linux")]
+    devices.extend(alsadevice::list_devices());
+    #[cfg(target_os = "macos")]
+    devices.extend(coreaudiodevice::list_devices());
+    #[cfg(target_os = "windows")]
+    devices.extend(wasapidevice::list_devices());
+    #[cfg(feature = "jack-backend")]
+    devices.extend(cpaldevice::list_devices());
+    devices
+}
+
+/// Find the device to open, among the names of the available devices.
+/// With exact matching the configured name is used as it is.
+/// With substring matching, the name must be contained in exactly one of the device names.
+/// A device name that equals the configured name is always preferred.
+pub fn match_device_name(
+    devname: &str,
+    device_match: config::DeviceMatch,
+    available: &[String],
+) -> Res<String> {
+    let available: Vec<(String, Option<String>)> =
+        available.iter().map(|name| (name.clone(), None)).collect();
+    match_device_name_or_description(devname, device_match, &available)
+}
+
+/// Find the device to open, like `match_device_name`, among devices given as names and optional descriptions.
+/// When no device name contains the configured name, it is instead looked for in the descriptions.
+pub fn match_device_name_or_description(
+    devname: &str,
+    device_match: config::DeviceMatch,
+    available: &[(String, Option<String>)],
+) -> Res<String> {
+    if device_match == config::DeviceMatch::Exact
+        || available.iter().any(|(name, _)| name == devname)
+    {
+        return Ok(devname.to_string());
+    }
+    let mut matching: Vec<&String> = available
+        .iter()
+        .filter(|(name, _)| name.contains(devname))
+        .map(|(name, _)| name)
+        .collect();
+    if matching.is_empty() {
+        matching = available
+            .iter()
+            .filter(|(_, desc)| desc.as_ref().map_or(false, |desc| desc.contains(devname)))
+            .map(|(name, _)| name)
+            .collect();
+    }
+    match matching.len() {
+        1 => {
+            info!("Device \"{}\" resolved to \"{}\"", devname, matching[0]);
+            Ok(matching[0].to_string())
+        }
+        0 => {
+            let msg = format!(
+                "No device name or description contains \"{}\", available devices: {}",
+                devname,
+                available
+                    .iter()
+                    .map(|(name, _)| name.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(", ")
+            );
+            Err(config::ConfigError::new(&msg).into())
+        }
+        _ => {
+            let msg = format!(
+                "Several devices match \"{}\": {}",
+                devname,
+                matching
+                    .iter()
+                    .map(|name| name.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(", ")
+            );
+            Err(config::ConfigError::new(&msg).into())
+        }
+    }
+}
+
+/// Format a list of numbers, writing consecutive values as a range.
+fn format_numbers(values: &[usize]) -> String {
+    if values.is_empty() {
+        return "?".to_string();
+    }
+    if values.len() > 2 && values.windows(2).all(|pair| pair[1] == pair[0] + 1) {
+        return format!("{}-{}", values[0], values[values.len() - 1]);
+    }
+    values
+        .iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+/// Format a list of devices as a table, with one row per device and direction.
+pub fn format_device_table(devices: &[DeviceInfo]) -> String {
+    let header = [
+        "Backend".to_string(),
+        "Direction".to_string(),
+        "Name".to_string(),
+        "Channels".to_string(),
+        "Samplerates".to_string(),
+        "Formats".to_string(),
+    ];
+    let mut rows = vec![header];
+    for dev in devices.iter() {
+        let formats = if dev.formats.is_empty() {
+            "?".to_string()
+        } else {
+            dev.formats
+                .iter()
+                .map(|f| format!("{:?}", f))
+                .collect::<Vec<String>>()
+                .join(",")
+        };
+        rows.push([
+            dev.backend.clone(),
+            if dev.capture { "Capture" } else { "Playback" }.to_string(),
+            dev.name.clone(),
+            format_numbers(&dev.channels),
+            format_numbers(&dev.samplerates),
+            formats,
+        ]);
+    }
+    let mut widths = [0; 6];
+    for row in rows.iter() {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let mut table = String::new();
+    for (n, row) in rows.iter().enumerate() {
+        let line = row
+            .iter()
+            .zip(widths.iter())
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<String>>()
+            .join("  ");
+        table.push_str(line.trim_end());
+        table.push('\n');
+        if n > 0 {
+            if let Some(desc) = &devices[n - 1].description {
+                let desc = desc.lines().collect::<Vec<&str>>().join(", ");
+                table.push_str(&format!("    {}\n", desc));
+            }
+        }
+    }
+    table
+}
+
 /// Create a playback device.
-pub fn get_playback_device(conf: config::Devices) -> Box<dyn PlaybackDevice> {
-    match conf.playback {
+/// Create a playback device. The config hash is only used when writing metadata to an output file.
+pub fn get_playback_device(conf: config::Devices, config_hash: &str) -> Box<dyn PlaybackDevice> {
+    match conf.playback.clone() {
         #[cfg(target_os = "linux")]
         config::PlaybackDevice::Alsa {
             channels,
             device,
             format,
+            device_match,
         } => Box::new(alsadevice::AlsaPlaybackDevice {
             devname: device,
+            device_match,
             samplerate: conf.samplerate,
             chunksize: conf.chunksize,
             channels,
@@ -229,6 +498,7 @@ pub fn get_playback_device(conf: config::Devices) -> Box<dyn PlaybackDevice> {
             target_level: conf.target_level,
             adjust_period: conf.adjust_period,
             enable_rate_adjust: conf.enable_rate_adjust,
+            int_conversion: conf.int_conversion,
         }),
         #[cfg(feature = "pulse-backend")]
         config::PlaybackDevice::Pulse {
@@ -241,18 +511,32 @@ pub fn get_playback_device(conf: config::Devices) -> Box<dyn PlaybackDevice> {
             chunksize: conf.chunksize,
             channels,
             sample_format: format,
+            int_conversion: conf.int_conversion,
         }),
         config::PlaybackDevice::File {
             channels,
             filename,
             format,
-            ..
+            rollover_size,
+            rollover_duration,
+            bwf_metadata,
         } => Box::new(filedevice::FilePlaybackDevice {
             destination: filedevice::PlaybackDest::Filename(filename),
             samplerate: conf.samplerate,
             chunksize: conf.chunksize,
             channels,
             sample_format: format,
+            rollover_size,
+            rollover_duration,
+            bwf_metadata: if bwf_metadata {
+                Some(wavfile::BwfMetadata {
+                    version: crate_version!().to_string(),
+                    config_hash: config_hash.to_string(),
+                })
+            } else {
+                None
+            },
+            int_conversion: conf.int_conversion,
         }),
         config::PlaybackDevice::Stdout {
             channels, format, ..
@@ -262,6 +546,10 @@ pub fn get_playback_device(conf: config::Devices) -> Box<dyn PlaybackDevice> {
             chunksize: conf.chunksize,
             channels,
             sample_format: format,
+            rollover_size: None,
+            rollover_duration: None,
+            bwf_metadata: None,
+            int_conversion: conf.int_conversion,
         }),
         #[cfg(target_os = "macos")]
         config::PlaybackDevice::CoreAudio {
@@ -288,8 +576,10 @@ pub fn get_playback_device(conf: config::Devices) -> Box<dyn PlaybackDevice> {
             device,
             format,
             exclusive,
+            device_match,
         } => Box::new(wasapidevice::WasapiPlaybackDevice {
             devname: device,
+            device_match,
             samplerate: conf.samplerate,
             chunksize: conf.chunksize,
             exclusive,
@@ -298,19 +588,55 @@ pub fn get_playback_device(conf: config::Devices) -> Box<dyn PlaybackDevice> {
             target_level: conf.target_level,
             adjust_period: conf.adjust_period,
             enable_rate_adjust: conf.enable_rate_adjust,
+            int_conversion: conf.int_conversion,
         }),
         #[cfg(all(feature = "cpal-backend", feature = "jack-backend"))]
-        config::PlaybackDevice::Jack { channels, device } => {
-            Box::new(cpaldevice::CpalPlaybackDevice {
-                devname: device,
-                host: cpaldevice::CpalHost::Jack,
+        config::PlaybackDevice::Jack {
+            channels,
+            device,
+            autoconnect,
+            connect_timeout,
+        } => Box::new(cpaldevice::CpalPlaybackDevice {
+            devname: device,
+            host: cpaldevice::CpalHost::Jack {
+                autoconnect,
+                connect_timeout,
+            },
+            samplerate: conf.samplerate,
+            chunksize: conf.chunksize,
+            channels,
+            sample_format: config::SampleFormat::FLOAT32LE,
+            target_level: conf.target_level,
+            adjust_period: conf.adjust_period,
+            enable_rate_adjust: conf.enable_rate_adjust,
+        }),
+        config::PlaybackDevice::Multi { devices } => {
+            let channels = devices.iter().map(|dev| dev.channels()).collect();
+            // The first device controls the rate adjust of the capture device,
+            // and the others control the rate of their own resamplers.
+            let resampler_conf =
+                if !conf.enable_rate_adjust || conf.resampling == config::ResamplingMode::None {
+                    None
+                } else if resampler_is_async(&conf.resampler_type) {
+                    Some(conf.resampler_type.clone())
+                } else {
+                    Some(config::Resampler::BalancedAsync)
+                };
+            let devices = devices
+                .into_iter()
+                .map(|dev| {
+                    let mut dev_conf = conf.clone();
+                    dev_conf.playback = dev;
+                    get_playback_device(dev_conf, config_hash)
+                })
+                .collect();
+            Box::new(multidevice::MultiPlaybackDevice {
+                devices,
+                channels,
+                queuelimit: conf.queuelimit,
+                resampler_conf,
                 samplerate: conf.samplerate,
                 chunksize: conf.chunksize,
-                channels,
-                sample_format: config::SampleFormat::FLOAT32LE,
-                target_level: conf.target_level,
-                adjust_period: conf.adjust_period,
-                enable_rate_adjust: conf.enable_rate_adjust,
             })
         }
     }
@@ -454,34 +780,43 @@ pub fn get_resampler(
 /// Create a capture device.
 pub fn get_capture_device(conf: config::Devices) -> Box<dyn CaptureDevice> {
     //let resampler = get_resampler(&conf);
-    let capture_samplerate = if conf.capture_samplerate > 0 && conf.enable_resampling {
+    let enable_resampling =
+        conf.enable_resampling && conf.resampling != config::ResamplingMode::None;
+    if conf.resampling == config::ResamplingMode::None {
+        debug!("Resampling is set to none, captured audio is passed on without resampling");
+    }
+    let capture_samplerate = if conf.capture_samplerate > 0 && enable_resampling {
         conf.capture_samplerate
     } else {
         conf.samplerate
     };
     let diff_rates = capture_samplerate != conf.samplerate;
     // Check for non-optimal resampling settings
-    if !diff_rates && conf.enable_resampling && !conf.enable_rate_adjust {
+    if !diff_rates && enable_resampling && !conf.enable_rate_adjust {
         warn!(
             "Needless 1:1 sample rate conversion active. Not needed since enable_rate_adjust=False"
         );
     } else if diff_rates
-        && conf.enable_resampling
+        && enable_resampling
         && !conf.enable_rate_adjust
         && resampler_is_async(&conf.resampler_type)
     {
         info!("Using Async resampler for synchronous resampling. Consider switching to \"Synchronous\" to save CPU time.");
     }
-    match conf.capture {
+    match conf.capture.clone() {
         #[cfg(target_os = "linux")]
         config::CaptureDevice::Alsa {
             channels,
             device,
             format,
+            open_timeout_ms,
+            open_retry_ms,
+            device_match,
         } => Box::new(alsadevice::AlsaCaptureDevice {
             devname: device,
+            device_match,
             samplerate: conf.samplerate,
-            enable_resampling: conf.enable_resampling,
+            enable_resampling,
             capture_samplerate,
             resampler_conf: conf.resampler_type,
             chunksize: conf.chunksize,
@@ -491,6 +826,10 @@ pub fn get_capture_device(conf: config::Devices) -> Box<dyn CaptureDevice> {
             silence_timeout: conf.silence_timeout,
             stop_on_rate_change: conf.stop_on_rate_change,
             rate_measure_interval: conf.rate_measure_interval,
+            clock_source: conf.clock_source,
+            open_timeout_ms,
+            open_retry_ms,
+            monitor_file: conf.capture_monitor_file.clone(),
         }),
         #[cfg(feature = "pulse-backend")]
         config::CaptureDevice::Pulse {
@@ -500,7 +839,7 @@ pub fn get_capture_device(conf: config::Devices) -> Box<dyn CaptureDevice> {
         } => Box::new(pulsedevice::PulseCaptureDevice {
             devname: device,
             samplerate: conf.samplerate,
-            enable_resampling: conf.enable_resampling,
+            enable_resampling,
             resampler_conf: conf.resampler_type,
             capture_samplerate,
             chunksize: conf.chunksize,
@@ -508,6 +847,7 @@ pub fn get_capture_device(conf: config::Devices) -> Box<dyn CaptureDevice> {
             sample_format: format,
             silence_threshold: conf.silence_threshold,
             silence_timeout: conf.silence_timeout,
+            monitor_file: conf.capture_monitor_file.clone(),
         }),
         config::CaptureDevice::File {
             channels,
@@ -516,10 +856,13 @@ pub fn get_capture_device(conf: config::Devices) -> Box<dyn CaptureDevice> {
             extra_samples,
             skip_bytes,
             read_bytes,
+            loop_start,
+            loop_end,
+            loop_crossfade,
         } => Box::new(filedevice::FileCaptureDevice {
             source: filedevice::CaptureSource::Filename(filename),
             samplerate: conf.samplerate,
-            enable_resampling: conf.enable_resampling,
+            enable_resampling,
             capture_samplerate,
             resampler_conf: conf.resampler_type,
             chunksize: conf.chunksize,
@@ -530,8 +873,12 @@ pub fn get_capture_device(conf: config::Devices) -> Box<dyn CaptureDevice> {
             silence_timeout: conf.silence_timeout,
             skip_bytes,
             read_bytes,
+            loop_start: loop_start.unwrap_or(0),
+            loop_end,
+            loop_crossfade,
             stop_on_rate_change: conf.stop_on_rate_change,
             rate_measure_interval: conf.rate_measure_interval,
+            monitor_file: conf.capture_monitor_file.clone(),
         }),
         config::CaptureDevice::Stdin {
             channels,
@@ -542,7 +889,7 @@ pub fn get_capture_device(conf: config::Devices) -> Box<dyn CaptureDevice> {
         } => Box::new(filedevice::FileCaptureDevice {
             source: filedevice::CaptureSource::Stdin,
             samplerate: conf.samplerate,
-            enable_resampling: conf.enable_resampling,
+            enable_resampling,
             capture_samplerate,
             resampler_conf: conf.resampler_type,
             chunksize: conf.chunksize,
@@ -553,8 +900,12 @@ pub fn get_capture_device(conf: config::Devices) -> Box<dyn CaptureDevice> {
             silence_timeout: conf.silence_timeout,
             skip_bytes,
             read_bytes,
+            loop_start: 0,
+            loop_end: None,
+            loop_crossfade: 0,
             stop_on_rate_change: conf.stop_on_rate_change,
             rate_measure_interval: conf.rate_measure_interval,
+            monitor_file: conf.capture_monitor_file.clone(),
         }),
         #[cfg(target_os = "macos")]
         config::CaptureDevice::CoreAudio {
@@ -565,7 +916,7 @@ pub fn get_capture_device(conf: config::Devices) -> Box<dyn CaptureDevice> {
         } => Box::new(coreaudiodevice::CoreaudioCaptureDevice {
             devname: device,
             samplerate: conf.samplerate,
-            enable_resampling: conf.enable_resampling,
+            enable_resampling,
             resampler_conf: conf.resampler_type,
             capture_samplerate,
             chunksize: conf.chunksize,
@@ -576,6 +927,7 @@ pub fn get_capture_device(conf: config::Devices) -> Box<dyn CaptureDevice> {
             silence_timeout: conf.silence_timeout,
             stop_on_rate_change: conf.stop_on_rate_change,
             rate_measure_interval: conf.rate_measure_interval,
+            monitor_file: conf.capture_monitor_file.clone(),
         }),
         #[cfg(target_os = "windows")]
         config::CaptureDevice::Wasapi {
@@ -584,12 +936,14 @@ pub fn get_capture_device(conf: config::Devices) -> Box<dyn CaptureDevice> {
             format,
             exclusive,
             loopback,
+            device_match,
         } => Box::new(wasapidevice::WasapiCaptureDevice {
             devname: device,
+            device_match,
             samplerate: conf.samplerate,
             exclusive,
             loopback,
-            enable_resampling: conf.enable_resampling,
+            enable_resampling,
             resampler_conf: conf.resampler_type,
             capture_samplerate,
             chunksize: conf.chunksize,
@@ -599,23 +953,64 @@ pub fn get_capture_device(conf: config::Devices) -> Box<dyn CaptureDevice> {
             silence_timeout: conf.silence_timeout,
             stop_on_rate_change: conf.stop_on_rate_change,
             rate_measure_interval: conf.rate_measure_interval,
+            monitor_file: conf.capture_monitor_file.clone(),
         }),
         #[cfg(all(feature = "cpal-backend", feature = "jack-backend"))]
-        config::CaptureDevice::Jack { channels, device } => {
-            Box::new(cpaldevice::CpalCaptureDevice {
-                devname: device,
-                host: cpaldevice::CpalHost::Jack,
+        config::CaptureDevice::Jack {
+            channels,
+            device,
+            autoconnect,
+            connect_timeout,
+        } => Box::new(cpaldevice::CpalCaptureDevice {
+            devname: device,
+            host: cpaldevice::CpalHost::Jack {
+                autoconnect,
+                connect_timeout,
+            },
+            samplerate: conf.samplerate,
+            enable_resampling,
+            resampler_conf: conf.resampler_type,
+            capture_samplerate,
+            chunksize: conf.chunksize,
+            channels,
+            sample_format: config::SampleFormat::FLOAT32LE,
+            silence_threshold: conf.silence_threshold,
+            silence_timeout: conf.silence_timeout,
+            stop_on_rate_change: conf.stop_on_rate_change,
+            rate_measure_interval: conf.rate_measure_interval,
+            monitor_file: conf.capture_monitor_file.clone(),
+        }),
+        config::CaptureDevice::Multi { devices } => {
+            let channels = devices.iter().map(|dev| dev.channels()).collect();
+            // The devices after the first follow its clock using their own resamplers
+            let resampler_conf =
+                if !conf.enable_rate_adjust || conf.resampling == config::ResamplingMode::None {
+                    None
+                } else if resampler_is_async(&conf.resampler_type) {
+                    Some(conf.resampler_type.clone())
+                } else {
+                    Some(config::Resampler::BalancedAsync)
+                };
+            let devices = devices
+                .into_iter()
+                .map(|dev| {
+                    let mut dev_conf = conf.clone();
+                    dev_conf.capture = dev;
+                    // The combined capture is monitored, not the single devices
+                    dev_conf.capture_monitor_file = None;
+                    get_capture_device(dev_conf)
+                })
+                .collect();
+            Box::new(multidevice::MultiCaptureDevice {
+                devices,
+                channels,
+                queuelimit: conf.queuelimit,
+                resampler_conf,
                 samplerate: conf.samplerate,
-                enable_resampling: conf.enable_resampling,
-                resampler_conf: conf.resampler_type,
-                capture_samplerate,
                 chunksize: conf.chunksize,
-                channels,
-                sample_format: config::SampleFormat::FLOAT32LE,
-                silence_threshold: conf.silence_threshold,
-                silence_timeout: conf.silence_timeout,
-                stop_on_rate_change: conf.stop_on_rate_change,
-                rate_measure_interval: conf.rate_measure_interval,
+                adjust_period: conf.adjust_period,
+                monitor_file: conf.capture_monitor_file.clone(),
+                sample_format: conf.capture.sampleformat(),
             })
         }
     }
@@ -635,7 +1030,10 @@ pub fn calculate_speed(avg_level: f64, target_level: usize, adjust_period: f32,
 
 #[cfg(test)]
 mod tests {
-    use crate::audiodevice::{rms_and_peak, AudioChunk, ChunkStats};
+    use crate::audiodevice::{
+        match_device_name, match_device_name_or_description, rms_and_peak, AudioChunk, ChunkStats,
+    };
+    use crate::config::DeviceMatch;
 
     #[test]
     fn vec_rms_and_peak() {
@@ -668,4 +1066,122 @@ mod tests {
         assert_eq!(0.0, stats.peak_db()[0]);
         assert!(stats.rms_db()[1] > -6.1 && stats.rms_db()[1] < -5.9);
     }
+
+    #[test]
+    fn mix_unused_channels() {
+        let waveforms = vec![
+            vec![0.0, 0.0],
+            vec![1.0, 2.0],
+            vec![0.5, -1.0],
+            vec![3.0, 3.0],
+        ];
+        let mut chunk = AudioChunk::new(waveforms, 0.0, 0.0, 2, 2);
+        chunk.mix_unused_to_first(&[false, true, false, true]);
+        assert!(chunk.waveforms[0].is_empty());
+        assert_eq!(chunk.waveforms[1], vec![1.5, 1.0]);
+        assert!(chunk.waveforms[2].is_empty());
+        assert_eq!(chunk.waveforms[3], vec![3.0, 3.0]);
+    }
+
+    #[test]
+    fn duplicate_mono() {
+        let mut chunk = AudioChunk::new(vec![vec![0.5, -1.0]], 0.5, -1.0, 2, 2);
+        chunk.duplicate_first(3);
+        assert_eq!(chunk.channels, 3);
+        assert_eq!(chunk.waveforms, vec![vec![0.5, -1.0]; 3]);
+    }
+
+    #[test]
+    fn remove_surplus_channels() {
+        let waveforms = vec![vec![1.0, 2.0], vec![0.5, 0.5], vec![0.25, -1.0]];
+        let mut chunk = AudioChunk::new(waveforms.clone(), 2.0, -1.0, 2, 2);
+        chunk.remove_surplus(2, false);
+        assert_eq!(chunk.channels, 2);
+        assert_eq!(chunk.waveforms, vec![vec![1.0, 2.0], vec![0.5, 0.5]]);
+        let mut chunk = AudioChunk::new(waveforms, 2.0, -1.0, 2, 2);
+        chunk.remove_surplus(2, true);
+        assert_eq!(chunk.waveforms, vec![vec![1.0, 2.0], vec![0.75, -0.5]]);
+    }
+
+    #[test]
+    fn fade_chunk() {
+        let chunk = AudioChunk::fade_to_silence(&[1.0, -0.5], 3, 5);
+        assert_eq!(chunk.channels, 2);
+        assert_eq!(chunk.waveforms[0], vec![0.75, 0.5, 0.25, 0.0, 0.0]);
+        assert_eq!(chunk.waveforms[1], vec![-0.375, -0.25, -0.125, 0.0, 0.0]);
+        assert_eq!(chunk.maxval, 1.0);
+        assert_eq!(chunk.minval, -0.5);
+    }
+
+    #[test]
+    fn silent_chunk() {
+        let waveforms = vec![vec![1.0, -2.0], Vec::new()];
+        let mut chunk = AudioChunk::new(waveforms, 1.0, -2.0, 2, 2);
+        chunk.set_silent();
+        assert_eq!(chunk.waveforms, vec![vec![0.0, 0.0], Vec::new()]);
+        assert_eq!(chunk.maxval, 0.0);
+        assert_eq!(chunk.minval, 0.0);
+    }
+
+    #[test]
+    fn device_name_substring() {
+        let available = vec![
+            "hw:CARD=USB,DEV=0".to_string(),
+            "plughw:CARD=USB,DEV=0".to_string(),
+            "hw:CARD=PCH,DEV=0".to_string(),
+        ];
+        assert_eq!(
+            match_device_name("hw:CARD=PCH", DeviceMatch::Substring, &available).unwrap(),
+            "hw:CARD=PCH,DEV=0"
+        );
+        assert_eq!(
+            match_device_name("hw:CARD=USB,DEV=0", DeviceMatch::Substring, &available).unwrap(),
+            "hw:CARD=USB,DEV=0"
+        );
+        assert!(match_device_name("CARD=USB", DeviceMatch::Substring, &available).is_err());
+        assert!(match_device_name("Dummy", DeviceMatch::Substring, &available).is_err());
+        assert_eq!(
+            match_device_name("Dummy", DeviceMatch::Exact, &available).unwrap(),
+            "Dummy"
+        );
+    }
+
+    #[test]
+    fn device_description_substring() {
+        let available = vec![
+            (
+                "hw:CARD=USB,DEV=0".to_string(),
+                Some("USB Audio CODEC, USB Audio".to_string()),
+            ),
+            ("plughw:CARD=USB,DEV=0".to_string(), None),
+            (
+                "hw:CARD=PCH,DEV=0".to_string(),
+                Some("HDA Intel PCH, ALC892 Analog".to_string()),
+            ),
+            (
+                "hw:CARD=PCH,DEV=1".to_string(),
+                Some("HDA Intel PCH, ALC892 Digital".to_string()),
+            ),
+        ];
+        assert_eq!(
+            match_device_name_or_description("USB Audio CODEC", DeviceMatch::Substring, &available)
+                .unwrap(),
+            "hw:CARD=USB,DEV=0"
+        );
+        assert_eq!(
+            match_device_name_or_description("ALC892 Digital", DeviceMatch::Substring, &available)
+                .unwrap(),
+            "hw:CARD=PCH,DEV=1"
+        );
+        // a match on the name is preferred over one on the description
+        assert_eq!(
+            match_device_name_or_description("plughw:CARD=USB", DeviceMatch::Substring, &available)
+                .unwrap(),
+            "plughw:CARD=USB,DEV=0"
+        );
+        assert!(
+            match_device_name_or_description("HDA Intel", DeviceMatch::Substring, &available)
+                .is_err()
+        );
+    }
 }
@@ -1,9 +1,13 @@
+use rand::thread_rng;
+use rand_distr::{Distribution, Triangular};
 use std::sync::{Arc, RwLock};
 
+use crate::audiodevice::AudioChunk;
 use crate::biquad::{Biquad, BiquadCoefficients};
 use crate::config;
 use crate::fifoqueue::FifoQueue;
 use crate::filters::Filter;
+use num_complex::Complex;
 
 use crate::NewValue;
 use crate::PrcFmt;
@@ -23,32 +27,127 @@ pub struct Delay {
     biquad: Option<Biquad>,
 }
 
+/// Waveshaper that maps each sample through a piecewise linear transfer curve.
+pub struct Shaper {
+    pub name: String,
+    inputs: Vec<PrcFmt>,
+    outputs: Vec<PrcFmt>,
+}
+
+/// One-pole highpass filter for removing DC offset.
+pub struct DcBlock {
+    coeff: PrcFmt,
+    prev_input: PrcFmt,
+    prev_output: PrcFmt,
+}
+
+/// Slow automatic gain control, bringing the RMS level of the signal towards a target level.
+pub struct Agc {
+    target_level: PrcFmt,
+    max_gain: PrcFmt,
+    gate_level: PrcFmt,
+    attack_coeff: PrcFmt,
+    release_coeff: PrcFmt,
+    current_gain: PrcFmt,
+}
+
 pub struct Volume {
     pub name: String,
     ramptime_in_chunks: usize,
+    ramp_chunks: usize,
     current_volume: PrcFmt,
     target_volume: f32,
     target_linear_gain: PrcFmt,
     mute: bool,
     ramp_start: PrcFmt,
     ramp_step: usize,
+    mute_fade_step: Option<PrcFmt>,
+    mute_gain: PrcFmt,
+    gain_dither: bool,
     samplerate: usize,
     chunksize: usize,
     processing_status: Arc<RwLock<ProcessingParameters>>,
 }
 
+/// Get the change in linear gain per sample for a mute fade of the given length.
+pub fn get_mute_fade_step(mute_fade_ms: Option<f32>, samplerate: usize) -> Option<PrcFmt> {
+    mute_fade_ms.map(|fade_ms| {
+        let fade_samples = (fade_ms / 1000.0 * samplerate as f32).round();
+        if fade_samples >= 1.0 {
+            1.0 / fade_samples as PrcFmt
+        } else {
+            1.0
+        }
+    })
+}
+
+/// Longest delay that can be used, to avoid allocating huge buffers because of a typo.
+/// This also limits the `extra_samples` of the File and Stdin capture devices.
+pub const MAX_DELAY_SECONDS: PrcFmt = 10.0;
+
+/// Ramps longer than this make a volume control feel unresponsive.
+const LONG_RAMP_TIME_MS: f32 = 5000.0;
+
+/// Get the length of a volume ramp in chunks, rounded to the nearest number of chunks.
+pub fn get_ramp_chunks(ramp_time_ms: f32, chunksize: usize, samplerate: usize) -> usize {
+    (ramp_time_ms / (1000.0 * chunksize as f32 / samplerate as f32)).round() as usize
+}
+
+/// Check if a ramp time gives a reasonable ramp for the given chunksize,
+/// and return a description of the problem if not.
+pub fn check_ramp_time(ramp_time_ms: f32, chunksize: usize, samplerate: usize) -> Option<String> {
+    let chunk_time_ms = 1000.0 * chunksize as f32 / samplerate as f32;
+    let ramp_chunks = get_ramp_chunks(ramp_time_ms, chunksize, samplerate);
+    if ramp_time_ms > 0.0 && ramp_chunks < 2 {
+        Some(format!(
+            "ramp_time of {} ms is rounded to {} chunk(s) of {:.1} ms, the volume will change in a single step",
+            ramp_time_ms, ramp_chunks, chunk_time_ms
+        ))
+    } else if ramp_time_ms > LONG_RAMP_TIME_MS {
+        Some(format!(
+            "ramp_time of {} ms is very long, the volume will be slow to respond to changes",
+            ramp_time_ms
+        ))
+    } else {
+        None
+    }
+}
+
+/// Add triangular noise to the gain factors of a volume ramp.
+/// The noise amplitude is one step of the ramp, which masks the stepping of the gain
+/// when the output is quantized to a low bit depth.
+pub fn dither_gain_ramp(ramp: &mut [PrcFmt]) {
+    if ramp.len() < 2 {
+        return;
+    }
+    let step = (ramp[ramp.len() - 1] - ramp[0]).abs() / (ramp.len() - 1) as PrcFmt;
+    if step == 0.0 {
+        return;
+    }
+    let dith_rng = Triangular::new(-1.0, 1.0, 0.0).unwrap();
+    for (gain, dith) in ramp.iter_mut().zip(dith_rng.sample_iter(thread_rng())) {
+        *gain = (*gain + step * dith).max(0.0);
+    }
+}
+
 impl Volume {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         name: String,
         ramp_time_ms: f32,
+        mute_fade_ms: Option<f32>,
+        gain_dither: bool,
         current_volume: f32,
         mute: bool,
         chunksize: usize,
         samplerate: usize,
         processing_status: Arc<RwLock<ProcessingParameters>>,
     ) -> Self {
-        let ramptime_in_chunks =
-            (ramp_time_ms / (1000.0 * chunksize as f32 / samplerate as f32)).round() as usize;
+        let ramptime_in_chunks = get_ramp_chunks(ramp_time_ms, chunksize, samplerate);
+        let mute_fade_step = get_mute_fade_step(mute_fade_ms, samplerate);
+        let mute_gain = if mute { 0.0 } else { 1.0 };
+        // With a separate mute fade, the volume ramp never handles muting.
+        let mute = mute && mute_fade_step.is_none();
         let current_volume_with_mute = if mute { -100.0 } else { current_volume };
         let target_linear_gain = if mute {
             0.0
@@ -59,12 +158,16 @@ impl Volume {
         Volume {
             name,
             ramptime_in_chunks,
+            ramp_chunks: ramptime_in_chunks,
             current_volume: current_volume_with_mute as PrcFmt,
             ramp_start: current_volume as PrcFmt,
             target_volume: current_volume as f32,
             target_linear_gain,
             mute,
             ramp_step: 0,
+            mute_fade_step,
+            mute_gain,
+            gain_dither,
             samplerate,
             chunksize,
             processing_status,
@@ -78,11 +181,13 @@ impl Volume {
         samplerate: usize,
         processing_status: Arc<RwLock<ProcessingParameters>>,
     ) -> Self {
-        let current_volume = processing_status.read().unwrap().volume;
+        let current_volume = processing_status.read().unwrap().effective_volume();
         let mute = processing_status.read().unwrap().mute;
         Volume::new(
             name,
             conf.ramp_time,
+            conf.mute_fade_ms,
+            conf.gain_dither,
             current_volume,
             mute,
             chunksize,
@@ -98,8 +203,7 @@ impl Volume {
             self.target_volume
         };
 
-        let ramprange =
-            (target_volume as PrcFmt - self.ramp_start) / self.ramptime_in_chunks as PrcFmt;
+        let ramprange = (target_volume as PrcFmt - self.ramp_start) / self.ramp_chunks as PrcFmt;
         let stepsize = ramprange / self.chunksize as PrcFmt;
         (0..self.chunksize)
             .map(|val| {
@@ -112,6 +216,32 @@ impl Volume {
             })
             .collect()
     }
+
+    /// Apply the mute fade, stepping the mute gain towards its target one sample at a time.
+    fn apply_mute_fade(&mut self, waveform: &mut [PrcFmt], mute: bool, step: PrcFmt) {
+        apply_fade(waveform, &mut self.mute_gain, mute, step);
+    }
+}
+
+/// Fade a waveform out or in, stepping the gain towards 0 or 1 by `step` per sample.
+/// Once faded out, the waveform is silenced.
+pub fn apply_fade(waveform: &mut [PrcFmt], gain: &mut PrcFmt, fade_out: bool, step: PrcFmt) {
+    let target = if fade_out { 0.0 } else { 1.0 };
+    if *gain == target {
+        if fade_out {
+            waveform.iter_mut().for_each(|item| *item = 0.0);
+        }
+        return;
+    }
+    trace!("fade, gain {} -> {}", gain, target);
+    for item in waveform.iter_mut() {
+        *gain = if fade_out {
+            (*gain - step).max(target)
+        } else {
+            (*gain + step).min(target)
+        };
+        *item *= *gain;
+    }
 }
 
 impl Filter for Volume {
@@ -120,12 +250,25 @@ impl Filter for Volume {
     }
 
     fn process_waveform(&mut self, waveform: &mut [PrcFmt]) -> Res<()> {
-        let shared_vol = self.processing_status.read().unwrap().volume;
-        let shared_mute = self.processing_status.read().unwrap().mute;
+        let shared_vol = self.processing_status.read().unwrap().effective_volume();
+        let fade_mute = self.processing_status.read().unwrap().mute;
+        // Muting is handled either by the separate mute fade, or by the volume ramp.
+        let shared_mute = fade_mute && self.mute_fade_step.is_none();
 
         // Volume setting changed
         if (shared_vol - self.target_volume).abs() > 0.01 || self.mute != shared_mute {
-            if self.ramptime_in_chunks > 0 {
+            // A ramp time given together with the new volume is only used for this change
+            let ramp_override = self
+                .processing_status
+                .read()
+                .unwrap()
+                .ramp_time_override(shared_vol)
+                .filter(|_| (shared_vol - self.target_volume).abs() > 0.01);
+            self.ramp_chunks = match ramp_override {
+                Some(ramp_ms) => get_ramp_chunks(ramp_ms, self.chunksize, self.samplerate),
+                None => self.ramptime_in_chunks,
+            };
+            if self.ramp_chunks > 0 {
                 trace!(
                     "starting ramp: {} -> {}, mute: {}",
                     self.current_volume,
@@ -165,32 +308,60 @@ impl Filter for Volume {
             }
         }
         // Ramping
-        else if self.ramp_step <= self.ramptime_in_chunks {
+        else if self.ramp_step <= self.ramp_chunks {
             trace!("ramp step {}", self.ramp_step);
-            let ramp = self.make_ramp();
+            let mut ramp = self.make_ramp();
+            let last_gain = *ramp.last().unwrap();
+            if self.gain_dither {
+                dither_gain_ramp(&mut ramp);
+            }
             self.ramp_step += 1;
-            if self.ramp_step > self.ramptime_in_chunks {
+            if self.ramp_step > self.ramp_chunks {
                 // Last step of ramp
                 self.ramp_step = 0;
             }
             for (item, stepgain) in waveform.iter_mut().zip(ramp.iter()) {
                 *item *= *stepgain;
             }
-            self.current_volume = 20.0 * ramp.last().unwrap().log10();
+            self.current_volume = 20.0 * last_gain.log10();
+        }
+        if let Some(step) = self.mute_fade_step {
+            self.apply_mute_fade(waveform, fade_mute, step);
         }
         Ok(())
     }
 
+    // The volume ramp is not filter state, and is left running.
+    fn reset(&mut self) {}
+
     fn update_parameters(&mut self, conf: config::Filter) {
         if let config::Filter::Volume { parameters: conf } = conf {
-            self.ramptime_in_chunks = (conf.ramp_time
-                / (1000.0 * self.chunksize as f32 / self.samplerate as f32))
-                .round() as usize;
+            self.ramptime_in_chunks =
+                get_ramp_chunks(conf.ramp_time, self.chunksize, self.samplerate);
+            self.mute_fade_step = get_mute_fade_step(conf.mute_fade_ms, self.samplerate);
+            self.gain_dither = conf.gain_dither;
         } else {
             // This should never happen unless there is a bug somewhere else
             panic!("Invalid config change!");
         }
     }
+
+    /// The response is the current gain, following the volume ramp and the mute fade.
+    fn frequency_response(
+        &self,
+        frequencies: &[PrcFmt],
+        _samplerate: usize,
+    ) -> Option<Vec<Complex<PrcFmt>>> {
+        let gain = if self.ramp_step == 0 {
+            self.target_linear_gain
+        } else {
+            (10.0 as PrcFmt).powf(self.current_volume / 20.0)
+        };
+        Some(vec![
+            Complex::new(gain * self.mute_gain, 0.0);
+            frequencies.len()
+        ])
+    }
 }
 
 impl Gain {
@@ -227,6 +398,8 @@ impl Filter for Gain {
         Ok(())
     }
 
+    fn reset(&mut self) {}
+
     fn update_parameters(&mut self, conf: config::Filter) {
         if let config::Filter::Gain { parameters: conf } = conf {
             let gain_db = conf.gain;
@@ -245,6 +418,63 @@ impl Filter for Gain {
             panic!("Invalid config change!");
         }
     }
+
+    fn frequency_response(
+        &self,
+        frequencies: &[PrcFmt],
+        _samplerate: usize,
+    ) -> Option<Vec<Complex<PrcFmt>>> {
+        Some(vec![Complex::new(self.gain, 0.0); frequencies.len()])
+    }
+}
+
+impl Shaper {
+    pub fn from_config(name: String, conf: config::ShaperParameters) -> Self {
+        let (inputs, outputs) = conf.curve.iter().map(|point| (point[0], point[1])).unzip();
+        Shaper {
+            name,
+            inputs,
+            outputs,
+        }
+    }
+
+    /// Interpolate linearly between the points of the curve.
+    /// Values outside the curve give the output of the nearest end point.
+    fn apply(&self, value: PrcFmt) -> PrcFmt {
+        if value.is_nan() || value <= self.inputs[0] {
+            return self.outputs[0];
+        }
+        let idx = self.inputs.partition_point(|input| *input < value);
+        if idx == self.inputs.len() {
+            return self.outputs[idx - 1];
+        }
+        let fract = (value - self.inputs[idx - 1]) / (self.inputs[idx] - self.inputs[idx - 1]);
+        self.outputs[idx - 1] + fract * (self.outputs[idx] - self.outputs[idx - 1])
+    }
+}
+
+impl Filter for Shaper {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn process_waveform(&mut self, waveform: &mut [PrcFmt]) -> Res<()> {
+        for item in waveform.iter_mut() {
+            *item = self.apply(*item);
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) {}
+
+    fn update_parameters(&mut self, conf: config::Filter) {
+        if let config::Filter::Shaper { parameters: conf } = conf {
+            *self = Shaper::from_config(self.name.clone(), conf);
+        } else {
+            // This should never happen unless there is a bug somewhere else
+            panic!("Invalid config change!");
+        }
+    }
 }
 
 impl Delay {
@@ -289,6 +519,90 @@ impl Delay {
     }
 }
 
+impl Agc {
+    /// Creates an AGC. The attack and release times are the time constants in seconds
+    /// for decreasing and increasing the gain.
+    pub fn from_config(conf: &config::AgcParameters, samplerate: usize, chunksize: usize) -> Self {
+        let chunk_period = chunksize as PrcFmt / samplerate as PrcFmt;
+        Agc {
+            target_level: conf.target_level as PrcFmt,
+            max_gain: conf.max_gain as PrcFmt,
+            gate_level: conf.gate_level as PrcFmt,
+            attack_coeff: (-chunk_period / conf.attack as PrcFmt).exp(),
+            release_coeff: (-chunk_period / conf.release as PrcFmt).exp(),
+            current_gain: 0.0,
+        }
+    }
+
+    /// Get the current gain in dB.
+    pub fn gain(&self) -> PrcFmt {
+        self.current_gain
+    }
+
+    /// Update the gain from the RMS level of the chunk, and apply it with a ramp from the previous value.
+    /// The gain is held while the level is below the gate level, to avoid boosting noise during pauses.
+    pub fn process_chunk(&mut self, chunk: &mut AudioChunk) {
+        let (squaresum, nbr_values) =
+            chunk
+                .waveforms
+                .iter()
+                .filter(|wf| !wf.is_empty())
+                .fold((0.0, 0), |(sum, nbr), wf| {
+                    let valid = &wf[0..chunk.valid_frames.min(wf.len())];
+                    (
+                        sum + valid.iter().map(|v| v * v).sum::<PrcFmt>(),
+                        nbr + valid.len(),
+                    )
+                });
+        if nbr_values == 0 {
+            return;
+        }
+        let level = 10.0 * (squaresum / nbr_values as PrcFmt).max(1.0e-20).log10();
+        let prev_gain = self.current_gain;
+        if level > self.gate_level {
+            let wanted_gain = (self.target_level - level).clamp(-self.max_gain, self.max_gain);
+            let coeff = if wanted_gain < self.current_gain {
+                self.attack_coeff
+            } else {
+                self.release_coeff
+            };
+            self.current_gain = wanted_gain + coeff * (self.current_gain - wanted_gain);
+        }
+        let start = (10.0 as PrcFmt).powf(prev_gain / 20.0);
+        let end = (10.0 as PrcFmt).powf(self.current_gain / 20.0);
+        for wf in chunk.waveforms.iter_mut() {
+            let step = (end - start) / wf.len().max(1) as PrcFmt;
+            for (n, value) in wf.iter_mut().enumerate() {
+                *value *= start + step * n as PrcFmt;
+            }
+        }
+    }
+}
+
+impl DcBlock {
+    /// Creates a DC blocking filter with the given corner frequency.
+    pub fn new(samplerate: usize, freq: PrcFmt) -> Self {
+        let coeff = (-2.0 * (std::f64::consts::PI as PrcFmt) * freq / samplerate as PrcFmt).exp();
+        DcBlock {
+            coeff,
+            prev_input: 0.0,
+            prev_output: 0.0,
+        }
+    }
+
+    pub fn process_waveform(&mut self, waveform: &mut [PrcFmt]) {
+        for item in waveform.iter_mut() {
+            let output = *item - self.prev_input + self.coeff * self.prev_output;
+            self.prev_input = *item;
+            self.prev_output = output;
+            *item = output;
+        }
+        if self.prev_output.is_subnormal() {
+            self.prev_output = 0.0;
+        }
+    }
+}
+
 impl Filter for Delay {
     fn name(&self) -> String {
         self.name.clone()
@@ -305,6 +619,15 @@ impl Filter for Delay {
         Ok(())
     }
 
+    fn reset(&mut self) {
+        let mut queue = FifoQueue::filled_with(self.queue.capacity(), 0.0);
+        let _elem = queue.pop();
+        self.queue = queue;
+        if let Some(bq) = &mut self.biquad {
+            bq.reset();
+        }
+    }
+
     fn update_parameters(&mut self, conf: config::Filter) {
         if let config::Filter::Delay { parameters: conf } = conf {
             let delay_samples = match conf.unit {
@@ -345,10 +668,25 @@ impl Filter for Delay {
 }
 
 /// Validate a Loudness config.
-pub fn validate_delay_config(conf: &config::DelayParameters) -> Res<()> {
+pub fn validate_delay_config(samplerate: usize, conf: &config::DelayParameters) -> Res<()> {
+    if !conf.delay.is_finite() {
+        return Err(config::ConfigError::new("Delay must be a finite number").into());
+    }
     if conf.delay < 0.0 {
         return Err(config::ConfigError::new("Delay cannot be negative").into());
     }
+    let delay_seconds = match conf.unit {
+        config::TimeUnit::Milliseconds => conf.delay / 1000.0,
+        config::TimeUnit::Millimetres => conf.delay / 1000.0 / 343.0,
+        config::TimeUnit::Samples => conf.delay / samplerate as PrcFmt,
+    };
+    if delay_seconds > MAX_DELAY_SECONDS {
+        let msg = format!(
+            "Delay of {:.1} seconds is too long, the maximum is {} seconds",
+            delay_seconds, MAX_DELAY_SECONDS
+        );
+        return Err(config::ConfigError::new(&msg).into());
+    }
     Ok(())
 }
 
@@ -357,6 +695,11 @@ pub fn validate_volume_config(conf: &config::VolumeParameters) -> Res<()> {
     if conf.ramp_time < 0.0 {
         return Err(config::ConfigError::new("Ramp time cannot be negative").into());
     }
+    if let Some(mute_fade) = conf.mute_fade_ms {
+        if mute_fade < 0.0 {
+            return Err(config::ConfigError::new("Mute fade time cannot be negative").into());
+        }
+    }
     Ok(())
 }
 
@@ -370,10 +713,40 @@ pub fn validate_gain_config(conf: &config::GainParameters) -> Res<()> {
     Ok(())
 }
 
+/// Validate a Shaper config.
+pub fn validate_shaper_config(conf: &config::ShaperParameters) -> Res<()> {
+    if conf.curve.len() < 2 {
+        return Err(
+            config::ConfigError::new("The shaper curve must have at least 2 points").into(),
+        );
+    }
+    if conf.curve.iter().flatten().any(|value| !value.is_finite()) {
+        return Err(
+            config::ConfigError::new("The shaper curve must only contain finite values").into(),
+        );
+    }
+    if conf.curve.windows(2).any(|pair| pair[1][0] <= pair[0][0]) {
+        return Err(config::ConfigError::new(
+            "The inputs of the shaper curve must be strictly increasing",
+        )
+        .into());
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::basicfilters::{Delay, Gain};
+    use crate::audiodevice::AudioChunk;
+    use crate::basicfilters::{
+        check_ramp_time, dither_gain_ramp, get_ramp_chunks, validate_delay_config,
+        validate_shaper_config, Agc, DcBlock, Delay, Gain, Shaper, Volume,
+    };
+    use crate::config::{AgcParameters, DelayParameters, ShaperParameters, TimeUnit};
+    use crate::countertimer;
     use crate::filters::Filter;
+    use crate::{ProcessingParameters, VolumeRamp, MAX_VOLUME_DB, MIN_VOLUME_DB};
+    use std::collections::HashMap;
+    use std::sync::{Arc, RwLock};
 
     fn is_close(left: f64, right: f64, maxdiff: f64) -> bool {
         println!("{} - {}", left, right);
@@ -447,4 +820,274 @@ mod tests {
         delay.process_waveform(&mut waveform).unwrap();
         assert!(compare_waveforms(waveform, waveform_delayed, 1.0e-6));
     }
+
+    #[test]
+    fn delay_limits() {
+        let mut conf = DelayParameters {
+            delay: 500.0,
+            unit: TimeUnit::Milliseconds,
+            subsample: false,
+        };
+        assert!(validate_delay_config(48000, &conf).is_ok());
+        // an extra zero in a delay given in samples
+        conf.delay = 4800000.0;
+        conf.unit = TimeUnit::Samples;
+        assert!(validate_delay_config(48000, &conf).is_err());
+        conf.delay = -1.0;
+        assert!(validate_delay_config(48000, &conf).is_err());
+        conf.delay = f64::NAN as crate::PrcFmt;
+        assert!(validate_delay_config(48000, &conf).is_err());
+    }
+
+    #[test]
+    fn ramp_time_checks() {
+        assert_eq!(get_ramp_chunks(200.0, 1024, 44100), 9);
+        assert!(check_ramp_time(200.0, 1024, 44100).is_none());
+        assert!(check_ramp_time(0.0, 1024, 44100).is_none());
+        assert!(check_ramp_time(30.0, 1024, 44100).is_some());
+        assert!(check_ramp_time(10000.0, 1024, 44100).is_some());
+    }
+
+    #[test]
+    fn gain_dither() {
+        let ramp: Vec<f64> = (0..100).map(|n| 0.5 + 0.001 * n as f64).collect();
+        let mut dithered = ramp.clone();
+        dither_gain_ramp(&mut dithered);
+        for (orig, dith) in ramp.iter().zip(dithered.iter()) {
+            assert!(is_close(*orig, *dith, 0.001 + 1.0e-9));
+        }
+        assert_ne!(ramp, dithered);
+        let mut constant = vec![0.5; 100];
+        dither_gain_ramp(&mut constant);
+        assert_eq!(constant, vec![0.5; 100]);
+    }
+
+    #[test]
+    fn volume_mute_fade() {
+        let status = Arc::new(RwLock::new(ProcessingParameters {
+            volume: 0.0,
+            gain_offset: 0.0,
+            mute: false,
+            paused: false,
+            test_tone: None,
+            latency_measurement: None,
+            filter_responses: Vec::new(),
+            spectrum_requests: Vec::new(),
+            dynamic_eq_offsets: HashMap::new(),
+            loudness_boosts: HashMap::new(),
+            mixer_attenuations: HashMap::new(),
+            agc_gain: None,
+            processing_histogram: countertimer::ProcessingHistogram::new(),
+            profile_filters: false,
+            filter_times: HashMap::new(),
+            volume_ramp: None,
+            flush_filters: false,
+        }));
+        let mut vol = Volume::new(
+            "test".to_string(),
+            0.0,
+            Some(1.0),
+            false,
+            0.0,
+            false,
+            4,
+            4000,
+            status.clone(),
+        );
+        let mut waveform = vec![1.0; 4];
+        vol.process_waveform(&mut waveform).unwrap();
+        assert_eq!(waveform, vec![1.0; 4]);
+        status.write().unwrap().mute = true;
+        let mut waveform = vec![1.0; 4];
+        vol.process_waveform(&mut waveform).unwrap();
+        assert!(compare_waveforms(
+            waveform,
+            vec![0.75, 0.5, 0.25, 0.0],
+            1.0e-9
+        ));
+        let mut waveform = vec![1.0; 4];
+        vol.process_waveform(&mut waveform).unwrap();
+        assert_eq!(waveform, vec![0.0; 4]);
+        status.write().unwrap().mute = false;
+        let mut waveform = vec![1.0; 4];
+        vol.process_waveform(&mut waveform).unwrap();
+        assert!(compare_waveforms(
+            waveform,
+            vec![0.25, 0.5, 0.75, 1.0],
+            1.0e-9
+        ));
+        // A gain offset is applied on top of the volume
+        status.write().unwrap().gain_offset = -20.0;
+        let mut waveform = vec![1.0; 4];
+        vol.process_waveform(&mut waveform).unwrap();
+        assert!(compare_waveforms(waveform, vec![0.1; 4], 1.0e-9));
+    }
+
+    #[test]
+    fn volume_ramp_override() {
+        let status = Arc::new(RwLock::new(ProcessingParameters {
+            volume: 0.0,
+            gain_offset: 0.0,
+            mute: false,
+            paused: false,
+            test_tone: None,
+            latency_measurement: None,
+            filter_responses: Vec::new(),
+            spectrum_requests: Vec::new(),
+            dynamic_eq_offsets: HashMap::new(),
+            loudness_boosts: HashMap::new(),
+            mixer_attenuations: HashMap::new(),
+            agc_gain: None,
+            processing_histogram: countertimer::ProcessingHistogram::new(),
+            profile_filters: false,
+            filter_times: HashMap::new(),
+            volume_ramp: None,
+            flush_filters: false,
+        }));
+        // no configured ramp, chunks of 1 ms
+        let mut vol = Volume::new(
+            "test".to_string(),
+            0.0,
+            None,
+            false,
+            0.0,
+            false,
+            4,
+            4000,
+            status.clone(),
+        );
+        {
+            let mut params = status.write().unwrap();
+            params.volume = -20.0;
+            params.volume_ramp = Some(VolumeRamp {
+                target: -20.0,
+                ramp_ms: 2.0,
+            });
+        }
+        // the change to -20 dB is ramped over two chunks
+        let mut waveform = vec![1.0; 4];
+        vol.process_waveform(&mut waveform).unwrap();
+        assert!(is_close(waveform[0], 1.0, 1.0e-9));
+        assert!(is_close(waveform[3], (10.0_f64).powf(-7.5 / 20.0), 1.0e-9));
+        let mut waveform = vec![1.0; 4];
+        vol.process_waveform(&mut waveform).unwrap();
+        assert!(is_close(waveform[3], (10.0_f64).powf(-17.5 / 20.0), 1.0e-9));
+        let mut waveform = vec![1.0; 4];
+        vol.process_waveform(&mut waveform).unwrap();
+        assert!(compare_waveforms(waveform, vec![0.1; 4], 1.0e-9));
+        // a later change without a ramp time uses the configured ramp
+        status.write().unwrap().volume = 0.0;
+        let mut waveform = vec![1.0; 4];
+        vol.process_waveform(&mut waveform).unwrap();
+        assert_eq!(waveform, vec![1.0; 4]);
+    }
+
+    #[test]
+    fn effective_volume_is_clamped() {
+        let mut params = ProcessingParameters {
+            volume: 10.0,
+            gain_offset: 15.0,
+            mute: false,
+            paused: false,
+            test_tone: None,
+            latency_measurement: None,
+            filter_responses: Vec::new(),
+            spectrum_requests: Vec::new(),
+            dynamic_eq_offsets: HashMap::new(),
+            loudness_boosts: HashMap::new(),
+            mixer_attenuations: HashMap::new(),
+            agc_gain: None,
+            processing_histogram: countertimer::ProcessingHistogram::new(),
+            profile_filters: false,
+            filter_times: HashMap::new(),
+            volume_ramp: None,
+            flush_filters: false,
+        };
+        assert_eq!(params.effective_volume(), MAX_VOLUME_DB);
+        params.volume = -110.0;
+        params.gain_offset = -20.0;
+        assert_eq!(params.effective_volume(), MIN_VOLUME_DB);
+        params.gain_offset = 5.0;
+        assert_eq!(params.effective_volume(), -105.0);
+        // A ramp to a target that is clamped still applies
+        params.volume = 15.0;
+        params.gain_offset = 10.0;
+        params.volume_ramp = Some(VolumeRamp {
+            target: 15.0,
+            ramp_ms: 50.0,
+        });
+        assert_eq!(
+            params.ramp_time_override(params.effective_volume()),
+            Some(50.0)
+        );
+    }
+
+    #[test]
+    fn dc_block() {
+        let mut waveform = vec![0.5; 44100];
+        let mut dcblock = DcBlock::new(44100, 2.0);
+        dcblock.process_waveform(&mut waveform);
+        // Starts by passing the step, then decays towards zero
+        assert!(is_close(waveform[0], 0.5, 1.0e-9));
+        assert!(waveform[44099].abs() < 1.0e-5);
+    }
+
+    #[test]
+    fn agc_approaches_target() {
+        let conf = AgcParameters {
+            target_level: -20.0,
+            max_gain: 12.0,
+            attack: 0.5,
+            release: 0.5,
+            gate_level: -60.0,
+        };
+        let mut agc = Agc::from_config(&conf, 1000, 100);
+        // A square wave at -30 dB RMS needs 10 dB of gain
+        let amplitude = 10.0f64.powf(-30.0 / 20.0);
+        for _ in 0..200 {
+            let mut chunk = AudioChunk::new(vec![vec![amplitude; 100]], 1.0, -1.0, 100, 100);
+            agc.process_chunk(&mut chunk);
+        }
+        assert!(is_close(agc.gain(), 10.0, 0.01));
+        // A louder signal needs more than max_gain of attenuation
+        let amplitude = 1.0;
+        for _ in 0..200 {
+            let mut chunk = AudioChunk::new(vec![vec![amplitude; 100]], 1.0, -1.0, 100, 100);
+            agc.process_chunk(&mut chunk);
+        }
+        assert!(is_close(agc.gain(), -12.0, 0.01));
+        // Silence below the gate keeps the gain
+        for _ in 0..200 {
+            let mut chunk = AudioChunk::new(vec![vec![0.0; 100]], 1.0, -1.0, 100, 100);
+            agc.process_chunk(&mut chunk);
+        }
+        assert!(is_close(agc.gain(), -12.0, 0.01));
+    }
+
+    #[test]
+    fn shaper_curve() {
+        let conf = ShaperParameters {
+            curve: vec![[-1.0, -0.5], [0.0, 0.0], [1.0, 0.5]],
+        };
+        let mut shaper = Shaper::from_config("test".to_string(), conf);
+        let mut waveform = vec![-2.0, -0.5, 0.0, 0.25, 1.0, 3.0];
+        shaper.process_waveform(&mut waveform).unwrap();
+        assert_eq!(waveform, vec![-0.5, -0.25, 0.0, 0.125, 0.5, 0.5]);
+    }
+
+    #[test]
+    fn shaper_validation() {
+        let valid = ShaperParameters {
+            curve: vec![[-1.0, 1.0], [1.0, -1.0]],
+        };
+        assert!(validate_shaper_config(&valid).is_ok());
+        let single = ShaperParameters {
+            curve: vec![[0.0, 0.0]],
+        };
+        assert!(validate_shaper_config(&single).is_err());
+        let unordered = ShaperParameters {
+            curve: vec![[0.0, 0.0], [0.5, 0.5], [0.5, 1.0]],
+        };
+        assert!(validate_shaper_config(&unordered).is_err());
+    }
 }
@@ -9,6 +9,8 @@ extern crate lazy_static;
 extern crate libpulse_binding as pulse;
 #[cfg(feature = "pulse-backend")]
 extern crate libpulse_simple_binding as psimple;
+#[cfg(target_os = "linux")]
+extern crate nix;
 extern crate rand;
 extern crate rand_distr;
 #[cfg(not(feature = "FFTW"))]
@@ -26,12 +28,14 @@ extern crate time;
 extern crate log;
 
 use clap::{crate_authors, crate_description, crate_version, App, AppSettings, Arg};
+use std::collections::HashMap;
 use std::env;
 use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::mpsc;
 use std::sync::{Arc, Barrier, Mutex, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use flexi_logger::DeferredNow;
 use log::Record;
@@ -41,16 +45,21 @@ use camillalib::Res;
 
 use camillalib::audiodevice;
 use camillalib::config;
+use camillalib::countertimer;
+use camillalib::minidsp;
 use camillalib::processing;
+use camillalib::selftest;
+#[cfg(feature = "websocket")]
+use camillalib::snapshots;
 #[cfg(feature = "websocket")]
 use camillalib::socketserver;
 #[cfg(feature = "websocket")]
 use std::net::IpAddr;
 
 use camillalib::{
-    list_supported_devices, CaptureStatus, CommandMessage, ExitRequest, ExitState, PlaybackStatus,
-    ProcessingParameters, ProcessingState, ProcessingStatus, StatusMessage, StatusStructs,
-    StopReason,
+    list_features, list_supported_devices, CaptureStatus, CommandMessage, ExitRequest, ExitState,
+    PlaybackStatus, ProcessingParameters, ProcessingState, ProcessingStatus, StatusMessage,
+    StatusStructs, StopReason, MAX_VOLUME_DB, MIN_VOLUME_DB,
 };
 
 const EXIT_BAD_CONFIG: i32 = 101; // Error in config file
@@ -106,6 +115,7 @@ pub fn custom_logger_format(
 fn get_new_config(
     config_path: &Arc<Mutex<Option<String>>>,
     new_config_shared: &Arc<Mutex<Option<config::Configuration>>>,
+    raw_config_shared: &Arc<Mutex<Option<config::Configuration>>>,
 ) -> Res<config::Configuration> {
     let new_conf = new_config_shared.lock().unwrap().clone();
     let path = config_path.lock().unwrap().clone();
@@ -125,18 +135,22 @@ fn get_new_config(
             }
         }
     } else if let Some(file) = path {
-        match config::load_config(&file) {
-            Ok(mut conf) => match config::validate_config(&mut conf, Some(&file)) {
-                Ok(()) => {
-                    debug!("Reload using config file");
-                    Ok(conf)
-                }
-                Err(err) => {
-                    error!("Invalid config file!");
-                    error!("{}", err);
-                    Err(err)
+        match config::load_raw_config(&file) {
+            Ok(raw_conf) => {
+                let mut conf = raw_conf.clone();
+                match config::validate_config(&mut conf, None) {
+                    Ok(()) => {
+                        debug!("Reload using config file");
+                        *raw_config_shared.lock().unwrap() = Some(raw_conf);
+                        Ok(conf)
+                    }
+                    Err(err) => {
+                        error!("Invalid config file!");
+                        error!("{}", err);
+                        Err(err)
+                    }
                 }
-            },
+            }
             Err(err) => {
                 error!("Config file error:");
                 error!("{}", err);
@@ -149,14 +163,98 @@ fn get_new_config(
     }
 }
 
+/// Get the config to restart with after the capture samplerate changed.
+/// The config is validated again from the loaded config, with the samplerate override
+/// only applied for this validation, so that later reloads start from the configured samplerate.
+fn get_config_for_samplerate(
+    rate: usize,
+    raw_config_shared: &Arc<Mutex<Option<config::Configuration>>>,
+) -> Option<config::Configuration> {
+    let mut conf = match raw_config_shared.lock().unwrap().clone() {
+        Some(conf) => conf,
+        None => {
+            error!(
+                "Could not create config for samplerate {}: no config loaded",
+                rate
+            );
+            return None;
+        }
+    };
+    let previous_rate = config::OVERRIDES.write().unwrap().samplerate.replace(rate);
+    let result = config::validate_config(&mut conf, None);
+    config::OVERRIDES.write().unwrap().samplerate = previous_rate;
+    match result {
+        Ok(()) => Some(conf),
+        Err(err) => {
+            error!("Could not create config for samplerate {}: {}", rate, err);
+            None
+        }
+    }
+}
+
+/// Wait for a device thread to finish, logging instead of panicking if the thread panicked.
+fn join_thread(handle: thread::JoinHandle<()>, name: &str) {
+    if handle.join().is_err() {
+        error!("The {} thread panicked", name);
+    }
+}
+
+/// Request real-time scheduling with SCHED_FIFO at the given priority for a running thread.
+/// Failing is not fatal, the thread then keeps running with normal scheduling.
+#[cfg(target_os = "linux")]
+fn set_realtime_priority(handle: &thread::JoinHandle<()>, priority: u8, name: &str) {
+    use nix::errno::Errno;
+    use nix::libc;
+    use std::os::unix::thread::JoinHandleExt;
+
+    let param = libc::sched_param {
+        sched_priority: priority as i32,
+    };
+    let result =
+        unsafe { libc::pthread_setschedparam(handle.as_pthread_t(), libc::SCHED_FIFO, &param) };
+    match Errno::from_i32(result) {
+        Errno::UnknownErrno => {
+            debug!("Using real-time priority {} for the {} thread", priority, name)
+        }
+        Errno::EPERM => warn!(
+            "Not allowed to use real-time priority for the {} thread, the process needs the CAP_SYS_NICE capability or a sufficient rtprio limit",
+            name
+        ),
+        err => warn!(
+            "Unable to set real-time priority for the {} thread: {}",
+            name, err
+        ),
+    }
+}
+
+/// Reset volume and mute to the values from a newly loaded config, if it asks for it.
+fn apply_volume_on_reload(
+    conf: &config::Configuration,
+    processing_params: &Arc<RwLock<ProcessingParameters>>,
+) {
+    if conf.devices.volume_on_reload == config::VolumeOnReload::Config {
+        let mut params = processing_params.write().unwrap();
+        debug!(
+            "Resetting volume to {} dB from config",
+            conf.devices.initial_volume
+        );
+        params.volume = conf.devices.initial_volume;
+        params.mute = false;
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn run(
     signal_reload: Arc<AtomicBool>,
     signal_exit: Arc<AtomicUsize>,
     active_config_shared: Arc<Mutex<Option<config::Configuration>>>,
     config_path: Arc<Mutex<Option<String>>>,
     new_config_shared: Arc<Mutex<Option<config::Configuration>>>,
+    raw_config_shared: Arc<Mutex<Option<config::Configuration>>>,
     prev_config_shared: Arc<Mutex<Option<config::Configuration>>>,
     status_structs: StatusStructs,
+    drift_log: &mut Option<countertimer::DriftLog>,
+    restart_reason: &mut Option<String>,
 ) -> Res<ExitState> {
     status_structs.capture.write().unwrap().state = ProcessingState::Starting;
     let mut is_starting = true;
@@ -172,6 +270,7 @@ fn run(
 
     let (tx_status, rx_status) = mpsc::channel();
     let tx_status_pb = tx_status.clone();
+    let tx_status_proc = tx_status.clone();
     let tx_status_cap = tx_status;
 
     let (tx_command_cap, rx_command_cap) = mpsc::channel();
@@ -194,24 +293,46 @@ fn run(
     signal_exit.store(ExitRequest::NONE, Ordering::Relaxed);
 
     // Processing thread
-    processing::run_processing(
+    let proc_handle = processing::run_processing(
         conf_proc,
         barrier_proc,
         tx_pb,
         rx_cap,
         rx_pipeconf,
-        status_structs.processing,
+        status_structs.processing.clone(),
+        tx_status_proc,
     );
 
     // Playback thread
-    let mut playback_dev = audiodevice::get_playback_device(conf_pb.devices);
+    let config_hash = config::config_hash(&conf_pb);
+    let mut playback_dev = audiodevice::get_playback_device(conf_pb.devices, &config_hash);
     let pb_handle = playback_dev
-        .start(rx_pb, barrier_pb, tx_status_pb, status_structs.playback)
+        .start(
+            rx_pb,
+            barrier_pb,
+            tx_status_pb,
+            status_structs.playback.clone(),
+        )
         .unwrap();
 
-    let used_channels = config::get_used_capture_channels(&active_config);
+    let used_channels = config::get_converted_capture_channels(&active_config);
     debug!("Using channels {:?}", used_channels);
     status_structs.capture.write().unwrap().used_channels = used_channels;
+    status_structs.capture.write().unwrap().meter_used_only = active_config.devices.meter_used_only;
+    let labels = &active_config.devices.channel_labels;
+    status_structs.capture.write().unwrap().channel_labels =
+        config::get_channel_labels(&labels.capture, active_config.devices.capture.channels());
+    status_structs.playback.write().unwrap().channel_labels =
+        config::get_channel_labels(&labels.playback, active_config.devices.playback.channels());
+    status_structs.capture.write().unwrap().clock_source = active_config.devices.clock_source;
+    status_structs
+        .capture
+        .write()
+        .unwrap()
+        .measured_rate_smoothing = active_config.devices.measured_rate_smoothing;
+    status_structs.capture.write().unwrap().smoothed_samplerate = None;
+    // A frozen rate adjust belongs to the devices of the previous run
+    status_structs.capture.write().unwrap().rate_adjust_frozen = false;
 
     // Capture thread
     let mut capture_dev = audiodevice::get_capture_device(conf_cap.devices);
@@ -225,10 +346,39 @@ fn run(
         )
         .unwrap();
 
+    #[cfg(target_os = "linux")]
+    if let Some(priority) = active_config.devices.rt_priority {
+        set_realtime_priority(&proc_handle, priority, "processing");
+        set_realtime_priority(&pb_handle, priority, "playback");
+        set_realtime_priority(&cap_handle, priority, "capture");
+    }
+    #[cfg(not(target_os = "linux"))]
+    drop(proc_handle);
+
+    let mut clip_watcher = active_config
+        .devices
+        .overload_protection
+        .as_ref()
+        .map(|prot| countertimer::ClipWatcher::new(prot.clip_limit, prot.window));
+    let mut clip_indicator = countertimer::ClipIndicator::new(
+        active_config.devices.clip_hold_time,
+        status_structs.playback.read().unwrap().clipped_samples,
+    );
+
+    // The drift log uses the rate that the capture device runs at
+    let capture_samplerate = config::get_effective_config(&active_config)
+        .devices
+        .capture_samplerate;
+
     let delay = std::time::Duration::from_millis(100);
 
     let mut pb_ready = false;
     let mut cap_ready = false;
+    let mut proc_ready = false;
+    let startup_deadline = active_config
+        .devices
+        .startup_timeout
+        .map(|timeout| Instant::now() + Duration::from_secs_f32(timeout));
     #[cfg(any(target_os = "linux", target_os = "macos"))]
     signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&signal_reload))?;
     signal_hook::flag::register_usize(
@@ -236,38 +386,56 @@ fn run(
         Arc::clone(&signal_exit),
         ExitRequest::EXIT,
     )?;
+    // Service managers such as systemd use SIGTERM to stop a service
+    signal_hook::flag::register_usize(
+        signal_hook::consts::SIGTERM,
+        Arc::clone(&signal_exit),
+        ExitRequest::EXIT,
+    )?;
 
     loop {
         if signal_reload.load(Ordering::Relaxed) {
             debug!("Reloading configuration...");
             signal_reload.store(false, Ordering::Relaxed);
-            let new_config = get_new_config(&config_path, &new_config_shared);
+            let new_config = get_new_config(&config_path, &new_config_shared, &raw_config_shared);
 
             match new_config {
                 Ok(conf) => {
+                    apply_volume_on_reload(&conf, &status_structs.processing);
                     let comp = config::config_diff(&active_config, &conf);
                     match comp {
                         config::ConfigChange::Pipeline
                         | config::ConfigChange::MixerParameters
                         | config::ConfigChange::FilterParameters { .. } => {
-                            tx_pipeconf.send((comp, conf.clone())).unwrap();
+                            if tx_pipeconf.send((comp, conf.clone())).is_err() {
+                                debug!("Processing thread has already exited");
+                            }
                             active_config = conf;
                             *active_config_shared.lock().unwrap() = Some(active_config.clone());
                             *new_config_shared.lock().unwrap() = None;
-                            let used_channels = config::get_used_capture_channels(&active_config);
+                            let used_channels =
+                                config::get_converted_capture_channels(&active_config);
                             debug!("Using channels {:?}", used_channels);
                             status_structs.capture.write().unwrap().used_channels = used_channels;
                             debug!("Sent changes to pipeline");
                         }
-                        config::ConfigChange::Devices => {
-                            debug!("Devices changed, restart required.");
+                        config::ConfigChange::Devices | config::ConfigChange::ChannelCount => {
+                            match comp {
+                                config::ConfigChange::ChannelCount => {
+                                    debug!("Channel count changed, restart required.");
+                                }
+                                _ => {
+                                    debug!("Devices changed, restart required.");
+                                }
+                            }
+                            *restart_reason = Some(comp.name().to_string());
                             if tx_command_cap.send(CommandMessage::Exit).is_err() {
                                 debug!("Capture thread has already exited");
                             }
                             trace!("Wait for pb..");
-                            pb_handle.join().unwrap();
+                            join_thread(*pb_handle, "playback");
                             trace!("Wait for cap..");
-                            cap_handle.join().unwrap();
+                            join_thread(*cap_handle, "capture");
                             *new_config_shared.lock().unwrap() = Some(conf);
                             trace!("All threads stopped, returning");
                             return Ok(ExitState::Restart);
@@ -292,9 +460,9 @@ fn run(
                         debug!("Capture thread has already exited");
                     }
                     trace!("Wait for pb..");
-                    pb_handle.join().unwrap();
+                    join_thread(*pb_handle, "playback");
                     trace!("Wait for cap..");
-                    cap_handle.join().unwrap();
+                    join_thread(*cap_handle, "capture");
                     *prev_config_shared.lock().unwrap() = Some(active_config);
                     trace!("All threads stopped, exiting");
                     return Ok(ExitState::Exit);
@@ -306,9 +474,9 @@ fn run(
                         debug!("Capture thread has already exited");
                     }
                     trace!("Wait for pb..");
-                    pb_handle.join().unwrap();
+                    join_thread(*pb_handle, "playback");
                     trace!("Wait for cap..");
-                    cap_handle.join().unwrap();
+                    join_thread(*cap_handle, "capture");
                     *new_config_shared.lock().unwrap() = None;
                     *prev_config_shared.lock().unwrap() = Some(active_config);
                     trace!("All threads stopped, stopping");
@@ -317,6 +485,69 @@ fn run(
                 _ => {}
             };
         }
+        if let (Some(watcher), Some(protection)) = (
+            &mut clip_watcher,
+            &active_config.devices.overload_protection,
+        ) {
+            let clipped = status_structs.playback.read().unwrap().clipped_samples;
+            if !is_starting && watcher.check(clipped) {
+                let mut params = status_structs.processing.write().unwrap();
+                let new_volume = (params.volume - protection.volume_step).max(MIN_VOLUME_DB);
+                warn!(
+                    "Sustained clipping, reducing volume from {} dB to {} dB",
+                    params.volume, new_volume
+                );
+                params.volume = new_volume;
+                status_structs.status.write().unwrap().overload_reductions += 1;
+            }
+        }
+        {
+            let mut pb_stat = status_structs.playback.write().unwrap();
+            pb_stat.clipping_active = clip_indicator.check(pb_stat.clipped_samples);
+        }
+        if let (Some(log), false) = (drift_log.as_mut(), is_starting) {
+            let capt_stat = status_structs.capture.read().unwrap();
+            log.check(
+                capt_stat.status_updates,
+                capture_samplerate,
+                capt_stat.raw_samplerate,
+                capt_stat.rate_adjust,
+                capt_stat.state,
+            );
+        }
+        if let Some(deadline) = startup_deadline {
+            if is_starting && Instant::now() > deadline {
+                let not_ready: Vec<&str> = [
+                    (cap_ready, "capture"),
+                    (pb_ready, "playback"),
+                    (proc_ready, "processing"),
+                ]
+                .iter()
+                .filter(|(ready, _)| !ready)
+                .map(|(_, name)| *name)
+                .collect();
+                let msg = format!(
+                    "Startup timed out after {} seconds, thread(s) never ready: {}",
+                    active_config.devices.startup_timeout.unwrap_or_default(),
+                    not_ready.join(", ")
+                );
+                status_structs.status.write().unwrap().stop_reason =
+                    StopReason::UnknownError(msg.clone());
+                if tx_command_cap.send(CommandMessage::Exit).is_err() {
+                    debug!("Capture thread has already exited");
+                }
+                // The threads that are ready are waiting at the barrier.
+                // Release it with one helper thread for this thread, and one for each thread that isn't ready,
+                // since those may never get there.
+                debug!("Startup timed out, release barrier");
+                for _ in 0..=not_ready.len() {
+                    let barrier = barrier.clone();
+                    thread::spawn(move || barrier.wait());
+                }
+                *new_config_shared.lock().unwrap() = None;
+                return Err(config::ConfigError::new(&msg).into());
+            }
+        }
         match rx_status.recv_timeout(delay) {
             Ok(msg) => match msg {
                 StatusMessage::PlaybackReady => {
@@ -329,6 +560,10 @@ fn run(
                         is_starting = false;
                     }
                 }
+                StatusMessage::ProcessingReady => {
+                    debug!("Processing thread ready to start");
+                    proc_ready = true;
+                }
                 StatusMessage::CaptureReady => {
                     debug!("Capture thread ready to start");
                     cap_ready = true;
@@ -352,7 +587,7 @@ fn run(
                     debug!("Wait for capture thread to exit..");
                     status_structs.status.write().unwrap().stop_reason =
                         StopReason::PlaybackError(message);
-                    cap_handle.join().unwrap();
+                    join_thread(*cap_handle, "capture");
                     *new_config_shared.lock().unwrap() = None;
                     *prev_config_shared.lock().unwrap() = Some(active_config);
                     trace!("All threads stopped, returning");
@@ -367,7 +602,7 @@ fn run(
                     debug!("Wait for playback thread to exit..");
                     status_structs.status.write().unwrap().stop_reason =
                         StopReason::CaptureError(message);
-                    pb_handle.join().unwrap();
+                    join_thread(*pb_handle, "playback");
                     *new_config_shared.lock().unwrap() = None;
                     *prev_config_shared.lock().unwrap() = Some(active_config);
                     trace!("All threads stopped, returning");
@@ -385,7 +620,7 @@ fn run(
                     debug!("Wait for capture thread to exit..");
                     status_structs.status.write().unwrap().stop_reason =
                         StopReason::PlaybackFormatChange(rate);
-                    cap_handle.join().unwrap();
+                    join_thread(*cap_handle, "capture");
                     *new_config_shared.lock().unwrap() = None;
                     *prev_config_shared.lock().unwrap() = Some(active_config);
                     trace!("All threads stopped, returning");
@@ -400,20 +635,62 @@ fn run(
                     debug!("Wait for playback thread to exit..");
                     status_structs.status.write().unwrap().stop_reason =
                         StopReason::CaptureFormatChange(rate);
-                    pb_handle.join().unwrap();
+                    join_thread(*pb_handle, "playback");
                     *new_config_shared.lock().unwrap() = None;
+                    if !active_config.samplerates.is_empty() {
+                        if let Some(conf) = get_config_for_samplerate(rate, &raw_config_shared) {
+                            info!("Restarting with config for samplerate {}", rate);
+                            *new_config_shared.lock().unwrap() = Some(conf);
+                        }
+                    }
                     *prev_config_shared.lock().unwrap() = Some(active_config);
                     trace!("All threads stopped, returning");
                     return Ok(ExitState::Restart);
                 }
+                StatusMessage::ProcessingError(message) => {
+                    let action = active_config.devices.on_processing_error;
+                    if action == config::ProcessingErrorAction::Silence {
+                        warn!("Processing error, continuing with silent output");
+                        continue;
+                    }
+                    if tx_command_cap.send(CommandMessage::Exit).is_err() {
+                        debug!("Capture thread has already exited");
+                    }
+                    status_structs.status.write().unwrap().stop_reason =
+                        StopReason::ProcessingError(message);
+                    trace!("Wait for pb..");
+                    join_thread(*pb_handle, "playback");
+                    trace!("Wait for cap..");
+                    join_thread(*cap_handle, "capture");
+                    *prev_config_shared.lock().unwrap() = Some(active_config.clone());
+                    trace!("All threads stopped, returning");
+                    if action == config::ProcessingErrorAction::Exit {
+                        return Ok(ExitState::Exit);
+                    }
+                    let mut new_config = new_config_shared.lock().unwrap();
+                    if new_config.is_none() {
+                        info!("Restarting with the same config");
+                        *new_config = Some(active_config);
+                    }
+                    return Ok(ExitState::Restart);
+                }
                 StatusMessage::PlaybackDone => {
                     info!("Playback finished");
                     let mut stat = status_structs.status.write().unwrap();
                     if stat.stop_reason == StopReason::None {
                         stat.stop_reason = StopReason::Done;
                     }
+                    let action = active_config.devices.on_playback_done;
+                    let mut new_config = new_config_shared.lock().unwrap();
+                    if action == config::PlaybackDoneAction::Loop && new_config.is_none() {
+                        info!("Starting over with the same config");
+                        *new_config = Some(active_config.clone());
+                    }
                     *prev_config_shared.lock().unwrap() = Some(active_config);
                     trace!("All threads stopped, returning");
+                    if action == config::PlaybackDoneAction::Exit {
+                        return Ok(ExitState::Exit);
+                    }
                     return Ok(ExitState::Restart);
                 }
                 StatusMessage::CaptureDone => {
@@ -421,7 +698,9 @@ fn run(
                 }
                 StatusMessage::SetSpeed(speed) => {
                     debug!("SetSpeed message received");
-                    if tx_command_cap
+                    if status_structs.capture.read().unwrap().rate_adjust_frozen {
+                        trace!("Rate adjust is frozen, ignoring new speed {}", speed);
+                    } else if tx_command_cap
                         .send(CommandMessage::SetSpeed { speed })
                         .is_err()
                     {
@@ -442,34 +721,7 @@ fn run(
 }
 
 fn main_process() -> i32 {
-    let mut features = Vec::new();
-    if cfg!(feature = "pulse-backend") {
-        features.push("pulse-backend");
-    }
-    if cfg!(feature = "cpal-backend") {
-        features.push("cpal-backend");
-    }
-    if cfg!(feature = "jack-backend") {
-        features.push("jack-backend");
-    }
-    if cfg!(feature = "websocket") {
-        features.push("websocket");
-    }
-    if cfg!(feature = "secure-websocket") {
-        features.push("secure-websocket");
-    }
-    if cfg!(feature = "FFTW") {
-        features.push("FFTW");
-    }
-    if cfg!(feature = "32bit") {
-        features.push("32bit");
-    }
-    if cfg!(feature = "neon") {
-        features.push("neon");
-    }
-    if cfg!(feature = "debug") {
-        features.push("debug");
-    }
+    let features = list_features();
     let featurelist = format!("Built with features: {}", features.join(", "));
 
     let (pb_types, cap_types) = list_supported_devices();
@@ -494,7 +746,12 @@ fn main_process() -> i32 {
                 .help("The configuration file to use")
                 .index(1)
                 //.required(true),
-                .required_unless("wait"),
+                .required_unless_one(&[
+                    "wait",
+                    "dump_default_config",
+                    "list_devices",
+                    "import_minidsp",
+                ]),
         )
         .arg(
             Arg::with_name("check")
@@ -503,6 +760,42 @@ fn main_process() -> i32 {
                 .long("check")
                 .requires("configfile"),
         )
+        .arg(
+            Arg::with_name("selftest")
+                .help("Run a short self test of the processing and exit")
+                .long("selftest")
+                .requires("configfile")
+                .conflicts_with("check"),
+        )
+        .arg(
+            Arg::with_name("dump_default_config")
+                .help("Print a minimal config for stereo passthrough and exit")
+                .long("dump-default-config")
+                .conflicts_with_all(&["check", "selftest"]),
+        )
+        .arg(
+            Arg::with_name("list_devices")
+                .help("List the available audio devices with their capabilities and exit, as a table or as json")
+                .long("list-devices")
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .possible_value("table")
+                .possible_value("json")
+                .conflicts_with_all(&["check", "selftest", "dump_default_config"]),
+        )
+        .arg(
+            Arg::with_name("import_minidsp")
+                .help("Convert a miniDSP XML export to a config, print it and exit")
+                .long("import-minidsp")
+                .takes_value(true)
+                .conflicts_with_all(&[
+                    "check",
+                    "selftest",
+                    "dump_default_config",
+                    "list_devices",
+                ]),
+        )
         .arg(
             Arg::with_name("verbosity")
                 .short("v")
@@ -541,11 +834,14 @@ fn main_process() -> i32 {
                 .takes_value(true)
                 .validator(|v: String| -> Result<(), String> {
                     if let Ok(gain) = v.parse::<f32>() {
-                        if (-120.0..=20.0).contains(&gain) {
+                        if (MIN_VOLUME_DB..=MAX_VOLUME_DB).contains(&gain) {
                             return Ok(());
                         }
                     }
-                    Err(String::from("Must be a number between -120 and +20"))
+                    Err(format!(
+                        "Must be a number between {} and +{}",
+                        MIN_VOLUME_DB, MAX_VOLUME_DB
+                    ))
                 }),
         )
         .arg(
@@ -555,6 +851,19 @@ fn main_process() -> i32 {
                 .long("mute")
                 .display_order(200),
         )
+        .arg(
+            Arg::with_name("profile_filters")
+                .help("Measure and log the processing time of each filter")
+                .long("profile-filters")
+                .display_order(200),
+        )
+        .arg(
+            Arg::with_name("drift_log")
+                .help("Append the measured capture sample rate and rate adjust to a CSV file")
+                .long("drift-log")
+                .display_order(200)
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("samplerate")
                 .help("Override samplerate in config")
@@ -598,7 +907,7 @@ fn main_process() -> i32 {
                     if let Ok(_samples) = v.parse::<usize>() {
                         return Ok(());
                     }
-                    Err(String::from("Must be an integer > 0"))
+                    Err(String::from("Must be an integer >= 0"))
                 }),
         )
         .arg(
@@ -654,6 +963,46 @@ fn main_process() -> i32 {
                 .long("wait")
                 .help("Wait for config from websocket")
                 .requires("port"),
+        )
+        .arg(
+            Arg::with_name("snapshot_dir")
+                .long("snapshot-dir")
+                .display_order(200)
+                .takes_value(true)
+                .help("Directory for storing config snapshots saved via websocket")
+                .requires("port"),
+        )
+        .arg(
+            Arg::with_name("ping_interval")
+                .long("ping-interval")
+                .display_order(200)
+                .takes_value(true)
+                .help("Send a websocket ping to each client at this interval in seconds")
+                .requires("port")
+                .validator(|v: String| -> Result<(), String> {
+                    if let Ok(interval) = v.parse::<f32>() {
+                        if interval > 0.0 {
+                            return Ok(());
+                        }
+                    }
+                    Err(String::from("Must be a number > 0"))
+                }),
+        )
+        .arg(
+            Arg::with_name("ping_timeout")
+                .long("ping-timeout")
+                .display_order(200)
+                .takes_value(true)
+                .help("Close websocket connections that don't answer a ping within this time in seconds [default: 10]")
+                .requires("ping_interval")
+                .validator(|v: String| -> Result<(), String> {
+                    if let Ok(timeout) = v.parse::<f32>() {
+                        if timeout > 0.0 {
+                            return Ok(());
+                        }
+                    }
+                    Err(String::from("Must be a number > 0"))
+                }),
         );
     #[cfg(feature = "secure-websocket")]
     let clapapp = clapapp
@@ -684,13 +1033,16 @@ fn main_process() -> i32 {
         loglevel = level;
     }
 
-    let _logger = if let Some(logfile) = matches.value_of("logfile") {
+    let logfile = matches.value_of("logfile").map(|logfile| {
         let mut path = PathBuf::from(logfile);
         if !path.is_absolute() {
             let mut fullpath = std::env::current_dir().unwrap();
             fullpath.push(path);
             path = fullpath;
         }
+        path
+    });
+    let logger = if let Some(path) = &logfile {
         flexi_logger::Logger::try_with_str(loglevel)
             .unwrap()
             .format(custom_logger_format)
@@ -708,6 +1060,13 @@ fn main_process() -> i32 {
             .start()
             .unwrap()
     };
+    #[cfg(feature = "websocket")]
+    let logger = Arc::new(Mutex::new(socketserver::LogControl {
+        handle: logger,
+        logfile,
+    }));
+    #[cfg(not(feature = "websocket"))]
+    let _logger = (logger, logfile);
     info!("CamillaDSP version {}", crate_version!());
     info!(
         "Running on {}, {}",
@@ -753,6 +1112,46 @@ fn main_process() -> i32 {
 
     debug!("Read config file {:?}", configname);
 
+    if matches.is_present("dump_default_config") {
+        let conf = config::default_config();
+        println!("# Minimal config for stereo passthrough, with all optional fields at their default values.");
+        println!("# Replace the device names and formats under 'capture' and 'playback' with the ones to use.");
+        println!(
+            "# Optional fields can be removed, see the README for a description of each field."
+        );
+        print!("{}", serde_yaml::to_string(&conf).unwrap());
+        return EXIT_OK;
+    }
+
+    if let Some(filename) = matches.value_of("import_minidsp") {
+        match minidsp::import_file(filename) {
+            Ok(import) => {
+                println!("# Converted from miniDSP export '{}'.", filename);
+                println!("# Replace the device names and formats under 'capture' and 'playback' with the ones to use.");
+                for item in import.unsupported.iter() {
+                    println!("# Not converted: {}", item);
+                }
+                print!("{}", serde_yaml::to_string(&import.config).unwrap());
+                return EXIT_OK;
+            }
+            Err(err) => {
+                println!("Could not convert miniDSP export");
+                println!("{}", err);
+                return EXIT_BAD_CONFIG;
+            }
+        }
+    }
+
+    if matches.is_present("list_devices") {
+        let devices = audiodevice::list_devices();
+        if matches.value_of("list_devices") == Some("json") {
+            println!("{}", serde_json::to_string_pretty(&devices).unwrap());
+        } else {
+            print!("{}", audiodevice::format_device_table(&devices));
+        }
+        return EXIT_OK;
+    }
+
     if matches.is_present("check") {
         match config::load_validate_config(&configname.unwrap()) {
             Ok(_) => {
@@ -767,11 +1166,33 @@ fn main_process() -> i32 {
         }
     }
 
-    let configuration = match &configname {
-        Some(path) => match config::load_validate_config(&path.clone()) {
+    if matches.is_present("selftest") {
+        match config::load_validate_config(&configname.unwrap()) {
             Ok(conf) => {
+                let report = selftest::run_selftest(conf);
+                println!("{}", report);
+                if report.passed() {
+                    return EXIT_OK;
+                }
+                return EXIT_PROCESSING_ERROR;
+            }
+            Err(err) => {
+                println!("Config is not valid");
+                println!("{}", err);
+                return EXIT_BAD_CONFIG;
+            }
+        }
+    }
+
+    let (configuration, raw_configuration) = match &configname {
+        Some(path) => match config::load_raw_config(path).and_then(|raw_conf| {
+            let mut conf = raw_conf.clone();
+            config::validate_config(&mut conf, None)?;
+            Ok((conf, raw_conf))
+        }) {
+            Ok((conf, raw_conf)) => {
                 debug!("Config is valid");
-                Some(conf)
+                (Some(conf), Some(raw_conf))
             }
             Err(err) => {
                 error!("{}", err);
@@ -779,36 +1200,73 @@ fn main_process() -> i32 {
                 return EXIT_BAD_CONFIG;
             }
         },
-        None => None,
+        None => (None, None),
     };
 
     let wait = matches.is_present("wait");
+    let profile_filters = matches.is_present("profile_filters");
+
+    let status_interval = configuration
+        .as_ref()
+        .map(|conf| conf.devices.status_interval_ms)
+        .unwrap_or(1000);
 
     let signal_reload = Arc::new(AtomicBool::new(false));
     let signal_exit = Arc::new(AtomicUsize::new(0));
     let capture_status = Arc::new(RwLock::new(CaptureStatus {
         measured_samplerate: 0,
-        update_interval: 1000,
+        measured_rate_smoothing: 0.0,
+        smoothed_samplerate: None,
+        raw_samplerate: 0.0,
+        update_interval: status_interval,
         signal_range: 0.0,
         rate_adjust: 0.0,
+        rate_adjust_frozen: false,
         state: ProcessingState::Inactive,
         signal_rms: Vec::new(),
         signal_peak: Vec::new(),
         used_channels: Vec::new(),
+        meter_used_only: false,
+        clock_source: config::ClockSource::Playback,
+        channel_labels: Vec::new(),
+        status_updates: 0,
     }));
     let playback_status = Arc::new(RwLock::new(PlaybackStatus {
         buffer_level: 0,
         clipped_samples: 0,
-        update_interval: 1000,
+        clipping_active: false,
+        update_interval: status_interval,
         signal_rms: Vec::new(),
         signal_peak: Vec::new(),
+        output_file: None,
+        channel_labels: Vec::new(),
     }));
     let processing_status = Arc::new(RwLock::new(ProcessingParameters {
         volume: initial_volume,
+        gain_offset: 0.0,
         mute: initial_mute,
+        paused: false,
+        test_tone: None,
+        latency_measurement: None,
+        filter_responses: Vec::new(),
+        spectrum_requests: Vec::new(),
+        dynamic_eq_offsets: HashMap::new(),
+        loudness_boosts: HashMap::new(),
+        mixer_attenuations: HashMap::new(),
+        agc_gain: None,
+        processing_histogram: countertimer::ProcessingHistogram::new(),
+        profile_filters,
+        filter_times: HashMap::new(),
+        volume_ramp: None,
+        flush_filters: false,
     }));
     let status = Arc::new(RwLock::new(ProcessingStatus {
         stop_reason: StopReason::None,
+        overload_reductions: 0,
+        start_time: Instant::now(),
+        error_restarts: 0,
+        config_restarts: 0,
+        config_restart_reason: None,
     }));
 
     let status_structs = StatusStructs {
@@ -819,10 +1277,22 @@ fn main_process() -> i32 {
     };
     let active_config = Arc::new(Mutex::new(None));
     let new_config = Arc::new(Mutex::new(configuration));
+    let raw_config = Arc::new(Mutex::new(raw_configuration));
     let previous_config = Arc::new(Mutex::new(None));
 
     let active_config_path = Arc::new(Mutex::new(configname));
 
+    let mut drift_log = match matches.value_of("drift_log") {
+        Some(path) => match countertimer::DriftLog::new(&PathBuf::from(path)) {
+            Ok(log) => Some(log),
+            Err(err) => {
+                error!("Could not open drift log {}: {}", path, err);
+                return EXIT_BAD_CONFIG;
+            }
+        },
+        None => None,
+    };
+
     #[cfg(feature = "websocket")]
     {
         if let Some(port_str) = matches.value_of("port") {
@@ -834,11 +1304,16 @@ fn main_process() -> i32 {
                 active_config: active_config.clone(),
                 active_config_path: active_config_path.clone(),
                 new_config: new_config.clone(),
+                raw_config: raw_config.clone(),
                 previous_config: previous_config.clone(),
+                snapshots: Arc::new(Mutex::new(snapshots::Snapshots::new(
+                    matches.value_of("snapshot_dir").map(PathBuf::from),
+                ))),
                 capture_status,
                 playback_status,
                 processing_status,
                 status,
+                logger: logger.clone(),
             };
             let server_params = socketserver::ServerParameters {
                 port: serverport,
@@ -847,12 +1322,25 @@ fn main_process() -> i32 {
                 cert_file: matches.value_of("cert"),
                 #[cfg(feature = "secure-websocket")]
                 cert_pass: matches.value_of("pass"),
+                heartbeat: matches.value_of("ping_interval").map(|interval| {
+                    socketserver::HeartbeatParameters {
+                        interval: Duration::from_secs_f32(interval.parse().unwrap()),
+                        timeout: Duration::from_secs_f32(
+                            matches
+                                .value_of("ping_timeout")
+                                .map(|timeout| timeout.parse().unwrap())
+                                .unwrap_or(socketserver::DEFAULT_PING_TIMEOUT),
+                        ),
+                    }
+                }),
             };
             socketserver::start_server(server_params, shared_data);
         }
     }
 
     let delay = std::time::Duration::from_millis(100);
+    let mut has_run = false;
+    let mut restart_reason = None;
     loop {
         debug!("Wait for config");
         while new_config.lock().unwrap().is_none() {
@@ -867,13 +1355,14 @@ fn main_process() -> i32 {
             } else if signal_reload.load(Ordering::Relaxed) {
                 debug!("Reloading configuration...");
                 signal_reload.store(false, Ordering::Relaxed);
-                let conf_loaded = get_new_config(&active_config_path, &new_config);
+                let conf_loaded = get_new_config(&active_config_path, &new_config, &raw_config);
                 match conf_loaded {
                     Ok(conf) => {
                         debug!(
                             "Loaded config file: {:?}",
                             active_config_path.lock().unwrap()
                         );
+                        apply_volume_on_reload(&conf, &status_structs.processing);
                         *new_config.lock().unwrap() = Some(conf);
                     }
                     Err(err) => {
@@ -888,14 +1377,29 @@ fn main_process() -> i32 {
             thread::sleep(delay);
         }
         debug!("Config ready");
+        if has_run {
+            let mut stat = status_structs.status.write().unwrap();
+            if stat.stop_reason.is_error() {
+                stat.error_restarts += 1;
+            } else if restart_reason.is_some() {
+                // A stop, or starting over after playback is done, isn't counted
+                stat.config_restarts += 1;
+            }
+            // Only a restart for a new config has a reason, any other restart clears it
+            stat.config_restart_reason = restart_reason.take();
+        }
+        has_run = true;
         let exitstatus = run(
             signal_reload.clone(),
             signal_exit.clone(),
             active_config.clone(),
             active_config_path.clone(),
             new_config.clone(),
+            raw_config.clone(),
             previous_config.clone(),
             status_structs.clone(),
+            &mut drift_log,
+            &mut restart_reason,
         );
         match exitstatus {
             Err(e) => {
@@ -5,6 +5,7 @@
 
 use crate::config;
 use crate::filters::Filter;
+use num_complex::Complex;
 
 // Sample format
 //type SmpFmt = i16;
@@ -50,6 +51,40 @@ impl BiquadCoefficients {
         }
     }
 
+    /// Create a first order emphasis filter, with unity gain at DC and a pole and a zero given by
+    /// the time constants of the parameters. The pole is placed with the matched z-transform,
+    /// and the zero is chosen to give unity gain at DC and the analog gain at the Nyquist frequency.
+    /// The pre-emphasis filter is the exact inverse of the de-emphasis filter.
+    pub fn from_emphasis(
+        fs: usize,
+        parameters: config::EmphasisParameters,
+        deemphasis: bool,
+    ) -> Self {
+        let t1 = parameters.t1 / 1.0e6;
+        let t2 = parameters.t2 / 1.0e6;
+        let omega_nyquist = std::f64::consts::PI as PrcFmt * fs as PrcFmt;
+        let gain_nyquist =
+            ((1.0 + (omega_nyquist * t2).powi(2)) / (1.0 + (omega_nyquist * t1).powi(2))).sqrt();
+        let pole = (-1.0 / (t1 * fs as PrcFmt)).exp();
+        let a0 = 1.0;
+        let a1 = -pole;
+        let b0 = ((1.0 - pole) + gain_nyquist * (1.0 + pole)) / 2.0;
+        let b1 = ((1.0 - pole) - gain_nyquist * (1.0 + pole)) / 2.0;
+        if deemphasis {
+            BiquadCoefficients::normalize(a0, a1, 0.0, b0, b1, 0.0)
+        } else {
+            BiquadCoefficients::normalize(b0, b1, 0.0, a0, a1, 0.0)
+        }
+    }
+
+    /// Evaluate the complex frequency response at a frequency in Hz.
+    pub fn response(&self, freq: PrcFmt, fs: usize) -> Complex<PrcFmt> {
+        let omega = 2.0 * std::f64::consts::PI as PrcFmt * freq / fs as PrcFmt;
+        let z1 = Complex::from_polar(1.0, -omega);
+        let z2 = z1 * z1;
+        (self.b0 + self.b1 * z1 + self.b2 * z2) / (1.0 + self.a1 * z1 + self.a2 * z2)
+    }
+
     pub fn is_stable(&self) -> bool {
         self.a2.abs() < 1.0 && (self.a1.abs() < (self.a2 + 1.0))
     }
@@ -270,30 +305,41 @@ impl BiquadCoefficients {
                 let a2 = 1.0 - alpha;
                 BiquadCoefficients::normalize(a0, a1, a2, b0, b1, b2)
             }
-            config::BiquadParameters::Bandpass(config::NotchWidth::Q { freq, q }) => {
+            config::BiquadParameters::Bandpass(config::BandpassWidth::Q {
+                freq,
+                q,
+                normalization,
+            }) => {
                 let omega = 2.0 * (std::f64::consts::PI as PrcFmt) * freq / (fs as PrcFmt);
                 let sn = omega.sin();
                 let cs = omega.cos();
                 let alpha = sn / (2.0 * q);
-                let b0 = alpha;
+                let b0 = match normalization {
+                    config::BandpassNormalization::Peak => alpha,
+                    config::BandpassNormalization::Skirt => sn / 2.0,
+                };
                 let b1 = 0.0;
-                let b2 = -alpha;
+                let b2 = -b0;
                 let a0 = 1.0 + alpha;
                 let a1 = -2.0 * cs;
                 let a2 = 1.0 - alpha;
                 BiquadCoefficients::normalize(a0, a1, a2, b0, b1, b2)
             }
-            config::BiquadParameters::Bandpass(config::NotchWidth::Bandwidth {
+            config::BiquadParameters::Bandpass(config::BandpassWidth::Bandwidth {
                 freq,
                 bandwidth,
+                normalization,
             }) => {
                 let omega = 2.0 * (std::f64::consts::PI as PrcFmt) * freq / (fs as PrcFmt);
                 let sn = omega.sin();
                 let cs = omega.cos();
                 let alpha = sn * ((2.0 as PrcFmt).ln() / 2.0 * bandwidth * omega / sn).sinh();
-                let b0 = alpha;
+                let b0 = match normalization {
+                    config::BandpassNormalization::Peak => alpha,
+                    config::BandpassNormalization::Skirt => sn / 2.0,
+                };
                 let b1 = 0.0;
-                let b2 = -alpha;
+                let b2 = -b0;
                 let a0 = 1.0 + alpha;
                 let a1 = -2.0 * cs;
                 let a2 = 1.0 - alpha;
@@ -398,6 +444,11 @@ impl Biquad {
         out
     }
 
+    /// Evaluate the complex frequency response of the current coefficients.
+    pub fn response(&self, freq: PrcFmt) -> Complex<PrcFmt> {
+        self.coeffs.response(freq, self.samplerate)
+    }
+
     /// Flush stored subnormal numbers to zero.
     fn flush_subnormals(&mut self) {
         if self.s1.is_subnormal() {
@@ -424,15 +475,53 @@ impl Filter for Biquad {
         Ok(())
     }
 
+    fn reset(&mut self) {
+        self.s1 = 0.0;
+        self.s2 = 0.0;
+    }
+
     fn update_parameters(&mut self, conf: config::Filter) {
-        if let config::Filter::Biquad { parameters: conf } = conf {
-            let coeffs = BiquadCoefficients::from_config(self.samplerate, conf);
-            self.coeffs = coeffs;
-        } else {
-            // This should never happen unless there is a bug somewhere else
-            panic!("Invalid config change!");
+        match conf {
+            config::Filter::Biquad { parameters } => {
+                self.coeffs = BiquadCoefficients::from_config(self.samplerate, parameters);
+            }
+            config::Filter::Deemphasis { parameters } => {
+                self.coeffs = BiquadCoefficients::from_emphasis(self.samplerate, parameters, true);
+            }
+            config::Filter::Preemphasis { parameters } => {
+                self.coeffs = BiquadCoefficients::from_emphasis(self.samplerate, parameters, false);
+            }
+            _ => {
+                // This should never happen unless there is a bug somewhere else
+                panic!("Invalid config change!");
+            }
         }
     }
+
+    fn frequency_response(
+        &self,
+        frequencies: &[PrcFmt],
+        _samplerate: usize,
+    ) -> Option<Vec<Complex<PrcFmt>>> {
+        Some(
+            frequencies
+                .iter()
+                .map(|freq| self.response(*freq))
+                .collect(),
+        )
+    }
+}
+
+pub fn validate_emphasis_config(parameters: &config::EmphasisParameters) -> Res<()> {
+    if parameters.t2 <= 0.0 {
+        return Err(config::ConfigError::new("Emphasis time constant t2 must be > 0").into());
+    }
+    if parameters.t1 <= parameters.t2 {
+        return Err(
+            config::ConfigError::new("Emphasis time constant t1 must be larger than t2").into(),
+        );
+    }
+    Ok(())
 }
 
 pub fn validate_config(samplerate: usize, parameters: &config::BiquadParameters) -> Res<()> {
@@ -452,10 +541,10 @@ pub fn validate_config(samplerate: usize, parameters: &config::BiquadParameters)
         | config::BiquadParameters::HighshelfFO { freq, .. }
         | config::BiquadParameters::LowshelfFO { freq, .. }
         | config::BiquadParameters::Notch(config::NotchWidth::Q { freq, .. })
-        | config::BiquadParameters::Bandpass(config::NotchWidth::Q { freq, .. })
+        | config::BiquadParameters::Bandpass(config::BandpassWidth::Q { freq, .. })
         | config::BiquadParameters::Allpass(config::NotchWidth::Q { freq, .. })
         | config::BiquadParameters::Notch(config::NotchWidth::Bandwidth { freq, .. })
-        | config::BiquadParameters::Bandpass(config::NotchWidth::Bandwidth { freq, .. })
+        | config::BiquadParameters::Bandpass(config::BandpassWidth::Bandwidth { freq, .. })
         | config::BiquadParameters::Allpass(config::NotchWidth::Bandwidth { freq, .. })
         | config::BiquadParameters::AllpassFO { freq, .. } => {
             if *freq <= 0.0 {
@@ -472,7 +561,7 @@ pub fn validate_config(samplerate: usize, parameters: &config::BiquadParameters)
         | config::BiquadParameters::Lowpass { q, .. }
         | config::BiquadParameters::Peaking(config::PeakingWidth::Q { q, .. })
         | config::BiquadParameters::Notch(config::NotchWidth::Q { q, .. })
-        | config::BiquadParameters::Bandpass(config::NotchWidth::Q { q, .. })
+        | config::BiquadParameters::Bandpass(config::BandpassWidth::Q { q, .. })
         | config::BiquadParameters::Allpass(config::NotchWidth::Q { q, .. })
         | config::BiquadParameters::Highshelf(config::ShelfSteepness::Q { q, .. })
         | config::BiquadParameters::Lowshelf(config::ShelfSteepness::Q { q, .. }) => {
@@ -488,7 +577,9 @@ pub fn validate_config(samplerate: usize, parameters: &config::BiquadParameters)
             bandwidth, ..
         })
         | config::BiquadParameters::Notch(config::NotchWidth::Bandwidth { bandwidth, .. })
-        | config::BiquadParameters::Bandpass(config::NotchWidth::Bandwidth { bandwidth, .. })
+        | config::BiquadParameters::Bandpass(config::BandpassWidth::Bandwidth {
+            bandwidth, ..
+        })
         | config::BiquadParameters::Allpass(config::NotchWidth::Bandwidth { bandwidth, .. }) => {
             if *bandwidth <= 0.0 {
                 return Err(config::ConfigError::new("Bandwidth must be > 0").into());
@@ -534,8 +625,11 @@ pub fn validate_config(samplerate: usize, parameters: &config::BiquadParameters)
 
 #[cfg(test)]
 mod tests {
-    use crate::biquad::{validate_config, Biquad, BiquadCoefficients};
-    use crate::config::{BiquadParameters, NotchWidth, PeakingWidth, ShelfSteepness};
+    use crate::biquad::{validate_config, validate_emphasis_config, Biquad, BiquadCoefficients};
+    use crate::config::{
+        BandpassNormalization, BandpassWidth, BiquadParameters, EmphasisParameters, NotchWidth,
+        PeakingWidth, ShelfSteepness,
+    };
     use crate::filters::Filter;
     use crate::PrcFmt;
     use num_complex::Complex;
@@ -661,9 +755,10 @@ mod tests {
 
     #[test]
     fn make_bandpass() {
-        let conf = BiquadParameters::Bandpass(NotchWidth::Q {
+        let conf = BiquadParameters::Bandpass(BandpassWidth::Q {
             freq: 100.0,
             q: 1.0,
+            normalization: BandpassNormalization::Peak,
         });
         let coeffs = BiquadCoefficients::from_config(44100, conf);
         assert!(coeffs.is_stable());
@@ -675,6 +770,19 @@ mod tests {
         assert!(is_close(gain_hf, -12.0, 0.3));
     }
 
+    #[test]
+    fn make_bandpass_skirt() {
+        let conf = BiquadParameters::Bandpass(BandpassWidth::Q {
+            freq: 100.0,
+            q: 4.0,
+            normalization: BandpassNormalization::Skirt,
+        });
+        let coeffs = BiquadCoefficients::from_config(44100, conf);
+        assert!(coeffs.is_stable());
+        let (gain_f0, _) = gain_and_phase(coeffs, 100.0, 44100);
+        assert!(is_close(gain_f0, 20.0 * (4.0 as PrcFmt).log10(), 0.1));
+    }
+
     #[test]
     fn make_notch() {
         let conf = BiquadParameters::Notch(NotchWidth::Q {
@@ -747,6 +855,52 @@ mod tests {
         assert!(is_close(gain_hf, -24.0, 0.1));
     }
 
+    #[test]
+    fn shelves_boost_and_cut_are_inverse() {
+        let shelves: Vec<fn(PrcFmt) -> BiquadParameters> = vec![
+            |gain| {
+                BiquadParameters::Highshelf(ShelfSteepness::Slope {
+                    freq: 1000.0,
+                    slope: 6.0,
+                    gain,
+                })
+            },
+            |gain| {
+                BiquadParameters::Lowshelf(ShelfSteepness::Slope {
+                    freq: 100.0,
+                    slope: 12.0,
+                    gain,
+                })
+            },
+            |gain| {
+                BiquadParameters::Highshelf(ShelfSteepness::Q {
+                    freq: 3000.0,
+                    q: 0.9,
+                    gain,
+                })
+            },
+            |gain| {
+                BiquadParameters::Lowshelf(ShelfSteepness::Q {
+                    freq: 200.0,
+                    q: 0.5,
+                    gain,
+                })
+            },
+            |gain| BiquadParameters::HighshelfFO { freq: 2000.0, gain },
+            |gain| BiquadParameters::LowshelfFO { freq: 300.0, gain },
+        ];
+        for shelf in shelves {
+            let boost = BiquadCoefficients::from_config(44100, shelf(6.0));
+            let cut = BiquadCoefficients::from_config(44100, shelf(-6.0));
+            for f in [20.0, 100.0, 300.0, 1000.0, 3000.0, 10000.0, 20000.0] {
+                let (gain_boost, phase_boost) = gain_and_phase(boost, f, 44100);
+                let (gain_cut, phase_cut) = gain_and_phase(cut, f, 44100);
+                assert!(is_close(gain_boost, -gain_cut, 1.0e-3));
+                assert!(is_close(phase_boost, -phase_cut, 1.0e-2));
+            }
+        }
+    }
+
     #[test]
     fn make_lowshelf() {
         let conf = BiquadParameters::Lowshelf(ShelfSteepness::Slope {
@@ -812,13 +966,15 @@ mod tests {
 
     #[test]
     fn bandpass_bw_vs_q() {
-        let conf_bw = BiquadParameters::Bandpass(NotchWidth::Bandwidth {
+        let conf_bw = BiquadParameters::Bandpass(BandpassWidth::Bandwidth {
             freq: 100.0,
             bandwidth: 1.0,
+            normalization: BandpassNormalization::Peak,
         });
-        let conf_q = BiquadParameters::Bandpass(NotchWidth::Q {
+        let conf_q = BiquadParameters::Bandpass(BandpassWidth::Q {
             freq: 100.0,
             q: 1.4142,
+            normalization: BandpassNormalization::Peak,
         });
         let coeffs_bw = BiquadCoefficients::from_config(44100, conf_bw);
         let coeffs_q = BiquadCoefficients::from_config(44100, conf_q);
@@ -970,4 +1126,52 @@ mod tests {
         });
         assert!(validate_config(fs, &badconf2).is_err());
     }
+
+    #[test]
+    fn check_free() {
+        let fs = 48000;
+        let okconf: BiquadParameters = serde_yaml::from_str(
+            "type: Coefficients\na1: -1.8\na2: 0.81\nb0: 0.01\nb1: 0.0\nb2: -0.01",
+        )
+        .unwrap();
+        assert!(matches!(okconf, BiquadParameters::Free { .. }));
+        assert!(validate_config(fs, &okconf).is_ok());
+        let badconf = BiquadParameters::Free {
+            a1: -2.1,
+            a2: 1.2,
+            b0: 1.0,
+            b1: 0.0,
+            b2: 0.0,
+        };
+        assert!(validate_config(fs, &badconf).is_err());
+    }
+
+    #[test]
+    fn make_emphasis() {
+        let params = EmphasisParameters::default();
+        let analog_gain = |f: PrcFmt| {
+            let omega = 2.0 * std::f64::consts::PI as PrcFmt * f;
+            10.0 * ((1.0 + (omega * 15.0e-6).powi(2)) / (1.0 + (omega * 50.0e-6).powi(2))).log10()
+        };
+        for fs in [44100, 96000] {
+            let deemph = BiquadCoefficients::from_emphasis(fs, params.clone(), true);
+            let preemph = BiquadCoefficients::from_emphasis(fs, params.clone(), false);
+            assert!(deemph.is_stable());
+            assert!(preemph.is_stable());
+            for f in [20.0, 1000.0, 5000.0, 10000.0, 20000.0] {
+                let (gain_de, _) = gain_and_phase(deemph, f, fs);
+                let (gain_pre, _) = gain_and_phase(preemph, f, fs);
+                assert!(is_close(gain_de, analog_gain(f), 0.3));
+                assert!(is_close(gain_de + gain_pre, 0.0, 1.0e-4));
+            }
+        }
+        let (gain_dc, _) = gain_and_phase(
+            BiquadCoefficients::from_emphasis(44100, params, true),
+            1.0,
+            44100,
+        );
+        assert!(is_close(gain_dc, 0.0, 1.0e-3));
+        let flat = EmphasisParameters { t1: 15.0, t2: 15.0 };
+        assert!(validate_emphasis_config(&flat).is_err());
+    }
 }
@@ -6,12 +6,23 @@
 use crate::biquad;
 use crate::config;
 use crate::filters::Filter;
+use num_complex::Complex;
 
 // Sample format
 //type SmpFmt = i16;
 use crate::PrcFmt;
 use crate::Res;
 
+/// Highest order supported for Bessel filters.
+const BESSEL_MAX_ORDER: usize = 8;
+
+/// Frequency range covered by a tilt filter.
+const TILT_MIN_FREQ: PrcFmt = 20.0;
+const TILT_MAX_FREQ: PrcFmt = 20000.0;
+
+/// Steepest tilt that a cascade of first order shelves can follow.
+const TILT_MAX_SLOPE: PrcFmt = 6.0;
+
 #[derive(Clone, Debug)]
 pub struct BiquadCombo {
     samplerate: usize,
@@ -80,6 +91,153 @@ impl BiquadCombo {
         qvalues
     }
 
+    /// Frequency scale factors and Q-values of the sections of a Bessel lowpass filter,
+    /// normalized to have its -3 dB point at the cutoff frequency.
+    /// A negative Q-value means a first order section.
+    fn bessel_sections(order: usize) -> Vec<(PrcFmt, PrcFmt)> {
+        match order {
+            1 => vec![(1.0, -1.0)],
+            2 => vec![(1.272019649514, 0.577350269190)],
+            3 => vec![(1.322675799910, -1.0), (1.447617133147, 0.691046625825)],
+            4 => vec![
+                (1.430171559994, 0.521934581669),
+                (1.603357516217, 0.805538281842),
+            ],
+            5 => vec![
+                (1.502316271447, -1.0),
+                (1.556347122297, 0.563535620851),
+                (1.755377776637, 0.916477373948),
+            ],
+            6 => vec![
+                (1.603919128774, 0.510317824749),
+                (1.689168267620, 0.611194546878),
+                (1.904707612303, 1.023313953827),
+            ],
+            7 => vec![
+                (1.684368179273, -1.0),
+                (1.716356044871, 0.532355697900),
+                (1.822417478858, 0.660821389297),
+                (2.049490900269, 1.126257541983),
+            ],
+            8 => vec![
+                (1.778465911775, 0.505991069397),
+                (1.832092601199, 0.559609164796),
+                (1.953195759022, 0.710852074442),
+                (2.188726230527, 1.225669425408),
+            ],
+            _ => panic!(
+                "Bessel filters are only available up to order {}",
+                BESSEL_MAX_ORDER
+            ),
+        }
+    }
+
+    /// Make a highpass or lowpass cascade with the given Q-values, all at the same frequency.
+    fn make_pass(
+        fs: usize,
+        freq: PrcFmt,
+        highpass: bool,
+        qvalues: Vec<PrcFmt>,
+    ) -> Vec<biquad::Biquad> {
+        if highpass {
+            BiquadCombo::make_highpass(fs, freq, qvalues)
+        } else {
+            BiquadCombo::make_lowpass(fs, freq, qvalues)
+        }
+    }
+
+    /// Create a highpass or lowpass filter of the given type and order, as a cascade of biquads.
+    /// The sections of a Bessel filter each get their own frequency, the frequency of a lowpass section
+    /// is multiplied by the scale factor, and of a highpass section divided by it.
+    pub fn from_pass_config(
+        name: String,
+        samplerate: usize,
+        highpass: bool,
+        parameters: config::PassFilterParameters,
+    ) -> Self {
+        let freq = parameters.freq;
+        let filters = match parameters.filter_type {
+            config::PassFilterType::Butterworth => BiquadCombo::make_pass(
+                samplerate,
+                freq,
+                highpass,
+                BiquadCombo::butterworth_q(parameters.order),
+            ),
+            config::PassFilterType::LinkwitzRiley => BiquadCombo::make_pass(
+                samplerate,
+                freq,
+                highpass,
+                BiquadCombo::linkwitzriley_q(parameters.order),
+            ),
+            config::PassFilterType::Bessel => BiquadCombo::bessel_sections(parameters.order)
+                .into_iter()
+                .flat_map(|(scale, q)| {
+                    let section_freq = if highpass { freq / scale } else { freq * scale };
+                    BiquadCombo::make_pass(samplerate, section_freq, highpass, vec![q])
+                })
+                .collect(),
+        };
+        BiquadCombo {
+            samplerate,
+            name,
+            filters,
+        }
+    }
+
+    /// Make a tilt from first order shelves, placed one octave apart on both sides of the pivot frequency.
+    /// Each shelf adds the slope to the gain of the octaves beyond it,
+    /// and the high and low shelves mirror each other so that the gain at the pivot is unchanged.
+    fn make_tilt(samplerate: usize, pivot_freq: PrcFmt, slope: PrcFmt) -> Vec<biquad::Biquad> {
+        let max_freq = TILT_MAX_FREQ.min(0.45 * samplerate as PrcFmt);
+        let mut filters = Vec::new();
+        let mut octave: PrcFmt = 0.5;
+        loop {
+            let high_freq = pivot_freq * (2.0 as PrcFmt).powf(octave);
+            let low_freq = pivot_freq / (2.0 as PrcFmt).powf(octave);
+            if high_freq >= max_freq && low_freq <= TILT_MIN_FREQ {
+                break;
+            }
+            if high_freq < max_freq {
+                filters.push(config::BiquadParameters::HighshelfFO {
+                    freq: high_freq,
+                    gain: slope,
+                });
+            }
+            if low_freq > TILT_MIN_FREQ {
+                filters.push(config::BiquadParameters::LowshelfFO {
+                    freq: low_freq,
+                    gain: -slope,
+                });
+            }
+            octave += 1.0;
+        }
+        filters
+            .into_iter()
+            .map(|filtconf| {
+                let coeffs = biquad::BiquadCoefficients::from_config(samplerate, filtconf);
+                biquad::Biquad::new("".to_string(), samplerate, coeffs)
+            })
+            .collect()
+    }
+
+    /// Create a tilt filter, with a gain changing linearly with the logarithm of the frequency.
+    pub fn from_tilt_config(
+        name: String,
+        samplerate: usize,
+        parameters: config::TiltParameters,
+    ) -> Self {
+        let filters = BiquadCombo::make_tilt(
+            samplerate,
+            parameters.pivot_freq,
+            parameters.slope_db_per_octave,
+        );
+        BiquadCombo {
+            samplerate,
+            name,
+            filters,
+        }
+    }
+
     fn make_peq5(
         samplerate: usize,
         f_all: [PrcFmt; 5],
@@ -114,6 +272,17 @@ impl BiquadCombo {
         filters
     }
 
+    /// Make biquads from second order sections, each given as [b0, b1, b2, a0, a1, a2].
+    fn make_sos(samplerate: usize, sections: &[[PrcFmt; 6]]) -> Vec<biquad::Biquad> {
+        sections
+            .iter()
+            .map(|[b0, b1, b2, a0, a1, a2]| {
+                let coeffs = biquad::BiquadCoefficients::normalize(*a0, *a1, *a2, *b0, *b1, *b2);
+                biquad::Biquad::new("".to_string(), samplerate, coeffs)
+            })
+            .collect()
+    }
+
     pub fn from_config(
         name: String,
         samplerate: usize,
@@ -185,8 +354,24 @@ impl BiquadCombo {
                     filters,
                 }
             }
+            config::BiquadComboParameters::Sos { sections } => {
+                let filters = BiquadCombo::make_sos(samplerate, &sections);
+                BiquadCombo {
+                    samplerate,
+                    name,
+                    filters,
+                }
+            }
         }
     }
+
+    /// Evaluate the complex frequency response of the whole cascade.
+    pub fn response(&self, freq: PrcFmt) -> Complex<PrcFmt> {
+        self.filters
+            .iter()
+            .map(|filter| filter.response(freq))
+            .product()
+    }
 }
 
 impl Filter for BiquadCombo {
@@ -201,15 +386,46 @@ impl Filter for BiquadCombo {
         Ok(())
     }
 
+    fn reset(&mut self) {
+        for filter in self.filters.iter_mut() {
+            filter.reset();
+        }
+    }
+
     fn update_parameters(&mut self, conf: config::Filter) {
-        if let config::Filter::BiquadCombo { parameters: conf } = conf {
-            let name = self.name.clone();
-            *self = BiquadCombo::from_config(name, self.samplerate, conf);
-        } else {
-            // This should never happen unless there is a bug somewhere else
-            panic!("Invalid config change!");
+        let name = self.name.clone();
+        match conf {
+            config::Filter::BiquadCombo { parameters } => {
+                *self = BiquadCombo::from_config(name, self.samplerate, parameters);
+            }
+            config::Filter::Highpass { parameters } => {
+                *self = BiquadCombo::from_pass_config(name, self.samplerate, true, parameters);
+            }
+            config::Filter::Lowpass { parameters } => {
+                *self = BiquadCombo::from_pass_config(name, self.samplerate, false, parameters);
+            }
+            config::Filter::Tilt { parameters } => {
+                *self = BiquadCombo::from_tilt_config(name, self.samplerate, parameters);
+            }
+            _ => {
+                // This should never happen unless there is a bug somewhere else
+                panic!("Invalid config change!");
+            }
         }
     }
+
+    fn frequency_response(
+        &self,
+        frequencies: &[PrcFmt],
+        _samplerate: usize,
+    ) -> Option<Vec<Complex<PrcFmt>>> {
+        Some(
+            frequencies
+                .iter()
+                .map(|freq| self.response(*freq))
+                .collect(),
+        )
+    }
 }
 
 /// Validate a BiquadCombo convolution config.
@@ -269,13 +485,82 @@ pub fn validate_config(samplerate: usize, conf: &config::BiquadComboParameters)
             }
             Ok(())
         }
+        config::BiquadComboParameters::Sos { sections } => {
+            if sections.is_empty() {
+                return Err(config::ConfigError::new("At least one section is needed").into());
+            }
+            for (n, [b0, b1, b2, a0, a1, a2]) in sections.iter().enumerate() {
+                if *a0 == 0.0 {
+                    let msg = format!("Section {} has a0 = 0", n);
+                    return Err(config::ConfigError::new(&msg).into());
+                }
+                let coeffs = biquad::BiquadCoefficients::normalize(*a0, *a1, *a2, *b0, *b1, *b2);
+                if !coeffs.is_stable() {
+                    let msg = format!("Section {} is unstable", n);
+                    return Err(config::ConfigError::new(&msg).into());
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Validate a Highpass or Lowpass filter config.
+pub fn validate_pass_config(samplerate: usize, conf: &config::PassFilterParameters) -> Res<()> {
+    let maxfreq = samplerate as PrcFmt / 2.0;
+    if conf.freq <= 0.0 {
+        return Err(config::ConfigError::new("Frequency must be > 0").into());
+    } else if conf.freq >= maxfreq {
+        return Err(config::ConfigError::new("Frequency must be < samplerate/2").into());
+    }
+    match conf.filter_type {
+        config::PassFilterType::Butterworth => {
+            if conf.order == 0 {
+                return Err(
+                    config::ConfigError::new("Butterworth order must be larger than zero").into(),
+                );
+            }
+        }
+        config::PassFilterType::LinkwitzRiley => {
+            if (conf.order % 2 > 0) || (conf.order == 0) {
+                return Err(
+                    config::ConfigError::new("LR order must be an even non-zero number").into(),
+                );
+            }
+        }
+        config::PassFilterType::Bessel => {
+            if conf.order == 0 || conf.order > BESSEL_MAX_ORDER {
+                let msg = format!("Bessel order must be between 1 and {}", BESSEL_MAX_ORDER);
+                return Err(config::ConfigError::new(&msg).into());
+            }
+        }
     }
+    Ok(())
+}
+
+/// Validate a Tilt filter config.
+pub fn validate_tilt_config(samplerate: usize, conf: &config::TiltParameters) -> Res<()> {
+    let maxfreq = samplerate as PrcFmt / 2.0;
+    if conf.pivot_freq <= 0.0 {
+        return Err(config::ConfigError::new("Pivot frequency must be > 0").into());
+    } else if conf.pivot_freq >= maxfreq {
+        return Err(config::ConfigError::new("Pivot frequency must be < samplerate/2").into());
+    }
+    if conf.slope_db_per_octave.abs() > TILT_MAX_SLOPE {
+        let msg = format!(
+            "Slope must be between -{} and {} dB per octave",
+            TILT_MAX_SLOPE, TILT_MAX_SLOPE
+        );
+        return Err(config::ConfigError::new(&msg).into());
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use crate::biquadcombo;
     use crate::config;
+    use crate::filters::Filter;
     use crate::PrcFmt;
 
     fn is_close(left: PrcFmt, right: PrcFmt, maxdiff: PrcFmt) -> bool {
@@ -390,4 +675,115 @@ mod tests {
         };
         assert!(biquadcombo::validate_config(fs, &badconf4).is_err());
     }
+
+    #[test]
+    fn check_sos() {
+        let fs = 48000;
+        let sections = vec![
+            [0.5, 0.0, 0.0, 1.0, 0.0, 0.0],
+            [2.0, 0.0, 0.0, 2.0, -0.5, 0.0],
+        ];
+        let conf = config::BiquadComboParameters::Sos { sections };
+        assert!(biquadcombo::validate_config(fs, &conf).is_ok());
+        let mut filter = biquadcombo::BiquadCombo::from_config("sos".to_string(), fs, conf);
+        let mut wave = vec![1.0, 0.0, 0.0, 0.0];
+        filter.process_waveform(&mut wave).unwrap();
+        let expect = vec![0.5, 0.125, 0.03125, 0.0078125];
+        assert!(compare_vecs(wave, expect, 1.0e-9));
+        let badconf = config::BiquadComboParameters::Sos {
+            sections: vec![[1.0, 0.0, 0.0, 1.0, -2.1, 1.2]],
+        };
+        assert!(biquadcombo::validate_config(fs, &badconf).is_err());
+        let emptyconf = config::BiquadComboParameters::Sos { sections: vec![] };
+        assert!(biquadcombo::validate_config(fs, &emptyconf).is_err());
+    }
+
+    fn sine_amplitude(filter: &mut biquadcombo::BiquadCombo, freq: PrcFmt, fs: usize) -> PrcFmt {
+        let mut wave: Vec<PrcFmt> = (0..fs)
+            .map(|n| {
+                (2.0 * std::f64::consts::PI as PrcFmt * freq * n as PrcFmt / fs as PrcFmt).sin()
+            })
+            .collect();
+        filter.process_waveform(&mut wave).unwrap();
+        wave[fs / 2..]
+            .iter()
+            .fold(0.0, |max, val| val.abs().max(max))
+    }
+
+    #[test]
+    fn bessel_cutoff() {
+        let fs = 48000;
+        for order in 1..=8 {
+            let conf = config::PassFilterParameters {
+                freq: 1000.0,
+                order,
+                filter_type: config::PassFilterType::Bessel,
+            };
+            assert!(biquadcombo::validate_pass_config(fs, &conf).is_ok());
+            let mut lowpass = biquadcombo::BiquadCombo::from_pass_config(
+                "lp".to_string(),
+                fs,
+                false,
+                conf.clone(),
+            );
+            let mut highpass =
+                biquadcombo::BiquadCombo::from_pass_config("hp".to_string(), fs, true, conf);
+            let half_power = (0.5 as PrcFmt).sqrt();
+            assert!(is_close(
+                sine_amplitude(&mut lowpass, 1000.0, fs),
+                half_power,
+                0.01
+            ));
+            assert!(is_close(
+                sine_amplitude(&mut highpass, 1000.0, fs),
+                half_power,
+                0.01
+            ));
+        }
+    }
+
+    #[test]
+    fn check_pass() {
+        let fs = 48000;
+        let mut conf = config::PassFilterParameters {
+            freq: 1000.0,
+            order: 9,
+            filter_type: config::PassFilterType::Butterworth,
+        };
+        assert!(biquadcombo::validate_pass_config(fs, &conf).is_ok());
+        conf.filter_type = config::PassFilterType::Bessel;
+        assert!(biquadcombo::validate_pass_config(fs, &conf).is_err());
+        conf.order = 3;
+        conf.filter_type = config::PassFilterType::LinkwitzRiley;
+        assert!(biquadcombo::validate_pass_config(fs, &conf).is_err());
+        conf.order = 4;
+        conf.freq = 25000.0;
+        assert!(biquadcombo::validate_pass_config(fs, &conf).is_err());
+    }
+
+    #[test]
+    fn tilt_slope() {
+        let fs = 48000;
+        let conf = config::TiltParameters {
+            pivot_freq: 1000.0,
+            slope_db_per_octave: 1.0,
+        };
+        assert!(biquadcombo::validate_tilt_config(fs, &conf).is_ok());
+        for (freq, gain_db) in [(250.0, -2.0), (500.0, -1.0), (1000.0, 0.0), (2000.0, 1.0)] {
+            let mut tilt =
+                biquadcombo::BiquadCombo::from_tilt_config("tilt".to_string(), fs, conf.clone());
+            let gain = (10.0 as PrcFmt).powf(gain_db / 20.0);
+            assert!(is_close(sine_amplitude(&mut tilt, freq, fs), gain, 0.02));
+        }
+        let steep = config::TiltParameters {
+            pivot_freq: 1000.0,
+            slope_db_per_octave: -7.0,
+        };
+        assert!(biquadcombo::validate_tilt_config(fs, &steep).is_err());
+        let high = config::TiltParameters {
+            pivot_freq: 25000.0,
+            slope_db_per_octave: 1.0,
+        };
+        assert!(biquadcombo::validate_tilt_config(fs, &high).is_err());
+    }
 }
@@ -1,4 +1,6 @@
+use crate::basicfilters;
 use crate::filters;
+use crate::flacfile;
 use crate::mixer;
 use serde::{de, Deserialize, Serialize};
 use serde_with;
@@ -13,6 +15,7 @@ use std::sync::RwLock;
 
 //type SmpFmt = i16;
 use crate::PrcFmt;
+use crate::{MAX_VOLUME_DB, MIN_VOLUME_DB};
 type Res<T> = Result<T, Box<dyn error::Error>>;
 
 pub struct Overrides {
@@ -20,6 +23,7 @@ pub struct Overrides {
     pub sample_format: Option<SampleFormat>,
     pub extra_samples: Option<usize>,
     pub channels: Option<usize>,
+    pub playback_sample_format: Option<SampleFormat>,
 }
 
 lazy_static! {
@@ -28,6 +32,7 @@ lazy_static! {
         sample_format: None,
         extra_samples: None,
         channels: None,
+        playback_sample_format: None,
     });
 }
 
@@ -129,6 +134,12 @@ pub enum CaptureDevice {
         channels: usize,
         device: String,
         format: SampleFormat,
+        #[serde(default)]
+        open_timeout_ms: u64,
+        #[serde(default = "default_open_retry_ms")]
+        open_retry_ms: u64,
+        #[serde(default)]
+        device_match: DeviceMatch,
     },
     #[cfg(feature = "pulse-backend")]
     #[serde(alias = "PULSE", alias = "pulse")]
@@ -150,6 +161,12 @@ pub enum CaptureDevice {
         skip_bytes: usize,
         #[serde(default)]
         read_bytes: usize,
+        #[serde(default)]
+        loop_start: Option<usize>,
+        #[serde(default)]
+        loop_end: Option<usize>,
+        #[serde(default)]
+        loop_crossfade: usize,
     },
     #[serde(alias = "STDIN", alias = "stdin")]
     Stdin {
@@ -185,6 +202,8 @@ pub enum CaptureDevice {
         exclusive: bool,
         #[serde(default)]
         loopback: bool,
+        #[serde(default)]
+        device_match: DeviceMatch,
     },
     #[cfg(all(feature = "cpal-backend", feature = "jack-backend"))]
     #[serde(alias = "JACK", alias = "jack")]
@@ -192,7 +211,47 @@ pub enum CaptureDevice {
         #[serde(deserialize_with = "validate_nonzero_usize")]
         channels: usize,
         device: String,
+        #[serde(default)]
+        autoconnect: Option<Vec<String>>,
+        #[serde(default = "default_jack_connect_timeout")]
+        connect_timeout: f32,
     },
+    #[serde(alias = "MULTI", alias = "multi")]
+    Multi { devices: Vec<CaptureDevice> },
+}
+
+/// Read a capture device, or a list of capture devices that are combined into one.
+fn deserialize_capture_device<'de, D>(d: D) -> Result<CaptureDevice, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    struct CaptureDeviceVisitor;
+
+    impl<'de> de::Visitor<'de> for CaptureDeviceVisitor {
+        type Value = CaptureDevice;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a capture device or a list of capture devices")
+        }
+
+        fn visit_map<A>(self, map: A) -> Result<CaptureDevice, A::Error>
+        where
+            A: de::MapAccess<'de>,
+        {
+            CaptureDevice::deserialize(de::value::MapAccessDeserializer::new(map))
+        }
+
+        fn visit_seq<A>(self, seq: A) -> Result<CaptureDevice, A::Error>
+        where
+            A: de::SeqAccess<'de>,
+        {
+            let devices =
+                Vec::<CaptureDevice>::deserialize(de::value::SeqAccessDeserializer::new(seq))?;
+            Ok(CaptureDevice::Multi { devices })
+        }
+    }
+
+    d.deserialize_any(CaptureDeviceVisitor)
 }
 
 impl CaptureDevice {
@@ -210,6 +269,7 @@ impl CaptureDevice {
             CaptureDevice::Wasapi { channels, .. } => *channels,
             #[cfg(all(feature = "cpal-backend", feature = "jack-backend"))]
             CaptureDevice::Jack { channels, .. } => *channels,
+            CaptureDevice::Multi { devices } => devices.iter().map(|dev| dev.channels()).sum(),
         }
     }
 
@@ -227,11 +287,15 @@ impl CaptureDevice {
             CaptureDevice::Wasapi { format, .. } => format.clone(),
             #[cfg(all(feature = "cpal-backend", feature = "jack-backend"))]
             CaptureDevice::Jack { .. } => SampleFormat::FLOAT32LE,
+            CaptureDevice::Multi { devices } => devices
+                .first()
+                .map(|dev| dev.sampleformat())
+                .unwrap_or(SampleFormat::FLOAT32LE),
         }
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 #[serde(tag = "type")]
 pub enum PlaybackDevice {
@@ -242,6 +306,8 @@ pub enum PlaybackDevice {
         channels: usize,
         device: String,
         format: SampleFormat,
+        #[serde(default)]
+        device_match: DeviceMatch,
     },
     #[cfg(feature = "pulse-backend")]
     #[serde(alias = "PULSE", alias = "pulse")]
@@ -257,6 +323,12 @@ pub enum PlaybackDevice {
         channels: usize,
         filename: String,
         format: SampleFormat,
+        #[serde(default)]
+        rollover_size: Option<usize>,
+        #[serde(default)]
+        rollover_duration: Option<f32>,
+        #[serde(default)]
+        bwf_metadata: bool,
     },
     #[serde(alias = "STDOUT", alias = "stdout")]
     Stdout {
@@ -286,6 +358,8 @@ pub enum PlaybackDevice {
         format: SampleFormat,
         #[serde(default)]
         exclusive: bool,
+        #[serde(default)]
+        device_match: DeviceMatch,
     },
     #[cfg(all(feature = "cpal-backend", feature = "jack-backend"))]
     #[serde(alias = "JACK", alias = "jack")]
@@ -293,7 +367,47 @@ pub enum PlaybackDevice {
         #[serde(deserialize_with = "validate_nonzero_usize")]
         channels: usize,
         device: String,
+        #[serde(default)]
+        autoconnect: Option<Vec<String>>,
+        #[serde(default = "default_jack_connect_timeout")]
+        connect_timeout: f32,
     },
+    #[serde(alias = "MULTI", alias = "multi")]
+    Multi { devices: Vec<PlaybackDevice> },
+}
+
+/// Read a playback device, or a list of playback devices that each get a part of the channels.
+fn deserialize_playback_device<'de, D>(d: D) -> Result<PlaybackDevice, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    struct PlaybackDeviceVisitor;
+
+    impl<'de> de::Visitor<'de> for PlaybackDeviceVisitor {
+        type Value = PlaybackDevice;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a playback device or a list of playback devices")
+        }
+
+        fn visit_map<A>(self, map: A) -> Result<PlaybackDevice, A::Error>
+        where
+            A: de::MapAccess<'de>,
+        {
+            PlaybackDevice::deserialize(de::value::MapAccessDeserializer::new(map))
+        }
+
+        fn visit_seq<A>(self, seq: A) -> Result<PlaybackDevice, A::Error>
+        where
+            A: de::SeqAccess<'de>,
+        {
+            let devices =
+                Vec::<PlaybackDevice>::deserialize(de::value::SeqAccessDeserializer::new(seq))?;
+            Ok(PlaybackDevice::Multi { devices })
+        }
+    }
+
+    d.deserialize_any(PlaybackDeviceVisitor)
 }
 
 impl PlaybackDevice {
@@ -311,6 +425,21 @@ impl PlaybackDevice {
             PlaybackDevice::Wasapi { channels, .. } => *channels,
             #[cfg(all(feature = "cpal-backend", feature = "jack-backend"))]
             PlaybackDevice::Jack { channels, .. } => *channels,
+            PlaybackDevice::Multi { devices } => devices.iter().map(|dev| dev.channels()).sum(),
+        }
+    }
+
+    /// Check if the device is always given float samples, leaving any conversion to integer to the driver.
+    pub fn takes_float_samples(&self) -> bool {
+        match self {
+            #[cfg(target_os = "macos")]
+            PlaybackDevice::CoreAudio { .. } => true,
+            #[cfg(all(feature = "cpal-backend", feature = "jack-backend"))]
+            PlaybackDevice::Jack { .. } => true,
+            PlaybackDevice::Multi { devices } => {
+                devices.iter().any(|dev| dev.takes_float_samples())
+            }
+            _ => false,
         }
     }
 }
@@ -328,7 +457,9 @@ pub struct Devices {
     pub silence_threshold: PrcFmt,
     #[serde(default)]
     pub silence_timeout: PrcFmt,
+    #[serde(deserialize_with = "deserialize_capture_device")]
     pub capture: CaptureDevice,
+    #[serde(deserialize_with = "deserialize_playback_device")]
     pub playback: PlaybackDevice,
     #[serde(default)]
     pub enable_rate_adjust: bool,
@@ -337,6 +468,8 @@ pub struct Devices {
     #[serde(default = "default_period")]
     pub adjust_period: f32,
     #[serde(default)]
+    pub resampling: ResamplingMode,
+    #[serde(default)]
     pub enable_resampling: bool,
     #[serde(default)]
     pub resampler_type: Resampler,
@@ -346,12 +479,281 @@ pub struct Devices {
     pub stop_on_rate_change: bool,
     #[serde(default = "default_measure_interval")]
     pub rate_measure_interval: f32,
+    #[serde(default)]
+    pub measured_rate_smoothing: f32,
+    #[serde(default)]
+    pub overload_protection: Option<OverloadProtection>,
+    #[serde(default)]
+    pub agc: Option<AgcParameters>,
+    #[serde(default)]
+    pub meter_used_only: bool,
+    #[serde(default)]
+    pub channel_labels: ChannelLabels,
+    #[serde(default)]
+    pub dc_block: bool,
+    #[serde(default = "default_dc_block_freq")]
+    pub dc_block_freq: PrcFmt,
+    #[serde(default)]
+    pub volume_on_reload: VolumeOnReload,
+    #[serde(default)]
+    pub initial_volume: f32,
+    #[serde(default)]
+    pub startup_volume: Option<f32>,
+    #[serde(default)]
+    pub extra_channels: ExtraChannels,
+    #[serde(default)]
+    pub surplus_output: SurplusOutput,
+    #[serde(default)]
+    pub mono_to_all: bool,
+    #[serde(default)]
+    pub clock_source: ClockSource,
+    #[serde(default)]
+    pub compensate_fir_latency: bool,
+    #[serde(default = "default_status_interval")]
+    pub status_interval_ms: usize,
+    #[serde(default)]
+    pub on_playback_done: PlaybackDoneAction,
+    #[serde(default)]
+    pub on_processing_error: ProcessingErrorAction,
+    #[serde(default)]
+    pub int_conversion: IntConversion,
+    #[serde(default)]
+    pub rt_priority: Option<u8>,
+    #[serde(default = "default_load_warning_level")]
+    pub load_warning_level: f32,
+    #[serde(default = "default_clip_hold_time")]
+    pub clip_hold_time: f32,
+    #[serde(default)]
+    pub startup_timeout: Option<f32>,
+    #[serde(default)]
+    pub capture_monitor_file: Option<String>,
+}
+
+/// How the configured device name is matched against the names of the available devices.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub enum DeviceMatch {
+    #[serde(rename = "exact")]
+    Exact,
+    #[serde(rename = "substring")]
+    Substring,
+}
+
+impl Default for DeviceMatch {
+    fn default() -> Self {
+        DeviceMatch::Exact
+    }
+}
+
+/// How samples are converted to an integer format on playback.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub enum IntConversion {
+    #[serde(rename = "truncate")]
+    Truncate,
+    #[serde(rename = "round")]
+    Round,
+    #[serde(rename = "dither_tpdf")]
+    DitherTpdf,
+}
+
+impl Default for IntConversion {
+    fn default() -> Self {
+        IntConversion::Truncate
+    }
+}
+
+/// Whether a resampler may be used at all.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub enum ResamplingMode {
+    #[serde(rename = "allowed")]
+    Allowed,
+    #[serde(rename = "none")]
+    None,
+}
+
+impl Default for ResamplingMode {
+    fn default() -> Self {
+        ResamplingMode::Allowed
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub enum PlaybackDoneAction {
+    #[serde(rename = "exit")]
+    Exit,
+    #[serde(rename = "restart")]
+    Restart,
+    #[serde(rename = "loop")]
+    Loop,
+}
+
+impl Default for PlaybackDoneAction {
+    fn default() -> Self {
+        PlaybackDoneAction::Restart
+    }
+}
+
+/// Names of the capture and playback channels, used when reporting signal levels.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ChannelLabels {
+    #[serde(default)]
+    pub capture: Vec<String>,
+    #[serde(default)]
+    pub playback: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub enum ProcessingErrorAction {
+    #[serde(rename = "silence")]
+    Silence,
+    #[serde(rename = "restart")]
+    Restart,
+    #[serde(rename = "exit")]
+    Exit,
+}
+
+impl Default for ProcessingErrorAction {
+    fn default() -> Self {
+        ProcessingErrorAction::Restart
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub enum ClockSource {
+    #[serde(rename = "playback")]
+    Playback,
+    #[serde(rename = "capture")]
+    Capture,
+}
+
+impl Default for ClockSource {
+    fn default() -> Self {
+        ClockSource::Playback
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub enum ExtraChannels {
+    #[serde(rename = "ignore")]
+    Ignore,
+    #[serde(rename = "error")]
+    Error,
+    #[serde(rename = "mix_to_first")]
+    MixToFirst,
+}
+
+impl Default for ExtraChannels {
+    fn default() -> Self {
+        ExtraChannels::Ignore
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub enum SurplusOutput {
+    #[serde(rename = "error")]
+    Error,
+    #[serde(rename = "drop")]
+    Drop,
+    #[serde(rename = "sum_to_last")]
+    SumToLast,
+}
+
+impl Default for SurplusOutput {
+    fn default() -> Self {
+        SurplusOutput::Error
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub enum VolumeOnReload {
+    #[serde(rename = "keep")]
+    Keep,
+    #[serde(rename = "config")]
+    Config,
+}
+
+impl Default for VolumeOnReload {
+    fn default() -> Self {
+        VolumeOnReload::Keep
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct OverloadProtection {
+    pub clip_limit: usize,
+    #[serde(default = "default_overload_step")]
+    pub volume_step: f32,
+    #[serde(default = "default_overload_window")]
+    pub window: f32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AgcParameters {
+    pub target_level: f32,
+    #[serde(default = "default_agc_max_gain")]
+    pub max_gain: f32,
+    #[serde(default = "default_agc_attack")]
+    pub attack: f32,
+    #[serde(default = "default_agc_release")]
+    pub release: f32,
+    #[serde(default = "default_agc_gate_level")]
+    pub gate_level: f32,
+}
+
+fn default_agc_max_gain() -> f32 {
+    20.0
+}
+
+fn default_agc_attack() -> f32 {
+    2.0
+}
+
+fn default_agc_release() -> f32 {
+    10.0
+}
+
+fn default_agc_gate_level() -> f32 {
+    -60.0
+}
+
+#[cfg(target_os = "linux")]
+fn default_open_retry_ms() -> u64 {
+    500
+}
+
+#[cfg(all(feature = "cpal-backend", feature = "jack-backend"))]
+fn default_jack_connect_timeout() -> f32 {
+    5.0
+}
+
+fn default_overload_step() -> f32 {
+    3.0
+}
+
+fn default_overload_window() -> f32 {
+    1.0
 }
 
 fn default_period() -> f32 {
     10.0
 }
 
+/// Largest allowed number of chunks in each of the queues between the threads.
+const MAX_QUEUELIMIT: usize = 128;
+/// Warn when the queues can buffer more than this, in milliseconds.
+const QUEUE_LATENCY_WARNING_MS: f32 = 1000.0;
+
 fn default_queuelimit() -> usize {
     4
 }
@@ -360,6 +762,22 @@ fn default_measure_interval() -> f32 {
     1.0
 }
 
+fn default_status_interval() -> usize {
+    1000
+}
+
+fn default_load_warning_level() -> f32 {
+    0.8
+}
+
+fn default_clip_hold_time() -> f32 {
+    2.0
+}
+
+fn default_dc_block_freq() -> PrcFmt {
+    2.0
+}
+
 #[cfg(target_os = "macos")]
 fn default_ca_format() -> SampleFormat {
     SampleFormat::S32LE
@@ -438,6 +856,33 @@ pub enum Filter {
     DiffEq {
         parameters: DiffEqParameters,
     },
+    DynamicEq {
+        parameters: DynamicEqParameters,
+    },
+    Highpass {
+        parameters: PassFilterParameters,
+    },
+    Lowpass {
+        parameters: PassFilterParameters,
+    },
+    Deemphasis {
+        #[serde(default)]
+        parameters: EmphasisParameters,
+    },
+    Preemphasis {
+        #[serde(default)]
+        parameters: EmphasisParameters,
+    },
+    Crossfeed {
+        #[serde(default)]
+        parameters: CrossfeedParameters,
+    },
+    Tilt {
+        parameters: TiltParameters,
+    },
+    Shaper {
+        parameters: ShaperParameters,
+    },
 }
 
 #[allow(clippy::upper_case_acronyms)]
@@ -492,16 +937,55 @@ pub enum ConvParameters {
         skip_bytes_lines: usize,
         #[serde(default)]
         read_bytes_lines: usize,
+        #[serde(default)]
+        normalize: ConvNormalization,
+        #[serde(default)]
+        minimum_phase: bool,
+        #[serde(default)]
+        fft_library: Option<FftLibrary>,
     },
     Wav {
         filename: String,
         #[serde(default)]
         channel: usize,
+        #[serde(default)]
+        normalize: ConvNormalization,
+        #[serde(default)]
+        minimum_phase: bool,
+        #[serde(default)]
+        fft_library: Option<FftLibrary>,
     },
     Values {
         values: Vec<PrcFmt>,
         #[serde(default)]
         length: usize,
+        #[serde(default)]
+        normalize: ConvNormalization,
+        #[serde(default)]
+        minimum_phase: bool,
+        #[serde(default)]
+        fft_library: Option<FftLibrary>,
+    },
+    Base64 {
+        values_base64: String,
+        format: FileFormat,
+        #[serde(default)]
+        normalize: ConvNormalization,
+        #[serde(default)]
+        minimum_phase: bool,
+        #[serde(default)]
+        fft_library: Option<FftLibrary>,
+    },
+    MultiRate {
+        filenames: HashMap<usize, String>,
+        #[serde(default)]
+        channel: usize,
+        #[serde(default)]
+        normalize: ConvNormalization,
+        #[serde(default)]
+        minimum_phase: bool,
+        #[serde(default)]
+        fft_library: Option<FftLibrary>,
     },
 }
 
@@ -516,21 +1000,88 @@ impl Default for ConvParameters {
         ConvParameters::Values {
             values: vec![1.0],
             length: 0,
+            normalize: ConvNormalization::None,
+            minimum_phase: false,
+            fft_library: None,
+        }
+    }
+}
+
+impl ConvParameters {
+    pub fn normalization(&self) -> ConvNormalization {
+        match self {
+            ConvParameters::Raw { normalize, .. }
+            | ConvParameters::Wav { normalize, .. }
+            | ConvParameters::Values { normalize, .. }
+            | ConvParameters::Base64 { normalize, .. }
+            | ConvParameters::MultiRate { normalize, .. } => *normalize,
+        }
+    }
+
+    pub fn minimum_phase(&self) -> bool {
+        match self {
+            ConvParameters::Raw { minimum_phase, .. }
+            | ConvParameters::Wav { minimum_phase, .. }
+            | ConvParameters::Values { minimum_phase, .. }
+            | ConvParameters::Base64 { minimum_phase, .. }
+            | ConvParameters::MultiRate { minimum_phase, .. } => *minimum_phase,
+        }
+    }
+
+    pub fn fft_library(&self) -> Option<FftLibrary> {
+        match self {
+            ConvParameters::Raw { fft_library, .. }
+            | ConvParameters::Wav { fft_library, .. }
+            | ConvParameters::Values { fft_library, .. }
+            | ConvParameters::Base64 { fft_library, .. }
+            | ConvParameters::MultiRate { fft_library, .. } => *fft_library,
         }
     }
 }
 
+/// FFT library used by a convolution filter.
+/// When not given, FFTW is used if it is compiled in, otherwise RustFFT via RealFFT.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub enum FftLibrary {
+    #[serde(rename = "realfft")]
+    RealFft,
+    #[serde(rename = "fftw")]
+    Fftw,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub enum ConvNormalization {
+    #[serde(rename = "none")]
+    None,
+    #[serde(rename = "peak")]
+    Peak,
+    #[serde(rename = "energy")]
+    Energy,
+    #[serde(rename = "dc")]
+    Dc,
+}
+
+impl Default for ConvNormalization {
+    fn default() -> Self {
+        ConvNormalization::None
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 #[serde(untagged)]
 pub enum ShelfSteepness {
     Q {
         freq: PrcFmt,
         q: PrcFmt,
+        #[serde(deserialize_with = "deserialize_gain")]
         gain: PrcFmt,
     },
     Slope {
         freq: PrcFmt,
         slope: PrcFmt,
+        #[serde(deserialize_with = "deserialize_gain")]
         gain: PrcFmt,
     },
 }
@@ -541,11 +1092,13 @@ pub enum PeakingWidth {
     Q {
         freq: PrcFmt,
         q: PrcFmt,
+        #[serde(deserialize_with = "deserialize_gain")]
         gain: PrcFmt,
     },
     Bandwidth {
         freq: PrcFmt,
         bandwidth: PrcFmt,
+        #[serde(deserialize_with = "deserialize_gain")]
         gain: PrcFmt,
     },
 }
@@ -557,11 +1110,45 @@ pub enum NotchWidth {
     Bandwidth { freq: PrcFmt, bandwidth: PrcFmt },
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum BandpassNormalization {
+    /// Constant 0 dB peak gain
+    #[serde(rename = "peak")]
+    Peak,
+    /// Constant skirt gain, the peak gain equals Q
+    #[serde(rename = "skirt")]
+    Skirt,
+}
+
+impl Default for BandpassNormalization {
+    fn default() -> Self {
+        BandpassNormalization::Peak
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum BandpassWidth {
+    Q {
+        freq: PrcFmt,
+        q: PrcFmt,
+        #[serde(default)]
+        normalization: BandpassNormalization,
+    },
+    Bandwidth {
+        freq: PrcFmt,
+        bandwidth: PrcFmt,
+        #[serde(default)]
+        normalization: BandpassNormalization,
+    },
+}
+
 #[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 #[serde(tag = "type")]
 #[serde(deny_unknown_fields)]
 pub enum BiquadParameters {
+    #[serde(alias = "Coefficients")]
     Free {
         a1: PrcFmt,
         a2: PrcFmt,
@@ -581,11 +1168,13 @@ pub enum BiquadParameters {
     Highshelf(ShelfSteepness),
     HighshelfFO {
         freq: PrcFmt,
+        #[serde(deserialize_with = "deserialize_gain")]
         gain: PrcFmt,
     },
     Lowshelf(ShelfSteepness),
     LowshelfFO {
         freq: PrcFmt,
+        #[serde(deserialize_with = "deserialize_gain")]
         gain: PrcFmt,
     },
     HighpassFO {
@@ -598,7 +1187,7 @@ pub enum BiquadParameters {
     AllpassFO {
         freq: PrcFmt,
     },
-    Bandpass(NotchWidth),
+    Bandpass(BandpassWidth),
     Notch(NotchWidth),
     LinkwitzTransform {
         freq_act: PrcFmt,
@@ -645,6 +1234,9 @@ pub enum BiquadComboParameters {
         qhs: PrcFmt,
         ghs: PrcFmt,
     },
+    Sos {
+        sections: Vec<[PrcFmt; 6]>,
+    },
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -652,6 +1244,10 @@ pub enum BiquadComboParameters {
 pub struct VolumeParameters {
     #[serde(default = "default_ramp_time")]
     pub ramp_time: f32,
+    #[serde(default)]
+    pub mute_fade_ms: Option<f32>,
+    #[serde(default)]
+    pub gain_dither: bool,
 }
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
@@ -663,6 +1259,136 @@ pub struct LoudnessParameters {
     pub high_boost: f32,
     #[serde(default = "default_loudness_boost")]
     pub low_boost: f32,
+    #[serde(default)]
+    pub max_boost_db: Option<f32>,
+    #[serde(default)]
+    pub gain_dither: bool,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub enum PassFilterType {
+    #[serde(rename = "butterworth")]
+    Butterworth,
+    #[serde(rename = "bessel")]
+    Bessel,
+    #[serde(rename = "linkwitzriley")]
+    LinkwitzRiley,
+}
+
+impl Default for PassFilterType {
+    fn default() -> Self {
+        PassFilterType::Butterworth
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PassFilterParameters {
+    pub freq: PrcFmt,
+    pub order: usize,
+    #[serde(rename = "type", default)]
+    pub filter_type: PassFilterType,
+}
+
+/// A tilt of the spectrum in dB per octave, pivoting around a frequency in Hz.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TiltParameters {
+    pub pivot_freq: PrcFmt,
+    pub slope_db_per_octave: PrcFmt,
+}
+
+/// A transfer curve for waveshaping, as pairs of input and output sample values.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ShaperParameters {
+    pub curve: Vec<[PrcFmt; 2]>,
+}
+
+/// Time constants in microseconds of an emphasis curve, with a pole at t1 and a zero at t2.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct EmphasisParameters {
+    #[serde(default = "default_emphasis_t1")]
+    pub t1: PrcFmt,
+    #[serde(default = "default_emphasis_t2")]
+    pub t2: PrcFmt,
+}
+
+fn default_emphasis_t1() -> PrcFmt {
+    50.0
+}
+
+fn default_emphasis_t2() -> PrcFmt {
+    15.0
+}
+
+impl Default for EmphasisParameters {
+    fn default() -> Self {
+        EmphasisParameters {
+            t1: default_emphasis_t1(),
+            t2: default_emphasis_t2(),
+        }
+    }
+}
+
+/// Parameters of a headphone crossfeed, with the cutoff frequency in Hz,
+/// the delay in milliseconds and the gain in dB of the signal fed to the opposite channel.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CrossfeedParameters {
+    #[serde(default = "default_crossfeed_freq")]
+    pub freq: PrcFmt,
+    #[serde(default = "default_crossfeed_delay")]
+    pub delay: PrcFmt,
+    #[serde(default = "default_crossfeed_gain")]
+    pub gain: PrcFmt,
+}
+
+fn default_crossfeed_freq() -> PrcFmt {
+    700.0
+}
+
+fn default_crossfeed_delay() -> PrcFmt {
+    0.3
+}
+
+fn default_crossfeed_gain() -> PrcFmt {
+    -4.5
+}
+
+impl Default for CrossfeedParameters {
+    fn default() -> Self {
+        CrossfeedParameters {
+            freq: default_crossfeed_freq(),
+            delay: default_crossfeed_delay(),
+            gain: default_crossfeed_gain(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DynamicEqParameters {
+    pub freq: PrcFmt,
+    pub q: PrcFmt,
+    #[serde(default)]
+    pub gain: PrcFmt,
+    pub threshold: PrcFmt,
+    pub ratio: PrcFmt,
+    #[serde(default = "default_dynamic_eq_attack")]
+    pub attack: PrcFmt,
+    #[serde(default = "default_dynamic_eq_release")]
+    pub release: PrcFmt,
+}
+
+fn default_dynamic_eq_attack() -> PrcFmt {
+    10.0
+}
+
+fn default_dynamic_eq_release() -> PrcFmt {
+    100.0
 }
 
 fn default_loudness_boost() -> f32 {
@@ -676,6 +1402,7 @@ fn default_ramp_time() -> f32 {
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct GainParameters {
+    #[serde(deserialize_with = "deserialize_gain")]
     pub gain: PrcFmt,
     #[serde(default)]
     pub inverted: bool,
@@ -746,7 +1473,7 @@ pub struct MixerChannels {
 #[serde(deny_unknown_fields)]
 pub struct MixerSource {
     pub channel: usize,
-    #[serde(default)]
+    #[serde(default, deserialize_with = "deserialize_gain")]
     pub gain: PrcFmt,
     #[serde(default)]
     pub inverted: bool,
@@ -767,15 +1494,124 @@ pub struct MixerMapping {
 #[serde(deny_unknown_fields)]
 pub struct Mixer {
     pub channels: MixerChannels,
-    pub mapping: Vec<MixerMapping>,
+    /// The mapping may only be left out when a downmix is given, that is then expanded into a mapping.
+    #[serde(default)]
+    pub mapping: Option<Vec<MixerMapping>>,
+    #[serde(default)]
+    pub auto_attenuate: bool,
+    #[serde(default)]
+    pub downmix: Option<DownmixPreset>,
+}
+
+impl Mixer {
+    /// Get the mapping of the mixer, empty if none was given.
+    pub fn mapping(&self) -> &[MixerMapping] {
+        self.mapping.as_deref().unwrap_or(&[])
+    }
 }
 
+/// A standard downmix, that is expanded into the mapping of a mixer when the config is validated.
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct DownmixPreset {
+    pub layout_in: ChannelLayout,
+    pub layout_out: ChannelLayout,
+    pub recipe: DownmixRecipe,
+}
+
+/// Speaker layouts, with channels in the usual order of wav files.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ChannelLayout {
+    /// Left, Right
+    #[serde(rename = "2.0")]
+    Stereo,
+    /// Left, Right, Center, LFE, Left surround, Right surround
+    #[serde(rename = "5.1")]
+    Surround51,
+    /// Left, Right, Center, LFE, Left back, Right back, Left side, Right side
+    #[serde(rename = "7.1")]
+    Surround71,
+}
+
+impl ChannelLayout {
+    pub fn channels(&self) -> usize {
+        match self {
+            ChannelLayout::Stereo => 2,
+            ChannelLayout::Surround51 => 6,
+            ChannelLayout::Surround71 => 8,
+        }
+    }
+}
+
+impl fmt::Display for ChannelLayout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ChannelLayout::Stereo => "2.0",
+            ChannelLayout::Surround51 => "5.1",
+            ChannelLayout::Surround71 => "7.1",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum DownmixRecipe {
+    #[serde(rename = "itu")]
+    Itu,
+    #[serde(rename = "dolby")]
+    Dolby,
+    #[serde(rename = "dolby_pl2")]
+    DolbyPl2,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 #[serde(tag = "type")]
 #[serde(deny_unknown_fields)]
 pub enum PipelineStep {
-    Mixer { name: String },
-    Filter { channel: usize, names: Vec<String> },
+    Mixer {
+        name: String,
+    },
+    Filter {
+        channel: usize,
+        names: Vec<String>,
+        #[serde(default)]
+        gain_compensate: bool,
+        #[serde(default = "default_mix")]
+        mix: PrcFmt,
+    },
+    Crossfeed {
+        name: String,
+    },
+}
+
+fn default_mix() -> PrcFmt {
+    1.0
+}
+
+impl fmt::Display for PipelineStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PipelineStep::Mixer { name } => write!(f, "Mixer {}", name),
+            PipelineStep::Filter { channel, names, .. } => {
+                write!(f, "Filter {}: {}", channel, names.join(", "))
+            }
+            PipelineStep::Crossfeed { name } => write!(f, "Crossfeed {}", name),
+        }
+    }
+}
+
+/// Mixers, filters and pipeline to use at a specific samplerate.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SamplerateConfig {
+    #[serde(default)]
+    #[serde(deserialize_with = "serde_with::rust::maps_duplicate_key_is_error::deserialize")]
+    pub mixers: HashMap<String, Mixer>,
+    #[serde(default)]
+    #[serde(deserialize_with = "serde_with::rust::maps_duplicate_key_is_error::deserialize")]
+    pub filters: HashMap<String, Filter>,
+    #[serde(default)]
+    pub pipeline: Option<Vec<PipelineStep>>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -783,12 +1619,72 @@ pub enum PipelineStep {
 pub struct Configuration {
     pub devices: Devices,
     #[serde(default)]
+    #[serde(deserialize_with = "serde_with::rust::maps_duplicate_key_is_error::deserialize")]
     pub mixers: HashMap<String, Mixer>,
     #[serde(default)]
     #[serde(deserialize_with = "serde_with::rust::maps_duplicate_key_is_error::deserialize")]
     pub filters: HashMap<String, Filter>,
     #[serde(default)]
     pub pipeline: Vec<PipelineStep>,
+    #[serde(default)]
+    pub samplerates: HashMap<usize, SamplerateConfig>,
+}
+
+/// Unit of a gain value given together with its unit.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+pub enum GainUnit {
+    #[serde(rename = "dB")]
+    Decibel,
+    #[serde(rename = "linear")]
+    Linear,
+}
+impl Default for GainUnit {
+    fn default() -> Self {
+        GainUnit::Decibel
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GainWithUnit {
+    value: PrcFmt,
+    #[serde(default)]
+    unit: GainUnit,
+}
+
+/// A gain is either a plain number in dB, or a value with a unit.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum GainValue {
+    Decibel(PrcFmt),
+    WithUnit(GainWithUnit),
+}
+
+/// Read a gain that may be given as a linear factor, and convert it to dB.
+fn deserialize_gain<'de, D>(d: D) -> Result<PrcFmt, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    match GainValue::deserialize(d)? {
+        GainValue::Decibel(gain) => Ok(gain),
+        GainValue::WithUnit(GainWithUnit {
+            value,
+            unit: GainUnit::Decibel,
+        }) => Ok(value),
+        GainValue::WithUnit(GainWithUnit {
+            value,
+            unit: GainUnit::Linear,
+        }) => {
+            if value > 0.0 {
+                Ok(20.0 * value.log10())
+            } else {
+                Err(de::Error::custom(format!(
+                    "linear gain must be > 0, got {}",
+                    value
+                )))
+            }
+        }
+    }
 }
 
 fn validate_nonzero_usize<'de, D>(d: D) -> Result<usize, D::Error>
@@ -805,6 +1701,218 @@ where
     Ok(value)
 }
 
+#[cfg(target_os = "linux")]
+const DEFAULT_DEVICES: &str = "
+  capture:
+    type: Alsa
+    channels: 2
+    device: \"hw:0\"
+    format: S32LE
+  playback:
+    type: Alsa
+    channels: 2
+    device: \"hw:0\"
+    format: S32LE
+";
+
+#[cfg(target_os = "macos")]
+const DEFAULT_DEVICES: &str = "
+  capture:
+    type: CoreAudio
+    channels: 2
+    device: \"default\"
+    format: FLOAT32LE
+  playback:
+    type: CoreAudio
+    channels: 2
+    device: \"default\"
+    format: FLOAT32LE
+";
+
+#[cfg(target_os = "windows")]
+const DEFAULT_DEVICES: &str = "
+  capture:
+    type: Wasapi
+    channels: 2
+    device: \"default\"
+    format: FLOAT32LE
+  playback:
+    type: Wasapi
+    channels: 2
+    device: \"default\"
+    format: FLOAT32LE
+";
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+const DEFAULT_DEVICES: &str = "
+  capture:
+    type: Stdin
+    channels: 2
+    format: S16LE
+  playback:
+    type: Stdout
+    channels: 2
+    format: S16LE
+";
+
+/// Get a minimal config for stereo passthrough, using the default audio backend of the platform.
+/// The device names are placeholders that need to be replaced by real devices.
+pub fn default_config() -> Configuration {
+    default_config_with_channels(2)
+}
+
+/// Get a minimal passthrough config like [default_config], with the given number of channels.
+pub fn default_config_with_channels(channels: usize) -> Configuration {
+    let yaml = format!(
+        "devices:\n  samplerate: 44100\n  chunksize: 1024\n{}",
+        DEFAULT_DEVICES.replace("channels: 2", &format!("channels: {}", channels))
+    );
+    serde_yaml::from_str(&yaml).unwrap()
+}
+
+/// A step on the way to a value in a serialized config.
+#[derive(Clone)]
+enum ValuePathStep {
+    Key(serde_yaml::Value),
+    Index(usize),
+}
+
+/// Collect the paths to all mapping entries of a serialized config, with nested entries before their parents.
+fn collect_value_paths(
+    value: &serde_yaml::Value,
+    prefix: &mut Vec<ValuePathStep>,
+    paths: &mut Vec<Vec<ValuePathStep>>,
+) {
+    match value {
+        serde_yaml::Value::Mapping(mapping) => {
+            for (key, child) in mapping.iter() {
+                prefix.push(ValuePathStep::Key(key.clone()));
+                collect_value_paths(child, prefix, paths);
+                paths.push(prefix.clone());
+                prefix.pop();
+            }
+        }
+        serde_yaml::Value::Sequence(sequence) => {
+            for (idx, child) in sequence.iter().enumerate() {
+                prefix.push(ValuePathStep::Index(idx));
+                collect_value_paths(child, prefix, paths);
+                prefix.pop();
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Remove the mapping entry at the end of a path, returning false if it wasn't found.
+fn remove_value(value: &mut serde_yaml::Value, path: &[ValuePathStep]) -> bool {
+    let (last, parents) = match path.split_last() {
+        Some(split) => split,
+        None => return false,
+    };
+    let mut current = value;
+    for step in parents {
+        let next = match (step, current) {
+            (ValuePathStep::Key(key), serde_yaml::Value::Mapping(mapping)) => mapping.get_mut(key),
+            (ValuePathStep::Index(idx), serde_yaml::Value::Sequence(sequence)) => {
+                sequence.get_mut(*idx)
+            }
+            _ => None,
+        };
+        current = match next {
+            Some(next) => next,
+            None => return false,
+        };
+    }
+    match (last, current) {
+        (ValuePathStep::Key(key), serde_yaml::Value::Mapping(mapping)) => {
+            if !mapping.contains_key(key) {
+                return false;
+            }
+            // Rebuild the mapping instead of removing the entry, to keep the order of the fields
+            *mapping = mapping
+                .iter()
+                .filter(|(other, _)| *other != key)
+                .map(|(other, child)| (other.clone(), child.clone()))
+                .collect();
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Remove the fields of a serialized value that are optional and set to their default value.
+/// Each field is left out if the value still deserializes to the same `T` without it.
+/// With `nested` set, fields of nested mappings are also checked, otherwise only the top level.
+fn strip_default_values<T>(value: &mut serde_yaml::Value, nested: bool) -> Res<()>
+where
+    T: serde::de::DeserializeOwned + PartialEq,
+{
+    let original: T = serde_yaml::from_value(value.clone())?;
+    let mut paths = Vec::new();
+    if nested {
+        collect_value_paths(value, &mut Vec::new(), &mut paths);
+    } else if let serde_yaml::Value::Mapping(mapping) = value {
+        paths = mapping
+            .iter()
+            .map(|(key, _)| vec![ValuePathStep::Key(key.clone())])
+            .collect();
+    }
+    for path in paths.iter() {
+        let mut candidate = value.clone();
+        if remove_value(&mut candidate, path)
+            && serde_yaml::from_value::<T>(candidate.clone())
+                .map_or(false, |candidate_value| candidate_value == original)
+        {
+            *value = candidate;
+        }
+    }
+    Ok(())
+}
+
+/// Strip the default values from each entry of a serialized map or list of `T`.
+fn strip_default_entries<T>(value: &mut serde_yaml::Value) -> Res<()>
+where
+    T: serde::de::DeserializeOwned + PartialEq,
+{
+    match value {
+        serde_yaml::Value::Mapping(mapping) => {
+            for (_, entry) in mapping.iter_mut() {
+                strip_default_values::<T>(entry, true)?;
+            }
+        }
+        serde_yaml::Value::Sequence(sequence) => {
+            for entry in sequence.iter_mut() {
+                strip_default_values::<T>(entry, true)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Get the parts of a config that differ from the default values.
+/// Optional fields that are set to their default value are left out, required fields are always included.
+/// The devices and each mixer, filter, pipeline step and samplerate config are checked separately,
+/// so that a field is only compared within the part of the config it belongs to.
+pub fn get_non_default_config(conf: &Configuration) -> Res<serde_yaml::Value> {
+    let mut stripped = serde_yaml::to_value(conf)?;
+    if let serde_yaml::Value::Mapping(sections) = &mut stripped {
+        for (key, section) in sections.iter_mut() {
+            match key.as_str() {
+                Some("devices") => strip_default_values::<Devices>(section, true)?,
+                Some("mixers") => strip_default_entries::<Mixer>(section)?,
+                Some("filters") => strip_default_entries::<Filter>(section)?,
+                Some("pipeline") => strip_default_entries::<PipelineStep>(section)?,
+                Some("samplerates") => strip_default_entries::<SamplerateConfig>(section)?,
+                _ => {}
+            }
+        }
+    }
+    // Leave out the sections that are empty
+    strip_default_values::<Configuration>(&mut stripped, false)?;
+    Ok(stripped)
+}
+
 pub fn load_config(filename: &str) -> Res<Configuration> {
     let file = match File::open(filename) {
         Ok(f) => f,
@@ -854,6 +1962,12 @@ fn apply_overrides(configuration: &mut Configuration) {
                 cfg_chunksize, scaled_chunksize
             );
             configuration.devices.chunksize = scaled_chunksize;
+            // Without a resampler, a given capture_samplerate must follow the samplerate
+            if configuration.devices.resampling == ResamplingMode::None
+                && configuration.devices.capture_samplerate > 0
+            {
+                configuration.devices.capture_samplerate = rate;
+            }
             #[allow(unreachable_patterns)]
             match &mut configuration.devices.capture {
                 CaptureDevice::File { extra_samples, .. } => {
@@ -919,6 +2033,9 @@ fn apply_overrides(configuration: &mut Configuration) {
             CaptureDevice::Jack { channels, .. } => {
                 *channels = chans;
             }
+            CaptureDevice::Multi { .. } => {
+                error!("Not possible to override capture channels for multiple devices, ignoring");
+            }
         }
     }
     if let Some(fmt) = OVERRIDES.read().unwrap().sample_format.clone() {
@@ -950,6 +2067,43 @@ fn apply_overrides(configuration: &mut Configuration) {
             CaptureDevice::Jack { .. } => {
                 error!("Not possible to override capture format for Jack, ignoring");
             }
+            CaptureDevice::Multi { .. } => {
+                error!("Not possible to override capture format for multiple devices, ignoring");
+            }
+        }
+    }
+    if let Some(fmt) = OVERRIDES.read().unwrap().playback_sample_format.clone() {
+        debug!("Apply override for playback sample format: {}", fmt);
+        match &mut configuration.devices.playback {
+            PlaybackDevice::File { format, .. } => {
+                *format = fmt;
+            }
+            PlaybackDevice::Stdout { format, .. } => {
+                *format = fmt;
+            }
+            #[cfg(target_os = "linux")]
+            PlaybackDevice::Alsa { format, .. } => {
+                *format = fmt;
+            }
+            #[cfg(feature = "pulse-backend")]
+            PlaybackDevice::Pulse { format, .. } => {
+                *format = fmt;
+            }
+            #[cfg(target_os = "macos")]
+            PlaybackDevice::CoreAudio { format, .. } => {
+                *format = fmt;
+            }
+            #[cfg(target_os = "windows")]
+            PlaybackDevice::Wasapi { format, .. } => {
+                *format = fmt;
+            }
+            #[cfg(all(feature = "cpal-backend", feature = "jack-backend"))]
+            PlaybackDevice::Jack { .. } => {
+                error!("Not possible to override playback format for Jack, ignoring");
+            }
+            PlaybackDevice::Multi { .. } => {
+                error!("Not possible to override playback format for multiple devices, ignoring");
+            }
         }
     }
 }
@@ -962,6 +2116,52 @@ fn replace_tokens(string: &str, samplerate: usize, channels: usize) -> String {
         .replace("$channels$", &ch)
 }
 
+/// Merge the mixers, filters and pipeline for the current samplerate into the config.
+fn apply_samplerate_config(config: &mut Configuration) {
+    if let Some(rate_conf) = config.samplerates.get(&config.devices.samplerate).cloned() {
+        debug!("Apply config for samplerate {}", config.devices.samplerate);
+        config.mixers.extend(rate_conf.mixers);
+        config.filters.extend(rate_conf.filters);
+        if let Some(pipeline) = rate_conf.pipeline {
+            config.pipeline = pipeline;
+        }
+    }
+}
+
+// Replace MultiRate Conv filters by a Wav filter using the file for the current samplerate, if there is one
+fn select_multirate_files(config: &mut Configuration) {
+    let samplerate = config.devices.samplerate;
+    for (name, filter) in config.filters.iter_mut() {
+        if let Filter::Conv {
+            parameters:
+                ConvParameters::MultiRate {
+                    filenames,
+                    channel,
+                    normalize,
+                    minimum_phase,
+                    fft_library,
+                },
+        } = filter
+        {
+            if let Some(filename) = filenames.get(&samplerate) {
+                debug!(
+                    "Using file '{}' for filter '{}' at samplerate {}",
+                    filename, name, samplerate
+                );
+                *filter = Filter::Conv {
+                    parameters: ConvParameters::Wav {
+                        filename: filename.clone(),
+                        channel: *channel,
+                        normalize: *normalize,
+                        minimum_phase: *minimum_phase,
+                        fft_library: *fft_library,
+                    },
+                };
+            }
+        }
+    }
+}
+
 fn replace_tokens_in_config(config: &mut Configuration) {
     let samplerate = config.devices.samplerate;
     let num_channels = config.devices.capture.channels();
@@ -985,7 +2185,7 @@ fn replace_tokens_in_config(config: &mut Configuration) {
                     *name = replace_tokens(name, samplerate, num_channels);
                 }
             }
-            PipelineStep::Mixer { name } => {
+            PipelineStep::Mixer { name } | PipelineStep::Crossfeed { name } => {
                 *name = replace_tokens(name, samplerate, num_channels);
             }
         }
@@ -1045,25 +2245,121 @@ pub enum ConfigChange {
     },
     MixerParameters,
     Pipeline,
+    /// The number of capture or playback channels changed, which needs a restart like other device changes.
+    ChannelCount,
     Devices,
     None,
 }
 
+impl ConfigChange {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ConfigChange::FilterParameters { .. } => "FilterParameters",
+            ConfigChange::MixerParameters => "MixerParameters",
+            ConfigChange::Pipeline => "Pipeline",
+            ConfigChange::ChannelCount => "ChannelCount",
+            ConfigChange::Devices => "Devices",
+            ConfigChange::None => "None",
+        }
+    }
+}
+
+/// Short hash identifying a config, as 16 hex digits.
+/// This is a 64-bit FNV-1a hash of the config serialized as json, which has sorted keys.
+pub fn config_hash(conf: &Configuration) -> String {
+    let text =
+        serde_json::to_string(&serde_json::to_value(conf).unwrap_or_default()).unwrap_or_default();
+    let hash = text.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("{:016x}", hash)
+}
+
 pub fn load_validate_config(configname: &str) -> Res<Configuration> {
     let mut configuration = load_config(configname)?;
     validate_config(&mut configuration, Some(configname))?;
     Ok(configuration)
 }
 
-pub fn config_diff(currentconf: &Configuration, newconf: &Configuration) -> ConfigChange {
-    if currentconf == newconf {
-        return ConfigChange::None;
+/// Load a config file, only making relative paths absolute.
+/// The result is kept as the base for changes at runtime, that are then validated from scratch,
+/// since validating applies overrides and other changes that must not be applied twice.
+pub fn load_raw_config(configname: &str) -> Res<Configuration> {
+    let mut configuration = load_config(configname)?;
+    replace_relative_paths_in_config(&mut configuration, configname);
+    Ok(configuration)
+}
+
+/// List what differs between two configs, as "section.name" for changed device settings,
+/// mixers, filters and samplerate configs, and "pipeline" if the pipeline changed.
+pub fn config_diff_summary(currentconf: &Configuration, newconf: &Configuration) -> Vec<String> {
+    let current = serde_json::to_value(currentconf).unwrap_or_default();
+    let new = serde_json::to_value(newconf).unwrap_or_default();
+    let mut differences = Vec::new();
+    if let (Some(current), Some(new)) = (current.as_object(), new.as_object()) {
+        for (section, new_value) in new {
+            let current_value = current.get(section).unwrap_or(&serde_json::Value::Null);
+            match (current_value.as_object(), new_value.as_object()) {
+                (Some(current_items), Some(new_items)) => {
+                    let mut names: Vec<&String> =
+                        current_items.keys().chain(new_items.keys()).collect();
+                    names.sort();
+                    names.dedup();
+                    for name in names {
+                        if current_items.get(name) != new_items.get(name) {
+                            differences.push(format!("{}.{}", section, name));
+                        }
+                    }
+                }
+                _ => {
+                    if current_value != new_value {
+                        differences.push(section.clone());
+                    }
+                }
+            }
+        }
     }
-    if currentconf.devices != newconf.devices {
-        return ConfigChange::Devices;
+    differences
+}
+
+/// Remove the channel counts from a serialized capture or playback device,
+/// including the sub-devices of a Multi device.
+fn remove_channels(device: &mut serde_json::Value) {
+    if let Some(fields) = device.as_object_mut() {
+        fields.remove("channels");
+        if let Some(serde_json::Value::Array(devices)) = fields.get_mut("devices") {
+            devices.iter_mut().for_each(remove_channels);
+        }
     }
-    if currentconf.pipeline != newconf.pipeline {
-        return ConfigChange::Pipeline;
+}
+
+/// Serialize the device settings, leaving out the channel counts.
+fn devices_without_channels(devices: &Devices) -> serde_json::Value {
+    let mut value = serde_json::to_value(devices).unwrap_or_default();
+    if let Some(capture) = value.get_mut("capture") {
+        remove_channels(capture);
+    }
+    if let Some(playback) = value.get_mut("playback") {
+        remove_channels(playback);
+    }
+    value
+}
+
+pub fn config_diff(currentconf: &Configuration, newconf: &Configuration) -> ConfigChange {
+    if currentconf == newconf {
+        return ConfigChange::None;
+    }
+    if currentconf.devices != newconf.devices {
+        // Changes of other device settings take precedence over the channel count
+        if devices_without_channels(&currentconf.devices)
+            == devices_without_channels(&newconf.devices)
+        {
+            return ConfigChange::ChannelCount;
+        }
+        return ConfigChange::Devices;
+    }
+    if currentconf.pipeline != newconf.pipeline {
+        return ConfigChange::Pipeline;
     }
     if currentconf.mixers != newconf.mixers {
         return ConfigChange::MixerParameters;
@@ -1075,6 +2371,15 @@ pub fn config_diff(currentconf: &Configuration, newconf: &Configuration) -> Conf
         if let Some(current_filter) = currentconf.filters.get(filter) {
             // Did the filter change type?
             match (params, current_filter) {
+                (
+                    Filter::Conv { parameters: new },
+                    Filter::Conv {
+                        parameters: current,
+                    },
+                ) if new.fft_library() != current.fft_library() => {
+                    // A different FFT library needs a new filter
+                    return ConfigChange::Pipeline;
+                }
                 (Filter::Biquad { .. }, Filter::Biquad { .. })
                 | (Filter::BiquadCombo { .. }, Filter::BiquadCombo { .. })
                 | (Filter::Conv { .. }, Filter::Conv { .. })
@@ -1082,8 +2387,16 @@ pub fn config_diff(currentconf: &Configuration, newconf: &Configuration) -> Conf
                 | (Filter::Gain { .. }, Filter::Gain { .. })
                 | (Filter::Dither { .. }, Filter::Dither { .. })
                 | (Filter::DiffEq { .. }, Filter::DiffEq { .. })
+                | (Filter::DynamicEq { .. }, Filter::DynamicEq { .. })
                 | (Filter::Volume { .. }, Filter::Volume { .. })
-                | (Filter::Loudness { .. }, Filter::Loudness { .. }) => {}
+                | (Filter::Loudness { .. }, Filter::Loudness { .. })
+                | (Filter::Highpass { .. }, Filter::Highpass { .. })
+                | (Filter::Lowpass { .. }, Filter::Lowpass { .. })
+                | (Filter::Deemphasis { .. }, Filter::Deemphasis { .. })
+                | (Filter::Preemphasis { .. }, Filter::Preemphasis { .. })
+                | (Filter::Crossfeed { .. }, Filter::Crossfeed { .. })
+                | (Filter::Tilt { .. }, Filter::Tilt { .. })
+                | (Filter::Shaper { .. }, Filter::Shaper { .. }) => {}
                 _ => {
                     // A filter changed type, need to rebuild the pipeline
                     return ConfigChange::Pipeline;
@@ -1103,18 +2416,186 @@ pub fn config_diff(currentconf: &Configuration, newconf: &Configuration) -> Conf
             }
         }
     }
+    // A changed FIR filter may have a different latency, that needs new compensating delays
+    if newconf.devices.compensate_fir_latency
+        && filters
+            .iter()
+            .any(|name| matches!(newconf.filters.get(name), Some(Filter::Conv { .. })))
+    {
+        return ConfigChange::Pipeline;
+    }
     ConfigChange::FilterParameters { filters, mixers }
 }
 
+/// Check that the sample format can be used for a File device, if the file is a FLAC file.
+/// Check that the loop region of a File capture device is usable.
+fn check_loop_region(
+    loop_start: Option<usize>,
+    loop_end: Option<usize>,
+    loop_crossfade: usize,
+) -> Res<()> {
+    let loop_end = match loop_end {
+        Some(end) => end,
+        None if loop_start.is_some() || loop_crossfade > 0 => {
+            let msg = "loop_start and loop_crossfade can only be used together with loop_end";
+            return Err(ConfigError::new(msg).into());
+        }
+        None => return Ok(()),
+    };
+    let loop_start = loop_start.unwrap_or(0);
+    if loop_end <= loop_start {
+        let msg = format!(
+            "loop_end ({}) must be larger than loop_start ({})",
+            loop_end, loop_start
+        );
+        return Err(ConfigError::new(&msg).into());
+    }
+    if loop_crossfade > loop_start {
+        let msg = format!(
+            "loop_crossfade ({}) can't be longer than loop_start ({})",
+            loop_crossfade, loop_start
+        );
+        return Err(ConfigError::new(&msg).into());
+    }
+    if loop_crossfade > loop_end - loop_start {
+        let msg = format!(
+            "loop_crossfade ({}) can't be longer than the loop ({})",
+            loop_crossfade,
+            loop_end - loop_start
+        );
+        return Err(ConfigError::new(&msg).into());
+    }
+    Ok(())
+}
+
+fn check_flac_format(filename: &str, format: &SampleFormat, channels: usize) -> Res<()> {
+    if !flacfile::is_flac_file(filename) {
+        return Ok(());
+    }
+    if flacfile::flac_bits(format).is_none() {
+        let msg = format!(
+            "FLAC file {} can't use format {:?}, use S16LE, S24LE or S24LE3",
+            filename, format
+        );
+        return Err(ConfigError::new(&msg).into());
+    }
+    if channels > flacfile::MAX_CHANNELS {
+        let msg = format!(
+            "FLAC file {} can't have {} channels, the maximum is {}",
+            filename,
+            channels,
+            flacfile::MAX_CHANNELS
+        );
+        return Err(ConfigError::new(&msg).into());
+    }
+    Ok(())
+}
+
+/// Check that the Jack ports to connect to fit the number of channels.
+#[cfg(all(feature = "cpal-backend", feature = "jack-backend"))]
+fn check_jack_ports(
+    channels: usize,
+    autoconnect: &Option<Vec<String>>,
+    connect_timeout: f32,
+) -> Res<()> {
+    if let Some(ports) = autoconnect {
+        if ports.len() > channels {
+            let msg = format!(
+                "Jack autoconnect lists {} ports, but the device only has {} channels",
+                ports.len(),
+                channels
+            );
+            return Err(ConfigError::new(&msg).into());
+        }
+    }
+    if connect_timeout < 0.0 {
+        return Err(ConfigError::new("Jack connect_timeout cannot be negative").into());
+    }
+    Ok(())
+}
+
+/// Limit the wet/dry mix of all filter steps to the range 0 to 1.
+fn clamp_pipeline_mix(conf: &mut Configuration) {
+    for step in conf.pipeline.iter_mut() {
+        if let PipelineStep::Filter { channel, mix, .. } = step {
+            if !(0.0..=1.0).contains(mix) {
+                let clamped = if mix.is_nan() {
+                    1.0
+                } else {
+                    mix.clamp(0.0, 1.0)
+                };
+                warn!(
+                    "Mix {} of filter step for channel {} is out of range, using {}",
+                    mix, channel, clamped
+                );
+                *mix = clamped;
+            }
+        }
+    }
+}
+
+/// Replace the downmix presets of the mixers by the corresponding mappings.
+fn expand_downmix_presets(conf: &mut Configuration) -> Res<()> {
+    for (name, mixer) in conf.mixers.iter_mut() {
+        let preset = match mixer.downmix.take() {
+            Some(preset) => preset,
+            None => continue,
+        };
+        if mixer.mapping.is_some() {
+            let msg = format!("Mixer '{}' can't have both a mapping and a downmix", name);
+            return Err(ConfigError::new(&msg).into());
+        }
+        if mixer.channels.r#in != preset.layout_in.channels()
+            || mixer.channels.out != preset.layout_out.channels()
+        {
+            let msg = format!(
+                "Mixer '{}' has {} input and {} output channels, but a downmix from {} to {} needs {} and {}",
+                name,
+                mixer.channels.r#in,
+                mixer.channels.out,
+                preset.layout_in,
+                preset.layout_out,
+                preset.layout_in.channels(),
+                preset.layout_out.channels()
+            );
+            return Err(ConfigError::new(&msg).into());
+        }
+        debug!(
+            "Expanding {:?} downmix from {} to {} for mixer '{}'",
+            preset.recipe, preset.layout_in, preset.layout_out, name
+        );
+        mixer.mapping = Some(mixer::downmix_mapping(&preset)?);
+    }
+    Ok(())
+}
+
 /// Validate the loaded configuration, stop on errors and print a helpful message.
 pub fn validate_config(conf: &mut Configuration, filename: Option<&str>) -> Res<()> {
     // pre-process by applying overrides and replacing tokens
     apply_overrides(conf);
+    apply_samplerate_config(conf);
+    select_multirate_files(conf);
     replace_tokens_in_config(conf);
     if let Some(fname) = filename {
         replace_relative_paths_in_config(conf, fname);
     }
-
+    clamp_pipeline_mix(conf);
+    expand_downmix_presets(conf)?;
+
+    for (name, filter) in conf.filters.iter() {
+        if let Filter::Conv {
+            parameters: ConvParameters::MultiRate { filenames, .. },
+        } = filter
+        {
+            let mut rates: Vec<&usize> = filenames.keys().collect();
+            rates.sort();
+            let msg = format!(
+                "Filter '{}' has no file for samplerate {}, available samplerates are {:?}",
+                name, conf.devices.samplerate, rates
+            );
+            return Err(ConfigError::new(&msg).into());
+        }
+    }
     if conf.devices.target_level >= 2 * conf.devices.chunksize {
         let msg = format!(
             "target_level can't be larger than {}",
@@ -1122,15 +2603,265 @@ pub fn validate_config(conf: &mut Configuration, filename: Option<&str>) -> Res<
         );
         return Err(ConfigError::new(&msg).into());
     }
+    if conf.devices.queuelimit == 0 {
+        return Err(ConfigError::new("queuelimit must be at least 1").into());
+    }
+    if conf.devices.queuelimit > MAX_QUEUELIMIT {
+        let msg = format!("queuelimit can't be larger than {}", MAX_QUEUELIMIT);
+        return Err(ConfigError::new(&msg).into());
+    }
+    let queue_latency_ms = 2000.0 * (conf.devices.queuelimit * conf.devices.chunksize) as f32
+        / conf.devices.samplerate as f32;
+    if queue_latency_ms > QUEUE_LATENCY_WARNING_MS {
+        warn!(
+            "The queues can hold up to {:.0} ms of audio with queuelimit {} and chunksize {}",
+            queue_latency_ms, conf.devices.queuelimit, conf.devices.chunksize
+        );
+    }
+    if conf.devices.resampling == ResamplingMode::None {
+        if conf.devices.enable_resampling {
+            let msg = "enable_resampling can't be used when resampling is set to none";
+            return Err(ConfigError::new(msg).into());
+        }
+        let capture_samplerate = conf.devices.capture_samplerate;
+        if capture_samplerate > 0 && capture_samplerate != conf.devices.samplerate {
+            let msg = format!(
+                "capture_samplerate ({}) differs from samplerate ({}), but resampling is set to none",
+                capture_samplerate, conf.devices.samplerate
+            );
+            return Err(ConfigError::new(&msg).into());
+        }
+    }
     if conf.devices.adjust_period <= 0.0 {
         return Err(ConfigError::new("adjust_period must be positive and > 0").into());
     }
+    if let Some(priority) = conf.devices.rt_priority {
+        if !(1..=99).contains(&priority) {
+            let msg = format!("rt_priority must be between 1 and 99, got {}", priority);
+            return Err(ConfigError::new(&msg).into());
+        }
+        #[cfg(not(target_os = "linux"))]
+        warn!("rt_priority is only supported on Linux, ignoring");
+    }
+    if let Some(timeout) = conf.devices.startup_timeout {
+        if !timeout.is_finite() || timeout <= 0.0 {
+            return Err(ConfigError::new("startup_timeout must be a finite number > 0").into());
+        }
+    }
+    if conf.devices.load_warning_level <= 0.0 {
+        return Err(ConfigError::new("load_warning_level must be positive and > 0").into());
+    }
+    if conf.devices.clip_hold_time < 0.0 {
+        return Err(ConfigError::new("clip_hold_time must be positive or zero").into());
+    }
+    if !(0.0..1.0).contains(&conf.devices.measured_rate_smoothing) {
+        return Err(
+            ConfigError::new("measured_rate_smoothing must be at least 0 and less than 1").into(),
+        );
+    }
     if conf.devices.silence_threshold > 0.0 {
         return Err(ConfigError::new("silence_threshold must be less than or equal to 0").into());
     }
     if conf.devices.silence_timeout < 0.0 {
         return Err(ConfigError::new("silence_timeout cannot be negative").into());
     }
+    if let Some(protection) = &conf.devices.overload_protection {
+        if protection.volume_step <= 0.0 {
+            return Err(ConfigError::new(
+                "overload_protection volume_step must be positive and > 0",
+            )
+            .into());
+        }
+        if protection.window <= 0.0 {
+            return Err(
+                ConfigError::new("overload_protection window must be positive and > 0").into(),
+            );
+        }
+    }
+    if let Some(agc) = &conf.devices.agc {
+        if agc.target_level >= 0.0 {
+            return Err(ConfigError::new("agc target_level must be negative").into());
+        }
+        if agc.max_gain < 0.0 {
+            return Err(ConfigError::new("agc max_gain cannot be negative").into());
+        }
+        if agc.attack <= 0.0 || agc.release <= 0.0 {
+            return Err(ConfigError::new("agc attack and release must be positive and > 0").into());
+        }
+    }
+    if conf.devices.clock_source == ClockSource::Capture
+        && conf.devices.enable_rate_adjust
+        && (!conf.devices.enable_resampling
+            || conf.devices.resampler_type == Resampler::Synchronous)
+    {
+        return Err(ConfigError::new(
+            "Rate adjust with clock_source 'capture' requires resampling with an Async resampler",
+        )
+        .into());
+    }
+    if !(MIN_VOLUME_DB..=MAX_VOLUME_DB).contains(&conf.devices.initial_volume) {
+        let msg = format!(
+            "initial_volume must be between {} and +{} dB",
+            MIN_VOLUME_DB, MAX_VOLUME_DB
+        );
+        return Err(ConfigError::new(&msg).into());
+    }
+    if let Some(startup_volume) = conf.devices.startup_volume {
+        if !(MIN_VOLUME_DB..=MAX_VOLUME_DB).contains(&startup_volume) {
+            let msg = format!(
+                "startup_volume must be between {} and +{} dB",
+                MIN_VOLUME_DB, MAX_VOLUME_DB
+            );
+            return Err(ConfigError::new(&msg).into());
+        }
+    }
+    if conf.devices.status_interval_ms == 0 {
+        return Err(ConfigError::new("status_interval_ms must be > 0").into());
+    }
+    if conf.devices.dc_block_freq <= 0.0 {
+        return Err(ConfigError::new("dc_block_freq must be positive and > 0").into());
+    }
+    if conf.devices.dc_block_freq >= conf.devices.samplerate as PrcFmt / 2.0 {
+        return Err(ConfigError::new("dc_block_freq must be < samplerate/2").into());
+    }
+    if let PlaybackDevice::File {
+        rollover_size,
+        rollover_duration,
+        ..
+    } = &conf.devices.playback
+    {
+        if *rollover_size == Some(0) {
+            return Err(ConfigError::new("rollover_size must be larger than zero").into());
+        }
+        if let Some(duration) = rollover_duration {
+            if *duration <= 0.0 {
+                return Err(ConfigError::new("rollover_duration must be positive and > 0").into());
+            }
+        }
+    }
+    if let CaptureDevice::File { extra_samples, .. } | CaptureDevice::Stdin { extra_samples, .. } =
+        &conf.devices.capture
+    {
+        let capture_samplerate = if conf.devices.capture_samplerate > 0 {
+            conf.devices.capture_samplerate
+        } else {
+            conf.devices.samplerate
+        };
+        let max_extra_samples =
+            (basicfilters::MAX_DELAY_SECONDS * capture_samplerate as PrcFmt) as usize;
+        if *extra_samples > max_extra_samples {
+            let msg = format!(
+                "extra_samples of {} is too large, the maximum is {} ({} seconds)",
+                extra_samples,
+                max_extra_samples,
+                basicfilters::MAX_DELAY_SECONDS
+            );
+            return Err(ConfigError::new(&msg).into());
+        }
+    }
+    if let CaptureDevice::File {
+        channels,
+        filename,
+        format,
+        loop_start,
+        loop_end,
+        loop_crossfade,
+        ..
+    } = &conf.devices.capture
+    {
+        check_flac_format(filename, format, *channels)?;
+        check_loop_region(*loop_start, *loop_end, *loop_crossfade)?;
+    }
+    if let PlaybackDevice::File {
+        channels,
+        filename,
+        format,
+        bwf_metadata,
+        ..
+    } = &conf.devices.playback
+    {
+        check_flac_format(filename, format, *channels)?;
+        if *bwf_metadata && flacfile::is_flac_file(filename) {
+            let msg = format!(
+                "FLAC file {} can't be written with bwf_metadata, use a .wav file",
+                filename
+            );
+            return Err(ConfigError::new(&msg).into());
+        }
+    }
+    if let Some(filename) = &conf.devices.capture_monitor_file {
+        check_flac_format(
+            filename,
+            &conf.devices.capture.sampleformat(),
+            conf.devices.capture.channels(),
+        )?;
+    }
+    #[cfg(target_os = "linux")]
+    if let CaptureDevice::Alsa { open_retry_ms, .. } = &conf.devices.capture {
+        if *open_retry_ms == 0 {
+            return Err(ConfigError::new("open_retry_ms must be larger than zero").into());
+        }
+    }
+    #[cfg(all(feature = "cpal-backend", feature = "jack-backend"))]
+    if let CaptureDevice::Jack {
+        channels,
+        autoconnect,
+        connect_timeout,
+        ..
+    } = &conf.devices.capture
+    {
+        check_jack_ports(*channels, autoconnect, *connect_timeout)?;
+    }
+    #[cfg(all(feature = "cpal-backend", feature = "jack-backend"))]
+    if let PlaybackDevice::Jack {
+        channels,
+        autoconnect,
+        connect_timeout,
+        ..
+    } = &conf.devices.playback
+    {
+        check_jack_ports(*channels, autoconnect, *connect_timeout)?;
+    }
+    if let CaptureDevice::Multi { devices } = &conf.devices.capture {
+        if devices.is_empty() {
+            return Err(ConfigError::new("At least one capture device is needed").into());
+        }
+        if devices
+            .iter()
+            .any(|dev| matches!(dev, CaptureDevice::Multi { .. }))
+        {
+            return Err(ConfigError::new("Lists of capture devices can't be nested").into());
+        }
+    }
+    if let PlaybackDevice::Multi { devices } = &conf.devices.playback {
+        if devices.is_empty() {
+            return Err(ConfigError::new("At least one playback device is needed").into());
+        }
+        if devices
+            .iter()
+            .any(|dev| matches!(dev, PlaybackDevice::Multi { .. }))
+        {
+            return Err(ConfigError::new("Lists of playback devices can't be nested").into());
+        }
+    }
+    if conf.devices.int_conversion != IntConversion::Truncate
+        && conf.devices.playback.takes_float_samples()
+    {
+        return Err(ConfigError::new(
+            "int_conversion can't be used with CoreAudio or Jack playback devices, since they convert the samples themselves",
+        )
+        .into());
+    }
+    check_channel_labels(
+        "capture",
+        &conf.devices.channel_labels.capture,
+        conf.devices.capture.channels(),
+    )?;
+    check_channel_labels(
+        "playback",
+        &conf.devices.channel_labels.playback,
+        conf.devices.playback.channels(),
+    )?;
     #[cfg(target_os = "windows")]
     if let CaptureDevice::Wasapi { format, .. } = &conf.devices.capture {
         if *format == SampleFormat::FLOAT64LE {
@@ -1223,24 +2954,43 @@ pub fn validate_config(conf: &mut Configuration, filename: Option<&str>) -> Res<
             .into());
         }
     }
-    let mut num_channels = conf.devices.capture.channels();
+    if conf.devices.mono_to_all && conf.devices.capture.channels() != 1 {
+        let msg = format!(
+            "mono_to_all requires a capture device with 1 channel, but it has {}",
+            conf.devices.capture.channels()
+        );
+        return Err(ConfigError::new(&msg).into());
+    }
+    let mut num_channels = get_pipeline_input_channels(conf);
+    // Describes where the current number of channels comes from, for use in error messages
+    let mut channels_source = if conf.devices.mono_to_all {
+        "mono capture device duplicated by mono_to_all".to_string()
+    } else if OVERRIDES.read().unwrap().channels.is_some() {
+        "capture device (overridden on command line)".to_string()
+    } else {
+        "capture device".to_string()
+    };
     let fs = conf.devices.samplerate;
-    for step in &conf.pipeline {
+    let mut used_filters: Vec<&String> = Vec::new();
+    let mut used_mixers: Vec<&String> = Vec::new();
+    for (idx, step) in conf.pipeline.iter().enumerate() {
         match step {
             PipelineStep::Mixer { name } => {
+                used_mixers.push(name);
                 if !conf.mixers.contains_key(name) {
-                    let msg = format!("Use of missing mixer '{}'", name);
+                    let msg = format!("Use of missing mixer '{}' in pipeline step {}", name, idx);
                     return Err(ConfigError::new(&msg).into());
                 } else {
                     let chan_in = conf.mixers.get(name).unwrap().channels.r#in;
                     if chan_in != num_channels {
                         let msg = format!(
-                            "Mixer '{}' has wrong number of input channels. Expected {}, found {}.",
-                            name, num_channels, chan_in
+                            "Mixer '{}' has wrong number of input channels. Expected {} from {}, found {}.",
+                            name, num_channels, channels_source, chan_in
                         );
                         return Err(ConfigError::new(&msg).into());
                     }
                     num_channels = conf.mixers.get(name).unwrap().channels.out;
+                    channels_source = format!("mixer '{}'", name);
                     match mixer::validate_mixer(conf.mixers.get(name).unwrap()) {
                         Ok(_) => {}
                         Err(err) => {
@@ -1250,14 +3000,26 @@ pub fn validate_config(conf: &mut Configuration, filename: Option<&str>) -> Res<
                     }
                 }
             }
-            PipelineStep::Filter { channel, names } => {
+            PipelineStep::Filter { channel, names, .. } => {
                 if *channel >= num_channels {
-                    let msg = format!("Use of non existing channel {}", channel);
+                    let msg = format!(
+                        "Use of non existing channel {}, {} has {} channels",
+                        channel, channels_source, num_channels
+                    );
                     return Err(ConfigError::new(&msg).into());
                 }
                 for name in names {
+                    used_filters.push(name);
                     if !conf.filters.contains_key(name) {
-                        let msg = format!("Use of missing filter '{}'", name);
+                        let msg =
+                            format!("Use of missing filter '{}' in pipeline step {}", name, idx);
+                        return Err(ConfigError::new(&msg).into());
+                    }
+                    if let Some(Filter::Crossfeed { .. }) = conf.filters.get(name) {
+                        let msg = format!(
+                            "Crossfeed filter '{}' can only be used in a Crossfeed pipeline step",
+                            name
+                        );
                         return Err(ConfigError::new(&msg).into());
                     }
                     match filters::validate_filter(fs, conf.filters.get(name).unwrap()) {
@@ -1269,21 +3031,245 @@ pub fn validate_config(conf: &mut Configuration, filename: Option<&str>) -> Res<
                     }
                 }
             }
+            PipelineStep::Crossfeed { name } => {
+                used_filters.push(name);
+                let filter = match conf.filters.get(name) {
+                    Some(filter @ Filter::Crossfeed { .. }) => filter,
+                    Some(_) => {
+                        let msg = format!("Filter '{}' used as crossfeed is not a Crossfeed", name);
+                        return Err(ConfigError::new(&msg).into());
+                    }
+                    None => {
+                        let msg =
+                            format!("Use of missing filter '{}' in pipeline step {}", name, idx);
+                        return Err(ConfigError::new(&msg).into());
+                    }
+                };
+                if num_channels != 2 {
+                    let msg = format!(
+                        "Crossfeed '{}' needs 2 channels, {} has {} channels",
+                        name, channels_source, num_channels
+                    );
+                    return Err(ConfigError::new(&msg).into());
+                }
+                if let Err(err) = filters::validate_filter(fs, filter) {
+                    let msg = format!("Invalid filter '{}'. Reason: {}", name, err);
+                    return Err(ConfigError::new(&msg).into());
+                }
+            }
+        }
+    }
+    warn_unused("Filter", conf.filters.keys(), &used_filters);
+    warn_unused("Mixer", conf.mixers.keys(), &used_mixers);
+    for (name, filter) in conf.filters.iter() {
+        let ramp_time = match filter {
+            Filter::Volume { parameters } => parameters.ramp_time,
+            Filter::Loudness { parameters } => parameters.ramp_time,
+            _ => continue,
+        };
+        if let Some(warning) = basicfilters::check_ramp_time(ramp_time, conf.devices.chunksize, fs)
+        {
+            warn!("Filter '{}': {}", name, warning);
         }
     }
     let num_channels_out = conf.devices.playback.channels();
-    if num_channels != num_channels_out {
+    let surplus_allowed = conf.devices.surplus_output != SurplusOutput::Error;
+    if num_channels < num_channels_out || (num_channels > num_channels_out && !surplus_allowed) {
+        let msg = format!(
+            "Pipeline outputs {} channels from {}, but playback device has {}.",
+            num_channels, channels_source, num_channels_out
+        );
+        return Err(ConfigError::new(&msg).into());
+    }
+    if conf.devices.extra_channels == ExtraChannels::Error {
+        let unused: Vec<String> = get_used_capture_channels(conf)
+            .iter()
+            .enumerate()
+            .filter(|(_, used)| !**used)
+            .map(|(n, _)| n.to_string())
+            .collect();
+        if !unused.is_empty() {
+            let msg = format!(
+                "Capture channels {} are not used by the pipeline",
+                unused.join(", ")
+            );
+            return Err(ConfigError::new(&msg).into());
+        }
+    }
+    Ok(())
+}
+
+/// Warn about filters or mixers that are defined but not used by any pipeline step.
+fn warn_unused<'a>(kind: &str, defined: impl Iterator<Item = &'a String>, used: &[&String]) {
+    let mut unused: Vec<&String> = defined.filter(|name| !used.contains(name)).collect();
+    unused.sort();
+    for name in unused {
+        warn!(
+            "{} '{}' is defined but not used in the pipeline",
+            kind, name
+        );
+    }
+}
+
+/// Name of the mixer used for quick channel swaps and inversions, placed last in the pipeline.
+pub const WIRING_MIXER: &str = "wiring";
+
+/// Get the wiring mixer of a config, adding an identity mixer at the end of the pipeline if needed.
+fn wiring_mixer(conf: &mut Configuration) -> &mut Mixer {
+    let channels = conf.devices.playback.channels();
+    let step = PipelineStep::Mixer {
+        name: WIRING_MIXER.to_string(),
+    };
+    if !conf.pipeline.contains(&step) {
+        conf.pipeline.push(step.clone());
+    }
+    if let Some(rate_conf) = conf.samplerates.get_mut(&conf.devices.samplerate) {
+        if let Some(pipeline) = rate_conf.pipeline.as_mut() {
+            if !pipeline.contains(&step) {
+                pipeline.push(step);
+            }
+        }
+    }
+    conf.mixers
+        .entry(WIRING_MIXER.to_string())
+        .or_insert_with(|| Mixer {
+            channels: MixerChannels {
+                r#in: channels,
+                out: channels,
+            },
+            mapping: Some(
+                (0..channels)
+                    .map(|ch| MixerMapping {
+                        dest: ch,
+                        sources: vec![MixerSource {
+                            channel: ch,
+                            gain: 0.0,
+                            inverted: false,
+                            mute: false,
+                        }],
+                        mute: false,
+                    })
+                    .collect(),
+            ),
+            auto_attenuate: false,
+            downmix: None,
+        })
+}
+
+fn check_channel_labels(side: &str, labels: &[String], channels: usize) -> Res<()> {
+    if !labels.is_empty() && labels.len() != channels {
+        let msg = format!(
+            "Got {} {} channel labels, the device has {} channels",
+            labels.len(),
+            side,
+            channels
+        );
+        return Err(ConfigError::new(&msg).into());
+    }
+    Ok(())
+}
+
+/// Get the label of each channel, using the channel numbers for channels without a configured label.
+pub fn get_channel_labels(labels: &[String], channels: usize) -> Vec<String> {
+    (0..channels)
+        .map(|ch| labels.get(ch).cloned().unwrap_or_else(|| ch.to_string()))
+        .collect()
+}
+
+fn check_output_channel(conf: &Configuration, channel: usize) -> Res<()> {
+    let channels = conf.devices.playback.channels();
+    if channel >= channels {
         let msg = format!(
-            "Pipeline outputs {} channels, playback device has {}.",
-            num_channels, num_channels_out
+            "Invalid channel {}, playback device has {} channels",
+            channel, channels
         );
         return Err(ConfigError::new(&msg).into());
     }
     Ok(())
 }
 
+/// Swap two output channels using the wiring mixer, and return the updated mixer.
+pub fn swap_output_channels(conf: &mut Configuration, a: usize, b: usize) -> Res<Mixer> {
+    check_output_channel(conf, a)?;
+    check_output_channel(conf, b)?;
+    let mixer = wiring_mixer(conf);
+    let mappings = mixer.mapping.get_or_insert_with(Vec::new);
+    for mapping in mappings.iter_mut() {
+        if mapping.dest == a {
+            mapping.dest = b;
+        } else if mapping.dest == b {
+            mapping.dest = a;
+        }
+    }
+    mappings.sort_by_key(|mapping| mapping.dest);
+    Ok(mixer.clone())
+}
+
+/// Toggle the polarity of an output channel using the wiring mixer, and return the updated mixer.
+pub fn invert_output_channel(conf: &mut Configuration, channel: usize) -> Res<Mixer> {
+    check_output_channel(conf, channel)?;
+    let mixer = wiring_mixer(conf);
+    for mapping in mixer
+        .mapping
+        .get_or_insert_with(Vec::new)
+        .iter_mut()
+        .filter(|m| m.dest == channel)
+    {
+        for source in mapping.sources.iter_mut() {
+            source.inverted = !source.inverted;
+        }
+    }
+    Ok(mixer.clone())
+}
+
+/// Get a config showing the settings that are actually used.
+/// Overrides are already applied when a config is validated, so this only differs from the active config
+/// in that the capture samplerate is resolved in the same way as when the devices are opened.
+/// Validation also ensures that resampling is disabled when `resampling` is `none`.
+pub fn get_effective_config(conf: &Configuration) -> Configuration {
+    let mut effective = conf.clone();
+    let devices = &mut effective.devices;
+    if !devices.enable_resampling || devices.capture_samplerate == 0 {
+        devices.capture_samplerate = devices.samplerate;
+    }
+    effective
+}
+
+/// Get the capture channels that must be converted and passed on to the processing.
+/// Unused channels are included when they are to be mixed into the first used channel.
+pub fn get_converted_capture_channels(conf: &Configuration) -> Vec<bool> {
+    let used_channels = get_used_capture_channels(conf);
+    if conf.devices.extra_channels == ExtraChannels::MixToFirst {
+        vec![true; used_channels.len()]
+    } else {
+        used_channels
+    }
+}
+
+/// Get the number of channels entering the pipeline.
+/// This is the number of capture channels, unless a mono capture is duplicated by `mono_to_all`.
+/// Then the channel is duplicated to the number of inputs of the first mixer,
+/// or to the number of playback channels if the pipeline has no mixer.
+pub fn get_pipeline_input_channels(conf: &Configuration) -> usize {
+    if !conf.devices.mono_to_all {
+        return conf.devices.capture.channels();
+    }
+    for step in conf.pipeline.iter() {
+        if let PipelineStep::Mixer { name } = step {
+            if let Some(mixerconf) = conf.mixers.get(name) {
+                return mixerconf.channels.r#in;
+            }
+        }
+    }
+    conf.devices.playback.channels()
+}
+
 /// Get a vector telling which channels are actually used in the pipeline
 pub fn get_used_capture_channels(conf: &Configuration) -> Vec<bool> {
+    if conf.devices.mono_to_all {
+        // The single capture channel feeds all the pipeline inputs
+        return vec![true; conf.devices.capture.channels()];
+    }
     for step in conf.pipeline.iter() {
         if let PipelineStep::Mixer { name } = step {
             let mixerconf = conf.mixers.get(name).unwrap();
@@ -1293,3 +3279,340 @@ pub fn get_used_capture_channels(conf: &Configuration) -> Vec<bool> {
     let capture_channels = conf.devices.capture.channels();
     vec![true; capture_channels]
 }
+
+/// Reorder the steps of the pipeline.
+/// The order gives the current index of each step, in the new order, and must include every step once.
+/// Get the pipeline that is used at the configured samplerate.
+/// This is the pipeline given for the samplerate if there is one, otherwise the base pipeline.
+pub fn effective_pipeline(conf: &Configuration) -> &Vec<PipelineStep> {
+    match conf
+        .samplerates
+        .get(&conf.devices.samplerate)
+        .and_then(|rate_conf| rate_conf.pipeline.as_ref())
+    {
+        Some(pipeline) => pipeline,
+        None => &conf.pipeline,
+    }
+}
+
+fn effective_pipeline_mut(conf: &mut Configuration) -> &mut Vec<PipelineStep> {
+    match conf
+        .samplerates
+        .get_mut(&conf.devices.samplerate)
+        .and_then(|rate_conf| rate_conf.pipeline.as_mut())
+    {
+        Some(pipeline) => pipeline,
+        None => &mut conf.pipeline,
+    }
+}
+
+/// Reorder the steps of the pipeline that is used at the configured samplerate.
+pub fn reorder_pipeline(conf: &mut Configuration, order: &[usize]) -> Res<()> {
+    let pipeline = effective_pipeline_mut(conf);
+    let mut sorted = order.to_vec();
+    sorted.sort_unstable();
+    if !sorted.iter().copied().eq(0..pipeline.len()) {
+        let msg = format!(
+            "Invalid pipeline order {:?}, must include each of the {} steps once",
+            order,
+            pipeline.len()
+        );
+        return Err(ConfigError::new(&msg).into());
+    }
+    *pipeline = order.iter().map(|idx| pipeline[*idx].clone()).collect();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{
+        config_diff, effective_pipeline, get_effective_config, get_non_default_config,
+        reorder_pipeline, validate_config, ConfigChange, Configuration, GainParameters,
+    };
+    use crate::PrcFmt;
+
+    const DEVICES: &str = "
+devices:
+  samplerate: 44100
+  chunksize: 1024
+  capture:
+    type: Stdin
+    channels: 2
+    format: S16LE
+  playback:
+    type: Stdout
+    channels: 2
+    format: S16LE
+";
+
+    fn non_default(yaml: &str) -> serde_yaml::Value {
+        let conf: Configuration = serde_yaml::from_str(yaml).unwrap();
+        get_non_default_config(&conf).unwrap()
+    }
+
+    #[test]
+    fn non_default_of_default_config() {
+        let expected: serde_yaml::Value = serde_yaml::from_str(DEVICES).unwrap();
+        assert_eq!(non_default(DEVICES), expected);
+    }
+
+    #[test]
+    fn non_default_changed_optional_field() {
+        let yaml = DEVICES.replace(
+            "  chunksize: 1024\n",
+            "  chunksize: 1024\n  queuelimit: 7\n",
+        );
+        let value = non_default(&yaml);
+        assert_eq!(value["devices"]["queuelimit"], serde_yaml::Value::from(7));
+        assert!(value["devices"].get("target_level").is_none());
+        assert!(value.get("filters").is_none());
+    }
+
+    #[test]
+    fn non_default_keeps_required_fields() {
+        let yaml = format!(
+            "{}filters:\n  gain:\n    type: Gain\n    parameters:\n      gain: 0.0\npipeline:\n  - type: Filter\n    channel: 0\n    names:\n      - gain\n",
+            DEVICES
+        );
+        let value = non_default(&yaml);
+        assert_eq!(value["devices"]["chunksize"], serde_yaml::Value::from(1024));
+        assert_eq!(
+            value["devices"]["capture"]["type"],
+            serde_yaml::Value::from("Stdin")
+        );
+        let gain = &value["filters"]["gain"];
+        assert_eq!(gain["type"], serde_yaml::Value::from("Gain"));
+        assert_eq!(gain["parameters"]["gain"], serde_yaml::Value::from(0.0));
+        assert!(gain["parameters"].get("inverted").is_none());
+        assert_eq!(value["pipeline"][0]["channel"], serde_yaml::Value::from(0));
+    }
+
+    #[test]
+    fn startup_timeout_must_be_finite_and_positive() {
+        for (timeout, valid) in [
+            ("2.5", true),
+            ("0.0", false),
+            ("-1.0", false),
+            (".nan", false),
+            (".inf", false),
+        ] {
+            let yaml = DEVICES.replace(
+                "  chunksize: 1024\n",
+                &format!("  chunksize: 1024\n  startup_timeout: {}\n", timeout),
+            );
+            let mut conf: Configuration = serde_yaml::from_str(&yaml).unwrap();
+            assert_eq!(
+                validate_config(&mut conf, None).is_ok(),
+                valid,
+                "startup_timeout: {}",
+                timeout
+            );
+        }
+    }
+
+    fn parse_gain(gain: &str) -> Result<PrcFmt, serde_yaml::Error> {
+        let params: GainParameters = serde_yaml::from_str(&format!("gain: {}", gain))?;
+        Ok(params.gain)
+    }
+
+    #[test]
+    fn gain_as_plain_number() {
+        assert_eq!(parse_gain("-6.5").unwrap(), -6.5);
+        assert_eq!(parse_gain("3").unwrap(), 3.0);
+    }
+
+    #[test]
+    fn gain_with_decibel_unit() {
+        assert_eq!(parse_gain("{value: -6.5, unit: dB}").unwrap(), -6.5);
+        assert_eq!(parse_gain("{value: 4.0}").unwrap(), 4.0);
+    }
+
+    #[test]
+    fn gain_with_linear_unit() {
+        let gain = parse_gain("{value: 2.0, unit: linear}").unwrap();
+        assert!((gain - 6.0206).abs() < 1.0e-4);
+        assert!(parse_gain("{value: 0.5, unit: linear}").unwrap() < -6.0);
+        assert_eq!(parse_gain("{value: 1.0, unit: linear}").unwrap(), 0.0);
+    }
+
+    #[test]
+    fn gain_malformed() {
+        assert!(parse_gain("{value: 0.0, unit: linear}").is_err());
+        assert!(parse_gain("{value: -2.0, unit: linear}").is_err());
+        assert!(parse_gain("{value: 2.0, unit: percent}").is_err());
+        assert!(parse_gain("{value: 2.0, unit: dB, scale: 1}").is_err());
+        assert!(parse_gain("{unit: dB}").is_err());
+        assert!(parse_gain("\"6 dB\"").is_err());
+    }
+
+    fn conv_config(fft_library: &str, value: f32) -> Configuration {
+        let yaml = format!(
+            "{}filters:\n  fir:\n    type: Conv\n    parameters:\n      type: Values\n      values: [{}, 0.5]\n{}pipeline:\n  - type: Filter\n    channel: 0\n    names:\n      - fir\n",
+            DEVICES,
+            value,
+            fft_library
+        );
+        serde_yaml::from_str(&yaml).unwrap()
+    }
+
+    #[test]
+    fn config_diff_fft_library() {
+        let default = conv_config("", 1.0);
+        let realfft = conv_config("      fft_library: realfft\n", 1.0);
+        assert!(matches!(
+            config_diff(&default, &realfft),
+            ConfigChange::Pipeline
+        ));
+        let new_values = conv_config("", 0.8);
+        match config_diff(&default, &new_values) {
+            ConfigChange::FilterParameters { filters, .. } => assert_eq!(filters, vec!["fir"]),
+            other => panic!("unexpected change {:?}", other),
+        }
+    }
+
+    #[test]
+    fn config_diff_devices_and_channel_count() {
+        let current: Configuration = serde_yaml::from_str(DEVICES).unwrap();
+        let channels: Configuration =
+            serde_yaml::from_str(&DEVICES.replacen("channels: 2", "channels: 4", 1)).unwrap();
+        assert!(matches!(
+            config_diff(&current, &channels),
+            ConfigChange::ChannelCount
+        ));
+        let both: Configuration = serde_yaml::from_str(
+            &DEVICES
+                .replacen("channels: 2", "channels: 4", 1)
+                .replace("chunksize: 1024", "chunksize: 2048"),
+        )
+        .unwrap();
+        assert!(matches!(
+            config_diff(&current, &both),
+            ConfigChange::Devices
+        ));
+    }
+
+    fn pipeline_names(pipeline: &[crate::config::PipelineStep]) -> Vec<String> {
+        pipeline.iter().map(|step| step.to_string()).collect()
+    }
+
+    #[test]
+    fn reorder_samplerate_pipeline() {
+        let yaml = format!(
+            "{}{}",
+            DEVICES,
+            "
+filters:
+  a:
+    type: Gain
+    parameters:
+      gain: -1
+  b:
+    type: Gain
+    parameters:
+      gain: -2
+pipeline:
+  - type: Filter
+    channel: 0
+    names:
+      - a
+samplerates:
+  44100:
+    pipeline:
+      - type: Filter
+        channel: 0
+        names:
+          - a
+      - type: Filter
+        channel: 1
+        names:
+          - b
+"
+        );
+        let mut conf: Configuration = serde_yaml::from_str(&yaml).unwrap();
+        let base = conf.pipeline.clone();
+        let before = pipeline_names(effective_pipeline(&conf));
+        assert_eq!(before.len(), 2);
+        reorder_pipeline(&mut conf, &[1, 0]).unwrap();
+        let after = pipeline_names(effective_pipeline(&conf));
+        assert_eq!(after, vec![before[1].clone(), before[0].clone()]);
+        assert_eq!(conf.pipeline, base);
+        assert!(reorder_pipeline(&mut conf, &[0]).is_err());
+    }
+
+    #[test]
+    fn effective_capture_samplerate() {
+        for (settings, capture_samplerate) in [
+            ("", 44100),
+            ("  capture_samplerate: 48000\n", 44100),
+            ("  enable_resampling: true\n", 44100),
+            (
+                "  enable_resampling: true\n  capture_samplerate: 48000\n",
+                48000,
+            ),
+        ] {
+            let yaml = DEVICES.replace(
+                "  chunksize: 1024\n",
+                &format!("  chunksize: 1024\n{}", settings),
+            );
+            let mut conf: Configuration = serde_yaml::from_str(&yaml).unwrap();
+            validate_config(&mut conf, None).unwrap();
+            let effective = get_effective_config(&conf);
+            assert_eq!(
+                effective.devices.capture_samplerate, capture_samplerate,
+                "{}",
+                settings
+            );
+            assert_eq!(effective.devices, {
+                let mut devices = conf.devices.clone();
+                devices.capture_samplerate = capture_samplerate;
+                devices
+            });
+        }
+    }
+
+    #[test]
+    fn resampling_none_requires_equal_rates() {
+        for (settings, valid) in [
+            (
+                "  capture_samplerate: 48000
+",
+                true,
+            ),
+            (
+                "  resampling: none
+",
+                true,
+            ),
+            (
+                "  resampling: none
+  capture_samplerate: 44100
+",
+                true,
+            ),
+            (
+                "  resampling: none
+  capture_samplerate: 48000
+",
+                false,
+            ),
+            (
+                "  resampling: none
+  enable_resampling: true
+",
+                false,
+            ),
+        ] {
+            let yaml = DEVICES.replace(
+                "  chunksize: 1024\n",
+                &format!("  chunksize: 1024\n{}", settings),
+            );
+            let mut conf: Configuration = serde_yaml::from_str(&yaml).unwrap();
+            assert_eq!(
+                validate_config(&mut conf, None).is_ok(),
+                valid,
+                "{}",
+                settings
+            );
+        }
+    }
+}
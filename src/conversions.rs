@@ -1,8 +1,11 @@
 use crate::audiodevice::*;
-use crate::config::{FileFormat, SampleFormat};
+use crate::config::{FileFormat, IntConversion, SampleFormat};
+use crate::NewValue;
 use crate::PrcFmt;
 #[cfg(feature = "cpal-backend")]
 use num_traits;
+use rand::thread_rng;
+use rand_distr::{Distribution, Triangular};
 use rawsample;
 use rawsample::{SampleReader, SampleWriter};
 #[cfg(feature = "cpal-backend")]
@@ -32,6 +35,38 @@ pub fn map_file_formats(fileformat: &FileFormat) -> rawsample::SampleFormat {
     }
 }
 
+/// Prepare an AudioChunk for conversion to an integer sample format,
+/// by rounding the values to the resolution of the format, optionally with TPDF dither.
+/// The conversion to raw bytes truncates towards zero, so nothing is done for `Truncate`, or for float formats.
+pub fn apply_int_conversion(
+    chunk: &mut AudioChunk,
+    sampleformat: &SampleFormat,
+    conversion: IntConversion,
+) {
+    if conversion == IntConversion::Truncate
+        || matches!(
+            sampleformat,
+            SampleFormat::FLOAT32LE | SampleFormat::FLOAT64LE
+        )
+    {
+        return;
+    }
+    let scalefact = PrcFmt::new(2.0).powi(sampleformat.bits_per_sample() as i32 - 1);
+    for waveform in chunk.waveforms.iter_mut() {
+        if conversion == IntConversion::DitherTpdf {
+            let rng = thread_rng();
+            let dith_rng = Triangular::new(-1.0, 1.0, 0.0).unwrap();
+            for (item, dith) in waveform.iter_mut().zip(dith_rng.sample_iter(rng)) {
+                *item = (*item * scalefact + dith).round() / scalefact;
+            }
+        } else {
+            for item in waveform.iter_mut() {
+                *item = (*item * scalefact).round() / scalefact;
+            }
+        }
+    }
+}
+
 /// Convert an AudioChunk to an interleaved buffer of u8.
 pub fn chunk_to_buffer_rawbytes(
     chunk: &AudioChunk,
@@ -286,8 +321,10 @@ pub fn queue_to_chunk_float<T: num_traits::cast::AsPrimitive<PrcFmt>>(
 #[cfg(test)]
 mod tests {
     use crate::audiodevice::AudioChunk;
-    use crate::config::SampleFormat;
-    use crate::conversions::{buffer_to_chunk_rawbytes, chunk_to_buffer_rawbytes};
+    use crate::config::{IntConversion, SampleFormat};
+    use crate::conversions::{
+        apply_int_conversion, buffer_to_chunk_rawbytes, chunk_to_buffer_rawbytes,
+    };
     #[cfg(feature = "cpal-backend")]
     use crate::PrcFmt;
     #[cfg(feature = "cpal-backend")]
@@ -308,6 +345,32 @@ mod tests {
         assert_eq!(buffer, expected);
     }
 
+    #[test]
+    fn to_buffer_int16_round_dither() {
+        let sampleformat = SampleFormat::S16LE;
+        let waveforms = vec![vec![0.1, -0.1]; 1];
+        let mut chunk = AudioChunk::new(waveforms.clone(), 0.0, 0.0, 2, 2);
+        apply_int_conversion(&mut chunk, &sampleformat, IntConversion::Round);
+        let mut buffer = vec![0u8; 4];
+        chunk_to_buffer_rawbytes(&chunk, &mut buffer, &sampleformat);
+        // 3276.8 is rounded to 3277, and -3276.8 to -3277
+        let expected = vec![0xCD, 0x0C, 0x33, 0xF3];
+        assert_eq!(buffer, expected);
+
+        let waveforms = vec![vec![0.1; 1000]; 1];
+        let mut chunk = AudioChunk::new(waveforms, 0.0, 0.0, 1000, 1000);
+        apply_int_conversion(&mut chunk, &sampleformat, IntConversion::DitherTpdf);
+        let mut buffer = vec![0u8; 2000];
+        chunk_to_buffer_rawbytes(&chunk, &mut buffer, &sampleformat);
+        let values: Vec<i16> = buffer
+            .chunks(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        assert!(values.iter().all(|v| (3276..=3278).contains(v)));
+        let mean = values.iter().map(|v| *v as f64).sum::<f64>() / values.len() as f64;
+        assert!((mean - 3276.8).abs() < 0.1, "mean is {}", mean);
+    }
+
     #[test]
     fn to_buffer_int24_3() {
         let sampleformat = SampleFormat::S24LE3;
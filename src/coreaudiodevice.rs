@@ -3,6 +3,7 @@ use crate::config;
 use crate::config::{ConfigError, SampleFormat};
 use crate::conversions::{buffer_to_chunk_rawbytes, chunk_to_buffer_rawbytes};
 use crate::countertimer;
+use crate::filedevice::start_capture_monitor;
 use crossbeam_channel::{bounded, TryRecvError, TrySendError};
 use dispatch::Semaphore;
 use rubato::VecResampler;
@@ -15,8 +16,9 @@ use std::time::Duration;
 
 use coreaudio::audio_unit::audio_format::LinearPcmFlags;
 use coreaudio::audio_unit::macos_helpers::{
-    audio_unit_from_device_id, find_matching_physical_format, get_default_device_id,
-    get_device_id_from_name, get_hogging_pid, set_device_physical_stream_format,
+    audio_unit_from_device_id, find_matching_physical_format, get_audio_device_ids_for_scope,
+    get_default_device_id, get_device_id_from_name, get_device_name, get_hogging_pid,
+    get_supported_physical_stream_formats, set_device_physical_stream_format,
     set_device_sample_rate, toggle_hog_mode, AliveListener, RateListener,
 };
 use coreaudio::audio_unit::render_callback::{self, data};
@@ -105,6 +107,81 @@ pub struct CoreaudioCaptureDevice {
     pub silence_timeout: PrcFmt,
     pub stop_on_rate_change: bool,
     pub rate_measure_interval: f32,
+    pub monitor_file: Option<String>,
+}
+
+/// Get the sample format matching a physical stream format, if CamillaDSP supports it.
+fn sample_format_from_description(desc: &AudioStreamBasicDescription) -> Option<SampleFormat> {
+    let is_float = desc.mFormatFlags & kAudioFormatFlagIsFloat != 0;
+    let bytes_per_sample = if desc.mChannelsPerFrame > 0 {
+        desc.mBytesPerFrame / desc.mChannelsPerFrame
+    } else {
+        0
+    };
+    match (is_float, desc.mBitsPerChannel, bytes_per_sample) {
+        (false, 16, _) => Some(SampleFormat::S16LE),
+        (false, 24, 3) => Some(SampleFormat::S24LE3),
+        (false, 24, _) => Some(SampleFormat::S24LE),
+        (false, 32, _) => Some(SampleFormat::S32LE),
+        (true, 32, _) => Some(SampleFormat::FLOAT32LE),
+        (true, 64, _) => Some(SampleFormat::FLOAT64LE),
+        _ => None,
+    }
+}
+
+/// List the CoreAudio devices, with the capabilities given by their physical stream formats.
+pub fn list_devices() -> Vec<DeviceInfo> {
+    let mut devices = Vec::new();
+    for (capture, scope) in [(true, Scope::Input), (false, Scope::Output)] {
+        let device_ids = match get_audio_device_ids_for_scope(scope) {
+            Ok(ids) => ids,
+            Err(err) => {
+                warn!("Unable to list CoreAudio devices: {}", err);
+                continue;
+            }
+        };
+        for device_id in device_ids {
+            let name = match get_device_name(device_id) {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            let mut info = DeviceInfo {
+                backend: "CoreAudio".to_string(),
+                capture,
+                name,
+                description: None,
+                channels: Vec::new(),
+                samplerates: Vec::new(),
+                formats: Vec::new(),
+            };
+            if let Ok(formats) = get_supported_physical_stream_formats(device_id) {
+                for format in formats {
+                    let desc = format.mFormat;
+                    info.channels.push(desc.mChannelsPerFrame as usize);
+                    let range = format.mSampleRateRange;
+                    info.samplerates.extend(
+                        STANDARD_RATES
+                            .iter()
+                            .filter(|rate| {
+                                (range.mMinimum..=range.mMaximum).contains(&(**rate as f64))
+                            })
+                            .map(|rate| *rate as usize),
+                    );
+                    if let Some(sample_format) = sample_format_from_description(&desc) {
+                        if !info.formats.contains(&sample_format) {
+                            info.formats.push(sample_format);
+                        }
+                    }
+                }
+            }
+            info.channels.sort_unstable();
+            info.channels.dedup();
+            info.samplerates.sort_unstable();
+            info.samplerates.dedup();
+            devices.push(info);
+        }
+    }
+    devices
 }
 
 fn open_coreaudio_playback(
@@ -558,6 +635,7 @@ impl CaptureDevice for CoreaudioCaptureDevice {
         let stop_on_rate_change = self.stop_on_rate_change;
         let rate_measure_interval = (1000.0 * self.rate_measure_interval) as u64;
         let blockalign = 4 * channels;
+        let monitor_file = self.monitor_file.clone();
 
         let handle = thread::Builder::new()
             .name("CoreaudioCapture".to_string())
@@ -681,6 +759,9 @@ impl CaptureDevice for CoreaudioCaptureDevice {
                 // TODO check if this ever needs to be resized
                 let mut data_buffer = vec![0u8; 4 * blockalign * capture_frames];
                 let mut expected_chunk_nbr = 0;
+                let mut monitor = start_capture_monitor(&monitor_file, channels, capture_samplerate, &sample_format);
+                // The device gives 32-bit floats, these are converted to the configured format for the monitor
+                let all_channels = vec![true; channels];
                 let mut prev_len = 0;
                 debug!("Capture device ready and waiting");
                 match status_channel.send(StatusMessage::CaptureReady) {
@@ -787,6 +868,8 @@ impl CaptureDevice for CoreaudioCaptureDevice {
                     if data_queue.len() < (blockalign * capture_frames) {
                         let mut capture_status = capture_status.write().unwrap();
                         capture_status.measured_samplerate = 0;
+                        capture_status.smoothed_samplerate = None;
+                        capture_status.raw_samplerate = 0.0;
                         capture_status.signal_range = 0.0;
                         capture_status.rate_adjust = 0.0;
                         capture_status.state = ProcessingState::Stalled;
@@ -801,6 +884,15 @@ impl CaptureDevice for CoreaudioCaptureDevice {
                     for element in data_buffer.iter_mut().take(capture_bytes) {
                         *element = data_queue.pop_front().unwrap();
                     }
+                    if let Some(monitor) = &mut monitor {
+                        monitor.write(&buffer_to_chunk_rawbytes(
+                            &data_buffer[0..capture_bytes],
+                            channels,
+                            &SampleFormat::FLOAT32LE,
+                            capture_bytes,
+                            &all_channels,
+                        ));
+                    }
                     let mut chunk = buffer_to_chunk_rawbytes(
                         &data_buffer[0..capture_bytes],
                         channels,
@@ -819,7 +911,7 @@ impl CaptureDevice for CoreaudioCaptureDevice {
                             measured_rate_f
                         );
                         let mut capture_status = capture_status.write().unwrap();
-                        capture_status.measured_samplerate = measured_rate_f as usize;
+                        capture_status.update_measured_samplerate(measured_rate_f);
                         capture_status.signal_range = value_range as f32;
                         capture_status.rate_adjust = rate_adjust as f32;
                         capture_status.state = state;
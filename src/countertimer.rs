@@ -1,7 +1,12 @@
 use crate::NewValue;
 use crate::PrcFmt;
 use crate::ProcessingState;
-use std::time::{Duration, Instant};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 pub struct Averager {
     sum: f64,
@@ -201,11 +206,236 @@ impl ValueWatcher {
     }
 }
 
+/// Watches a running count of clipped samples, and reports when too many were clipped within a time window.
+pub struct ClipWatcher {
+    clip_limit: usize,
+    window_millis: u64,
+    timer: Stopwatch,
+    start_count: Option<usize>,
+}
+
+impl ClipWatcher {
+    pub fn new(clip_limit: usize, window: f32) -> ClipWatcher {
+        ClipWatcher {
+            clip_limit,
+            window_millis: (1000.0 * window) as u64,
+            timer: Stopwatch::new(),
+            start_count: None,
+        }
+    }
+
+    /// Check the current total number of clipped samples.
+    /// Returns true at the end of a window with more clipped samples than the limit.
+    pub fn check(&mut self, clipped_total: usize) -> bool {
+        let start_count = match self.start_count {
+            Some(count) => count,
+            None => {
+                self.start_count = Some(clipped_total);
+                self.timer.restart();
+                return false;
+            }
+        };
+        if !self.timer.larger_than_millis(self.window_millis) {
+            return false;
+        }
+        self.timer.restart();
+        self.start_count = Some(clipped_total);
+        clipped_total.saturating_sub(start_count) > self.clip_limit
+    }
+}
+
+/// Turns a running count of clipped samples into a clip indicator,
+/// that stays active for a hold time after the count last increased.
+pub struct ClipIndicator {
+    hold_millis: u64,
+    timer: Stopwatch,
+    last_count: usize,
+    active: bool,
+}
+
+impl ClipIndicator {
+    /// Create a new indicator, starting from the current total number of clipped samples.
+    /// The total is kept between restarts of processing, and earlier clipping must not activate it.
+    pub fn new(hold_time: f32, clipped_total: usize) -> ClipIndicator {
+        ClipIndicator {
+            hold_millis: (1000.0 * hold_time) as u64,
+            timer: Stopwatch::new(),
+            last_count: clipped_total,
+            active: false,
+        }
+    }
+
+    /// Check the current total number of clipped samples, and return if the indicator is active.
+    /// A count that goes down, because the counter was reset, does not count as clipping.
+    pub fn check(&mut self, clipped_total: usize) -> bool {
+        if clipped_total > self.last_count {
+            self.timer.restart();
+            self.active = true;
+        } else if self.active && self.timer.larger_than_millis(self.hold_millis) {
+            self.active = false;
+        }
+        self.last_count = clipped_total;
+        self.active
+    }
+}
+
+/// Appends the measured capture sample rate and rate adjust to a CSV file,
+/// for following the clock drift over long sessions.
+/// A line is added for each status update of the capture device.
+pub struct DriftLog {
+    file: File,
+    last_update: usize,
+}
+
+impl DriftLog {
+    /// Open a file for appending, writing the header line if the file is new or empty.
+    pub fn new(path: &Path) -> io::Result<DriftLog> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        if file.metadata()?.len() == 0 {
+            writeln!(
+                file,
+                "time,samplerate,measured_samplerate,rate_adjust,state"
+            )?;
+        }
+        Ok(DriftLog {
+            file,
+            last_update: 0,
+        })
+    }
+
+    /// Add a line when the capture device has made a new status update,
+    /// as counted by `status_updates` of the capture status.
+    /// The time is given as seconds since the unix epoch.
+    /// The measured samplerate is the raw measurement, with decimals so that drifts of a few ppm are visible.
+    pub fn check(
+        &mut self,
+        status_updates: usize,
+        samplerate: usize,
+        measured_samplerate: f64,
+        rate_adjust: f32,
+        state: ProcessingState,
+    ) {
+        if status_updates == self.last_update {
+            return;
+        }
+        self.last_update = status_updates;
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        if let Err(err) = writeln!(
+            self.file,
+            "{:.3},{},{:.3},{},{}",
+            time, samplerate, measured_samplerate, rate_adjust, state
+        ) {
+            warn!("Could not write to drift log: {}", err);
+        }
+    }
+}
+
+/// Number of histogram buckets covering processing times from zero to one chunk period.
+/// One more bucket collects the chunks that took longer than that.
+pub const HISTOGRAM_BUCKETS: usize = 10;
+
+/// Histogram of processing times, given as fractions of the chunk period.
+#[derive(Clone, Debug, Default)]
+pub struct ProcessingHistogram {
+    counts: [usize; HISTOGRAM_BUCKETS + 1],
+}
+
+impl ProcessingHistogram {
+    pub fn new() -> ProcessingHistogram {
+        ProcessingHistogram::default()
+    }
+
+    /// Add a processing time, as a fraction of the chunk period.
+    pub fn add(&mut self, fraction: f32) {
+        let bucket =
+            ((fraction.max(0.0) * HISTOGRAM_BUCKETS as f32) as usize).min(HISTOGRAM_BUCKETS);
+        self.counts[bucket] += 1;
+    }
+
+    pub fn reset(&mut self) {
+        self.counts = [0; HISTOGRAM_BUCKETS + 1];
+    }
+
+    /// Add the counts of another histogram to this one.
+    pub fn merge(&mut self, other: &ProcessingHistogram) {
+        for (count, other_count) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *count += other_count;
+        }
+    }
+
+    pub fn counts(&self) -> Vec<usize> {
+        self.counts.to_vec()
+    }
+}
+
+/// Processing time of a filter, average and maximum per call in microseconds.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct FilterTime {
+    pub average: f32,
+    pub max: f32,
+}
+
+/// Collects the processing times of the filters, and summarizes them over a window of chunks.
+pub struct FilterTimer {
+    window: usize,
+    chunks: usize,
+    // Total time, max time and number of calls for each filter
+    times: HashMap<String, (Duration, Duration, usize)>,
+}
+
+impl FilterTimer {
+    pub fn new(window: usize) -> FilterTimer {
+        FilterTimer {
+            window: window.max(1),
+            chunks: 0,
+            times: HashMap::new(),
+        }
+    }
+
+    /// Add the time spent by one call of a filter.
+    pub fn add(&mut self, name: &str, time: Duration) {
+        let entry =
+            self.times
+                .entry(name.to_string())
+                .or_insert((Duration::ZERO, Duration::ZERO, 0));
+        entry.0 += time;
+        entry.1 = entry.1.max(time);
+        entry.2 += 1;
+    }
+
+    /// Mark the end of a chunk. Returns the times of all filters when the window is complete.
+    pub fn end_chunk(&mut self) -> Option<HashMap<String, FilterTime>> {
+        self.chunks += 1;
+        if self.chunks < self.window {
+            return None;
+        }
+        self.chunks = 0;
+        let summary = self
+            .times
+            .drain()
+            .map(|(name, (total, max, calls))| {
+                let time = FilterTime {
+                    average: 1.0e6 * total.as_secs_f32() / calls.max(1) as f32,
+                    max: 1.0e6 * max.as_secs_f32(),
+                };
+                (name, time)
+            })
+            .collect();
+        Some(summary)
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::countertimer::{Averager, SilenceCounter, Stopwatch, TimeAverage, ValueWatcher};
+    use crate::countertimer::{
+        Averager, ClipIndicator, ClipWatcher, DriftLog, FilterTimer, ProcessingHistogram,
+        SilenceCounter, Stopwatch, TimeAverage, ValueWatcher,
+    };
     use crate::ProcessingState;
-    use std::time::Instant;
+    use std::time::{Duration, Instant};
 
     fn spinsleep(time: u128) {
         let start = Instant::now();
@@ -341,4 +571,90 @@ mod tests {
             assert_eq!(watcher.check_value(88200.0), true);
         }
     }
+
+    #[test]
+    fn clipwatcher() {
+        let mut w = ClipWatcher::new(10, 0.01);
+        assert!(!w.check(100));
+        assert!(!w.check(200));
+        spinsleep(15);
+        assert!(w.check(200));
+        spinsleep(15);
+        assert!(!w.check(205));
+    }
+
+    #[test]
+    fn clip_indicator_hold() {
+        let mut indicator = ClipIndicator::new(0.01, 0);
+        assert!(!indicator.check(0));
+        assert!(indicator.check(3));
+        assert!(indicator.check(3));
+        spinsleep(15);
+        assert!(!indicator.check(3));
+        assert!(!indicator.check(0));
+        assert!(indicator.check(1));
+    }
+
+    #[test]
+    fn clip_indicator_starts_from_current_count() {
+        let mut indicator = ClipIndicator::new(0.01, 5);
+        assert!(!indicator.check(5));
+        assert!(indicator.check(6));
+    }
+
+    #[test]
+    fn processing_histogram() {
+        let mut hist = ProcessingHistogram::new();
+        hist.add(0.05);
+        hist.add(0.15);
+        hist.add(0.19);
+        hist.add(0.99);
+        hist.add(1.0);
+        hist.add(3.5);
+        assert_eq!(hist.counts(), vec![1, 2, 0, 0, 0, 0, 0, 0, 0, 1, 2]);
+        let mut total = ProcessingHistogram::new();
+        total.add(0.15);
+        total.merge(&hist);
+        assert_eq!(total.counts(), vec![1, 3, 0, 0, 0, 0, 0, 0, 0, 1, 2]);
+        hist.reset();
+        assert_eq!(hist.counts(), vec![0; 11]);
+    }
+
+    #[test]
+    fn filter_timer() {
+        let mut timer = FilterTimer::new(2);
+        timer.add("eq", Duration::from_micros(10));
+        timer.add("eq", Duration::from_micros(30));
+        assert!(timer.end_chunk().is_none());
+        timer.add("eq", Duration::from_micros(20));
+        let times = timer.end_chunk().unwrap();
+        assert!((times["eq"].average - 20.0).abs() < 0.01);
+        assert!((times["eq"].max - 30.0).abs() < 0.01);
+        assert!(timer.end_chunk().is_none());
+        assert!(timer.end_chunk().unwrap().is_empty());
+    }
+
+    #[test]
+    fn drift_log_appends() {
+        let path = std::env::temp_dir().join(format!("drift_log_{}.csv", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut log = DriftLog::new(&path).unwrap();
+        log.check(1, 44100, 44103.0, 1.0001, ProcessingState::Running);
+        // a reopened log appends without repeating the header
+        let mut log = DriftLog::new(&path).unwrap();
+        log.check(0, 44100, 44099.9561, 0.9999, ProcessingState::Running);
+        log.check(1, 44100, 44099.9561, 0.9999, ProcessingState::Running);
+        // nothing is added until the next status update
+        log.check(1, 44100, 44099.9561, 0.9999, ProcessingState::Running);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            lines[0],
+            "time,samplerate,measured_samplerate,rate_adjust,state"
+        );
+        assert!(lines[1].ends_with(",44100,44103.000,1.0001,RUNNING"));
+        assert!(lines[2].ends_with(",44100,44099.956,0.9999,RUNNING"));
+    }
 }
@@ -5,6 +5,7 @@ use crate::conversions::{
     chunk_to_queue_float, chunk_to_queue_int, queue_to_chunk_float, queue_to_chunk_int,
 };
 use crate::countertimer;
+use crate::filedevice::start_capture_monitor;
 use cpal;
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use cpal::Device;
@@ -30,9 +31,16 @@ pub enum CpalHost {
     #[cfg(target_os = "macos")]
     CoreAudio,
     #[cfg(feature = "jack-backend")]
-    Jack,
+    Jack {
+        autoconnect: Option<Vec<String>>,
+        connect_timeout: f32,
+    },
 }
 
+// Wait between attempts to connect to a Jack port that doesn't exist yet
+#[cfg(feature = "jack-backend")]
+const JACK_CONNECT_RETRY_MS: u64 = 100;
+
 #[derive(Clone, Debug)]
 pub struct CpalPlaybackDevice {
     pub devname: String,
@@ -61,10 +69,77 @@ pub struct CpalCaptureDevice {
     pub silence_timeout: PrcFmt,
     pub stop_on_rate_change: bool,
     pub rate_measure_interval: f32,
+    pub monitor_file: Option<String>,
+}
+
+/// List the Jack devices of CPAL, with their supported configurations.
+#[cfg(feature = "jack-backend")]
+pub fn list_devices() -> Vec<DeviceInfo> {
+    let mut devices = Vec::new();
+    let host = match cpal::host_from_id(HostId::Jack) {
+        Ok(host) => host,
+        Err(err) => {
+            warn!("Unable to list Jack devices: {}", err);
+            return devices;
+        }
+    };
+    for capture in [true, false] {
+        let host_devices = if capture {
+            host.input_devices()
+        } else {
+            host.output_devices()
+        };
+        let host_devices = match host_devices {
+            Ok(host_devices) => host_devices,
+            Err(_) => continue,
+        };
+        for device in host_devices {
+            let name = match device.name() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            let mut info = DeviceInfo {
+                backend: "Jack".to_string(),
+                capture,
+                name,
+                description: None,
+                channels: Vec::new(),
+                samplerates: Vec::new(),
+                formats: vec![SampleFormat::FLOAT32LE],
+            };
+            let configs: Vec<cpal::SupportedStreamConfigRange> = if capture {
+                device
+                    .supported_input_configs()
+                    .map(|c| c.collect())
+                    .unwrap_or_default()
+            } else {
+                device
+                    .supported_output_configs()
+                    .map(|c| c.collect())
+                    .unwrap_or_default()
+            };
+            for config in configs {
+                info.channels.push(config.channels() as usize);
+                let (min_rate, max_rate) = (config.min_sample_rate().0, config.max_sample_rate().0);
+                info.samplerates.extend(
+                    STANDARD_RATES
+                        .iter()
+                        .filter(|rate| (min_rate..=max_rate).contains(rate))
+                        .map(|rate| *rate as usize),
+                );
+            }
+            info.channels.sort_unstable();
+            info.channels.dedup();
+            info.samplerates.sort_unstable();
+            info.samplerates.dedup();
+            devices.push(info);
+        }
+    }
+    devices
 }
 
 fn open_cpal_playback(
-    host_cfg: CpalHost,
+    host_cfg: &CpalHost,
     devname: &str,
     samplerate: usize,
     channels: usize,
@@ -74,7 +149,7 @@ fn open_cpal_playback(
         #[cfg(target_os = "macos")]
         CpalHost::CoreAudio => HostId::CoreAudio,
         #[cfg(feature = "jack-backend")]
-        CpalHost::Jack => HostId::Jack,
+        CpalHost::Jack { .. } => HostId::Jack,
     };
     let host = cpal::host_from_id(host_id)?;
     let device = if devname == "default" {
@@ -113,7 +188,7 @@ fn open_cpal_playback(
 }
 
 fn open_cpal_capture(
-    host_cfg: CpalHost,
+    host_cfg: &CpalHost,
     devname: &str,
     samplerate: usize,
     channels: usize,
@@ -123,7 +198,7 @@ fn open_cpal_capture(
         #[cfg(target_os = "macos")]
         CpalHost::CoreAudio => HostId::CoreAudio,
         #[cfg(feature = "jack-backend")]
-        CpalHost::Jack => HostId::Jack,
+        CpalHost::Jack { .. } => HostId::Jack,
     };
     let host = cpal::host_from_id(host_id)?;
     let device = if devname == "default" {
@@ -162,6 +237,66 @@ fn open_cpal_capture(
     Ok((device, stream_config, cpal_format))
 }
 
+/// Replace the automatic connections of the ports of a Jack device by connections to the given ports.
+/// Ports that don't exist yet are retried until the timeout, in seconds, has passed.
+#[cfg(feature = "jack-backend")]
+fn connect_jack_ports(
+    client_name: &str,
+    channels: usize,
+    capture: bool,
+    ports: &[String],
+    timeout: f32,
+) -> Res<()> {
+    let (client, _status) = jack::Client::new(
+        &format!("{}_connect", client_name),
+        jack::ClientOptions::NO_START_SERVER,
+    )?;
+    let direction = if capture { "in" } else { "out" };
+    let own_ports: Vec<String> = (0..channels)
+        .map(|n| format!("{}:{}_{}", client_name, direction, n))
+        .collect();
+    for own_port in own_ports.iter() {
+        if let Some(port) = client.port_by_name(own_port) {
+            for other in port.get_connections() {
+                debug!("Disconnecting Jack port {} from {}", own_port, other);
+                if capture {
+                    client.disconnect_ports_by_name(&other, own_port)?;
+                } else {
+                    client.disconnect_ports_by_name(own_port, &other)?;
+                }
+            }
+        }
+    }
+    let deadline = time::Instant::now() + time::Duration::from_secs_f32(timeout);
+    for (own_port, other) in own_ports.iter().zip(ports.iter()) {
+        loop {
+            let result = if capture {
+                client.connect_ports_by_name(other, own_port)
+            } else {
+                client.connect_ports_by_name(own_port, other)
+            };
+            match result {
+                Ok(()) => {
+                    debug!("Connected Jack port {} to {}", own_port, other);
+                    break;
+                }
+                Err(err) => {
+                    if time::Instant::now() >= deadline {
+                        let msg = format!(
+                            "Could not connect Jack port {} to {}, error: {}",
+                            own_port, other, err
+                        );
+                        return Err(ConfigError::new(&msg).into());
+                    }
+                    trace!("Jack port {} not available, retrying", other);
+                    thread::sleep(time::Duration::from_millis(JACK_CONNECT_RETRY_MS));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
 fn write_data_to_device<T>(output: &mut [T], queue: &mut VecDeque<T>)
 where
     T: cpal::Sample,
@@ -201,7 +336,7 @@ impl PlaybackDevice for CpalPlaybackDevice {
         let handle = thread::Builder::new()
             .name("CpalPlayback".to_string())
             .spawn(move || {
-                match open_cpal_playback(host_cfg, &devname, samplerate, channels, &sample_format) {
+                match open_cpal_playback(&host_cfg, &devname, samplerate, channels, &sample_format) {
                     Ok((device, stream_config, _sample_format)) => {
                         match status_channel.send(StatusMessage::PlaybackReady) {
                             Ok(()) => {}
@@ -346,6 +481,16 @@ impl PlaybackDevice for CpalPlaybackDevice {
                                     .unwrap(),
                             }
                         }
+                        #[cfg(feature = "jack-backend")]
+                        if let CpalHost::Jack { autoconnect: Some(ports), connect_timeout } = &host_cfg {
+                            // The name of the device is the name of the Jack client
+                            let client_name = device.name().unwrap_or_else(|_| devname.clone());
+                            if let Err(err) = connect_jack_ports(&client_name, channels, false, ports, *connect_timeout) {
+                                status_channel
+                                    .send(StatusMessage::PlaybackError(err.to_string()))
+                                    .unwrap();
+                            }
+                        }
                         loop {
                             match channel.recv() {
                                 Ok(AudioMessage::Audio(chunk)) => {
@@ -466,6 +611,7 @@ impl CaptureDevice for CpalCaptureDevice {
         let silence_threshold = self.silence_threshold;
         let stop_on_rate_change = self.stop_on_rate_change;
         let rate_measure_interval = self.rate_measure_interval;
+        let monitor_file = self.monitor_file.clone();
         let handle = thread::Builder::new()
             .name("CpalCapture".to_string())
             .spawn(move || {
@@ -481,8 +627,9 @@ impl CaptureDevice for CpalCaptureDevice {
                 } else {
                     None
                 };
-                match open_cpal_capture(host_cfg, &devname, capture_samplerate, channels, &sample_format) {
+                match open_cpal_capture(&host_cfg, &devname, capture_samplerate, channels, &sample_format) {
                     Ok((device, stream_config, _sample_format)) => {
+                        let mut monitor = start_capture_monitor(&monitor_file, channels, capture_samplerate, &sample_format);
                         match status_channel.send(StatusMessage::CaptureReady) {
                             Ok(()) => {}
                             Err(_err) => {}
@@ -539,6 +686,16 @@ impl CaptureDevice for CpalCaptureDevice {
                                     .unwrap(),
                             }
                         }
+                        #[cfg(feature = "jack-backend")]
+                        if let CpalHost::Jack { autoconnect: Some(ports), connect_timeout } = &host_cfg {
+                            // The name of the device is the name of the Jack client
+                            let client_name = device.name().unwrap_or_else(|_| devname.clone());
+                            if let Err(err) = connect_jack_ports(&client_name, channels, true, ports, *connect_timeout) {
+                                status_channel
+                                    .send(StatusMessage::CaptureError(err.to_string()))
+                                    .unwrap();
+                            }
+                        }
                         let chunksize_samples = channels * chunksize;
                         let mut capture_samples = chunksize_samples;
                         let mut sample_queue_i: VecDeque<i16> = VecDeque::with_capacity(2*chunksize*channels);
@@ -632,6 +789,9 @@ impl CaptureDevice for CpalCaptureDevice {
                                 },
                                 _ => panic!("Unsupported sample format"),
                             };
+                            if let Some(monitor) = &mut monitor {
+                                monitor.write(&chunk);
+                            }
                             averager.add_value(capture_samples);
                             if averager.larger_than_millis(capture_status.read().unwrap().update_interval as u64)
                             {
@@ -643,7 +803,7 @@ impl CaptureDevice for CpalCaptureDevice {
                                     measured_rate_f
                                 );
                                 let mut capt_stat = capture_status.write().unwrap();
-                                capt_stat.measured_samplerate = measured_rate_f as usize;
+                                capt_stat.update_measured_samplerate(measured_rate_f);
                                 capt_stat.signal_range = value_range as f32;
                                 capt_stat.rate_adjust = rate_adjust as f32;
                                 capt_stat.state = state;
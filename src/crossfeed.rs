@@ -0,0 +1,174 @@
+use crate::audiodevice::AudioChunk;
+use crate::basicfilters::Delay;
+use crate::biquad;
+use crate::config;
+use crate::filters::Filter;
+
+use crate::PrcFmt;
+use crate::Res;
+
+/// A crossfeed for headphone listening, mixing a delayed and lowpass filtered copy
+/// of each channel of a stereo signal into the opposite channel.
+/// The direct signals are attenuated so that the level of a centered sound is unchanged at low frequencies.
+pub struct Crossfeed {
+    pub name: String,
+    samplerate: usize,
+    direct_gain: PrcFmt,
+    cross_gain: PrcFmt,
+    delays: Vec<Delay>,
+    lowpasses: Vec<biquad::Biquad>,
+}
+
+fn lowpass_config(freq: PrcFmt) -> config::BiquadParameters {
+    config::BiquadParameters::LowpassFO { freq }
+}
+
+impl Crossfeed {
+    pub fn from_config(name: String, samplerate: usize, conf: config::CrossfeedParameters) -> Self {
+        let cross_gain = (10.0 as PrcFmt).powf(conf.gain / 20.0);
+        let delay_samples = conf.delay / 1000.0 * samplerate as PrcFmt;
+        debug!(
+            "Building crossfeed '{}' with cutoff {} Hz, delay {} samples and gain {} dB",
+            name, conf.freq, delay_samples, conf.gain
+        );
+        let delays = (0..2)
+            .map(|_| {
+                Delay::new(
+                    "crossfeed_delay".to_string(),
+                    samplerate,
+                    delay_samples,
+                    false,
+                )
+            })
+            .collect();
+        let lowpasses = (0..2)
+            .map(|_| {
+                biquad::Biquad::new(
+                    "crossfeed_lowpass".to_string(),
+                    samplerate,
+                    biquad::BiquadCoefficients::from_config(samplerate, lowpass_config(conf.freq)),
+                )
+            })
+            .collect();
+        Crossfeed {
+            name,
+            samplerate,
+            direct_gain: 1.0 / (1.0 + cross_gain),
+            cross_gain: cross_gain / (1.0 + cross_gain),
+            delays,
+            lowpasses,
+        }
+    }
+
+    pub fn update_parameters(&mut self, conf: config::Filter) {
+        if let config::Filter::Crossfeed { parameters: conf } = conf {
+            *self = Crossfeed::from_config(self.name.clone(), self.samplerate, conf);
+        } else {
+            // This should never happen unless there is a bug somewhere else
+            panic!("Invalid config change!");
+        }
+    }
+
+    pub fn reset(&mut self) {
+        for delay in self.delays.iter_mut() {
+            delay.reset();
+        }
+        for lowpass in self.lowpasses.iter_mut() {
+            lowpass.reset();
+        }
+    }
+
+    /// Apply the crossfeed to the two channels of a chunk.
+    /// An unused channel is treated as silence.
+    pub fn process_chunk(&mut self, chunk: &mut AudioChunk) -> Res<()> {
+        if chunk.waveforms.iter().all(|waveform| waveform.is_empty()) {
+            return Ok(());
+        }
+        for waveform in chunk.waveforms.iter_mut() {
+            if waveform.is_empty() {
+                *waveform = vec![0.0; chunk.frames];
+            }
+        }
+        let mut crossed = Vec::with_capacity(2);
+        for (channel, waveform) in chunk.waveforms.iter().enumerate() {
+            let mut bleed = waveform.clone();
+            self.delays[channel].process_waveform(&mut bleed)?;
+            self.lowpasses[channel].process_waveform(&mut bleed)?;
+            crossed.push(bleed);
+        }
+        for (waveform, bleed) in chunk.waveforms.iter_mut().zip(crossed.iter().rev()) {
+            for (value, cross) in waveform.iter_mut().zip(bleed.iter()) {
+                *value = self.direct_gain * *value + self.cross_gain * cross;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Validate a Crossfeed config.
+pub fn validate_config(samplerate: usize, conf: &config::CrossfeedParameters) -> Res<()> {
+    biquad::validate_config(samplerate, &lowpass_config(conf.freq))?;
+    if conf.delay < 0.0 {
+        return Err(config::ConfigError::new("Delay must be positive or zero").into());
+    } else if conf.gain > 0.0 {
+        return Err(config::ConfigError::new("Gain must be less than or equal to 0").into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audiodevice::AudioChunk;
+    use crate::config::CrossfeedParameters;
+    use crate::crossfeed::{validate_config, Crossfeed};
+    use crate::PrcFmt;
+
+    fn make_crossfeed(delay: PrcFmt) -> Crossfeed {
+        let conf = CrossfeedParameters {
+            freq: 700.0,
+            delay,
+            gain: -6.0,
+        };
+        Crossfeed::from_config("test".to_string(), 44100, conf)
+    }
+
+    #[test]
+    fn centered_dc_unchanged() {
+        let mut crossfeed = make_crossfeed(0.3);
+        let mut chunk = AudioChunk::new(
+            vec![vec![1.0; 4096], vec![1.0; 4096]],
+            1.0,
+            -1.0,
+            4096,
+            4096,
+        );
+        crossfeed.process_chunk(&mut chunk).unwrap();
+        assert!((chunk.waveforms[0][4095] - 1.0).abs() < 1.0e-6);
+        assert!((chunk.waveforms[1][4095] - 1.0).abs() < 1.0e-6);
+    }
+
+    #[test]
+    fn one_side_bleeds_delayed() {
+        let mut crossfeed = make_crossfeed(1.0);
+        let mut chunk = AudioChunk::new(vec![vec![1.0; 4096], vec![]], 1.0, -1.0, 4096, 4096);
+        crossfeed.process_chunk(&mut chunk).unwrap();
+        let cross_gain: PrcFmt = 10.0_f64.powf(-6.0 / 20.0) as PrcFmt;
+        let direct_gain = 1.0 / (1.0 + cross_gain);
+        assert!((chunk.waveforms[0][0] - direct_gain).abs() < 1.0e-6);
+        // nothing reaches the other side until the delay of 44 samples has passed
+        assert!(chunk.waveforms[1][0..44].iter().all(|value| *value == 0.0));
+        assert!(chunk.waveforms[1][45] > 0.0);
+        assert!((chunk.waveforms[1][4095] - cross_gain * direct_gain).abs() < 1.0e-6);
+    }
+
+    #[test]
+    fn check_config() {
+        let mut conf = CrossfeedParameters::default();
+        assert!(validate_config(44100, &conf).is_ok());
+        conf.gain = 1.0;
+        assert!(validate_config(44100, &conf).is_err());
+        conf.gain = -6.0;
+        conf.freq = 30000.0;
+        assert!(validate_config(44100, &conf).is_err());
+    }
+}
@@ -1,5 +1,6 @@
 use crate::config;
 use crate::filters::Filter;
+use num_complex::Complex;
 
 // Sample format
 //type SmpFmt = i16;
@@ -49,6 +50,23 @@ impl DiffEq {
         DiffEq::new(name, a, b)
     }
 
+    /// Evaluate the complex frequency response at a frequency in Hz.
+    /// The first `a` coefficient is assumed to be 1.0, as in the processing.
+    pub fn response(&self, freq: PrcFmt, fs: usize) -> Complex<PrcFmt> {
+        let omega = 2.0 * std::f64::consts::PI as PrcFmt * freq / fs as PrcFmt;
+        let polynomial = |coeffs: &[PrcFmt]| -> Complex<PrcFmt> {
+            coeffs
+                .iter()
+                .enumerate()
+                .map(|(n, coeff)| Complex::from_polar(*coeff, -omega * n as PrcFmt))
+                .sum()
+        };
+        let numerator = polynomial(&self.b);
+        let denominator =
+            Complex::new(1.0, 0.0) + polynomial(&self.a[1..]) * Complex::from_polar(1.0, -omega);
+        numerator / denominator
+    }
+
     /// Process a single sample
     fn process_single(&mut self, input: PrcFmt) -> PrcFmt {
         let mut out = 0.0;
@@ -105,6 +123,13 @@ impl Filter for DiffEq {
         Ok(())
     }
 
+    fn reset(&mut self) {
+        self.x.iter_mut().for_each(|value| *value = 0.0);
+        self.y.iter_mut().for_each(|value| *value = 0.0);
+        self.idx_x = 0;
+        self.idx_y = 0;
+    }
+
     fn update_parameters(&mut self, conf: config::Filter) {
         if let config::Filter::DiffEq { parameters: conf } = conf {
             let name = self.name.clone();
@@ -114,6 +139,19 @@ impl Filter for DiffEq {
             panic!("Invalid config change!");
         }
     }
+
+    fn frequency_response(
+        &self,
+        frequencies: &[PrcFmt],
+        samplerate: usize,
+    ) -> Option<Vec<Complex<PrcFmt>>> {
+        Some(
+            frequencies
+                .iter()
+                .map(|freq| self.response(*freq, samplerate))
+                .collect(),
+        )
+    }
 }
 
 pub fn validate_config(_parameters: &config::DiffEqParameters) -> Res<()> {
@@ -208,6 +208,11 @@ impl Filter for Dither {
         Ok(())
     }
 
+    fn reset(&mut self) {
+        self.buffer.iter_mut().for_each(|value| *value = 0.0);
+        self.idx = 0;
+    }
+
     fn update_parameters(&mut self, conf: config::Filter) {
         if let config::Filter::Dither { parameters: conf } = conf {
             let name = self.name.clone();
@@ -0,0 +1,281 @@
+use crate::biquad;
+use crate::config;
+use crate::filters::Filter;
+use num_complex::Complex;
+
+use crate::PrcFmt;
+use crate::ProcessingParameters;
+use crate::Res;
+
+/// Number of samples between updates of the peaking filter gain.
+const UPDATE_INTERVAL: usize = 32;
+
+/// A peaking filter where the gain is reduced when the level in the band is above a threshold.
+pub struct DynamicEq {
+    pub name: String,
+    samplerate: usize,
+    freq: PrcFmt,
+    q: PrcFmt,
+    gain: PrcFmt,
+    threshold: PrcFmt,
+    ratio: PrcFmt,
+    attack: PrcFmt,
+    release: PrcFmt,
+    envelope: PrcFmt,
+    current_gain: PrcFmt,
+    detector: biquad::Biquad,
+    peaking: biquad::Biquad,
+}
+
+/// Get the smoothing coefficient for a time constant in milliseconds.
+fn get_time_coefficient(time_ms: PrcFmt, samplerate: usize) -> PrcFmt {
+    (-1000.0 / (time_ms * samplerate as PrcFmt)).exp()
+}
+
+fn peaking_config(freq: PrcFmt, q: PrcFmt, gain: PrcFmt) -> config::BiquadParameters {
+    config::BiquadParameters::Peaking(config::PeakingWidth::Q { freq, gain, q })
+}
+
+fn bandpass_config(freq: PrcFmt, q: PrcFmt) -> config::BiquadParameters {
+    config::BiquadParameters::Bandpass(config::BandpassWidth::Q {
+        freq,
+        q,
+        normalization: config::BandpassNormalization::Peak,
+    })
+}
+
+impl DynamicEq {
+    pub fn from_config(name: String, samplerate: usize, conf: config::DynamicEqParameters) -> Self {
+        let detector = biquad::Biquad::new(
+            "detector".to_string(),
+            samplerate,
+            biquad::BiquadCoefficients::from_config(samplerate, bandpass_config(conf.freq, conf.q)),
+        );
+        let peaking = biquad::Biquad::new(
+            "peaking".to_string(),
+            samplerate,
+            biquad::BiquadCoefficients::from_config(
+                samplerate,
+                peaking_config(conf.freq, conf.q, conf.gain),
+            ),
+        );
+        DynamicEq {
+            name,
+            samplerate,
+            freq: conf.freq,
+            q: conf.q,
+            gain: conf.gain,
+            threshold: conf.threshold,
+            ratio: conf.ratio,
+            attack: get_time_coefficient(conf.attack, samplerate),
+            release: get_time_coefficient(conf.release, samplerate),
+            envelope: 0.0,
+            current_gain: conf.gain,
+            detector,
+            peaking,
+        }
+    }
+
+    /// Get the gain offset in dB for the current envelope level.
+    fn gain_offset(&self) -> PrcFmt {
+        let level = 20.0 * self.envelope.max(1.0e-9).log10();
+        if level > self.threshold {
+            -(level - self.threshold) * (1.0 - 1.0 / self.ratio)
+        } else {
+            0.0
+        }
+    }
+
+    /// Update the envelope follower with a block of band filtered samples.
+    fn update_envelope(&mut self, band: &[PrcFmt]) {
+        for value in band.iter() {
+            let level = value.abs();
+            let coeff = if level > self.envelope {
+                self.attack
+            } else {
+                self.release
+            };
+            self.envelope = coeff * self.envelope + (1.0 - coeff) * level;
+        }
+    }
+}
+
+impl Filter for DynamicEq {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn process_waveform(&mut self, waveform: &mut [PrcFmt]) -> Res<()> {
+        let mut band = waveform.to_vec();
+        self.detector.process_waveform(&mut band)?;
+        for (block, band_block) in waveform
+            .chunks_mut(UPDATE_INTERVAL)
+            .zip(band.chunks(UPDATE_INTERVAL))
+        {
+            self.update_envelope(band_block);
+            let new_gain = self.gain + self.gain_offset();
+            if (new_gain - self.current_gain).abs() > 0.01 {
+                self.current_gain = new_gain;
+                self.peaking.update_parameters(config::Filter::Biquad {
+                    parameters: peaking_config(self.freq, self.q, new_gain),
+                });
+            }
+            self.peaking.process_waveform(block)?;
+        }
+        Ok(())
+    }
+
+    fn publish_status(&self, status: &mut ProcessingParameters) {
+        let offset = (self.current_gain - self.gain) as f32;
+        match status.dynamic_eq_offsets.get_mut(&self.name) {
+            Some(value) => *value = offset,
+            None => {
+                status.dynamic_eq_offsets.insert(self.name.clone(), offset);
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.envelope = 0.0;
+        self.detector.reset();
+        self.peaking.reset();
+    }
+
+    fn update_parameters(&mut self, conf: config::Filter) {
+        if let config::Filter::DynamicEq { parameters: conf } = conf {
+            self.detector.update_parameters(config::Filter::Biquad {
+                parameters: bandpass_config(conf.freq, conf.q),
+            });
+            self.freq = conf.freq;
+            self.q = conf.q;
+            self.gain = conf.gain;
+            self.threshold = conf.threshold;
+            self.ratio = conf.ratio;
+            self.attack = get_time_coefficient(conf.attack, self.samplerate);
+            self.release = get_time_coefficient(conf.release, self.samplerate);
+            self.current_gain = self.gain + self.gain_offset();
+            self.peaking.update_parameters(config::Filter::Biquad {
+                parameters: peaking_config(self.freq, self.q, self.current_gain),
+            });
+        } else {
+            // This should never happen unless there is a bug somewhere else
+            panic!("Invalid config change!");
+        }
+    }
+
+    /// The response of the peaking filter, at the gain given by the current envelope.
+    fn frequency_response(
+        &self,
+        frequencies: &[PrcFmt],
+        _samplerate: usize,
+    ) -> Option<Vec<Complex<PrcFmt>>> {
+        Some(
+            frequencies
+                .iter()
+                .map(|freq| self.peaking.response(*freq))
+                .collect(),
+        )
+    }
+}
+
+/// Validate a DynamicEq config.
+pub fn validate_config(samplerate: usize, conf: &config::DynamicEqParameters) -> Res<()> {
+    biquad::validate_config(samplerate, &peaking_config(conf.freq, conf.q, conf.gain))?;
+    if conf.ratio < 1.0 {
+        return Err(config::ConfigError::new("Ratio must be at least 1").into());
+    } else if conf.threshold > 0.0 {
+        return Err(config::ConfigError::new("Threshold must be less than or equal to 0").into());
+    } else if conf.attack <= 0.0 {
+        return Err(config::ConfigError::new("Attack time must be positive and > 0").into());
+    } else if conf.release <= 0.0 {
+        return Err(config::ConfigError::new("Release time must be positive and > 0").into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::DynamicEqParameters;
+    use crate::countertimer;
+    use crate::dynamiceq::DynamicEq;
+    use crate::filters::Filter;
+    use crate::PrcFmt;
+    use crate::ProcessingParameters;
+    use std::collections::HashMap;
+    use std::f64::consts::PI;
+
+    fn make_status() -> ProcessingParameters {
+        ProcessingParameters {
+            volume: 0.0,
+            gain_offset: 0.0,
+            mute: false,
+            paused: false,
+            test_tone: None,
+            latency_measurement: None,
+            filter_responses: Vec::new(),
+            spectrum_requests: Vec::new(),
+            dynamic_eq_offsets: HashMap::new(),
+            loudness_boosts: HashMap::new(),
+            mixer_attenuations: HashMap::new(),
+            agc_gain: None,
+            processing_histogram: countertimer::ProcessingHistogram::new(),
+            profile_filters: false,
+            filter_times: HashMap::new(),
+            volume_ramp: None,
+            flush_filters: false,
+        }
+    }
+
+    fn make_filter() -> DynamicEq {
+        let conf = DynamicEqParameters {
+            freq: 1000.0,
+            q: 1.0,
+            gain: 0.0,
+            threshold: -20.0,
+            ratio: 4.0,
+            attack: 5.0,
+            release: 50.0,
+        };
+        DynamicEq::from_config("deq".to_string(), 48000, conf)
+    }
+
+    fn sine(amplitude: PrcFmt, freq: f64, length: usize) -> Vec<PrcFmt> {
+        (0..length)
+            .map(|n| amplitude * (2.0 * PI * freq * n as f64 / 48000.0).sin() as PrcFmt)
+            .collect()
+    }
+
+    fn peak(values: &[PrcFmt]) -> PrcFmt {
+        values.iter().fold(0.0, |max, val| val.abs().max(max))
+    }
+
+    #[test]
+    fn quiet_signal_unchanged() {
+        let mut filter = make_filter();
+        let mut status = make_status();
+        let mut wave = sine(0.01, 1000.0, 48000);
+        filter.process_waveform(&mut wave).unwrap();
+        assert!((peak(&wave[24000..]) - 0.01).abs() < 1.0e-4);
+        filter.publish_status(&mut status);
+        assert_eq!(status.dynamic_eq_offsets["deq"], 0.0);
+    }
+
+    #[test]
+    fn loud_signal_reduced() {
+        let mut filter = make_filter();
+        let mut status = make_status();
+        let mut wave = sine(0.9, 1000.0, 48000);
+        filter.process_waveform(&mut wave).unwrap();
+        assert!(peak(&wave[24000..]) < 0.3);
+        filter.publish_status(&mut status);
+        assert!(status.dynamic_eq_offsets["deq"] < -10.0);
+    }
+
+    #[test]
+    fn loud_signal_outside_band_unchanged() {
+        let mut filter = make_filter();
+        let mut wave = sine(0.1, 50.0, 48000);
+        filter.process_waveform(&mut wave).unwrap();
+        assert!((peak(&wave[24000..]) - 0.1).abs() < 0.01);
+    }
+}
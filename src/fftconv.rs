@@ -76,21 +76,7 @@ impl FftConv {
     }
 
     pub fn from_config(name: String, data_length: usize, conf: config::ConvParameters) -> Self {
-        let values = match conf {
-            config::ConvParameters::Values { values, length } => {
-                filters::pad_vector(&values, length)
-            }
-            config::ConvParameters::Raw {
-                filename,
-                format,
-                read_bytes_lines,
-                skip_bytes_lines,
-            } => filters::read_coeff_file(&filename, &format, read_bytes_lines, skip_bytes_lines)
-                .unwrap(),
-            config::ConvParameters::Wav { filename, channel } => {
-                filters::read_wav(&filename, channel).unwrap()
-            }
-        };
+        let values = filters::get_normalized_conv_values(&name, &conf).unwrap();
         FftConv::new(name, data_length, &values)
     }
 }
@@ -156,25 +142,18 @@ impl Filter for FftConv {
         Ok(())
     }
 
+    fn reset(&mut self) {
+        self.overlap.iter_mut().for_each(|value| *value = 0.0);
+        for segment in self.input_f.iter_mut() {
+            segment
+                .iter_mut()
+                .for_each(|value| *value = Complex::zero());
+        }
+    }
+
     fn update_parameters(&mut self, conf: config::Filter) {
         if let config::Filter::Conv { parameters: conf } = conf {
-            let coeffs = match conf {
-                config::ConvParameters::Values { values, length } => {
-                    filters::pad_vector(&values, length)
-                }
-                config::ConvParameters::Raw {
-                    filename,
-                    format,
-                    read_bytes_lines,
-                    skip_bytes_lines,
-                } => {
-                    filters::read_coeff_file(&filename, &format, read_bytes_lines, skip_bytes_lines)
-                        .unwrap()
-                }
-                config::ConvParameters::Wav { filename, channel } => {
-                    filters::read_wav(&filename, channel).unwrap()
-                }
-            };
+            let coeffs = filters::get_normalized_conv_values(&self.name, &conf).unwrap();
 
             let nsegments = ((coeffs.len() as PrcFmt) / (self.npoints as PrcFmt)).ceil() as usize;
 
@@ -208,38 +187,30 @@ impl Filter for FftConv {
             panic!("Invalid config change!");
         }
     }
+
+    fn frequency_response(
+        &self,
+        frequencies: &[PrcFmt],
+        samplerate: usize,
+    ) -> Option<Vec<Complex<PrcFmt>>> {
+        let segments = self.coeffs_f.iter().map(|segment| &segment[..]);
+        filters::conv_segments_response(segments, self.npoints, samplerate, frequencies).ok()
+    }
 }
 
 /// Validate a FFT convolution config.
 pub fn validate_config(conf: &config::ConvParameters) -> Res<()> {
-    match conf {
-        config::ConvParameters::Values { .. } => Ok(()),
-        config::ConvParameters::Raw {
-            filename,
-            format,
-            read_bytes_lines,
-            skip_bytes_lines,
-        } => {
-            let coeffs =
-                filters::read_coeff_file(filename, format, *read_bytes_lines, *skip_bytes_lines)?;
-            if coeffs.is_empty() {
-                return Err(config::ConfigError::new("Conv coefficients are empty").into());
-            }
-            Ok(())
-        }
-        config::ConvParameters::Wav { filename, channel } => {
-            let coeffs = filters::read_wav(filename, *channel)?;
-            if coeffs.is_empty() {
-                return Err(config::ConfigError::new("Conv coefficients are empty").into());
-            }
-            Ok(())
-        }
+    let mut coeffs = filters::get_conv_values(conf)?;
+    if coeffs.is_empty() && !matches!(conf, config::ConvParameters::Values { .. }) {
+        return Err(config::ConfigError::new("Conv coefficients are empty").into());
     }
+    filters::normalize_conv_values(&mut coeffs, conf.normalization())?;
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::config::ConvParameters;
+    use crate::config::{ConvNormalization, ConvParameters};
     use crate::fftconv::FftConv;
     use crate::filters::Filter;
     use crate::PrcFmt;
@@ -264,6 +235,9 @@ mod tests {
         let conf = ConvParameters::Values {
             values: coeffs,
             length: 0,
+            normalize: ConvNormalization::None,
+            minimum_phase: false,
+            fft_library: None,
         };
         let mut filter = FftConv::from_config("test".to_string(), 8, conf);
         let mut wave1 = vec![1.0, 1.0, 1.0, 0.0, 0.0, -1.0, 0.0, 0.0];
@@ -4,6 +4,7 @@ use crate::filters::Filter;
 use fftw::array::AlignedVec;
 use fftw::plan::*;
 use fftw::types::*;
+use num_complex::Complex;
 //use helpers::{multiply_add_elements, multiply_elements};
 
 // Sample format
@@ -145,21 +146,7 @@ impl FftConv {
     }
 
     pub fn from_config(name: String, data_length: usize, conf: config::ConvParameters) -> Self {
-        let values = match conf {
-            config::ConvParameters::Values { values, length } => {
-                filters::pad_vector(&values, length)
-            }
-            config::ConvParameters::Raw {
-                filename,
-                format,
-                read_bytes_lines,
-                skip_bytes_lines,
-            } => filters::read_coeff_file(&filename, &format, read_bytes_lines, skip_bytes_lines)
-                .unwrap(),
-            config::ConvParameters::Wav { filename, channel } => {
-                filters::read_wav(&filename, channel).unwrap()
-            }
-        };
+        let values = filters::get_normalized_conv_values(&name, &conf).unwrap();
         FftConv::new(name, data_length, &values)
     }
 }
@@ -209,25 +196,18 @@ impl Filter for FftConv {
         Ok(())
     }
 
+    fn reset(&mut self) {
+        self.overlap.iter_mut().for_each(|value| *value = 0.0);
+        for segment in self.input_f.iter_mut() {
+            segment
+                .iter_mut()
+                .for_each(|value| *value = ComplexFmt::new(0.0, 0.0));
+        }
+    }
+
     fn update_parameters(&mut self, conf: config::Filter) {
         if let config::Filter::Conv { parameters: conf } = conf {
-            let coeffs = match conf {
-                config::ConvParameters::Values { values, length } => {
-                    filters::pad_vector(&values, length)
-                }
-                config::ConvParameters::Raw {
-                    filename,
-                    format,
-                    read_bytes_lines,
-                    skip_bytes_lines,
-                } => {
-                    filters::read_coeff_file(&filename, &format, read_bytes_lines, skip_bytes_lines)
-                        .unwrap()
-                }
-                config::ConvParameters::Wav { filename, channel } => {
-                    filters::read_wav(&filename, channel).unwrap()
-                }
-            };
+            let coeffs = filters::get_normalized_conv_values(&self.name, &conf).unwrap();
 
             let nsegments = ((coeffs.len() as PrcFmt) / (self.npoints as PrcFmt)).ceil() as usize;
 
@@ -259,41 +239,48 @@ impl Filter for FftConv {
             panic!("Invalid config change!");
         }
     }
+
+    fn frequency_response(
+        &self,
+        frequencies: &[PrcFmt],
+        samplerate: usize,
+    ) -> Option<Vec<Complex<PrcFmt>>> {
+        let segments: Vec<Vec<Complex<PrcFmt>>> = self
+            .coeffs_f
+            .iter()
+            .map(|segment| {
+                segment
+                    .iter()
+                    .map(|value| Complex::new(value.re, value.im))
+                    .collect()
+            })
+            .collect();
+        filters::conv_segments_response(
+            segments.iter().map(|segment| &segment[..]),
+            self.npoints,
+            samplerate,
+            frequencies,
+        )
+        .ok()
+    }
 }
 
 /// Validate a FFT convolution config.
 pub fn validate_config(conf: &config::ConvParameters) -> Res<()> {
-    match conf {
-        config::ConvParameters::Values { .. } => Ok(()),
-        config::ConvParameters::Raw {
-            filename,
-            format,
-            read_bytes_lines,
-            skip_bytes_lines,
-        } => {
-            let coeffs =
-                filters::read_coeff_file(&filename, &format, *read_bytes_lines, *skip_bytes_lines)?;
-            if coeffs.is_empty() {
-                return Err(config::ConfigError::new("Conv coefficients are empty").into());
-            }
-            Ok(())
-        }
-        config::ConvParameters::Wav { filename, channel } => {
-            let coeffs = filters::read_wav(&filename, *channel)?;
-            if coeffs.is_empty() {
-                return Err(config::ConfigError::new("Conv coefficients are empty").into());
-            }
-            Ok(())
-        }
+    let mut coeffs = filters::get_conv_values(conf)?;
+    if coeffs.is_empty() && !matches!(conf, config::ConvParameters::Values { .. }) {
+        return Err(config::ConfigError::new("Conv coefficients are empty").into());
     }
+    filters::normalize_conv_values(&mut coeffs, conf.normalization())?;
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::config::{ConvNormalization, ConvParameters};
+    use crate::fftconv_fftw::FftConv;
+    use crate::filters::Filter;
     use crate::PrcFmt;
-    use config::ConvParameters;
-    use fftconv_fftw::FftConv;
-    use filters::Filter;
 
     fn is_close(left: PrcFmt, right: PrcFmt, maxdiff: PrcFmt) -> bool {
         println!("{} - {}", left, right);
@@ -315,6 +302,9 @@ mod tests {
         let conf = ConvParameters::Values {
             values: coeffs,
             length: 0,
+            normalize: ConvNormalization::None,
+            minimum_phase: false,
+            fft_library: None,
         };
         let mut filter = FftConv::from_config("test".to_string(), 8, conf);
         let mut wave1 = vec![1.0, 1.0, 1.0, 0.0, 0.0, -1.0, 0.0, 0.0];
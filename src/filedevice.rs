@@ -1,27 +1,32 @@
 use crate::audiodevice::*;
 use crate::config;
 use crate::config::SampleFormat;
-use crate::conversions::{buffer_to_chunk_rawbytes, chunk_to_buffer_rawbytes};
+use crate::conversions::{
+    apply_int_conversion, buffer_to_chunk_rawbytes, chunk_to_buffer_rawbytes,
+};
 use crate::countertimer;
 
 use std::error::Error;
 use std::fs::File;
-#[cfg(target_os = "linux")]
 use std::fs::OpenOptions;
-use std::io::{stdin, stdout, Write};
+use std::io::{sink, stdin, stdout, Write};
 #[cfg(target_os = "linux")]
 use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
 use std::sync::mpsc;
 use std::sync::{Arc, Barrier, RwLock};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use rubato::VecResampler;
 
-#[cfg(not(target_os = "linux"))]
 use crate::filereader::BlockingReader;
+use crate::filereader_loop::LoopingReader;
 #[cfg(target_os = "linux")]
 use crate::filereader_nonblock::NonBlockingReader;
+use crate::flacfile;
+use crate::flacfile::{FlacDecoder, FlacEncoder};
+use crate::wavfile;
 use crate::CommandMessage;
 use crate::PrcFmt;
 use crate::ProcessingState;
@@ -35,6 +40,10 @@ pub struct FilePlaybackDevice {
     pub samplerate: usize,
     pub channels: usize,
     pub sample_format: SampleFormat,
+    pub rollover_size: Option<usize>,
+    pub rollover_duration: Option<f32>,
+    pub bwf_metadata: Option<wavfile::BwfMetadata>,
+    pub int_conversion: config::IntConversion,
 }
 
 #[derive(Clone)]
@@ -63,8 +72,12 @@ pub struct FileCaptureDevice {
     pub extra_samples: usize,
     pub skip_bytes: usize,
     pub read_bytes: usize,
+    pub loop_start: usize,
+    pub loop_end: Option<usize>,
+    pub loop_crossfade: usize,
     pub stop_on_rate_change: bool,
     pub rate_measure_interval: f32,
+    pub monitor_file: Option<String>,
 }
 
 struct CaptureChannels {
@@ -101,6 +114,273 @@ pub trait Reader {
     fn read(&mut self, data: &mut [u8]) -> Result<ReadResult, Box<dyn Error>>;
 }
 
+/// Read and discard bytes from the input, until the given number has been read or the input ends.
+/// Returns the number of bytes that were skipped.
+pub fn skip_input(file: &mut dyn Reader, nbr_bytes: usize) -> Result<usize, Box<dyn Error>> {
+    let mut buf = vec![0u8; nbr_bytes.min(65536)];
+    let mut skipped = 0;
+    while skipped < nbr_bytes {
+        let len = (nbr_bytes - skipped).min(buf.len());
+        match file.read(&mut buf[0..len])? {
+            ReadResult::Complete(bytes) | ReadResult::Timeout(bytes) => skipped += bytes,
+            ReadResult::EndOfFile(bytes) => return Ok(skipped + bytes),
+        }
+    }
+    Ok(skipped)
+}
+
+/// Get the name of a rolled over output file, by inserting the index before the extension.
+fn rollover_filename(filename: &str, index: usize) -> String {
+    let path = Path::new(filename);
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let newname = match path.extension() {
+        Some(ext) => format!("{}_{}.{}", stem, index, ext.to_string_lossy()),
+        None => format!("{}_{}", stem, index),
+    };
+    path.with_file_name(newname).to_string_lossy().to_string()
+}
+
+/// Get the number of bytes to write to a file before rolling over to a new one.
+fn get_rollover_bytes(
+    rollover_size: Option<usize>,
+    rollover_duration: Option<f32>,
+    samplerate: usize,
+    bytes_per_frame: usize,
+) -> Option<usize> {
+    let duration_bytes = rollover_duration
+        .map(|duration| (duration * samplerate as f32).round() as usize * bytes_per_frame);
+    match (rollover_size, duration_bytes) {
+        (Some(size), Some(dur)) => Some(size.min(dur)),
+        (Some(size), None) => Some(size),
+        (None, dur) => dur,
+    }
+}
+
+/// Create an output file, encoded as FLAC if the filename has the .flac extension.
+/// With metadata, the file is written as a Broadcast Wave file.
+fn create_output_file(
+    filename: &str,
+    channels: usize,
+    samplerate: usize,
+    sample_format: &SampleFormat,
+    bwf_metadata: &Option<wavfile::BwfMetadata>,
+) -> Res<Box<dyn Write>> {
+    if let Some(metadata) = bwf_metadata {
+        let writer =
+            wavfile::BwfWriter::create(filename, channels, samplerate, sample_format, metadata)?;
+        Ok(Box::new(writer))
+    } else if flacfile::is_flac_file(filename) {
+        let encoder = FlacEncoder::create(filename, channels, samplerate, sample_format)?;
+        Ok(Box::new(encoder))
+    } else {
+        Ok(Box::new(File::create(filename)?))
+    }
+}
+
+/// Number of chunks that can be waiting to be written by a capture monitor
+/// before chunks start being dropped.
+const MONITOR_QUEUE_CHUNKS: usize = 64;
+
+/// Shortest time between two warnings about dropped capture monitor chunks.
+const MONITOR_WARNING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Open the file for a capture monitor.
+/// Raw samples are appended, so that a restart doesn't overwrite what was recorded before.
+/// A FLAC file can't be continued, and is replaced.
+fn open_monitor_file(
+    filename: &str,
+    channels: usize,
+    samplerate: usize,
+    sample_format: &SampleFormat,
+) -> Res<Box<dyn Write>> {
+    if flacfile::is_flac_file(filename) {
+        create_output_file(filename, channels, samplerate, sample_format, &None)
+    } else {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(filename)?;
+        Ok(Box::new(file))
+    }
+}
+
+/// Writes a copy of the captured audio to a file, from a separate thread
+/// so that slow disk writes don't hold up the capture.
+/// The capture threads queue the raw bytes read from the device,
+/// and the buffers are handed back by the writer thread to be used again.
+pub struct CaptureMonitor {
+    tx: Option<mpsc::SyncSender<Vec<u8>>>,
+    rx_returned: mpsc::Receiver<Vec<u8>>,
+    handle: Option<thread::JoinHandle<()>>,
+    channels: usize,
+    sample_format: SampleFormat,
+    dropped_chunks: usize,
+    warned_at: Option<Instant>,
+}
+
+impl CaptureMonitor {
+    /// Open the monitor file and start the thread writing to it.
+    pub fn start(
+        filename: String,
+        channels: usize,
+        samplerate: usize,
+        sample_format: SampleFormat,
+    ) -> Res<Self> {
+        let (tx, rx) = mpsc::sync_channel::<Vec<u8>>(MONITOR_QUEUE_CHUNKS);
+        let (tx_returned, rx_returned) = mpsc::channel::<Vec<u8>>();
+        let (tx_created, rx_created) = mpsc::channel();
+        let file_format = sample_format.clone();
+        let handle = thread::Builder::new()
+            .name("CaptureMonitor".to_string())
+            .spawn(move || {
+                // The file is opened in the writer thread, since an encoder can't be sent between threads.
+                let mut file =
+                    match open_monitor_file(&filename, channels, samplerate, &file_format) {
+                        Ok(file) => {
+                            tx_created.send(Ok(())).unwrap_or(());
+                            file
+                        }
+                        Err(err) => {
+                            tx_created.send(Err(err.to_string())).unwrap_or(());
+                            return;
+                        }
+                    };
+                debug!("Writing captured audio to monitor file {}", filename);
+                // The loop ends when the monitor is dropped, the file is then closed and finalized.
+                for buffer in rx.iter() {
+                    if let Err(err) = file.write_all(&buffer) {
+                        error!("Could not write to monitor file {}: {}", filename, err);
+                        break;
+                    }
+                    tx_returned.send(buffer).unwrap_or(());
+                }
+                debug!("Closing monitor file {}", filename);
+            })?;
+        if let Err(err) = rx_created.recv()? {
+            return Err(config::ConfigError::new(&err).into());
+        }
+        Ok(CaptureMonitor {
+            tx: Some(tx),
+            rx_returned,
+            handle: Some(handle),
+            channels,
+            sample_format,
+            dropped_chunks: 0,
+            warned_at: None,
+        })
+    }
+
+    /// Queue the bytes read from a capture device for writing.
+    /// If the writer can't keep up, they are dropped.
+    pub fn write_bytes(&mut self, data: &[u8]) {
+        let mut buffer = self.rx_returned.try_recv().unwrap_or_default();
+        buffer.clear();
+        buffer.extend_from_slice(data);
+        self.send(buffer);
+    }
+
+    /// Queue a chunk for writing, for backends that don't capture raw bytes.
+    /// If the writer can't keep up, the chunk is dropped.
+    pub fn write(&mut self, chunk: &AudioChunk) {
+        let mut buffer = self.rx_returned.try_recv().unwrap_or_default();
+        buffer.resize(
+            chunk.frames * self.channels * self.sample_format.bytes_per_sample(),
+            0,
+        );
+        let (valid_bytes, _) = chunk_to_buffer_rawbytes(chunk, &mut buffer, &self.sample_format);
+        buffer.truncate(valid_bytes);
+        self.send(buffer);
+    }
+
+    fn send(&mut self, buffer: Vec<u8>) {
+        let sent = self
+            .tx
+            .as_ref()
+            .map_or(false, |tx| tx.try_send(buffer).is_ok());
+        if !sent {
+            self.dropped_chunks += 1;
+            if self
+                .warned_at
+                .map_or(true, |t| t.elapsed() >= MONITOR_WARNING_INTERVAL)
+            {
+                warn!(
+                    "Capture monitor is not keeping up, {} chunk(s) dropped so far",
+                    self.dropped_chunks
+                );
+                self.warned_at = Some(Instant::now());
+            }
+        }
+    }
+}
+
+/// Start the capture monitor of a capture device, if a monitor file is given.
+/// The file gets the channels, sample rate and sample format of the device.
+/// Capturing continues without a monitor if the file can't be opened.
+pub fn start_capture_monitor(
+    filename: &Option<String>,
+    channels: usize,
+    samplerate: usize,
+    sample_format: &SampleFormat,
+) -> Option<CaptureMonitor> {
+    filename.as_ref().and_then(|filename| {
+        CaptureMonitor::start(
+            filename.clone(),
+            channels,
+            samplerate,
+            sample_format.clone(),
+        )
+        .map_err(|err| error!("Could not start capture monitor: {}", err))
+        .ok()
+    })
+}
+
+impl Drop for CaptureMonitor {
+    /// Wait for the queued chunks to be written and the file to be closed.
+    fn drop(&mut self) {
+        drop(self.tx.take());
+        if let Some(handle) = self.handle.take() {
+            handle.join().unwrap_or(());
+        }
+    }
+}
+
+/// Open the source for a capture device.
+/// Files with the .flac extension are decoded, and must match the channels, samplerate and format.
+fn open_capture_source(
+    source: &CaptureSource,
+    channels: usize,
+    samplerate: usize,
+    sample_format: &SampleFormat,
+    timeout_millis: u64,
+) -> Res<Box<dyn Reader>> {
+    match source {
+        CaptureSource::Filename(filename) if flacfile::is_flac_file(filename) => {
+            let decoder = FlacDecoder::open(filename, channels, samplerate, sample_format)?;
+            Ok(Box::new(BlockingReader::new(decoder)))
+        }
+        #[cfg(not(target_os = "linux"))]
+        CaptureSource::Filename(filename) => {
+            let _ = timeout_millis;
+            Ok(Box::new(BlockingReader::new(File::open(filename)?)))
+        }
+        #[cfg(not(target_os = "linux"))]
+        CaptureSource::Stdin => Ok(Box::new(BlockingReader::new(stdin()))),
+        #[cfg(target_os = "linux")]
+        CaptureSource::Filename(filename) => {
+            let file = OpenOptions::new()
+                .read(true)
+                .custom_flags(nix::libc::O_NONBLOCK)
+                .open(filename)?;
+            Ok(Box::new(NonBlockingReader::new(file, timeout_millis)))
+        }
+        #[cfg(target_os = "linux")]
+        CaptureSource::Stdin => Ok(Box::new(NonBlockingReader::new(stdin(), timeout_millis))),
+    }
+}
+
 /// Start a playback thread listening for AudioMessages via a channel.
 impl PlaybackDevice for FilePlaybackDevice {
     fn start(
@@ -115,12 +395,31 @@ impl PlaybackDevice for FilePlaybackDevice {
         let channels = self.channels;
         let store_bytes_per_sample = self.sample_format.bytes_per_sample();
         let sample_format = self.sample_format.clone();
+        let int_conversion = self.int_conversion;
+        let samplerate = self.samplerate;
+        let bwf_metadata = self.bwf_metadata.clone();
+        let rollover_bytes = match destination {
+            PlaybackDest::Filename(_) => get_rollover_bytes(
+                self.rollover_size,
+                self.rollover_duration,
+                self.samplerate,
+                channels * store_bytes_per_sample,
+            ),
+            PlaybackDest::Stdout => None,
+        };
         let handle = thread::Builder::new()
             .name("FilePlayback".to_string())
             .spawn(move || {
-                let file_res: Result<Box<dyn Write>, std::io::Error> = match destination {
+                let file_res: Res<Box<dyn Write>> = match &destination {
                     PlaybackDest::Filename(filename) => {
-                        File::create(filename).map(|f| Box::new(f) as Box<dyn Write>)
+                        playback_status.write().unwrap().output_file = Some(filename.clone());
+                        create_output_file(
+                            filename,
+                            channels,
+                            samplerate,
+                            &sample_format,
+                            &bwf_metadata,
+                        )
                     }
                     PlaybackDest::Stdout => Ok(Box::new(stdout())),
                 };
@@ -137,9 +436,16 @@ impl PlaybackDevice for FilePlaybackDevice {
                         barrier.wait();
                         debug!("starting playback loop");
                         let mut buffer = vec![0u8; chunksize * channels * store_bytes_per_sample];
+                        let mut bytes_in_file = 0;
+                        let mut file_index = 0;
                         loop {
                             match channel.recv() {
-                                Ok(AudioMessage::Audio(chunk)) => {
+                                Ok(AudioMessage::Audio(mut chunk)) => {
+                                    apply_int_conversion(
+                                        &mut chunk,
+                                        &sample_format,
+                                        int_conversion,
+                                    );
                                     let (valid_bytes, nbr_clipped) = chunk_to_buffer_rawbytes(
                                         &chunk,
                                         &mut buffer,
@@ -154,6 +460,37 @@ impl PlaybackDevice for FilePlaybackDevice {
                                                 .unwrap_or(());
                                         }
                                     };
+                                    bytes_in_file += valid_bytes;
+                                    if let (Some(limit), PlaybackDest::Filename(filename)) =
+                                        (rollover_bytes, &destination)
+                                    {
+                                        if bytes_in_file >= limit {
+                                            file_index += 1;
+                                            let newname = rollover_filename(filename, file_index);
+                                            info!("Rolling over to new output file {}", newname);
+                                            match create_output_file(
+                                                &newname,
+                                                channels,
+                                                samplerate,
+                                                &sample_format,
+                                                &bwf_metadata,
+                                            ) {
+                                                Ok(f) => {
+                                                    file = f;
+                                                    bytes_in_file = 0;
+                                                    playback_status.write().unwrap().output_file =
+                                                        Some(newname);
+                                                }
+                                                Err(err) => {
+                                                    status_channel
+                                                        .send(StatusMessage::PlaybackError(
+                                                            err.to_string(),
+                                                        ))
+                                                        .unwrap_or(());
+                                                }
+                                            }
+                                        }
+                                    }
                                     if nbr_clipped > 0 {
                                         playback_status.write().unwrap().clipped_samples +=
                                             nbr_clipped;
@@ -173,6 +510,9 @@ impl PlaybackDevice for FilePlaybackDevice {
                                     trace!("Pause message received");
                                 }
                                 Ok(AudioMessage::EndOfStream) => {
+                                    // Close the file before reporting that playback is done,
+                                    // to make sure that an encoded file gets finalized.
+                                    drop(std::mem::replace(&mut file, Box::new(sink())));
                                     status_channel
                                         .send(StatusMessage::PlaybackDone)
                                         .unwrap_or(());
@@ -259,6 +599,7 @@ fn capture_loop(
     params: CaptureParams,
     msg_channels: CaptureChannels,
     mut resampler: Option<Box<dyn VecResampler<PrcFmt>>>,
+    mut monitor: Option<CaptureMonitor>,
 ) {
     debug!("starting captureloop");
     let chunksize_bytes = params.channels * params.chunksize * params.store_bytes_per_sample;
@@ -333,6 +674,17 @@ fn capture_loop(
             get_capture_bytes(params.read_bytes, nbr_bytes_read, capture_bytes, &mut buf);
         //let read_res = read_retry(&mut file, &mut buf[0..capture_bytes_temp]);
         let read_res = file.read(&mut buf[0..capture_bytes_temp]);
+        if let (
+            Some(monitor),
+            Ok(
+                ReadResult::Complete(bytes)
+                | ReadResult::Timeout(bytes)
+                | ReadResult::EndOfFile(bytes),
+            ),
+        ) = (monitor.as_mut(), &read_res)
+        {
+            monitor.write_bytes(&buf[0..*bytes]);
+        }
         match read_res {
             Ok(ReadResult::EndOfFile(bytes)) => {
                 bytes_read = bytes;
@@ -364,6 +716,8 @@ fn capture_loop(
                         params.chunksize,
                         &msg_channels.audio,
                     );
+                    // Finish writing the monitor file before playback reports that it's done.
+                    drop(monitor.take());
                     let msg = AudioMessage::EndOfStream;
                     msg_channels.audio.send(msg).unwrap_or(());
                     msg_channels
@@ -430,7 +784,7 @@ fn capture_loop(
                         bytes_per_sec / (params.channels * params.store_bytes_per_sample) as f64;
                     trace!("Measured sample rate is {} Hz", measured_rate_f);
                     let mut capt_stat = params.capture_status.write().unwrap();
-                    capt_stat.measured_samplerate = measured_rate_f as usize;
+                    capt_stat.update_measured_samplerate(measured_rate_f);
                     capt_stat.signal_range = value_range as f32;
                     capt_stat.rate_adjust = rate_adjust as f32;
                     capt_stat.state = state;
@@ -543,10 +897,14 @@ impl CaptureDevice for FileCaptureDevice {
         let extra_bytes = self.extra_samples * store_bytes_per_sample * channels;
         let skip_bytes = self.skip_bytes;
         let read_bytes = self.read_bytes;
+        let loop_start = self.loop_start;
+        let loop_end = self.loop_end;
+        let loop_crossfade = self.loop_crossfade;
         let silence_timeout = self.silence_timeout;
         let silence_threshold = self.silence_threshold;
         let stop_on_rate_change = self.stop_on_rate_change;
         let rate_measure_interval = self.rate_measure_interval;
+        let monitor_file = self.monitor_file.clone();
         let handle = thread::Builder::new()
             .name("FileCapture".to_string())
             .spawn(move || {
@@ -579,31 +937,22 @@ impl CaptureDevice for FileCaptureDevice {
                     stop_on_rate_change,
                     rate_measure_interval,
                 };
-                #[cfg(not(target_os = "linux"))]
-                let file_res: Result<Box<dyn Reader>, std::io::Error> = match source {
-                    CaptureSource::Filename(filename) => File::open(filename)
-                        .map(|f| Box::new(BlockingReader::new(f)) as Box<dyn Reader>),
-                    CaptureSource::Stdin => Ok(Box::new(BlockingReader::new(stdin()))),
-                };
-                #[cfg(target_os = "linux")]
-                let file_res: Result<Box<dyn Reader>, std::io::Error> = match source {
-                    CaptureSource::Filename(filename) => OpenOptions::new()
-                        .read(true)
-                        .custom_flags(nix::libc::O_NONBLOCK)
-                        .open(filename)
-                        .map(|f| {
-                            Box::new(NonBlockingReader::new(
-                                f,
-                                2 * 1000 * chunksize as u64 / samplerate as u64,
-                            )) as Box<dyn Reader>
-                        }),
-                    CaptureSource::Stdin => Ok(Box::new(NonBlockingReader::new(
-                        stdin(),
-                        2 * 1000 * chunksize as u64 / samplerate as u64,
-                    ))),
-                };
+                let timeout_millis = 2 * 1000 * chunksize as u64 / samplerate as u64;
+                let file_res = open_capture_source(
+                    &source,
+                    channels,
+                    capture_samplerate,
+                    &params.sample_format,
+                    timeout_millis,
+                );
                 match file_res {
                     Ok(mut file) => {
+                        let monitor = start_capture_monitor(
+                            &monitor_file,
+                            channels,
+                            capture_samplerate,
+                            &params.sample_format,
+                        );
                         match status_channel.send(StatusMessage::CaptureReady) {
                             Ok(()) => {}
                             Err(_err) => {}
@@ -616,12 +965,50 @@ impl CaptureDevice for FileCaptureDevice {
                         };
                         if skip_bytes > 0 {
                             debug!("skipping the first {} bytes", skip_bytes);
-                            let mut tempbuf = vec![0u8; skip_bytes];
-                            let _ = file.read(&mut tempbuf);
+                            match skip_input(file.as_mut(), skip_bytes) {
+                                Ok(skipped) if skipped < skip_bytes => {
+                                    warn!(
+                                        "Reached the end of the input after skipping {} of {} bytes",
+                                        skipped, skip_bytes
+                                    );
+                                }
+                                Ok(_) => {}
+                                Err(err) => {
+                                    warn!("Could not skip the first {} bytes: {}", skip_bytes, err);
+                                }
+                            }
+                        }
+                        if let Some(loop_end) = loop_end {
+                            debug!(
+                                "looping from frame {} to {}, crossfade {} frames",
+                                loop_start, loop_end, loop_crossfade
+                            );
+                            let source = source.clone();
+                            let sample_format = params.sample_format.clone();
+                            let reopen = Box::new(move || -> Res<Box<dyn Reader>> {
+                                let mut file = open_capture_source(
+                                    &source,
+                                    channels,
+                                    capture_samplerate,
+                                    &sample_format,
+                                    timeout_millis,
+                                )?;
+                                skip_input(file.as_mut(), skip_bytes)?;
+                                Ok(file)
+                            });
+                            file = Box::new(LoopingReader::new(
+                                file,
+                                reopen,
+                                channels,
+                                &params.sample_format,
+                                loop_start,
+                                loop_end,
+                                loop_crossfade,
+                            ));
                         }
 
                         debug!("starting captureloop");
-                        capture_loop(file, params, msg_channels, resampler);
+                        capture_loop(file, params, msg_channels, resampler, monitor);
                     }
                     Err(err) => {
                         let send_result =
@@ -667,3 +1054,79 @@ fn sleep_until_next(bytes_per_frame: usize, samplerate: usize, nbr_bytes: usize)
         thread::sleep(io_duration - Duration::from_millis(2));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{start_capture_monitor, CaptureMonitor};
+    use crate::audiodevice::AudioChunk;
+    use crate::config::SampleFormat;
+
+    fn monitor_filename(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!(
+                "camilladsp_monitor_{}_{}",
+                name,
+                std::process::id()
+            ))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    #[test]
+    fn monitor_writes_raw_bytes() {
+        let filename = monitor_filename("raw.raw");
+        std::fs::remove_file(&filename).unwrap_or(());
+        let mut monitor =
+            CaptureMonitor::start(filename.clone(), 2, 44100, SampleFormat::S16LE).unwrap();
+        monitor.write_bytes(&[1, 2, 3, 4]);
+        monitor.write_bytes(&[5, 6, 7, 8]);
+        drop(monitor);
+        assert_eq!(
+            std::fs::read(&filename).unwrap(),
+            vec![1, 2, 3, 4, 5, 6, 7, 8]
+        );
+        std::fs::remove_file(&filename).unwrap();
+    }
+
+    #[test]
+    fn monitor_appends_after_restart() {
+        let filename = monitor_filename("append.raw");
+        std::fs::remove_file(&filename).unwrap_or(());
+        for data in [[1, 2, 3, 4], [5, 6, 7, 8]] {
+            let mut monitor =
+                start_capture_monitor(&Some(filename.clone()), 1, 44100, &SampleFormat::S16LE)
+                    .unwrap();
+            monitor.write_bytes(&data);
+        }
+        assert_eq!(
+            std::fs::read(&filename).unwrap(),
+            vec![1, 2, 3, 4, 5, 6, 7, 8]
+        );
+        std::fs::remove_file(&filename).unwrap();
+    }
+
+    #[test]
+    fn monitor_converts_chunks() {
+        let filename = monitor_filename("chunk.raw");
+        std::fs::remove_file(&filename).unwrap_or(());
+        let mut monitor =
+            CaptureMonitor::start(filename.clone(), 2, 44100, SampleFormat::S16LE).unwrap();
+        let waveforms = vec![vec![0.5, -0.5, 0.0], vec![0.25, 0.0, 0.0]];
+        // Only the valid frames are written
+        monitor.write(&AudioChunk::new(waveforms, 0.5, -0.5, 3, 2));
+        drop(monitor);
+        let expected: Vec<u8> = [16384i16, 8192, -16384, 0]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        assert_eq!(std::fs::read(&filename).unwrap(), expected);
+        std::fs::remove_file(&filename).unwrap();
+    }
+
+    #[test]
+    fn no_monitor_without_file() {
+        assert!(start_capture_monitor(&None, 2, 44100, &SampleFormat::S16LE).is_none());
+        let filename = monitor_filename("missing_dir/monitor.raw");
+        assert!(start_capture_monitor(&Some(filename), 2, 44100, &SampleFormat::S16LE).is_none());
+    }
+}
@@ -0,0 +1,280 @@
+use std::error::Error;
+use std::f64::consts::PI;
+use std::io;
+
+use crate::config::SampleFormat;
+use crate::conversions::{buffer_to_chunk_rawbytes, chunk_to_buffer_rawbytes};
+use crate::filedevice::{skip_input, ReadResult, Reader};
+use crate::PrcFmt;
+use crate::Res;
+
+/// A Reader that repeats a region of the wrapped source.
+/// The source is streamed as usual up to the end of the region.
+/// It is then opened again and read from the loop start, so that only the crossfade is kept in memory.
+/// With a crossfade, the last frames of the region are faded over to the frames
+/// just before the loop start, so that the wrap around to the loop start is seamless.
+pub struct LoopingReader {
+    inner: Box<dyn Reader>,
+    reopen: Box<dyn FnMut() -> Res<Box<dyn Reader>>>,
+    channels: usize,
+    sample_format: SampleFormat,
+    crossfade: usize,
+    position: usize,
+    lead_in_start: usize,
+    loop_start: usize,
+    tail_start: usize,
+    lead_in: Vec<u8>,
+    pending: Vec<u8>,
+    pending_pos: usize,
+}
+
+impl LoopingReader {
+    /// Create a reader looping between the frames `loop_start` and `loop_end`,
+    /// with a crossfade of `crossfade` frames. The crossfade may not be longer than `loop_start`.
+    /// The `reopen` function must return a new reader, positioned at the start of the source.
+    pub fn new(
+        inner: Box<dyn Reader>,
+        reopen: Box<dyn FnMut() -> Res<Box<dyn Reader>>>,
+        channels: usize,
+        sample_format: &SampleFormat,
+        loop_start: usize,
+        loop_end: usize,
+        crossfade: usize,
+    ) -> Self {
+        let bytes_per_frame = channels * sample_format.bytes_per_sample();
+        LoopingReader {
+            inner,
+            reopen,
+            channels,
+            sample_format: sample_format.clone(),
+            crossfade,
+            position: 0,
+            lead_in_start: (loop_start - crossfade) * bytes_per_frame,
+            loop_start: loop_start * bytes_per_frame,
+            tail_start: (loop_end - crossfade) * bytes_per_frame,
+            lead_in: Vec::new(),
+            pending: Vec::new(),
+            pending_pos: 0,
+        }
+    }
+
+    fn bytes_per_frame(&self) -> usize {
+        self.channels * self.sample_format.bytes_per_sample()
+    }
+
+    /// Read the given number of bytes from the source, waiting for more data on timeouts.
+    fn read_exact(&mut self, nbr_bytes: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut buffer = vec![0u8; nbr_bytes];
+        let mut bytes_read = 0;
+        while bytes_read < nbr_bytes {
+            match self.inner.read(&mut buffer[bytes_read..])? {
+                ReadResult::Complete(bytes) | ReadResult::Timeout(bytes) => bytes_read += bytes,
+                ReadResult::EndOfFile(bytes) => {
+                    return Err(self.end_of_file_error(self.position + bytes_read + bytes));
+                }
+            }
+        }
+        self.position += nbr_bytes;
+        Ok(buffer)
+    }
+
+    fn end_of_file_error(&self, position: usize) -> Box<dyn Error> {
+        let msg = if position < self.loop_start {
+            "Capture file ended before loop_start".to_string()
+        } else {
+            format!(
+                "Capture file ended {} frames before loop_end",
+                (self.tail_start + self.crossfade * self.bytes_per_frame() - position)
+                    / self.bytes_per_frame()
+            )
+        };
+        Box::new(io::Error::new(io::ErrorKind::UnexpectedEof, msg))
+    }
+
+    /// Open the source again, and skip to the loop start.
+    fn restart(&mut self) -> Result<(), Box<dyn Error>> {
+        trace!("Reached loop end, continuing from loop start");
+        self.inner = (self.reopen)()?;
+        self.position = skip_input(self.inner.as_mut(), self.loop_start)?;
+        if self.position < self.loop_start {
+            return Err(self.end_of_file_error(self.position));
+        }
+        Ok(())
+    }
+
+    /// Fade the end of the loop over to the frames preceding the loop start.
+    fn apply_crossfade(&self, tail: &mut [u8]) {
+        let fade_bytes = tail.len();
+        let used_channels = vec![true; self.channels];
+        let lead_in = buffer_to_chunk_rawbytes(
+            &self.lead_in,
+            self.channels,
+            &self.sample_format,
+            fade_bytes,
+            &used_channels,
+        );
+        let mut tail_chunk = buffer_to_chunk_rawbytes(
+            tail,
+            self.channels,
+            &self.sample_format,
+            fade_bytes,
+            &used_channels,
+        );
+        for (tail_wf, lead_wf) in tail_chunk
+            .waveforms
+            .iter_mut()
+            .zip(lead_in.waveforms.iter())
+        {
+            for (n, (value, lead)) in tail_wf.iter_mut().zip(lead_wf.iter()).enumerate() {
+                let gain =
+                    (0.5 - 0.5 * (PI * (n as f64 + 0.5) / self.crossfade as f64).cos()) as PrcFmt;
+                *value = (1.0 - gain) * *value + gain * *lead;
+            }
+        }
+        chunk_to_buffer_rawbytes(&tail_chunk, tail, &self.sample_format);
+    }
+}
+
+impl Reader for LoopingReader {
+    fn read(&mut self, data: &mut [u8]) -> Result<ReadResult, Box<dyn Error>> {
+        if data.is_empty() {
+            // An empty read means that the read_bytes limit was reached.
+            return Ok(ReadResult::EndOfFile(0));
+        }
+        let fade_bytes = self.crossfade * self.bytes_per_frame();
+        let mut filled = 0;
+        while filled < data.len() {
+            if self.pending_pos < self.pending.len() {
+                let bytes = (data.len() - filled).min(self.pending.len() - self.pending_pos);
+                data[filled..filled + bytes]
+                    .copy_from_slice(&self.pending[self.pending_pos..self.pending_pos + bytes]);
+                filled += bytes;
+                self.pending_pos += bytes;
+                continue;
+            }
+            let need_lead_in = fade_bytes > 0 && self.lead_in.is_empty();
+            if need_lead_in && self.position == self.lead_in_start {
+                // Keep the frames before the loop start for the crossfade, and pass them on as usual
+                self.lead_in = self.read_exact(fade_bytes)?;
+                self.pending = self.lead_in.clone();
+                self.pending_pos = 0;
+                continue;
+            }
+            if self.position == self.tail_start {
+                let mut tail = self.read_exact(fade_bytes)?;
+                if fade_bytes > 0 {
+                    self.apply_crossfade(&mut tail);
+                }
+                self.pending = tail;
+                self.pending_pos = 0;
+                self.restart()?;
+                continue;
+            }
+            let boundary = if need_lead_in {
+                self.lead_in_start
+            } else {
+                self.tail_start
+            };
+            let bytes_to_read = (data.len() - filled).min(boundary - self.position);
+            match self.inner.read(&mut data[filled..filled + bytes_to_read])? {
+                ReadResult::Complete(bytes) => {
+                    self.position += bytes;
+                    filled += bytes;
+                }
+                ReadResult::Timeout(bytes) => {
+                    self.position += bytes;
+                    return Ok(ReadResult::Timeout(filled + bytes));
+                }
+                ReadResult::EndOfFile(bytes) => {
+                    return Err(self.end_of_file_error(self.position + bytes));
+                }
+            }
+        }
+        Ok(ReadResult::Complete(filled))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::SampleFormat;
+    use crate::filedevice::{ReadResult, Reader};
+    use crate::filereader::BlockingReader;
+    use crate::filereader_loop::LoopingReader;
+    use crate::Res;
+    use std::io::Cursor;
+
+    // Each frame holds its own index, scaled to stay within +-1.0.
+    const SCALE: f32 = 0.05;
+
+    fn make_reader(
+        frames: usize,
+        loop_start: usize,
+        loop_end: usize,
+        crossfade: usize,
+    ) -> LoopingReader {
+        let data: Vec<u8> = (0..frames)
+            .flat_map(|n| (SCALE * n as f32).to_le_bytes().to_vec())
+            .collect();
+        let inner = Box::new(BlockingReader::new(Cursor::new(data.clone())));
+        let reopen = Box::new(move || -> Res<Box<dyn Reader>> {
+            Ok(Box::new(BlockingReader::new(Cursor::new(data.clone()))))
+        });
+        LoopingReader::new(
+            inner,
+            reopen,
+            1,
+            &SampleFormat::FLOAT32LE,
+            loop_start,
+            loop_end,
+            crossfade,
+        )
+    }
+
+    fn assert_frames(reader: &mut LoopingReader, expected: &[f32]) {
+        let mut buf = vec![0u8; 4 * expected.len()];
+        match reader.read(&mut buf).unwrap() {
+            ReadResult::Complete(bytes) => assert_eq!(bytes, buf.len()),
+            other => panic!("unexpected read result {:?}", other),
+        }
+        let values = buf
+            .chunks(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]) / SCALE);
+        for (value, exp) in values.zip(expected.iter()) {
+            assert!((value - exp).abs() < 1.0e-4, "{} != {}", value, exp);
+        }
+    }
+
+    #[test]
+    fn loop_region() {
+        let mut reader = make_reader(20, 5, 10, 0);
+        assert_frames(&mut reader, &[0.0, 1.0, 2.0]);
+        assert_frames(
+            &mut reader,
+            &[
+                3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 5.0, 6.0, 7.0, 8.0, 9.0, 5.0, 6.0,
+            ],
+        );
+    }
+
+    #[test]
+    fn loop_crossfade() {
+        let mut reader = make_reader(20, 5, 10, 2);
+        // the last two frames of the loop are faded over to frames 3 and 4
+        let faded_1 = 0.8535534 * 8.0 + 0.1464466 * 3.0;
+        let faded_2 = 0.1464466 * 9.0 + 0.8535534 * 4.0;
+        assert_frames(
+            &mut reader,
+            &[
+                0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, faded_1, faded_2, 5.0, 6.0, 7.0, faded_1,
+                faded_2, 5.0, 6.0,
+            ],
+        );
+    }
+
+    #[test]
+    fn loop_end_after_eof() {
+        let mut reader = make_reader(8, 5, 10, 0);
+        let mut buf = vec![0u8; 4 * 12];
+        assert!(reader.read(&mut buf).is_err());
+    }
+}
@@ -4,21 +4,27 @@ use crate::biquad;
 use crate::biquadcombo;
 use crate::config;
 use crate::conversions;
+use crate::countertimer;
+use crate::crossfeed;
 use crate::diffeq;
 use crate::dither;
-#[cfg(not(feature = "FFTW"))]
+use crate::dynamiceq;
 use crate::fftconv;
 #[cfg(feature = "FFTW")]
-use crate::fftconv_fftw as fftconv;
+use crate::fftconv_fftw;
 use crate::loudness;
 use crate::mixer;
+use num_complex::Complex;
 use rawsample::SampleReader;
+use realfft::RealFftPlanner;
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::fs::File;
 use std::io::BufReader;
+use std::io::Cursor;
 use std::io::{BufRead, Read, Seek, SeekFrom};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
 
 use crate::PrcFmt;
 use crate::ProcessingParameters;
@@ -80,7 +86,24 @@ pub trait Filter {
 
     fn update_parameters(&mut self, config: config::Filter);
 
+    /// Clear the internal state, such as delay lines and envelopes, without changing the parameters.
+    fn reset(&mut self);
+
     fn name(&self) -> String;
+
+    /// Store values that are reported via the websocket server in the processing status.
+    /// This is called at the status update interval, not for every chunk.
+    fn publish_status(&self, _status: &mut ProcessingParameters) {}
+
+    /// Evaluate the complex frequency response of the current coefficients at the given frequencies.
+    /// Filters without a fixed response, such as those that are not linear, return None.
+    fn frequency_response(
+        &self,
+        _frequencies: &[PrcFmt],
+        _samplerate: usize,
+    ) -> Option<Vec<Complex<PrcFmt>>> {
+        None
+    }
 }
 
 pub fn pad_vector(values: &[PrcFmt], length: usize) -> Vec<PrcFmt> {
@@ -257,7 +280,10 @@ pub fn find_data_in_wav(filename: &str) -> Res<WavParams> {
                         (SUBTYPE_PCM, 16, 2, 16) => config::FileFormat::S16LE,
                         (SUBTYPE_PCM, 24, 3, 24) => config::FileFormat::S24LE3,
                         (SUBTYPE_PCM, 24, 4, 24) => config::FileFormat::S24LE,
-                        (SUBTYPE_PCM, 32, 4, 32) => config::FileFormat::S32LE,
+                        // 24 valid bits in a 32-bit container are stored in the upper bytes
+                        (SUBTYPE_PCM, 32, 4, 24) | (SUBTYPE_PCM, 32, 4, 32) => {
+                            config::FileFormat::S32LE
+                        }
                         (SUBTYPE_FLOAT, 32, 4, 32) => config::FileFormat::FLOAT32LE,
                         (SUBTYPE_FLOAT, 64, 8, 64) => config::FileFormat::FLOAT64LE,
                         (_, _, _, _) => {
@@ -302,6 +328,39 @@ pub fn find_data_in_wav(filename: &str) -> Res<WavParams> {
     Err(config::ConfigError::new(&msg).into())
 }
 
+/// Decode filter coefficients given as a base64 string of raw samples in the given format.
+pub fn decode_base64_coeffs(values_base64: &str, format: &config::FileFormat) -> Res<Vec<PrcFmt>> {
+    if *format == config::FileFormat::TEXT {
+        return Err(
+            config::ConfigError::new("Format TEXT can't be used for base64 coefficients").into(),
+        );
+    }
+    let bytes = match base64::decode(values_base64.trim()) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            let msg = format!("Could not decode base64 coefficients. Error: {}", err);
+            return Err(config::ConfigError::new(&msg).into());
+        }
+    };
+    if bytes.len() % format.bytes_per_sample() != 0 {
+        let msg = format!(
+            "Length of base64 coefficients, {} bytes, is not a multiple of the sample size of {:?}",
+            bytes.len(),
+            format
+        );
+        return Err(config::ConfigError::new(&msg).into());
+    }
+    let mut coefficients = vec![0.0; bytes.len() / format.bytes_per_sample()];
+    let rawformat = conversions::map_file_formats(format);
+    PrcFmt::read_samples(&mut Cursor::new(bytes), &mut coefficients, &rawformat)?;
+    debug!(
+        "Decoded base64 data, format: {:?}, number of coeffs: {}",
+        format,
+        coefficients.len()
+    );
+    Ok(coefficients)
+}
+
 pub fn read_wav(filename: &str, channel: usize) -> Res<Vec<PrcFmt>> {
     let params = find_data_in_wav(filename)?;
     if channel >= params.channels {
@@ -337,74 +396,577 @@ pub fn read_wav(filename: &str, channel: usize) -> Res<Vec<PrcFmt>> {
     Ok(data)
 }
 
+/// Length in seconds of the impulse response used to measure the broadband gain of a filter group.
+const GAIN_MEASURE_TIME: usize = 1;
+
+/// Length in seconds of the window used to summarize the processing times of the filters.
+const FILTER_TIME_WINDOW: usize = 5;
+
+/// Load the coefficients of a FIR filter, converted to minimum phase if requested by the config.
+pub fn get_conv_values(conf: &config::ConvParameters) -> Res<Vec<PrcFmt>> {
+    let values = match conf {
+        config::ConvParameters::Values { values, length, .. } => Ok(pad_vector(values, *length)),
+        config::ConvParameters::Raw {
+            filename,
+            format,
+            read_bytes_lines,
+            skip_bytes_lines,
+            ..
+        } => read_coeff_file(filename, format, *read_bytes_lines, *skip_bytes_lines),
+        config::ConvParameters::Wav {
+            filename, channel, ..
+        } => read_wav(filename, *channel),
+        config::ConvParameters::Base64 {
+            values_base64,
+            format,
+            ..
+        } => decode_base64_coeffs(values_base64, format),
+        // Replaced by a Wav filter when the config is validated, if there is a file for the samplerate
+        config::ConvParameters::MultiRate { .. } => Err(config::ConfigError::new(
+            "MultiRate filter has no file for the current samplerate",
+        )
+        .into()),
+    }?;
+    if conf.minimum_phase() && !values.is_empty() {
+        return cached_minimum_phase(&values);
+    }
+    Ok(values)
+}
+
+/// Largest total number of coefficients kept in the cache of minimum phase conversions,
+/// counting both the original and the converted coefficients.
+const MINIMUM_PHASE_CACHE_MAX_VALUES: usize = 8_000_000;
+
+lazy_static! {
+    /// Recent minimum phase conversions, as pairs of original and converted coefficients, oldest first.
+    static ref MINIMUM_PHASE_CACHE: Mutex<Vec<(Vec<PrcFmt>, Vec<PrcFmt>)>> = Mutex::new(Vec::new());
+}
+
+/// Convert FIR coefficients to minimum phase, reusing the result of an earlier conversion of the same coefficients.
+/// The coefficients of a filter are loaded several times when a config is applied,
+/// for the latency, the gain measurement, the filter itself and the delay of the dry path,
+/// and the conversion is the expensive part.
+fn cached_minimum_phase(values: &[PrcFmt]) -> Res<Vec<PrcFmt>> {
+    {
+        let mut cache = MINIMUM_PHASE_CACHE.lock().unwrap();
+        if let Some(idx) = cache.iter().position(|(original, _)| original == values) {
+            let entry = cache.remove(idx);
+            let converted = entry.1.clone();
+            cache.push(entry);
+            return Ok(converted);
+        }
+    }
+    let converted = to_minimum_phase(values)?;
+    let mut cache = MINIMUM_PHASE_CACHE.lock().unwrap();
+    cache.push((values.to_vec(), converted.clone()));
+    let mut total: usize = cache.iter().map(|(original, _)| 2 * original.len()).sum();
+    while total > MINIMUM_PHASE_CACHE_MAX_VALUES && cache.len() > 1 {
+        total -= 2 * cache.remove(0).0.len();
+    }
+    Ok(converted)
+}
+
+/// The FFT used for the minimum phase conversion is this many times longer than the filter,
+/// to keep the time aliasing of the cepstrum small.
+const MINIMUM_PHASE_PADDING: usize = 8;
+
+/// Lowest magnitude used when taking the logarithm of the spectrum, relative to the peak.
+/// This limits the depth of any zeros in the response to -200 dB.
+const MINIMUM_PHASE_FLOOR: PrcFmt = 1.0e-10;
+
+/// Convert the taps of a FIR filter to the minimum phase filter with the same magnitude response.
+/// This uses the cepstral method: the real cepstrum of the log magnitude spectrum is folded onto
+/// positive times, which gives the log spectrum of the minimum phase filter.
+/// The result has the same length as the input.
+pub fn to_minimum_phase(values: &[PrcFmt]) -> Res<Vec<PrcFmt>> {
+    let fft_len = (MINIMUM_PHASE_PADDING * values.len()).next_power_of_two();
+    let mut planner = RealFftPlanner::<PrcFmt>::new();
+    let fft = planner.plan_fft_forward(fft_len);
+    let ifft = planner.plan_fft_inverse(fft_len);
+
+    let mut time = pad_vector(values, fft_len);
+    let mut spectrum = fft.make_output_vec();
+    fft.process(&mut time, &mut spectrum)?;
+
+    // real cepstrum, from the log magnitude
+    let peak = spectrum
+        .iter()
+        .fold(0.0, |max: PrcFmt, val| val.norm().max(max));
+    let floor = peak * MINIMUM_PHASE_FLOOR;
+    for val in spectrum.iter_mut() {
+        *val = Complex::new(val.norm().max(floor).ln(), 0.0);
+    }
+    ifft.process(&mut spectrum, &mut time)?;
+
+    // fold the negative times onto the positive ones
+    let scale = 1.0 / fft_len as PrcFmt;
+    let half = fft_len / 2;
+    for (n, val) in time.iter_mut().enumerate() {
+        *val *= match n {
+            0 => scale,
+            n if n < half => 2.0 * scale,
+            n if n == half => scale,
+            _ => 0.0,
+        };
+    }
+
+    fft.process(&mut time, &mut spectrum)?;
+    for val in spectrum.iter_mut() {
+        *val = val.exp();
+    }
+    // The first and last bins of the spectrum of a real signal must be real
+    let last = spectrum.len() - 1;
+    spectrum[0].im = 0.0;
+    spectrum[last].im = 0.0;
+    ifft.process(&mut spectrum, &mut time)?;
+    Ok(time
+        .iter()
+        .take(values.len())
+        .map(|val| val * scale)
+        .collect())
+}
+
+/// Scale FIR coefficients to give unity peak, energy or DC gain.
+/// Returns the applied scale factor.
+pub fn normalize_conv_values(
+    values: &mut [PrcFmt],
+    normalization: config::ConvNormalization,
+) -> Res<PrcFmt> {
+    let reference = match normalization {
+        config::ConvNormalization::None => return Ok(1.0),
+        config::ConvNormalization::Peak => values.iter().fold(0.0, |max, val| val.abs().max(max)),
+        config::ConvNormalization::Energy => {
+            values.iter().map(|val| val * val).sum::<PrcFmt>().sqrt()
+        }
+        config::ConvNormalization::Dc => values.iter().sum::<PrcFmt>().abs(),
+    };
+    if !reference.is_normal() {
+        let msg = format!(
+            "Unable to normalize Conv coefficients, the {:?} value is {}",
+            normalization, reference
+        );
+        return Err(config::ConfigError::new(&msg).into());
+    }
+    let scale = 1.0 / reference;
+    for val in values.iter_mut() {
+        *val *= scale;
+    }
+    Ok(scale)
+}
+
+/// Load the coefficients of a FIR filter, normalized as given by the config.
+pub fn get_normalized_conv_values(name: &str, conf: &config::ConvParameters) -> Res<Vec<PrcFmt>> {
+    let mut values = get_conv_values(conf)?;
+    let normalization = conf.normalization();
+    if normalization != config::ConvNormalization::None {
+        let scale = normalize_conv_values(&mut values, normalization)?;
+        info!(
+            "Conv {}: {:?} normalization, scaling coefficients by {} ({:.2} dB)",
+            name,
+            normalization,
+            scale,
+            20.0 * scale.log10()
+        );
+    }
+    Ok(values)
+}
+
+/// Get the latency of a FIR filter in samples, as the position of the peak of the impulse response.
+pub fn get_conv_latency(conf: &config::ConvParameters) -> Res<usize> {
+    let values = get_conv_values(conf)?;
+    let latency = values
+        .iter()
+        .enumerate()
+        .fold((0, 0.0), |(max_idx, max_val), (idx, val)| {
+            if val.abs() > max_val {
+                (idx, val.abs())
+            } else {
+                (max_idx, max_val)
+            }
+        })
+        .0;
+    Ok(latency)
+}
+
+/// Shortest FFT used for evaluating the response of a FIR filter.
+/// Short filters are zero padded to give a fine enough frequency resolution.
+const FIR_RESPONSE_MIN_LENGTH: usize = 65536;
+
+/// Evaluate the response of a FIR filter at the given frequencies,
+/// by interpolating between the bins of an FFT of the zero padded taps.
+fn fir_response(
+    values: &[PrcFmt],
+    samplerate: usize,
+    frequencies: &[PrcFmt],
+) -> Res<Vec<Complex<PrcFmt>>> {
+    let fft_len = (2 * values.len())
+        .max(FIR_RESPONSE_MIN_LENGTH)
+        .next_power_of_two();
+    let mut planner = RealFftPlanner::<PrcFmt>::new();
+    let fft = planner.plan_fft_forward(fft_len);
+    let mut input = pad_vector(values, fft_len);
+    let mut spectrum = fft.make_output_vec();
+    fft.process(&mut input, &mut spectrum)?;
+    let last_bin = spectrum.len() - 1;
+    let response = frequencies
+        .iter()
+        .map(|freq| {
+            let position = freq / samplerate as PrcFmt * fft_len as PrcFmt;
+            let bin = (position.floor() as usize).min(last_bin);
+            let next = (bin + 1).min(last_bin);
+            let fraction = position - bin as PrcFmt;
+            spectrum[bin] * (1.0 - fraction) + spectrum[next] * fraction
+        })
+        .collect();
+    Ok(response)
+}
+
+/// Evaluate the response of a convolution filter from the transformed segments of its coefficients.
+/// Each segment is the FFT of `npoints` coefficients, zero padded to twice the length
+/// and scaled by the inverse of that length, so the inverse FFT gives back the coefficients.
+pub fn conv_segments_response<'a>(
+    segments: impl Iterator<Item = &'a [Complex<PrcFmt>]>,
+    npoints: usize,
+    samplerate: usize,
+    frequencies: &[PrcFmt],
+) -> Res<Vec<Complex<PrcFmt>>> {
+    let mut planner = RealFftPlanner::<PrcFmt>::new();
+    let ifft = planner.plan_fft_inverse(2 * npoints);
+    let mut spectrum = ifft.make_input_vec();
+    let mut segment_values = ifft.make_output_vec();
+    let mut values = Vec::new();
+    for segment in segments {
+        spectrum.copy_from_slice(segment);
+        spectrum[0].im = 0.0;
+        spectrum[npoints].im = 0.0;
+        ifft.process(&mut spectrum, &mut segment_values)?;
+        values.extend_from_slice(&segment_values[0..npoints]);
+    }
+    fir_response(&values, samplerate, frequencies)
+}
+
 pub struct FilterGroup {
     channel: usize,
     filters: Vec<Box<dyn Filter>>,
+    gain_compensate: bool,
+    compensation: PrcFmt,
+    mix: PrcFmt,
+    dry: Vec<PrcFmt>,
+    dry_latency: usize,
+    dry_delay: Option<basicfilters::Delay>,
+    waveform_length: usize,
+    sample_freq: usize,
+}
+
+/// Create the delay that aligns the dry signal of a group with the latency of its FIR filters.
+fn get_dry_delay(
+    channel: usize,
+    latency: usize,
+    sample_freq: usize,
+) -> Option<basicfilters::Delay> {
+    if latency > 0 {
+        debug!(
+            "Delaying the dry signal of channel {} by {} samples",
+            channel, latency
+        );
+        let name = format!("dry_latency_compensation_{}", channel);
+        Some(basicfilters::Delay::new(
+            name,
+            sample_freq,
+            latency as PrcFmt,
+            false,
+        ))
+    } else {
+        None
+    }
+}
+
+/// Get the FFT library that a convolution filter uses.
+/// Without a selection, FFTW is used if it is compiled in.
+/// A request for FFTW in a build without it falls back to RealFFT.
+fn get_fft_library(parameters: &config::ConvParameters) -> config::FftLibrary {
+    match parameters.fft_library() {
+        Some(config::FftLibrary::RealFft) => config::FftLibrary::RealFft,
+        _ if cfg!(feature = "FFTW") => config::FftLibrary::Fftw,
+        _ => config::FftLibrary::RealFft,
+    }
+}
+
+/// Create a convolution filter using the FFT library selected in the config.
+fn new_conv_filter(
+    name: String,
+    waveform_length: usize,
+    parameters: config::ConvParameters,
+) -> Box<dyn Filter> {
+    #[cfg(feature = "FFTW")]
+    {
+        if get_fft_library(&parameters) == config::FftLibrary::Fftw {
+            return Box::new(fftconv_fftw::FftConv::from_config(
+                name,
+                waveform_length,
+                parameters,
+            ));
+        }
+    }
+    Box::new(fftconv::FftConv::from_config(
+        name,
+        waveform_length,
+        parameters,
+    ))
+}
+
+/// Measure the broadband gain of a cascade of filters, as the rms gain for white noise.
+/// Only filters with fixed parameters are included, level and volume dependent filters are skipped.
+fn measure_broadband_gain(
+    names: &[String],
+    filter_configs: &HashMap<String, config::Filter>,
+    waveform_length: usize,
+    sample_freq: usize,
+) -> PrcFmt {
+    let mut filters = Vec::<Box<dyn Filter>>::new();
+    for name in names {
+        let filter: Box<dyn Filter> = match filter_configs[name].clone() {
+            config::Filter::Conv { parameters } => {
+                new_conv_filter(name.clone(), waveform_length, parameters)
+            }
+            config::Filter::Biquad { parameters } => Box::new(biquad::Biquad::new(
+                name.clone(),
+                sample_freq,
+                biquad::BiquadCoefficients::from_config(sample_freq, parameters),
+            )),
+            config::Filter::BiquadCombo { parameters } => Box::new(
+                biquadcombo::BiquadCombo::from_config(name.clone(), sample_freq, parameters),
+            ),
+            config::Filter::Highpass { parameters } => {
+                Box::new(biquadcombo::BiquadCombo::from_pass_config(
+                    name.clone(),
+                    sample_freq,
+                    true,
+                    parameters,
+                ))
+            }
+            config::Filter::Lowpass { parameters } => {
+                Box::new(biquadcombo::BiquadCombo::from_pass_config(
+                    name.clone(),
+                    sample_freq,
+                    false,
+                    parameters,
+                ))
+            }
+            config::Filter::Deemphasis { parameters } => Box::new(biquad::Biquad::new(
+                name.clone(),
+                sample_freq,
+                biquad::BiquadCoefficients::from_emphasis(sample_freq, parameters, true),
+            )),
+            config::Filter::Preemphasis { parameters } => Box::new(biquad::Biquad::new(
+                name.clone(),
+                sample_freq,
+                biquad::BiquadCoefficients::from_emphasis(sample_freq, parameters, false),
+            )),
+            config::Filter::Gain { parameters } => {
+                Box::new(basicfilters::Gain::from_config(name.clone(), parameters))
+            }
+            config::Filter::DiffEq { parameters } => {
+                Box::new(diffeq::DiffEq::from_config(name.clone(), parameters))
+            }
+            config::Filter::Tilt { parameters } => Box::new(
+                biquadcombo::BiquadCombo::from_tilt_config(name.clone(), sample_freq, parameters),
+            ),
+            _ => continue,
+        };
+        filters.push(filter);
+    }
+    let nbr_chunks = (GAIN_MEASURE_TIME * sample_freq + waveform_length - 1) / waveform_length;
+    let mut energy: PrcFmt = 0.0;
+    for n in 0..nbr_chunks {
+        let mut waveform = vec![0.0; waveform_length];
+        if n == 0 {
+            waveform[0] = 1.0;
+        }
+        for filter in filters.iter_mut() {
+            if filter.process_waveform(&mut waveform).is_err() {
+                return 1.0;
+            }
+        }
+        energy += waveform.iter().map(|value| value * value).sum::<PrcFmt>();
+    }
+    energy.sqrt()
+}
+
+/// Get the gain needed to compensate for a broadband gain.
+fn get_compensation(gain: PrcFmt) -> PrcFmt {
+    if gain.is_finite() && gain > 1.0e-6 {
+        1.0 / gain
+    } else {
+        warn!(
+            "Unable to compensate for a broadband gain of {}, ignoring",
+            gain
+        );
+        1.0
+    }
 }
 
 impl FilterGroup {
     /// Creates a group of filters to process a chunk.
+    #[allow(clippy::too_many_arguments)]
     pub fn from_config(
         channel: usize,
         names: Vec<String>,
+        gain_compensate: bool,
+        mix: PrcFmt,
         filter_configs: HashMap<String, config::Filter>,
         waveform_length: usize,
         sample_freq: usize,
         processing_status: Arc<RwLock<ProcessingParameters>>,
     ) -> Self {
         debug!("Build from config");
+        if mix < 1.0 {
+            debug!(
+                "Mixing {} of the filtered signal of channel {}",
+                mix, channel
+            );
+        }
+        let dry_latency = if mix < 1.0 {
+            Pipeline::get_latency(&names, &filter_configs)
+        } else {
+            0
+        };
+        let compensation = if gain_compensate {
+            let gain =
+                measure_broadband_gain(&names, &filter_configs, waveform_length, sample_freq);
+            debug!(
+                "Compensating broadband gain {} of channel {}",
+                gain, channel
+            );
+            get_compensation(gain)
+        } else {
+            1.0
+        };
         let mut filters = Vec::<Box<dyn Filter>>::new();
         for name in names {
             let filter_cfg = filter_configs[&name].clone();
-            let filter: Box<dyn Filter> =
-                match filter_cfg {
-                    config::Filter::Conv { parameters } => Box::new(fftconv::FftConv::from_config(
+            let filter: Box<dyn Filter> = match filter_cfg {
+                config::Filter::Conv { parameters } => {
+                    if parameters.fft_library() == Some(config::FftLibrary::Fftw)
+                        && get_fft_library(&parameters) != config::FftLibrary::Fftw
+                    {
+                        warn!(
+                            "Filter '{}': FFTW is not available in this build, using RealFFT instead",
+                            name
+                        );
+                    }
+                    new_conv_filter(name, waveform_length, parameters)
+                }
+                config::Filter::Biquad { parameters } => Box::new(biquad::Biquad::new(
+                    name,
+                    sample_freq,
+                    biquad::BiquadCoefficients::from_config(sample_freq, parameters),
+                )),
+                config::Filter::BiquadCombo { parameters } => Box::new(
+                    biquadcombo::BiquadCombo::from_config(name, sample_freq, parameters),
+                ),
+                config::Filter::Highpass { parameters } => Box::new(
+                    biquadcombo::BiquadCombo::from_pass_config(name, sample_freq, true, parameters),
+                ),
+                config::Filter::Lowpass { parameters } => {
+                    Box::new(biquadcombo::BiquadCombo::from_pass_config(
                         name,
-                        waveform_length,
+                        sample_freq,
+                        false,
                         parameters,
-                    )),
-                    config::Filter::Biquad { parameters } => Box::new(biquad::Biquad::new(
+                    ))
+                }
+                config::Filter::Deemphasis { parameters } => Box::new(biquad::Biquad::new(
+                    name,
+                    sample_freq,
+                    biquad::BiquadCoefficients::from_emphasis(sample_freq, parameters, true),
+                )),
+                config::Filter::Preemphasis { parameters } => Box::new(biquad::Biquad::new(
+                    name,
+                    sample_freq,
+                    biquad::BiquadCoefficients::from_emphasis(sample_freq, parameters, false),
+                )),
+                config::Filter::Delay { parameters } => Box::new(basicfilters::Delay::from_config(
+                    name,
+                    sample_freq,
+                    parameters,
+                )),
+                config::Filter::Gain { parameters } => {
+                    Box::new(basicfilters::Gain::from_config(name, parameters))
+                }
+                config::Filter::Volume { parameters } => {
+                    Box::new(basicfilters::Volume::from_config(
                         name,
+                        parameters,
+                        waveform_length,
                         sample_freq,
-                        biquad::BiquadCoefficients::from_config(sample_freq, parameters),
-                    )),
-                    config::Filter::BiquadCombo { parameters } => Box::new(
-                        biquadcombo::BiquadCombo::from_config(name, sample_freq, parameters),
-                    ),
-                    config::Filter::Delay { parameters } => Box::new(
-                        basicfilters::Delay::from_config(name, sample_freq, parameters),
-                    ),
-                    config::Filter::Gain { parameters } => {
-                        Box::new(basicfilters::Gain::from_config(name, parameters))
-                    }
-                    config::Filter::Volume { parameters } => {
-                        Box::new(basicfilters::Volume::from_config(
-                            name,
-                            parameters,
-                            waveform_length,
-                            sample_freq,
-                            processing_status.clone(),
-                        ))
-                    }
-                    config::Filter::Loudness { parameters } => {
-                        Box::new(loudness::Loudness::from_config(
-                            name,
-                            parameters,
-                            waveform_length,
-                            sample_freq,
-                            processing_status.clone(),
-                        ))
-                    }
-                    config::Filter::Dither { parameters } => {
-                        Box::new(dither::Dither::from_config(name, parameters))
-                    }
-                    config::Filter::DiffEq { parameters } => {
-                        Box::new(diffeq::DiffEq::from_config(name, parameters))
-                    }
-                };
+                        processing_status.clone(),
+                    ))
+                }
+                config::Filter::Loudness { parameters } => {
+                    Box::new(loudness::Loudness::from_config(
+                        name,
+                        parameters,
+                        waveform_length,
+                        sample_freq,
+                        processing_status.clone(),
+                    ))
+                }
+                config::Filter::Dither { parameters } => {
+                    Box::new(dither::Dither::from_config(name, parameters))
+                }
+                config::Filter::DiffEq { parameters } => {
+                    Box::new(diffeq::DiffEq::from_config(name, parameters))
+                }
+                config::Filter::DynamicEq { parameters } => Box::new(
+                    dynamiceq::DynamicEq::from_config(name, sample_freq, parameters),
+                ),
+                config::Filter::Tilt { parameters } => Box::new(
+                    biquadcombo::BiquadCombo::from_tilt_config(name, sample_freq, parameters),
+                ),
+                config::Filter::Shaper { parameters } => {
+                    Box::new(basicfilters::Shaper::from_config(name, parameters))
+                }
+                // Crossfeeds process two channels, and are only used in Crossfeed steps
+                config::Filter::Crossfeed { .. } => continue,
+            };
             filters.push(filter);
         }
-        FilterGroup { channel, filters }
+        FilterGroup {
+            channel,
+            filters,
+            gain_compensate,
+            compensation,
+            mix,
+            dry: Vec::new(),
+            dry_latency,
+            dry_delay: get_dry_delay(channel, dry_latency, sample_freq),
+            waveform_length,
+            sample_freq,
+        }
+    }
+
+    /// Creates a group with a single delay, used to compensate for the latency of FIR filters.
+    pub fn compensating_delay(
+        channel: usize,
+        delay: usize,
+        waveform_length: usize,
+        sample_freq: usize,
+    ) -> Self {
+        let name = format!("fir_latency_compensation_{}", channel);
+        let delay = basicfilters::Delay::new(name, sample_freq, delay as PrcFmt, false);
+        FilterGroup {
+            channel,
+            filters: vec![Box::new(delay)],
+            gain_compensate: false,
+            compensation: 1.0,
+            mix: 1.0,
+            dry: Vec::new(),
+            dry_latency: 0,
+            dry_delay: None,
+            waveform_length,
+            sample_freq,
+        }
     }
 
     pub fn update_parameters(
@@ -412,18 +974,104 @@ impl FilterGroup {
         filterconfigs: HashMap<String, config::Filter>,
         changed: Vec<String>,
     ) {
+        let mut any_changed = false;
         for filter in &mut self.filters {
             if changed.iter().any(|n| n == &filter.name()) {
                 filter.update_parameters(filterconfigs[&filter.name()].clone());
+                any_changed = true;
+            }
+        }
+        if self.mix < 1.0 && any_changed {
+            let names: Vec<String> = self.filters.iter().map(|f| f.name()).collect();
+            let latency = Pipeline::get_latency(&names, &filterconfigs);
+            if latency != self.dry_latency {
+                self.dry_latency = latency;
+                self.dry_delay = get_dry_delay(self.channel, latency, self.sample_freq);
             }
         }
+        if self.gain_compensate && any_changed {
+            let names: Vec<String> = self.filters.iter().map(|f| f.name()).collect();
+            let gain = measure_broadband_gain(
+                &names,
+                &filterconfigs,
+                self.waveform_length,
+                self.sample_freq,
+            );
+            debug!(
+                "Compensating broadband gain {} of channel {}",
+                gain, self.channel
+            );
+            self.compensation = get_compensation(gain);
+        }
+    }
+
+    fn reset(&mut self) {
+        for filter in &mut self.filters {
+            filter.reset();
+        }
+        if let Some(delay) = self.dry_delay.as_mut() {
+            delay.reset();
+        }
+    }
+
+    fn publish_status(&self, status: &mut ProcessingParameters) {
+        for filter in &self.filters {
+            filter.publish_status(status);
+        }
+    }
+
+    /// Find a filter by name, and evaluate its current response.
+    /// The result is None if the group doesn't contain the filter,
+    /// and the inner one is None if the filter doesn't have a fixed response.
+    fn filter_response(
+        &self,
+        name: &str,
+        frequencies: &[PrcFmt],
+    ) -> Option<Option<Vec<Complex<PrcFmt>>>> {
+        self.filters
+            .iter()
+            .find(|filter| filter.name() == name)
+            .map(|filter| filter.frequency_response(frequencies, self.sample_freq))
     }
 
-    /// Apply all the filters to an AudioChunk.
-    fn process_chunk(&mut self, input: &mut AudioChunk) -> Res<()> {
+    /// Apply all the filters to an AudioChunk, timing each filter if a timer is given.
+    fn process_chunk(
+        &mut self,
+        input: &mut AudioChunk,
+        timer: Option<&mut countertimer::FilterTimer>,
+    ) -> Res<()> {
         if !input.waveforms[self.channel].is_empty() {
-            for filter in &mut self.filters {
-                filter.process_waveform(&mut input.waveforms[self.channel])?;
+            // The dry signal is delayed by the latency of the FIR filters, to stay aligned with the filtered one
+            if self.mix < 1.0 {
+                self.dry.clear();
+                self.dry.extend_from_slice(&input.waveforms[self.channel]);
+                if let Some(delay) = self.dry_delay.as_mut() {
+                    delay.process_waveform(&mut self.dry)?;
+                }
+            }
+            if let Some(timer) = timer {
+                for filter in &mut self.filters {
+                    let start = Instant::now();
+                    filter.process_waveform(&mut input.waveforms[self.channel])?;
+                    timer.add(&filter.name(), start.elapsed());
+                }
+            } else {
+                for filter in &mut self.filters {
+                    filter.process_waveform(&mut input.waveforms[self.channel])?;
+                }
+            }
+            if self.compensation != 1.0 {
+                for value in input.waveforms[self.channel].iter_mut() {
+                    *value *= self.compensation;
+                }
+            }
+            if self.mix < 1.0 {
+                for (value, dry_value) in input.waveforms[self.channel]
+                    .iter_mut()
+                    .zip(self.dry.iter())
+                {
+                    *value = self.mix * *value + (1.0 - self.mix) * dry_value;
+                }
             }
         }
         Ok(())
@@ -431,14 +1079,17 @@ impl FilterGroup {
 }
 
 /// A Pipeline is made up of a series of PipelineSteps,
-/// each one can be a single Mixer of a group of Filters
+/// each one can be a single Mixer, a group of Filters or a Crossfeed
 pub enum PipelineStep {
     MixerStep(mixer::Mixer),
     FilterStep(FilterGroup),
+    CrossfeedStep(crossfeed::Crossfeed),
 }
 
 pub struct Pipeline {
     steps: Vec<PipelineStep>,
+    processing_status: Arc<RwLock<ProcessingParameters>>,
+    timer: Option<countertimer::FilterTimer>,
 }
 
 impl Pipeline {
@@ -449,17 +1100,33 @@ impl Pipeline {
     ) -> Self {
         debug!("Build new pipeline");
         let mut steps = Vec::<PipelineStep>::new();
+        let compensate_latency = conf.devices.compensate_fir_latency;
+        let mut latencies = vec![0; config::get_pipeline_input_channels(&conf)];
         for step in conf.pipeline {
             match step {
                 config::PipelineStep::Mixer { name } => {
                     let mixconf = conf.mixers[&name].clone();
+                    if compensate_latency {
+                        Pipeline::align_latencies(&mut steps, &latencies, &conf.devices);
+                        latencies = vec![0; mixconf.channels.out];
+                    }
                     let mixer = mixer::Mixer::from_config(name, mixconf);
                     steps.push(PipelineStep::MixerStep(mixer));
                 }
-                config::PipelineStep::Filter { channel, names } => {
+                config::PipelineStep::Filter {
+                    channel,
+                    names,
+                    gain_compensate,
+                    mix,
+                } => {
+                    if compensate_latency {
+                        latencies[channel] += Pipeline::get_latency(&names, &conf.filters);
+                    }
                     let fltgrp = FilterGroup::from_config(
                         channel,
                         names,
+                        gain_compensate,
+                        mix,
                         conf.filters.clone(),
                         conf.devices.chunksize,
                         conf.devices.samplerate,
@@ -467,9 +1134,88 @@ impl Pipeline {
                     );
                     steps.push(PipelineStep::FilterStep(fltgrp));
                 }
+                config::PipelineStep::Crossfeed { name } => {
+                    if let config::Filter::Crossfeed { parameters } = conf.filters[&name].clone() {
+                        let crossfeed = crossfeed::Crossfeed::from_config(
+                            name,
+                            conf.devices.samplerate,
+                            parameters,
+                        );
+                        steps.push(PipelineStep::CrossfeedStep(crossfeed));
+                    }
+                }
+            }
+        }
+        if compensate_latency {
+            Pipeline::align_latencies(&mut steps, &latencies, &conf.devices);
+        }
+        let timer = if processing_status.read().unwrap().profile_filters {
+            let window = FILTER_TIME_WINDOW * conf.devices.samplerate / conf.devices.chunksize;
+            Some(countertimer::FilterTimer::new(window))
+        } else {
+            None
+        };
+        let pipeline = Pipeline {
+            steps,
+            processing_status,
+            timer,
+        };
+        pipeline.publish_mixer_attenuations();
+        pipeline
+    }
+
+    /// Store the attenuations applied by the mixers in the processing status.
+    fn publish_mixer_attenuations(&self) {
+        let mut status = self.processing_status.write().unwrap();
+        status.mixer_attenuations.clear();
+        for step in self.steps.iter() {
+            if let PipelineStep::MixerStep(mix) = step {
+                status.mixer_attenuations.insert(
+                    mix.name.clone(),
+                    mix.attenuation.iter().map(|att| *att as f32).collect(),
+                );
+            }
+        }
+    }
+
+    /// Get the total latency of the FIR filters in a filter step.
+    fn get_latency(names: &[String], filter_configs: &HashMap<String, config::Filter>) -> usize {
+        names
+            .iter()
+            .map(|name| match &filter_configs[name] {
+                config::Filter::Conv { parameters } => {
+                    get_conv_latency(parameters).unwrap_or_else(|err| {
+                        warn!("Unable to get latency of filter '{}': {}", name, err);
+                        0
+                    })
+                }
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// Add delays to the channels with less latency than the others, so that all channels are aligned.
+    fn align_latencies(
+        steps: &mut Vec<PipelineStep>,
+        latencies: &[usize],
+        devices: &config::Devices,
+    ) {
+        let max_latency = latencies.iter().copied().max().unwrap_or_default();
+        for (channel, latency) in latencies.iter().enumerate() {
+            if *latency < max_latency {
+                debug!(
+                    "Delaying channel {} by {} samples to compensate for FIR latency",
+                    channel,
+                    max_latency - latency
+                );
+                steps.push(PipelineStep::FilterStep(FilterGroup::compensating_delay(
+                    channel,
+                    max_latency - latency,
+                    devices.chunksize,
+                    devices.samplerate,
+                )));
             }
         }
-        Pipeline { steps }
     }
 
     pub fn update_parameters(
@@ -489,10 +1235,62 @@ impl Pipeline {
                 PipelineStep::FilterStep(flt) => {
                     flt.update_parameters(conf.filters.clone(), filters.clone());
                 }
+                PipelineStep::CrossfeedStep(crossfeed) => {
+                    if filters.iter().any(|n| n == &crossfeed.name) {
+                        crossfeed.update_parameters(conf.filters[&crossfeed.name].clone());
+                    }
+                }
+            }
+        }
+        if !mixers.is_empty() {
+            self.publish_mixer_attenuations();
+        }
+    }
+
+    /// Clear the internal state of all filters, keeping the filters and their parameters.
+    pub fn reset(&mut self) {
+        for step in &mut self.steps {
+            match step {
+                PipelineStep::MixerStep(_) => {}
+                PipelineStep::FilterStep(flt) => flt.reset(),
+                PipelineStep::CrossfeedStep(crossfeed) => crossfeed.reset(),
+            }
+        }
+    }
+
+    /// Store the values reported by the filters in the processing status.
+    pub fn publish_status(&self, status: &mut ProcessingParameters) {
+        for step in &self.steps {
+            if let PipelineStep::FilterStep(flt) = step {
+                flt.publish_status(status);
             }
         }
     }
 
+    /// Evaluate the response of a filter of the running pipeline at the given frequencies.
+    /// This uses the current coefficients, including changes made while running such as
+    /// those of the Volume, Loudness and DynamicEq filters.
+    /// When the filter is used on several channels, the first one is evaluated.
+    pub fn filter_response(
+        &self,
+        name: &str,
+        frequencies: &[PrcFmt],
+    ) -> Result<Vec<Complex<PrcFmt>>, String> {
+        let response = self.steps.iter().find_map(|step| match step {
+            PipelineStep::FilterStep(flt) => flt.filter_response(name, frequencies),
+            PipelineStep::CrossfeedStep(crossfeed) if crossfeed.name == name => Some(None),
+            _ => None,
+        });
+        match response {
+            Some(Some(response)) => Ok(response),
+            Some(None) => Err(format!(
+                "Filter '{}' does not have a fixed frequency response",
+                name
+            )),
+            None => Err(format!("Filter '{}' is not used in the pipeline", name)),
+        }
+    }
+
     /// Process an AudioChunk by calling either a MixerStep or a FilterStep
     pub fn process_chunk(&mut self, mut chunk: AudioChunk) -> AudioChunk {
         for mut step in &mut self.steps {
@@ -501,12 +1299,35 @@ impl Pipeline {
                     chunk = mix.process_chunk(&chunk);
                 }
                 PipelineStep::FilterStep(flt) => {
-                    flt.process_chunk(&mut chunk).unwrap();
+                    flt.process_chunk(&mut chunk, self.timer.as_mut()).unwrap();
+                }
+                PipelineStep::CrossfeedStep(crossfeed) => {
+                    let start = Instant::now();
+                    crossfeed.process_chunk(&mut chunk).unwrap();
+                    if let Some(timer) = self.timer.as_mut() {
+                        timer.add(&crossfeed.name, start.elapsed());
+                    }
                 }
             }
         }
+        if let Some(times) = self.timer.as_mut().and_then(|timer| timer.end_chunk()) {
+            self.publish_filter_times(times);
+        }
         chunk
     }
+
+    /// Log the processing times of the filters, and store them in the processing status.
+    fn publish_filter_times(&self, times: HashMap<String, countertimer::FilterTime>) {
+        let mut names: Vec<&String> = times.keys().collect();
+        names.sort();
+        for name in names {
+            info!(
+                "Filter '{}': average {:.1} us, max {:.1} us",
+                name, times[name].average, times[name].max
+            );
+        }
+        self.processing_status.write().unwrap().filter_times = times;
+    }
 }
 
 /// Validate the filter config, to give a helpful message intead of a panic.
@@ -514,22 +1335,41 @@ pub fn validate_filter(fs: usize, filter_config: &config::Filter) -> Res<()> {
     match filter_config {
         config::Filter::Conv { parameters } => fftconv::validate_config(parameters),
         config::Filter::Biquad { parameters } => biquad::validate_config(fs, parameters),
-        config::Filter::Delay { parameters } => basicfilters::validate_delay_config(parameters),
+        config::Filter::Delay { parameters } => basicfilters::validate_delay_config(fs, parameters),
         config::Filter::Gain { parameters } => basicfilters::validate_gain_config(parameters),
         config::Filter::Dither { parameters } => dither::validate_config(parameters),
         config::Filter::DiffEq { parameters } => diffeq::validate_config(parameters),
+        config::Filter::DynamicEq { parameters } => dynamiceq::validate_config(fs, parameters),
         config::Filter::Volume { parameters } => basicfilters::validate_volume_config(parameters),
         config::Filter::Loudness { parameters } => loudness::validate_config(parameters),
         config::Filter::BiquadCombo { parameters } => biquadcombo::validate_config(fs, parameters),
+        config::Filter::Highpass { parameters } | config::Filter::Lowpass { parameters } => {
+            biquadcombo::validate_pass_config(fs, parameters)
+        }
+        config::Filter::Deemphasis { parameters } | config::Filter::Preemphasis { parameters } => {
+            biquad::validate_emphasis_config(parameters)
+        }
+        config::Filter::Crossfeed { parameters } => crossfeed::validate_config(fs, parameters),
+        config::Filter::Tilt { parameters } => biquadcombo::validate_tilt_config(fs, parameters),
+        config::Filter::Shaper { parameters } => basicfilters::validate_shaper_config(parameters),
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::config;
     use crate::config::FileFormat;
-    use crate::filters::{find_data_in_wav, read_wav};
-    use crate::filters::{pad_vector, read_coeff_file};
+    use crate::filters::Filter;
+    use crate::filters::FilterGroup;
+    use crate::filters::{decode_base64_coeffs, find_data_in_wav, read_wav};
+    use crate::filters::{
+        fir_response, get_dry_delay, get_fft_library, pad_vector, read_coeff_file, to_minimum_phase,
+    };
+    use crate::filters::{
+        get_conv_latency, get_conv_values, measure_broadband_gain, normalize_conv_values,
+    };
     use crate::PrcFmt;
+    use std::collections::HashMap;
 
     fn is_close(left: PrcFmt, right: PrcFmt, maxdiff: PrcFmt) -> bool {
         println!("{} - {} = {}", left, right, left - right);
@@ -551,6 +1391,25 @@ mod tests {
         true
     }
 
+    #[test]
+    fn decode_base64() {
+        let loaded =
+            decode_base64_coeffs("AACAvwAAAL8AAAAAAAAAPwAAgD8=", &FileFormat::FLOAT32LE).unwrap();
+        let expected: Vec<PrcFmt> = vec![-1.0, -0.5, 0.0, 0.5, 1.0];
+        assert!(compare_waveforms(&loaded, &expected, 1e-15));
+        let loaded =
+            decode_base64_coeffs("AAAAAAAA8L8AAAAAAADQPwAAAAAAAPA/", &FileFormat::FLOAT64LE)
+                .unwrap();
+        let expected: Vec<PrcFmt> = vec![-1.0, 0.25, 1.0];
+        assert!(compare_waveforms(&loaded, &expected, 1e-15));
+        // 20 bytes is not a multiple of 8
+        assert!(
+            decode_base64_coeffs("AACAvwAAAL8AAAAAAAAAPwAAgD8=", &FileFormat::FLOAT64LE).is_err()
+        );
+        assert!(decode_base64_coeffs("not base64!", &FileFormat::FLOAT32LE).is_err());
+        assert!(decode_base64_coeffs("AACAvw==", &FileFormat::TEXT).is_err());
+    }
+
     #[test]
     fn read_float32() {
         let loaded = read_coeff_file("testdata/float32.raw", &FileFormat::FLOAT32LE, 0, 0).unwrap();
@@ -719,4 +1578,315 @@ mod tests {
         let bad = read_wav("testdata/int32.wav", 1);
         assert!(bad.is_err());
     }
+
+    #[test]
+    fn broadband_gain() {
+        let mut filter_configs = HashMap::new();
+        filter_configs.insert(
+            "gain".to_string(),
+            config::Filter::Gain {
+                parameters: config::GainParameters {
+                    gain: -6.0,
+                    inverted: true,
+                    mute: false,
+                },
+            },
+        );
+        filter_configs.insert(
+            "fir".to_string(),
+            config::Filter::Conv {
+                parameters: config::ConvParameters::Values {
+                    values: vec![0.5, 0.5, 0.5, 0.5],
+                    length: 0,
+                    normalize: config::ConvNormalization::None,
+                    minimum_phase: false,
+                    fft_library: None,
+                },
+            },
+        );
+        let names = vec!["gain".to_string(), "fir".to_string()];
+        let gain = measure_broadband_gain(&names, &filter_configs, 1024, 48000);
+        assert!((gain - 10.0_f64.powf(-6.0 / 20.0) as PrcFmt).abs() < 1.0e-6);
+    }
+
+    #[test]
+    fn fir_response_matches_biquad() {
+        let biquad_conf = config::Filter::Biquad {
+            parameters: config::BiquadParameters::Peaking(config::PeakingWidth::Q {
+                freq: 1000.0,
+                q: 2.0,
+                gain: 6.0,
+            }),
+        };
+        let frequencies = vec![20.0, 500.0, 1000.0, 1234.5, 10000.0];
+        let mut biquad = match biquad_conf {
+            config::Filter::Biquad { parameters } => crate::biquad::Biquad::new(
+                "eq".to_string(),
+                48000,
+                crate::biquad::BiquadCoefficients::from_config(48000, parameters),
+            ),
+            _ => unreachable!(),
+        };
+        let biquad_resp = biquad.frequency_response(&frequencies, 48000).unwrap();
+        assert!((biquad_resp[2].norm() - 10.0_f64.powf(6.0 / 20.0) as PrcFmt).abs() < 1.0e-4);
+
+        // the impulse response of the biquad, used as FIR taps, should give the same response
+        let mut impulse = vec![0.0; 8192];
+        impulse[0] = 1.0;
+        biquad.process_waveform(&mut impulse).unwrap();
+        // the taps are split in several segments by the convolution filter
+        let fir = crate::fftconv::FftConv::new("fir".to_string(), 1024, &impulse);
+        let fir_resp = fir.frequency_response(&frequencies, 48000).unwrap();
+        for (fir, biquad) in fir_resp.iter().zip(biquad_resp.iter()) {
+            assert!((fir - biquad).norm() < 1.0e-3);
+        }
+    }
+
+    fn conv_with_library(fft_library: Option<config::FftLibrary>) -> config::ConvParameters {
+        config::ConvParameters::Values {
+            values: vec![1.0],
+            length: 0,
+            normalize: config::ConvNormalization::None,
+            minimum_phase: false,
+            fft_library,
+        }
+    }
+
+    #[test]
+    fn fft_library_selection() {
+        let realfft = conv_with_library(Some(config::FftLibrary::RealFft));
+        assert_eq!(get_fft_library(&realfft), config::FftLibrary::RealFft);
+        // FFTW is the default when compiled in, and falls back to RealFFT otherwise
+        let expected = if cfg!(feature = "FFTW") {
+            config::FftLibrary::Fftw
+        } else {
+            config::FftLibrary::RealFft
+        };
+        assert_eq!(get_fft_library(&conv_with_library(None)), expected);
+        let fftw = conv_with_library(Some(config::FftLibrary::Fftw));
+        assert_eq!(get_fft_library(&fftw), expected);
+    }
+
+    #[test]
+    fn filter_group_mix() {
+        // an inverting gain mixed in at 25% leaves half of the dry signal
+        let mut group = FilterGroup {
+            channel: 0,
+            filters: vec![Box::new(crate::basicfilters::Gain::new(
+                "invert".to_string(),
+                0.0,
+                true,
+                false,
+            ))],
+            gain_compensate: false,
+            compensation: 1.0,
+            mix: 0.25,
+            dry: Vec::new(),
+            dry_latency: 0,
+            dry_delay: None,
+            waveform_length: 4,
+            sample_freq: 44100,
+        };
+        let mut chunk =
+            crate::audiodevice::AudioChunk::new(vec![vec![1.0, -0.5, 0.0, 0.2]], 1.0, -1.0, 4, 4);
+        group.process_chunk(&mut chunk, None).unwrap();
+        assert!(compare_waveforms(
+            &chunk.waveforms[0],
+            &[0.5, -0.25, 0.0, 0.1],
+            1.0e-9
+        ));
+    }
+
+    #[test]
+    fn filter_group_mix_aligns_latency() {
+        // a FIR with its peak at 3 samples is mixed with a dry signal delayed by the same amount
+        let mut coeffs = vec![0.0; 8];
+        coeffs[3] = 1.0;
+        let mut group = FilterGroup {
+            channel: 0,
+            filters: vec![Box::new(crate::filters::fftconv::FftConv::new(
+                "fir".to_string(),
+                8,
+                &coeffs,
+            ))],
+            gain_compensate: false,
+            compensation: 1.0,
+            mix: 0.5,
+            dry: Vec::new(),
+            dry_latency: 3,
+            dry_delay: get_dry_delay(0, 3, 48000),
+            waveform_length: 8,
+            sample_freq: 48000,
+        };
+        let mut impulse = vec![0.0; 8];
+        impulse[6] = 1.0;
+        let mut chunk = crate::audiodevice::AudioChunk::new(vec![impulse], 1.0, -1.0, 8, 8);
+        group.process_chunk(&mut chunk, None).unwrap();
+        assert!(compare_waveforms(&chunk.waveforms[0], &[0.0; 8], 1.0e-9));
+        let mut chunk = crate::audiodevice::AudioChunk::new(vec![vec![0.0; 8]], 1.0, -1.0, 8, 8);
+        group.process_chunk(&mut chunk, None).unwrap();
+        let mut expected = vec![0.0; 8];
+        expected[1] = 1.0;
+        assert!(compare_waveforms(&chunk.waveforms[0], &expected, 1.0e-9));
+    }
+
+    #[test]
+    fn filter_group_reset() {
+        // after a reset, a biquad, a delay and a FIR filter have no ringing left from an impulse
+        let mut coeffs = vec![0.0; 16];
+        coeffs[3] = 1.0;
+        coeffs[10] = 0.5;
+        let mut group = FilterGroup {
+            channel: 0,
+            filters: vec![
+                Box::new(crate::biquad::Biquad::new(
+                    "eq".to_string(),
+                    48000,
+                    crate::biquad::BiquadCoefficients::from_config(
+                        48000,
+                        config::BiquadParameters::Lowpass {
+                            freq: 500.0,
+                            q: 5.0,
+                        },
+                    ),
+                )),
+                Box::new(crate::basicfilters::Delay::new(
+                    "delay".to_string(),
+                    48000,
+                    6.0,
+                    false,
+                )),
+                Box::new(crate::filters::fftconv::FftConv::new(
+                    "fir".to_string(),
+                    8,
+                    &coeffs,
+                )),
+            ],
+            gain_compensate: false,
+            compensation: 1.0,
+            mix: 1.0,
+            dry: Vec::new(),
+            dry_latency: 0,
+            dry_delay: None,
+            waveform_length: 8,
+            sample_freq: 48000,
+        };
+        let mut impulse = vec![0.0; 8];
+        impulse[0] = 1.0;
+        let mut chunk = crate::audiodevice::AudioChunk::new(vec![impulse], 1.0, -1.0, 8, 8);
+        group.process_chunk(&mut chunk, None).unwrap();
+        group.reset();
+        let mut chunk = crate::audiodevice::AudioChunk::new(vec![vec![0.0; 8]], 1.0, -1.0, 8, 8);
+        group.process_chunk(&mut chunk, None).unwrap();
+        assert!(chunk.waveforms[0].iter().all(|value| *value == 0.0));
+    }
+
+    fn biquad_impulse(length: usize) -> Vec<PrcFmt> {
+        let mut impulse = vec![0.0; length];
+        impulse[0] = 1.0;
+        let mut biquad = crate::biquad::Biquad::new(
+            "eq".to_string(),
+            48000,
+            crate::biquad::BiquadCoefficients::from_config(
+                48000,
+                config::BiquadParameters::Peaking(config::PeakingWidth::Q {
+                    freq: 1000.0,
+                    q: 2.0,
+                    gain: -6.0,
+                }),
+            ),
+        );
+        biquad.process_waveform(&mut impulse).unwrap();
+        impulse
+    }
+
+    #[test]
+    fn minimum_phase_of_minimum_phase() {
+        // a biquad with its zeros inside the unit circle is already minimum phase
+        let impulse = biquad_impulse(2048);
+        let converted = to_minimum_phase(&impulse).unwrap();
+        assert!(compare_waveforms(&converted, &impulse, 1.0e-4));
+    }
+
+    #[test]
+    fn minimum_phase_keeps_magnitude() {
+        // a linear phase filter, made by convolving the impulse response with its time reverse
+        let impulse = biquad_impulse(512);
+        let linear: Vec<PrcFmt> = (0..2 * impulse.len() - 1)
+            .map(|n| {
+                impulse
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(m, val)| {
+                        (n + m)
+                            .checked_sub(impulse.len() - 1)
+                            .and_then(|k| impulse.get(k))
+                            .map(|other| val * other)
+                    })
+                    .sum()
+            })
+            .collect();
+        let values = config::ConvParameters::Values {
+            values: linear.clone(),
+            length: 0,
+            normalize: config::ConvNormalization::None,
+            minimum_phase: true,
+            fft_library: None,
+        };
+        assert_eq!(get_conv_latency(&values).unwrap(), 0);
+        let converted = to_minimum_phase(&linear).unwrap();
+        assert_eq!(converted.len(), linear.len());
+        // Loading the filter again gives the same coefficients, from the cache
+        assert_eq!(get_conv_values(&values).unwrap(), converted);
+        assert_eq!(get_conv_values(&values).unwrap(), converted);
+        let frequencies = vec![20.0, 500.0, 1000.0, 1234.5, 10000.0];
+        let linear_resp = fir_response(&linear, 48000, &frequencies).unwrap();
+        let converted_resp = fir_response(&converted, 48000, &frequencies).unwrap();
+        for (lin, conv) in linear_resp.iter().zip(converted_resp.iter()) {
+            assert!((lin.norm() - conv.norm()).abs() < 1.0e-3);
+        }
+    }
+
+    #[test]
+    fn conv_latency() {
+        let conf = config::ConvParameters::Values {
+            values: vec![0.1, -0.2, 0.9, -0.3, 0.0],
+            length: 0,
+            normalize: config::ConvNormalization::None,
+            minimum_phase: false,
+            fft_library: None,
+        };
+        assert_eq!(get_conv_latency(&conf).unwrap(), 2);
+        let conf = config::ConvParameters::Values {
+            values: vec![0.1, -0.2, 0.5, -0.95, 0.0],
+            length: 0,
+            normalize: config::ConvNormalization::None,
+            minimum_phase: false,
+            fft_library: None,
+        };
+        assert_eq!(get_conv_latency(&conf).unwrap(), 3);
+    }
+
+    #[test]
+    fn conv_normalization() {
+        let values: Vec<PrcFmt> = vec![0.5, -1.0, 2.5, 0.0];
+        let mut peak = values.clone();
+        let scale = normalize_conv_values(&mut peak, config::ConvNormalization::Peak).unwrap();
+        assert!((scale - 0.4).abs() < 1.0e-6);
+        assert!((peak[2] - 1.0).abs() < 1.0e-6);
+        let mut energy = values.clone();
+        normalize_conv_values(&mut energy, config::ConvNormalization::Energy).unwrap();
+        let sum_sq: PrcFmt = energy.iter().map(|val| val * val).sum();
+        assert!((sum_sq - 1.0).abs() < 1.0e-6);
+        let mut dc = values.clone();
+        normalize_conv_values(&mut dc, config::ConvNormalization::Dc).unwrap();
+        let sum: PrcFmt = dc.iter().sum();
+        assert!((sum - 1.0).abs() < 1.0e-6);
+        let mut none = values.clone();
+        let scale = normalize_conv_values(&mut none, config::ConvNormalization::None).unwrap();
+        assert_eq!(scale, 1.0);
+        assert_eq!(none, values);
+        let mut highpass = vec![0.5, -0.5];
+        assert!(normalize_conv_values(&mut highpass, config::ConvNormalization::Dc).is_err());
+    }
 }
@@ -0,0 +1,670 @@
+// Reading and writing of FLAC files for the File capture and playback devices.
+// Decoding is done with claxon, while encoding uses a simple built-in encoder
+// with fixed linear predictors and Rice coded residuals.
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::config::{ConfigError, SampleFormat};
+use crate::Res;
+
+/// Number of frames in each encoded FLAC block.
+const BLOCKSIZE: usize = 4096;
+
+/// Largest Rice parameter used by the encoder, 15 is reserved as an escape code.
+const MAX_RICE_PARAM: u32 = 14;
+
+/// Largest number of channels of a FLAC stream.
+pub const MAX_CHANNELS: usize = 8;
+
+/// Check if a filename refers to a FLAC file, by looking at the extension.
+pub fn is_flac_file(filename: &str) -> bool {
+    filename.to_lowercase().ends_with(".flac")
+}
+
+/// Get the FLAC bits per sample for a sample format, or None if it can't be stored as FLAC.
+pub fn flac_bits(format: &SampleFormat) -> Option<usize> {
+    match format {
+        SampleFormat::S16LE => Some(16),
+        SampleFormat::S24LE | SampleFormat::S24LE3 => Some(24),
+        _ => None,
+    }
+}
+
+/// Reads a FLAC file, and provides the decoded samples as raw bytes in the given sample format.
+pub struct FlacDecoder {
+    reader: claxon::FlacReader<File>,
+    sample_format: SampleFormat,
+    buffer: Vec<u8>,
+    position: usize,
+    samples: Vec<i32>,
+}
+
+impl FlacDecoder {
+    /// Open a FLAC file and check that it matches the given number of channels, samplerate and format.
+    pub fn open(
+        filename: &str,
+        channels: usize,
+        samplerate: usize,
+        sample_format: &SampleFormat,
+    ) -> Res<Self> {
+        let reader = claxon::FlacReader::open(filename).map_err(|err| {
+            ConfigError::new(&format!("Unable to read FLAC file {}: {}", filename, err))
+        })?;
+        let info = reader.streaminfo();
+        if info.channels as usize != channels {
+            let msg = format!(
+                "FLAC file {} has {} channels, but the config specifies {}",
+                filename, info.channels, channels
+            );
+            return Err(ConfigError::new(&msg).into());
+        }
+        if info.sample_rate as usize != samplerate {
+            let msg = format!(
+                "FLAC file {} has a samplerate of {}, but the config specifies {}",
+                filename, info.sample_rate, samplerate
+            );
+            return Err(ConfigError::new(&msg).into());
+        }
+        if Some(info.bits_per_sample as usize) != flac_bits(sample_format) {
+            let msg = format!(
+                "FLAC file {} uses {} bits per sample, which doesn't match format {:?}",
+                filename, info.bits_per_sample, sample_format
+            );
+            return Err(ConfigError::new(&msg).into());
+        }
+        debug!(
+            "Opened FLAC file {}, {} channels, {} Hz, {} bits",
+            filename, info.channels, info.sample_rate, info.bits_per_sample
+        );
+        Ok(FlacDecoder {
+            reader,
+            sample_format: sample_format.clone(),
+            buffer: Vec::new(),
+            position: 0,
+            samples: Vec::new(),
+        })
+    }
+
+    /// Decode the next block into the byte buffer. Returns false at the end of the file.
+    fn decode_block(&mut self) -> io::Result<bool> {
+        let samples = std::mem::take(&mut self.samples);
+        let block = match self.reader.blocks().read_next_or_eof(samples) {
+            Ok(Some(block)) => block,
+            Ok(None) => return Ok(false),
+            Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+        };
+        self.buffer.clear();
+        self.position = 0;
+        for frame in 0..block.duration() {
+            for channel in 0..block.channels() {
+                let value = block.sample(channel, frame);
+                let bytes = value.to_le_bytes();
+                match self.sample_format {
+                    SampleFormat::S16LE => self.buffer.extend_from_slice(&bytes[0..2]),
+                    SampleFormat::S24LE3 => self.buffer.extend_from_slice(&bytes[0..3]),
+                    _ => {
+                        self.buffer.extend_from_slice(&bytes[0..3]);
+                        self.buffer.push(0);
+                    }
+                }
+            }
+        }
+        self.samples = block.into_buffer();
+        Ok(true)
+    }
+}
+
+impl Read for FlacDecoder {
+    fn read(&mut self, data: &mut [u8]) -> io::Result<usize> {
+        while self.position >= self.buffer.len() {
+            if !self.decode_block()? {
+                return Ok(0);
+            }
+        }
+        let nbr_bytes = data.len().min(self.buffer.len() - self.position);
+        data[0..nbr_bytes].copy_from_slice(&self.buffer[self.position..self.position + nbr_bytes]);
+        self.position += nbr_bytes;
+        Ok(nbr_bytes)
+    }
+}
+
+/// Helper for writing a stream of bits, most significant bit first.
+struct BitWriter {
+    data: Vec<u8>,
+    acc: u64,
+    nbr_bits: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        BitWriter {
+            data: Vec::new(),
+            acc: 0,
+            nbr_bits: 0,
+        }
+    }
+
+    /// Write the lowest `bits` bits of value, at most 32 at a time.
+    fn write(&mut self, value: u64, bits: u32) {
+        if bits == 0 {
+            return;
+        }
+        self.acc = (self.acc << bits) | (value & ((1u64 << bits) - 1));
+        self.nbr_bits += bits;
+        while self.nbr_bits >= 8 {
+            self.nbr_bits -= 8;
+            self.data.push((self.acc >> self.nbr_bits) as u8);
+        }
+    }
+
+    fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write(value as u64, bits);
+    }
+
+    fn write_unary(&mut self, zeros: u32) {
+        let mut remaining = zeros;
+        while remaining >= 32 {
+            self.write(0, 32);
+            remaining -= 32;
+        }
+        self.write(1, remaining + 1);
+    }
+
+    /// Pad with zeros up to the next byte boundary.
+    fn align(&mut self) {
+        if self.nbr_bits > 0 {
+            self.write(0, 8 - self.nbr_bits);
+        }
+    }
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Write the frame number using the UTF-8 like coding of FLAC.
+fn write_utf8_number(writer: &mut BitWriter, value: u64) {
+    if value < 0x80 {
+        writer.write(value, 8);
+        return;
+    }
+    let mut nbr_extra = 1;
+    while value >= 1 << (6 * nbr_extra + 6 - nbr_extra) {
+        nbr_extra += 1;
+    }
+    let prefix = (0xFF00u64 >> (nbr_extra + 1)) & 0xFF;
+    writer.write(prefix | (value >> (6 * nbr_extra)), 8);
+    for n in (0..nbr_extra).rev() {
+        writer.write(0x80 | ((value >> (6 * n)) & 0x3F), 8);
+    }
+}
+
+/// Calculate the residual of a fixed predictor of the given order.
+fn fixed_residual(samples: &[i64], order: usize) -> Vec<i64> {
+    samples
+        .iter()
+        .enumerate()
+        .skip(order)
+        .map(|(n, s)| match order {
+            0 => *s,
+            1 => s - samples[n - 1],
+            2 => s - 2 * samples[n - 1] + samples[n - 2],
+            3 => s - 3 * samples[n - 1] + 3 * samples[n - 2] - samples[n - 3],
+            _ => s - 4 * samples[n - 1] + 6 * samples[n - 2] - 4 * samples[n - 3] + samples[n - 4],
+        })
+        .collect()
+}
+
+/// Find the Rice parameter giving the smallest encoded size, returns the parameter and the size in bits.
+fn best_rice_param(folded: &[u64]) -> (u32, u64) {
+    (0..=MAX_RICE_PARAM)
+        .map(|param| {
+            let bits = folded
+                .iter()
+                .map(|u| (u >> param) + 1 + param as u64)
+                .sum::<u64>();
+            (param, bits)
+        })
+        .min_by_key(|(_, bits)| *bits)
+        .unwrap()
+}
+
+/// Encode one channel of a block as a subframe, choosing the smallest of constant,
+/// verbatim or fixed predictor coding.
+fn write_subframe(writer: &mut BitWriter, samples: &[i64], bits: u32) {
+    if samples.iter().all(|s| *s == samples[0]) {
+        writer.write(0, 8);
+        writer.write_signed(samples[0], bits);
+        return;
+    }
+    let verbatim_bits = samples.len() as u64 * bits as u64;
+    let mut best: Option<(usize, u32, u64, Vec<u64>)> = None;
+    for order in 0..=4.min(samples.len() - 1) {
+        let folded: Vec<u64> = fixed_residual(samples, order)
+            .iter()
+            .map(|r| ((r << 1) ^ (r >> 63)) as u64)
+            .collect();
+        let (param, residual_bits) = best_rice_param(&folded);
+        let total = order as u64 * bits as u64 + 10 + residual_bits;
+        if best.as_ref().map(|b| total < b.2).unwrap_or(true) {
+            best = Some((order, param, total, folded));
+        }
+    }
+    match best {
+        Some((order, param, total, folded)) if total < verbatim_bits => {
+            writer.write(0x10 | (order as u64) << 1, 8);
+            for sample in samples.iter().take(order) {
+                writer.write_signed(*sample, bits);
+            }
+            // Rice coding with 4-bit parameter, and a single partition
+            writer.write(0, 2);
+            writer.write(0, 4);
+            writer.write(param as u64, 4);
+            for value in folded {
+                writer.write_unary((value >> param) as u32);
+                writer.write(value, param);
+            }
+        }
+        _ => {
+            writer.write(0x02, 8);
+            for sample in samples {
+                writer.write_signed(*sample, bits);
+            }
+        }
+    }
+}
+
+/// Encode a complete frame for a block of interleaved samples.
+fn encode_frame(samples: &[i32], channels: usize, bits: u32, frame_number: u64) -> Vec<u8> {
+    let frames = samples.len() / channels;
+    let mut writer = BitWriter::new();
+    // Sync code, fixed blocksize
+    writer.write(0xFFF8, 16);
+    // Blocksize given as 16 bits after the header, samplerate taken from streaminfo
+    writer.write(0x70, 8);
+    let bits_code = if bits == 16 { 0b100 } else { 0b110 };
+    writer.write((channels as u64 - 1) << 4 | bits_code << 1, 8);
+    write_utf8_number(&mut writer, frame_number);
+    writer.write(frames as u64 - 1, 16);
+    let crc = crc8(&writer.data);
+    writer.write(crc as u64, 8);
+    for channel in 0..channels {
+        let channel_samples: Vec<i64> = samples
+            .iter()
+            .skip(channel)
+            .step_by(channels)
+            .map(|s| *s as i64)
+            .collect();
+        write_subframe(&mut writer, &channel_samples, bits);
+    }
+    writer.align();
+    let crc = crc16(&writer.data);
+    writer.write(crc as u64, 16);
+    writer.data
+}
+
+/// Writes raw bytes in the given sample format to a FLAC file.
+/// The file is finalized when the encoder is dropped.
+pub struct FlacEncoder {
+    file: File,
+    channels: usize,
+    samplerate: usize,
+    sample_format: SampleFormat,
+    bits: u32,
+    pending: Vec<u8>,
+    total_frames: u64,
+    frame_number: u64,
+}
+
+impl FlacEncoder {
+    /// Create a new FLAC file.
+    pub fn create(
+        filename: &str,
+        channels: usize,
+        samplerate: usize,
+        sample_format: &SampleFormat,
+    ) -> Res<Self> {
+        let bits = match flac_bits(sample_format) {
+            Some(bits) => bits as u32,
+            None => {
+                let msg = format!("Format {:?} can't be written to FLAC", sample_format);
+                return Err(ConfigError::new(&msg).into());
+            }
+        };
+        if channels == 0 || channels > MAX_CHANNELS {
+            let msg = format!(
+                "FLAC files can have 1 to {} channels, not {}",
+                MAX_CHANNELS, channels
+            );
+            return Err(ConfigError::new(&msg).into());
+        }
+        let file = File::create(filename)?;
+        let mut encoder = FlacEncoder {
+            file,
+            channels,
+            samplerate,
+            sample_format: sample_format.clone(),
+            bits,
+            pending: Vec::new(),
+            total_frames: 0,
+            frame_number: 0,
+        };
+        encoder.file.write_all(b"fLaC")?;
+        let header = encoder.streaminfo();
+        encoder.file.write_all(&header)?;
+        Ok(encoder)
+    }
+
+    /// Build the STREAMINFO metadata block, including its header.
+    fn streaminfo(&self) -> Vec<u8> {
+        let blocksize = if self.total_frames > 0 && self.total_frames < BLOCKSIZE as u64 {
+            self.total_frames.max(16)
+        } else {
+            BLOCKSIZE as u64
+        };
+        let mut writer = BitWriter::new();
+        // Last metadata block, type 0, 34 bytes long
+        writer.write(0x80, 8);
+        writer.write(34, 24);
+        writer.write(blocksize, 16);
+        writer.write(blocksize, 16);
+        writer.write(0, 24);
+        writer.write(0, 24);
+        writer.write(self.samplerate as u64, 20);
+        writer.write(self.channels as u64 - 1, 3);
+        writer.write(self.bits as u64 - 1, 5);
+        writer.write(self.total_frames >> 32, 4);
+        writer.write(self.total_frames & 0xFFFF_FFFF, 32);
+        // MD5 signature left as zeros, meaning unknown
+        for _ in 0..4 {
+            writer.write(0, 32);
+        }
+        writer.data
+    }
+
+    fn bytes_per_sample(&self) -> usize {
+        self.sample_format.bytes_per_sample()
+    }
+
+    /// Convert raw bytes to integer samples.
+    fn bytes_to_samples(&self, data: &[u8]) -> Vec<i32> {
+        data.chunks_exact(self.bytes_per_sample())
+            .map(|bytes| match self.sample_format {
+                SampleFormat::S16LE => i16::from_le_bytes([bytes[0], bytes[1]]) as i32,
+                _ => i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) >> 8,
+            })
+            .collect()
+    }
+
+    /// Encode a block from the start of the pending data.
+    fn encode_block(&mut self, nbr_bytes: usize) -> io::Result<()> {
+        let samples = self.bytes_to_samples(&self.pending[0..nbr_bytes]);
+        let frame = encode_frame(&samples, self.channels, self.bits, self.frame_number);
+        self.file.write_all(&frame)?;
+        self.pending.drain(0..nbr_bytes);
+        self.frame_number += 1;
+        self.total_frames += (samples.len() / self.channels) as u64;
+        Ok(())
+    }
+
+    /// Encode any remaining data and write the final STREAMINFO.
+    fn finish(&mut self) -> io::Result<()> {
+        let frame_bytes = self.channels * self.bytes_per_sample();
+        let remaining = self.pending.len() - self.pending.len() % frame_bytes;
+        if remaining > 0 {
+            self.encode_block(remaining)?;
+        }
+        self.pending.clear();
+        let header = self.streaminfo();
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&header)?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()
+    }
+}
+
+impl Write for FlacEncoder {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(data);
+        let block_bytes = BLOCKSIZE * self.channels * self.bytes_per_sample();
+        while self.pending.len() >= block_bytes {
+            self.encode_block(block_bytes)?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for FlacEncoder {
+    fn drop(&mut self) {
+        if let Err(err) = self.finish() {
+            error!("Unable to finalize FLAC file: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FlacDecoder, FlacEncoder, BLOCKSIZE};
+    use crate::config::SampleFormat;
+    use std::io::{Read, Write};
+
+    fn test_filename(name: &str) -> String {
+        format!(
+            "{}/camilladsp_test_{}_{}.flac",
+            std::env::temp_dir().to_string_lossy(),
+            name,
+            std::process::id()
+        )
+    }
+
+    fn to_bytes(samples: &[i32], bytes_per_sample: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        for value in samples {
+            let bytes = value.to_le_bytes();
+            match bytes_per_sample {
+                2 => data.extend_from_slice(&bytes[0..2]),
+                3 => data.extend_from_slice(&bytes[0..3]),
+                _ => {
+                    data.extend_from_slice(&bytes[0..3]);
+                    data.push(0);
+                }
+            }
+        }
+        data
+    }
+
+    /// Encode interleaved samples, and check that claxon decodes them unchanged.
+    /// Claxon checks the CRCs of the frame headers and frames while decoding.
+    fn roundtrip_samples(name: &str, format: SampleFormat, channels: usize, samples: &[i32]) {
+        let filename = test_filename(name);
+        let bits = if format == SampleFormat::S16LE {
+            16
+        } else {
+            24
+        };
+        let data = to_bytes(samples, format.bytes_per_sample());
+        {
+            let mut encoder = FlacEncoder::create(&filename, channels, 44100, &format).unwrap();
+            // Write in pieces that don't line up with the blocks
+            for piece in data.chunks(1000) {
+                encoder.write_all(piece).unwrap();
+            }
+        }
+        let mut reader = claxon::FlacReader::open(&filename).unwrap();
+        let info = reader.streaminfo();
+        assert_eq!(info.channels as usize, channels);
+        assert_eq!(info.bits_per_sample, bits);
+        assert_eq!(info.sample_rate, 44100);
+        assert_eq!(info.samples, Some((samples.len() / channels) as u64));
+        let mut decoded = Vec::with_capacity(samples.len());
+        let mut frame_reader = reader.blocks();
+        let mut buffer = Vec::new();
+        while let Some(block) = frame_reader.read_next_or_eof(buffer).unwrap() {
+            for frame in 0..block.duration() {
+                for channel in 0..channels {
+                    decoded.push(block.sample(channel as u32, frame));
+                }
+            }
+            buffer = block.into_buffer();
+        }
+        std::fs::remove_file(&filename).unwrap();
+        assert_eq!(decoded, samples);
+    }
+
+    /// A simple pseudo random sequence, covering the whole range of the given number of bits.
+    fn noise(len: usize, bits: u32) -> Vec<i32> {
+        let mut state: u32 = 12345;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+                (state as i32) >> (32 - bits)
+            })
+            .collect()
+    }
+
+    fn roundtrip(format: SampleFormat, bytes_per_sample: usize, frames: usize) {
+        let filename = format!(
+            "{}/camilladsp_test_{:?}_{}.flac",
+            std::env::temp_dir().to_string_lossy(),
+            format,
+            frames
+        );
+        let mut samples = Vec::new();
+        for n in 0..frames {
+            let left = ((n as f32 * 0.05).sin() * 20000.0) as i32;
+            let right = if n % 7 == 0 { -3 } else { n as i32 % 100 };
+            samples.push(left);
+            samples.push(right);
+        }
+        let data = to_bytes(&samples, bytes_per_sample);
+        {
+            let mut encoder = FlacEncoder::create(&filename, 2, 44100, &format).unwrap();
+            // Write in pieces that don't line up with the blocks
+            for piece in data.chunks(1000) {
+                encoder.write_all(piece).unwrap();
+            }
+        }
+        let reader = claxon::FlacReader::open(&filename).unwrap();
+        assert_eq!(reader.streaminfo().samples, Some(frames as u64));
+        let mut decoder = FlacDecoder::open(&filename, 2, 44100, &format).unwrap();
+        let mut decoded = Vec::new();
+        decoder.read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, data);
+        assert!(FlacDecoder::open(&filename, 1, 44100, &format).is_err());
+        assert!(FlacDecoder::open(&filename, 2, 48000, &format).is_err());
+        std::fs::remove_file(&filename).unwrap();
+    }
+
+    #[test]
+    fn flac_roundtrip_16bit() {
+        roundtrip(SampleFormat::S16LE, 2, 10000);
+    }
+
+    #[test]
+    fn flac_roundtrip_24bit() {
+        roundtrip(SampleFormat::S24LE3, 3, 5000);
+        roundtrip(SampleFormat::S24LE, 4, 300);
+    }
+
+    #[test]
+    fn flac_roundtrip_full_scale_noise() {
+        // Noise can't be predicted, and needs large Rice parameters or verbatim subframes
+        roundtrip_samples("noise16", SampleFormat::S16LE, 2, &noise(2 * 5000, 16));
+        roundtrip_samples("noise24", SampleFormat::S24LE3, 2, &noise(2 * 5000, 24));
+    }
+
+    #[test]
+    fn flac_roundtrip_extremes() {
+        // Alternating between the smallest and largest values gives the largest residuals
+        for (name, format, bits) in [
+            ("extremes16", SampleFormat::S16LE, 16),
+            ("extremes24", SampleFormat::S24LE, 24),
+        ] {
+            let max = (1 << (bits - 1)) - 1;
+            let samples: Vec<i32> = (0..3000)
+                .map(|n| match n % 5 {
+                    0 | 3 => max,
+                    _ => -max - 1,
+                })
+                .collect();
+            roundtrip_samples(name, format, 1, &samples);
+        }
+    }
+
+    #[test]
+    fn flac_roundtrip_constant_and_silence() {
+        let mut samples = vec![0; 3 * BLOCKSIZE];
+        samples.extend(vec![-1234; 3 * BLOCKSIZE]);
+        roundtrip_samples("constant", SampleFormat::S16LE, 3, &samples);
+    }
+
+    #[test]
+    fn flac_roundtrip_block_boundaries() {
+        for frames in [
+            1,
+            2,
+            5,
+            BLOCKSIZE - 1,
+            BLOCKSIZE,
+            BLOCKSIZE + 1,
+            3 * BLOCKSIZE,
+        ] {
+            let name = format!("frames{}", frames);
+            roundtrip_samples(&name, SampleFormat::S16LE, 1, &noise(frames, 12));
+        }
+    }
+
+    #[test]
+    fn flac_roundtrip_channels() {
+        for channels in 1..=8 {
+            let name = format!("channels{}", channels);
+            let samples: Vec<i32> = (0..channels * 2000)
+                .map(|n| {
+                    (((n / channels) as f32 * 0.01 * (n % channels + 1) as f32).sin() * 8000000.0)
+                        as i32
+                })
+                .collect();
+            roundtrip_samples(&name, SampleFormat::S24LE3, channels, &samples);
+        }
+        let filename = test_filename("channels9");
+        assert!(FlacEncoder::create(&filename, 9, 44100, &SampleFormat::S16LE).is_err());
+        assert!(FlacEncoder::create(&filename, 0, 44100, &SampleFormat::S16LE).is_err());
+    }
+
+    #[test]
+    fn flac_roundtrip_many_frames() {
+        // Frame numbers of 128 and above are coded with more than one byte
+        let samples = noise(300 * BLOCKSIZE, 8);
+        roundtrip_samples("many_frames", SampleFormat::S16LE, 1, &samples);
+    }
+}
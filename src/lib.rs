@@ -29,7 +29,6 @@ extern crate num_traits;
 extern crate rand;
 extern crate rand_distr;
 extern crate rawsample;
-#[cfg(not(feature = "FFTW"))]
 extern crate realfft;
 extern crate rubato;
 extern crate serde;
@@ -46,9 +45,11 @@ extern crate wasapi;
 extern crate log;
 
 use serde::Serialize;
+use std::collections::HashMap;
 use std::error;
 use std::fmt;
 use std::sync::{Arc, RwLock};
+use std::time::Instant;
 
 // Sample format
 #[cfg(feature = "32bit")]
@@ -81,37 +82,49 @@ pub mod coreaudiodevice;
 pub mod countertimer;
 #[cfg(feature = "cpal-backend")]
 pub mod cpaldevice;
+pub mod crossfeed;
 pub mod diffeq;
 pub mod dither;
-#[cfg(not(feature = "FFTW"))]
+pub mod dynamiceq;
 pub mod fftconv;
 #[cfg(feature = "FFTW")]
 pub mod fftconv_fftw;
 pub mod fifoqueue;
 pub mod filedevice;
-#[cfg(not(target_os = "linux"))]
 pub mod filereader;
+pub mod filereader_loop;
 #[cfg(target_os = "linux")]
 pub mod filereader_nonblock;
 pub mod filters;
+pub mod flacfile;
 pub mod helpers;
 pub mod loudness;
+pub mod minidsp;
 pub mod mixer;
+pub mod multidevice;
+pub mod peqfit;
 pub mod processing;
 #[cfg(feature = "pulse-backend")]
 pub mod pulsedevice;
+pub mod selftest;
+#[cfg(feature = "websocket")]
+pub mod snapshots;
 #[cfg(feature = "websocket")]
 pub mod socketserver;
+pub mod spectrum;
 #[cfg(target_os = "windows")]
 pub mod wasapidevice;
+pub mod wavfile;
 
 pub enum StatusMessage {
     PlaybackReady,
     CaptureReady,
+    ProcessingReady,
     PlaybackError(String),
     CaptureError(String),
     PlaybackFormatChange(usize),
     CaptureFormatChange(usize),
+    ProcessingError(String),
     PlaybackDone,
     CaptureDone,
     SetSpeed(f64),
@@ -153,32 +166,117 @@ impl ExitRequest {
 pub struct CaptureStatus {
     pub update_interval: usize,
     pub measured_samplerate: usize,
+    /// Factor of the exponential smoothing of the reported measured samplerate, 0 means no smoothing.
+    pub measured_rate_smoothing: f32,
+    /// The smoothed measured samplerate, before it is rounded for reporting.
+    pub smoothed_samplerate: Option<f64>,
+    /// The last measured samplerate, without smoothing or rounding.
+    pub raw_samplerate: f64,
     pub signal_range: f32,
     pub signal_rms: Vec<f32>,
     pub signal_peak: Vec<f32>,
     pub state: ProcessingState,
     pub rate_adjust: f32,
+    /// When set, new rate adjust values are not passed on to the capture device,
+    /// so that the resampler keeps its current ratio.
+    pub rate_adjust_frozen: bool,
     pub used_channels: Vec<bool>,
+    pub meter_used_only: bool,
+    pub clock_source: config::ClockSource,
+    pub channel_labels: Vec<String>,
+    /// Number of status updates made by the capture device, counting the samplerate measurements.
+    pub status_updates: usize,
+}
+
+impl CaptureStatus {
+    /// Update the reported samplerate from a new measurement, with the smoothing given by `measured_rate_smoothing`.
+    /// The rate change detection of the capture devices uses the raw measurements, and is not affected.
+    pub fn update_measured_samplerate(&mut self, measured_rate: f64) {
+        let smoothing = self.measured_rate_smoothing as f64;
+        let smoothed = match self.smoothed_samplerate {
+            Some(previous) => smoothing * previous + (1.0 - smoothing) * measured_rate,
+            None => measured_rate,
+        };
+        self.smoothed_samplerate = Some(smoothed);
+        self.measured_samplerate = smoothed as usize;
+        self.raw_samplerate = measured_rate;
+        self.status_updates = self.status_updates.wrapping_add(1);
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct PlaybackStatus {
     pub update_interval: usize,
     pub clipped_samples: usize,
+    pub clipping_active: bool,
     pub buffer_level: usize,
     pub signal_rms: Vec<f32>,
     pub signal_peak: Vec<f32>,
+    pub output_file: Option<String>,
+    pub channel_labels: Vec<String>,
 }
 
+// Allowed range for the volume setting in dB
+pub const MIN_VOLUME_DB: f32 = -120.0;
+pub const MAX_VOLUME_DB: f32 = 20.0;
+
 #[derive(Clone, Debug)]
 pub struct ProcessingParameters {
     pub volume: f32,
+    pub gain_offset: f32,
     pub mute: bool,
+    pub paused: bool,
+    pub test_tone: Option<processing::TestTone>,
+    pub latency_measurement: Option<processing::LatencyRequest>,
+    pub filter_responses: Vec<processing::FilterResponseRequest>,
+    pub spectrum_requests: Vec<processing::SpectrumRequest>,
+    pub dynamic_eq_offsets: HashMap<String, f32>,
+    pub loudness_boosts: HashMap<String, loudness::LoudnessBoost>,
+    pub mixer_attenuations: HashMap<String, Vec<f32>>,
+    pub agc_gain: Option<f32>,
+    pub processing_histogram: countertimer::ProcessingHistogram,
+    pub profile_filters: bool,
+    pub filter_times: HashMap<String, countertimer::FilterTime>,
+    pub volume_ramp: Option<VolumeRamp>,
+    pub flush_filters: bool,
+}
+
+/// A ramp time that replaces the configured one of the Volume and Loudness filters,
+/// for a single volume change to the given target volume.
+/// It is cleared by the processing loop once the filters have processed a chunk with it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VolumeRamp {
+    pub target: f32,
+    pub ramp_ms: f32,
+}
+
+impl ProcessingParameters {
+    /// Get the volume for the Volume and Loudness filters, including the temporary gain offset.
+    /// The sum is limited to the allowed volume range.
+    pub fn effective_volume(&self) -> f32 {
+        (self.volume + self.gain_offset).clamp(MIN_VOLUME_DB, MAX_VOLUME_DB)
+    }
+
+    /// Get the ramp time to use instead of the configured one, for a change to the given effective volume.
+    pub fn ramp_time_override(&self, effective_volume: f32) -> Option<f32> {
+        self.volume_ramp
+            .filter(|ramp| {
+                let target = (ramp.target + self.gain_offset).clamp(MIN_VOLUME_DB, MAX_VOLUME_DB);
+                (target - effective_volume).abs() <= 0.01
+            })
+            .map(|ramp| ramp.ramp_ms)
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct ProcessingStatus {
     pub stop_reason: StopReason,
+    pub overload_reductions: usize,
+    pub start_time: Instant,
+    pub error_restarts: usize,
+    pub config_restarts: usize,
+    /// The kind of config change, "Devices" or "ChannelCount", that caused the last restart for a new config.
+    pub config_restart_reason: Option<String>,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize)]
@@ -187,11 +285,19 @@ pub enum StopReason {
     Done,
     CaptureError(String),
     PlaybackError(String),
+    ProcessingError(String),
     UnknownError(String),
     CaptureFormatChange(usize),
     PlaybackFormatChange(usize),
 }
 
+impl StopReason {
+    /// Check if processing was stopped by an error or an external format change.
+    pub fn is_error(&self) -> bool {
+        !matches!(self, StopReason::None | StopReason::Done)
+    }
+}
+
 #[derive(Clone)]
 pub struct StatusStructs {
     pub capture: Arc<RwLock<CaptureStatus>>,
@@ -213,6 +319,38 @@ impl fmt::Display for ProcessingState {
     }
 }
 
+pub fn list_features() -> Vec<String> {
+    let mut features = Vec::new();
+    if cfg!(feature = "pulse-backend") {
+        features.push("pulse-backend".to_owned());
+    }
+    if cfg!(feature = "cpal-backend") {
+        features.push("cpal-backend".to_owned());
+    }
+    if cfg!(feature = "jack-backend") {
+        features.push("jack-backend".to_owned());
+    }
+    if cfg!(feature = "websocket") {
+        features.push("websocket".to_owned());
+    }
+    if cfg!(feature = "secure-websocket") {
+        features.push("secure-websocket".to_owned());
+    }
+    if cfg!(feature = "FFTW") {
+        features.push("FFTW".to_owned());
+    }
+    if cfg!(feature = "32bit") {
+        features.push("32bit".to_owned());
+    }
+    if cfg!(feature = "neon") {
+        features.push("neon".to_owned());
+    }
+    if cfg!(feature = "debug") {
+        features.push("debug".to_owned());
+    }
+    features
+}
+
 pub fn list_supported_devices() -> (Vec<String>, Vec<String>) {
     let mut playbacktypes = vec!["File".to_owned(), "Stdout".to_owned()];
     let mut capturetypes = vec!["File".to_owned(), "Stdin".to_owned()];
@@ -1,6 +1,9 @@
+use crate::basicfilters::{dither_gain_ramp, get_ramp_chunks};
 use crate::biquad;
 use crate::config;
 use crate::filters::Filter;
+use num_complex::Complex;
+use serde::Serialize;
 use std::sync::{Arc, RwLock};
 
 use crate::NewValue;
@@ -8,13 +11,29 @@ use crate::PrcFmt;
 use crate::ProcessingParameters;
 use crate::Res;
 
+// Corner frequencies of the shelving filters
+const HIGH_SHELF_FREQ: PrcFmt = 3500.0;
+const LOW_SHELF_FREQ: PrcFmt = 70.0;
+
+/// The loudness correction currently applied by a Loudness filter.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LoudnessBoost {
+    pub rel_boost: f32,
+    pub high_gain: f32,
+    pub low_gain: f32,
+    pub high_freq: f32,
+    pub low_freq: f32,
+}
+
 pub struct Loudness {
     pub name: String,
     ramptime_in_chunks: usize,
+    ramp_chunks: usize,
     current_volume: PrcFmt,
     target_volume: f32,
     target_linear_gain: PrcFmt,
     mute: bool,
+    gain_dither: bool,
     ramp_start: PrcFmt,
     ramp_step: usize,
     samplerate: usize,
@@ -23,6 +42,7 @@ pub struct Loudness {
     reference_level: f32,
     high_boost: f32,
     low_boost: f32,
+    max_boost: Option<f32>,
     high_biquad: biquad::Biquad,
     low_biquad: biquad::Biquad,
 }
@@ -37,6 +57,15 @@ fn get_rel_boost(level: f32, reference: f32) -> f32 {
     rel_boost
 }
 
+/// Scale a shelf boost by the relative boost, and limit it to the max boost if one is given.
+fn get_shelf_gain(rel_boost: f32, boost: f32, max_boost: Option<f32>) -> f32 {
+    let gain = rel_boost * boost;
+    match max_boost {
+        Some(max) => gain.min(max),
+        None => gain,
+    }
+}
+
 impl Loudness {
     pub fn from_config(
         name: String,
@@ -45,7 +74,7 @@ impl Loudness {
         samplerate: usize,
         processing_status: Arc<RwLock<ProcessingParameters>>,
     ) -> Self {
-        let current_volume = processing_status.read().unwrap().volume;
+        let current_volume = processing_status.read().unwrap().effective_volume();
         let mute = processing_status.read().unwrap().mute;
         let current_volume_with_mute = if mute { -100.0 } else { current_volume };
         let target_linear_gain = if mute {
@@ -54,18 +83,17 @@ impl Loudness {
             let tempgain: PrcFmt = 10.0;
             tempgain.powf(current_volume as PrcFmt / 20.0)
         };
-        let ramptime_in_chunks =
-            (conf.ramp_time / (1000.0 * chunksize as f32 / samplerate as f32)).round() as usize;
+        let ramptime_in_chunks = get_ramp_chunks(conf.ramp_time, chunksize, samplerate);
         let relboost = get_rel_boost(current_volume, conf.reference_level);
         let highshelf_conf = config::BiquadParameters::Highshelf(config::ShelfSteepness::Slope {
-            freq: 3500.0,
+            freq: HIGH_SHELF_FREQ,
             slope: 12.0,
-            gain: (relboost * conf.high_boost) as PrcFmt,
+            gain: get_shelf_gain(relboost, conf.high_boost, conf.max_boost_db) as PrcFmt,
         });
         let lowshelf_conf = config::BiquadParameters::Lowshelf(config::ShelfSteepness::Slope {
-            freq: 70.0,
+            freq: LOW_SHELF_FREQ,
             slope: 12.0,
-            gain: (relboost * conf.low_boost) as PrcFmt,
+            gain: get_shelf_gain(relboost, conf.low_boost, conf.max_boost_db) as PrcFmt,
         });
         let high_biquad_coeffs =
             biquad::BiquadCoefficients::from_config(samplerate, highshelf_conf);
@@ -76,14 +104,17 @@ impl Loudness {
         Loudness {
             name,
             ramptime_in_chunks,
+            ramp_chunks: ramptime_in_chunks,
             current_volume: current_volume_with_mute as PrcFmt,
             ramp_start: current_volume as PrcFmt,
             target_volume: current_volume as f32,
             target_linear_gain,
             mute,
+            gain_dither: conf.gain_dither,
             reference_level: conf.reference_level,
             high_boost: conf.high_boost,
             low_boost: conf.low_boost,
+            max_boost: conf.max_boost_db,
             high_biquad,
             low_biquad,
             ramp_step: 0,
@@ -100,8 +131,7 @@ impl Loudness {
             self.target_volume
         };
 
-        let ramprange =
-            (target_volume as PrcFmt - self.ramp_start) / self.ramptime_in_chunks as PrcFmt;
+        let ramprange = (target_volume as PrcFmt - self.ramp_start) / self.ramp_chunks as PrcFmt;
         let stepsize = ramprange / self.chunksize as PrcFmt;
         (0..self.chunksize)
             .map(|val| {
@@ -122,12 +152,23 @@ impl Filter for Loudness {
     }
 
     fn process_waveform(&mut self, waveform: &mut [PrcFmt]) -> Res<()> {
-        let shared_vol = self.processing_status.read().unwrap().volume;
+        let shared_vol = self.processing_status.read().unwrap().effective_volume();
         let shared_mute = self.processing_status.read().unwrap().mute;
 
         // Volume setting changed
         if (shared_vol - self.target_volume).abs() > 0.01 || self.mute != shared_mute {
-            if self.ramptime_in_chunks > 0 {
+            // A ramp time given together with the new volume is only used for this change
+            let ramp_override = self
+                .processing_status
+                .read()
+                .unwrap()
+                .ramp_time_override(shared_vol)
+                .filter(|_| (shared_vol - self.target_volume).abs() > 0.01);
+            self.ramp_chunks = match ramp_override {
+                Some(ramp_ms) => get_ramp_chunks(ramp_ms, self.chunksize, self.samplerate),
+                None => self.ramptime_in_chunks,
+            };
+            if self.ramp_chunks > 0 {
                 trace!(
                     "starting ramp: {} -> {}, mute: {}",
                     self.current_volume,
@@ -167,18 +208,22 @@ impl Filter for Loudness {
             }
         }
         // Ramping
-        else if self.ramp_step <= self.ramptime_in_chunks {
+        else if self.ramp_step <= self.ramp_chunks {
             trace!("ramp step {}", self.ramp_step);
-            let ramp = self.make_ramp();
+            let mut ramp = self.make_ramp();
+            let last_gain = *ramp.last().unwrap();
+            if self.gain_dither {
+                dither_gain_ramp(&mut ramp);
+            }
             self.ramp_step += 1;
-            if self.ramp_step > self.ramptime_in_chunks {
+            if self.ramp_step > self.ramp_chunks {
                 // Last step of ramp
                 self.ramp_step = 0;
             }
             for (item, stepgain) in waveform.iter_mut().zip(ramp.iter()) {
                 *item *= *stepgain;
             }
-            self.current_volume = 20.0 * ramp.last().unwrap().log10();
+            self.current_volume = 20.0 * last_gain.log10();
             let relboost = get_rel_boost(self.current_volume as f32, self.reference_level);
             trace!(
                 "Updating loudness biquads, relative boost {}%",
@@ -186,14 +231,14 @@ impl Filter for Loudness {
             );
             let highshelf_conf =
                 config::BiquadParameters::Highshelf(config::ShelfSteepness::Slope {
-                    freq: 3500.0,
+                    freq: HIGH_SHELF_FREQ,
                     slope: 12.0,
-                    gain: (relboost * self.high_boost) as PrcFmt,
+                    gain: get_shelf_gain(relboost, self.high_boost, self.max_boost) as PrcFmt,
                 });
             let lowshelf_conf = config::BiquadParameters::Lowshelf(config::ShelfSteepness::Slope {
-                freq: 70.0,
+                freq: LOW_SHELF_FREQ,
                 slope: 12.0,
-                gain: (relboost * self.low_boost) as PrcFmt,
+                gain: get_shelf_gain(relboost, self.low_boost, self.max_boost) as PrcFmt,
             });
             self.high_biquad.update_parameters(config::Filter::Biquad {
                 parameters: highshelf_conf,
@@ -202,7 +247,8 @@ impl Filter for Loudness {
                 parameters: lowshelf_conf,
             });
         }
-        if get_rel_boost(self.current_volume as f32, self.reference_level) > 0.0 {
+        let relboost = get_rel_boost(self.current_volume as f32, self.reference_level);
+        if relboost > 0.0 {
             trace!("Applying loudness biquads");
             self.high_biquad.process_waveform(waveform).unwrap();
             self.low_biquad.process_waveform(waveform).unwrap();
@@ -210,23 +256,45 @@ impl Filter for Loudness {
         Ok(())
     }
 
+    fn reset(&mut self) {
+        self.high_biquad.reset();
+        self.low_biquad.reset();
+    }
+
+    fn publish_status(&self, status: &mut ProcessingParameters) {
+        let relboost = get_rel_boost(self.current_volume as f32, self.reference_level);
+        let boost = LoudnessBoost {
+            rel_boost: relboost,
+            high_gain: get_shelf_gain(relboost, self.high_boost, self.max_boost),
+            low_gain: get_shelf_gain(relboost, self.low_boost, self.max_boost),
+            high_freq: HIGH_SHELF_FREQ as f32,
+            low_freq: LOW_SHELF_FREQ as f32,
+        };
+        match status.loudness_boosts.get_mut(&self.name) {
+            Some(value) => *value = boost,
+            None => {
+                status.loudness_boosts.insert(self.name.clone(), boost);
+            }
+        }
+    }
+
     fn update_parameters(&mut self, conf: config::Filter) {
         if let config::Filter::Loudness { parameters: conf } = conf {
-            self.ramptime_in_chunks = (conf.ramp_time
-                / (1000.0 * self.chunksize as f32 / self.samplerate as f32))
-                .round() as usize;
-            let current_volume = self.processing_status.read().unwrap().volume;
+            self.ramptime_in_chunks =
+                get_ramp_chunks(conf.ramp_time, self.chunksize, self.samplerate);
+            self.gain_dither = conf.gain_dither;
+            let current_volume = self.processing_status.read().unwrap().effective_volume();
             let relboost = get_rel_boost(current_volume, conf.reference_level);
             let highshelf_conf =
                 config::BiquadParameters::Highshelf(config::ShelfSteepness::Slope {
-                    freq: 3500.0,
+                    freq: HIGH_SHELF_FREQ,
                     slope: 12.0,
-                    gain: (relboost * conf.high_boost) as PrcFmt,
+                    gain: get_shelf_gain(relboost, conf.high_boost, conf.max_boost_db) as PrcFmt,
                 });
             let lowshelf_conf = config::BiquadParameters::Lowshelf(config::ShelfSteepness::Slope {
-                freq: 70.0,
+                freq: LOW_SHELF_FREQ,
                 slope: 12.0,
-                gain: (relboost * conf.low_boost) as PrcFmt,
+                gain: get_shelf_gain(relboost, conf.low_boost, conf.max_boost_db) as PrcFmt,
             });
             self.high_biquad.update_parameters(config::Filter::Biquad {
                 parameters: highshelf_conf,
@@ -237,11 +305,39 @@ impl Filter for Loudness {
             self.reference_level = conf.reference_level;
             self.high_boost = conf.high_boost;
             self.low_boost = conf.low_boost;
+            self.max_boost = conf.max_boost_db;
         } else {
             // This should never happen unless there is a bug somewhere else
             panic!("Invalid config change!");
         }
     }
+
+    /// The response is the current gain, following the volume ramp,
+    /// together with the shelving filters when they are applied.
+    fn frequency_response(
+        &self,
+        frequencies: &[PrcFmt],
+        _samplerate: usize,
+    ) -> Option<Vec<Complex<PrcFmt>>> {
+        let gain = if self.ramp_step == 0 {
+            self.target_linear_gain
+        } else {
+            (10.0 as PrcFmt).powf(self.current_volume / 20.0)
+        };
+        let boosted = get_rel_boost(self.current_volume as f32, self.reference_level) > 0.0;
+        Some(
+            frequencies
+                .iter()
+                .map(|freq| {
+                    if boosted {
+                        gain * self.high_biquad.response(*freq) * self.low_biquad.response(*freq)
+                    } else {
+                        Complex::new(gain, 0.0)
+                    }
+                })
+                .collect(),
+        )
+    }
 }
 
 /// Validate a Loudness config.
@@ -258,8 +354,113 @@ pub fn validate_config(conf: &config::LoudnessParameters) -> Res<()> {
         return Err(config::ConfigError::new("High boost cannot be larger than 20").into());
     } else if conf.low_boost > 20.0 {
         return Err(config::ConfigError::new("Low boost cannot be larger than 20").into());
+    } else if conf.max_boost_db.map(|max| max < 0.0).unwrap_or(false) {
+        return Err(config::ConfigError::new("Max boost cannot be less than 0").into());
     } else if conf.ramp_time < 0.0 {
         return Err(config::ConfigError::new("Ramp time cannot be negative").into());
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Filter, LoudnessParameters};
+    use crate::countertimer;
+    use crate::filters::Filter as _;
+    use crate::loudness::{get_shelf_gain, validate_config, Loudness};
+    use crate::ProcessingParameters;
+    use std::collections::HashMap;
+    use std::sync::{Arc, RwLock};
+
+    fn status_with_volume(volume: f32) -> Arc<RwLock<ProcessingParameters>> {
+        Arc::new(RwLock::new(ProcessingParameters {
+            volume,
+            gain_offset: 0.0,
+            mute: false,
+            paused: false,
+            test_tone: None,
+            latency_measurement: None,
+            filter_responses: Vec::new(),
+            spectrum_requests: Vec::new(),
+            dynamic_eq_offsets: HashMap::new(),
+            loudness_boosts: HashMap::new(),
+            mixer_attenuations: HashMap::new(),
+            agc_gain: None,
+            processing_histogram: countertimer::ProcessingHistogram::new(),
+            profile_filters: false,
+            filter_times: HashMap::new(),
+            volume_ramp: None,
+            flush_filters: false,
+        }))
+    }
+
+    fn loudness_params(max_boost_db: Option<f32>) -> LoudnessParameters {
+        LoudnessParameters {
+            ramp_time: 0.0,
+            reference_level: -10.0,
+            high_boost: 10.0,
+            low_boost: 6.0,
+            max_boost_db,
+            gain_dither: false,
+        }
+    }
+
+    #[test]
+    fn shelf_gain_clamped() {
+        assert_eq!(get_shelf_gain(1.0, 10.0, None), 10.0);
+        assert_eq!(get_shelf_gain(1.0, 10.0, Some(4.0)), 4.0);
+        assert_eq!(get_shelf_gain(0.5, 10.0, Some(8.0)), 5.0);
+        assert_eq!(get_shelf_gain(0.0, 10.0, Some(4.0)), 0.0);
+        assert_eq!(get_shelf_gain(1.0, 10.0, Some(0.0)), 0.0);
+    }
+
+    #[test]
+    fn published_boost_clamped() {
+        // Full boost, the high shelf is clamped but the low shelf is below the limit.
+        let status = status_with_volume(-40.0);
+        let loudness = Loudness::from_config(
+            "loud".to_string(),
+            loudness_params(Some(8.0)),
+            1024,
+            44100,
+            status.clone(),
+        );
+        let mut params = status.write().unwrap();
+        loudness.publish_status(&mut params);
+        let boost = &params.loudness_boosts["loud"];
+        assert_eq!(boost.rel_boost, 1.0);
+        assert_eq!(boost.high_gain, 8.0);
+        assert_eq!(boost.low_gain, 6.0);
+    }
+
+    #[test]
+    fn updated_max_boost_applied() {
+        let status = status_with_volume(-40.0);
+        let mut loudness = Loudness::from_config(
+            "loud".to_string(),
+            loudness_params(None),
+            1024,
+            44100,
+            status.clone(),
+        );
+        loudness.publish_status(&mut status.write().unwrap());
+        assert_eq!(
+            status.read().unwrap().loudness_boosts["loud"].high_gain,
+            10.0
+        );
+        loudness.update_parameters(Filter::Loudness {
+            parameters: loudness_params(Some(3.0)),
+        });
+        loudness.publish_status(&mut status.write().unwrap());
+        let boost = &status.read().unwrap().loudness_boosts["loud"];
+        assert_eq!(boost.high_gain, 3.0);
+        assert_eq!(boost.low_gain, 3.0);
+    }
+
+    #[test]
+    fn negative_max_boost_rejected() {
+        assert!(validate_config(&loudness_params(Some(-1.0))).is_err());
+        assert!(validate_config(&loudness_params(Some(0.0))).is_ok());
+        assert!(validate_config(&loudness_params(None)).is_ok());
+    }
+}
@@ -0,0 +1,548 @@
+use std::collections::HashMap;
+use std::fs;
+
+use crate::config;
+use crate::filters;
+use crate::PrcFmt;
+use crate::Res;
+
+/// An element of an XML document, with its attributes, child elements and text content.
+#[derive(Debug, Default, PartialEq)]
+struct Element {
+    name: String,
+    attributes: Vec<(String, String)>,
+    children: Vec<Element>,
+    text: String,
+}
+
+impl Element {
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.trim())
+    }
+
+    fn child_text(&self, name: &str) -> Option<&str> {
+        self.children
+            .iter()
+            .find(|child| child.name == name)
+            .map(|child| child.text.trim())
+    }
+}
+
+fn xml_error(msg: &str, pos: usize) -> Box<dyn std::error::Error> {
+    let msg = format!("Invalid XML at byte {}: {}", pos, msg);
+    config::ConfigError::new(&msg).into()
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// A minimal reader for the subset of XML used by miniDSP exports.
+/// Elements, attributes, text, comments and CDATA are supported,
+/// while declarations and processing instructions are skipped.
+struct XmlReader<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> XmlReader<'a> {
+    fn rest(&self) -> &'a str {
+        &self.text[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    /// Move past the next occurrence of `end`, returning the text before it.
+    fn read_until(&mut self, end: &str) -> Res<&'a str> {
+        let rest = self.rest();
+        match rest.find(end) {
+            Some(idx) => {
+                self.pos += idx + end.len();
+                Ok(&rest[..idx])
+            }
+            None => Err(xml_error(&format!("missing '{}'", end), self.pos)),
+        }
+    }
+
+    fn expect(&mut self, token: &str) -> Res<()> {
+        if self.rest().starts_with(token) {
+            self.pos += token.len();
+            Ok(())
+        } else {
+            Err(xml_error(&format!("expected '{}'", token), self.pos))
+        }
+    }
+
+    fn read_name(&mut self) -> Res<&'a str> {
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| c.is_whitespace() || "/>=".contains(c))
+            .unwrap_or(rest.len());
+        if len == 0 {
+            return Err(xml_error("expected a name", self.pos));
+        }
+        self.pos += len;
+        Ok(&rest[..len])
+    }
+
+    /// Skip a comment, declaration or processing instruction, if there is one at the current position.
+    fn skip_markup(&mut self) -> Res<bool> {
+        let rest = self.rest();
+        if rest.starts_with("<!--") {
+            self.read_until("-->")?;
+        } else if rest.starts_with("<?") {
+            self.read_until("?>")?;
+        } else if rest.starts_with("<!") && !rest.starts_with("<![CDATA[") {
+            self.read_until(">")?;
+        } else {
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    fn read_element(&mut self) -> Res<Element> {
+        self.expect("<")?;
+        let mut element = Element {
+            name: self.read_name()?.to_string(),
+            ..Default::default()
+        };
+        loop {
+            self.skip_whitespace();
+            if self.rest().starts_with("/>") {
+                self.pos += 2;
+                return Ok(element);
+            }
+            if self.rest().starts_with('>') {
+                self.pos += 1;
+                break;
+            }
+            let key = self.read_name()?.to_string();
+            self.skip_whitespace();
+            self.expect("=")?;
+            self.skip_whitespace();
+            let quote = if self.rest().starts_with('\'') {
+                "'"
+            } else {
+                "\""
+            };
+            self.expect(quote)?;
+            let value = unescape(self.read_until(quote)?);
+            element.attributes.push((key, value));
+        }
+        loop {
+            let rest = self.rest();
+            if rest.is_empty() {
+                let msg = format!("element <{}> is not closed", element.name);
+                return Err(xml_error(&msg, self.pos));
+            } else if rest.starts_with("</") {
+                self.pos += 2;
+                let name = self.read_name()?;
+                if name != element.name {
+                    let msg = format!("<{}> closed by </{}>", element.name, name);
+                    return Err(xml_error(&msg, self.pos));
+                }
+                self.skip_whitespace();
+                self.expect(">")?;
+                return Ok(element);
+            } else if rest.starts_with("<![CDATA[") {
+                self.pos += 9;
+                let data = self.read_until("]]>")?;
+                element.text.push_str(data);
+            } else if self.skip_markup()? {
+                continue;
+            } else if rest.starts_with('<') {
+                element.children.push(self.read_element()?);
+            } else {
+                let len = rest.find('<').unwrap_or(rest.len());
+                element.text.push_str(&unescape(&rest[..len]));
+                self.pos += len;
+            }
+        }
+    }
+}
+
+/// Parse an XML document and return its root element.
+fn parse_xml(text: &str) -> Res<Element> {
+    let mut reader = XmlReader { text, pos: 0 };
+    reader.skip_whitespace();
+    while reader.skip_markup()? {
+        reader.skip_whitespace();
+    }
+    let root = reader.read_element()?;
+    reader.skip_whitespace();
+    while reader.skip_markup()? {
+        reader.skip_whitespace();
+    }
+    if !reader.rest().is_empty() {
+        return Err(xml_error(
+            "unexpected content after the root element",
+            reader.pos,
+        ));
+    }
+    Ok(root)
+}
+
+/// Result of converting a miniDSP export.
+#[derive(Debug)]
+pub struct MinidspImport {
+    pub config: config::Configuration,
+    /// Descriptions of the elements that could not be converted and were left out.
+    pub unsupported: Vec<String>,
+}
+
+/// The settings found for one output channel.
+#[derive(Debug, Default)]
+struct ChannelSettings {
+    filters: Vec<(String, config::Filter)>,
+    gain: PrcFmt,
+    inverted: bool,
+    mute: bool,
+    delay: PrcFmt,
+}
+
+/// Split a name like `PEQ_2_5` into its prefix and numbers.
+fn split_name(name: &str) -> Option<(&str, Vec<usize>)> {
+    let mut parts = name.split('_');
+    let prefix = parts.next()?;
+    let numbers = parts
+        .map(|part| part.parse::<usize>().ok())
+        .collect::<Option<Vec<usize>>>()?;
+    Some((prefix, numbers))
+}
+
+fn number(element: &Element, name: &str) -> Result<PrcFmt, String> {
+    element
+        .child_text(name)
+        .or_else(|| element.attribute(name))
+        .and_then(|value| value.parse::<PrcFmt>().ok())
+        .filter(|value| value.is_finite())
+        .ok_or(format!("missing or invalid '{}'", name))
+}
+
+fn is_bypassed(element: &Element) -> bool {
+    element
+        .child_text("bypass")
+        .map_or(false, |value| value != "0")
+}
+
+/// Convert a PEQ band. Parametric types are converted to the matching biquad,
+/// other types fall back to the coefficients in `dec` if available.
+/// These are listed as b0, b1, b2, a1, a2, with a1 and a2 negated as usual for miniDSP.
+fn convert_peq(element: &Element) -> Result<config::BiquadParameters, String> {
+    let filter_type = element.child_text("type").unwrap_or("");
+    match filter_type {
+        "PK" => Ok(config::BiquadParameters::Peaking(config::PeakingWidth::Q {
+            freq: number(element, "freq")?,
+            q: number(element, "q")?,
+            gain: number(element, "boost")?,
+        })),
+        "SH_LO" | "LS" => Ok(config::BiquadParameters::Lowshelf(
+            config::ShelfSteepness::Q {
+                freq: number(element, "freq")?,
+                q: number(element, "q")?,
+                gain: number(element, "boost")?,
+            },
+        )),
+        "SH_HI" | "HS" => Ok(config::BiquadParameters::Highshelf(
+            config::ShelfSteepness::Q {
+                freq: number(element, "freq")?,
+                q: number(element, "q")?,
+                gain: number(element, "boost")?,
+            },
+        )),
+        _ => {
+            let coeffs = element.child_text("dec").and_then(|dec| {
+                dec.split(',')
+                    .map(|value| value.trim().parse::<PrcFmt>().ok())
+                    .collect::<Option<Vec<PrcFmt>>>()
+            });
+            match coeffs.as_deref() {
+                Some([b0, b1, b2, a1, a2]) => Ok(config::BiquadParameters::Free {
+                    a1: -a1,
+                    a2: -a2,
+                    b0: *b0,
+                    b1: *b1,
+                    b2: *b2,
+                }),
+                _ => Err(format!(
+                    "unsupported type '{}' without coefficients",
+                    filter_type
+                )),
+            }
+        }
+    }
+}
+
+/// Convert a crossover high- or lowpass, with the slope given in dB per octave.
+fn convert_crossover(element: &Element) -> Result<config::PassFilterParameters, String> {
+    let filter_type = match element.child_text("type").unwrap_or("") {
+        "BW" => config::PassFilterType::Butterworth,
+        "LR" => config::PassFilterType::LinkwitzRiley,
+        "BS" => config::PassFilterType::Bessel,
+        other => return Err(format!("unsupported type '{}'", other)),
+    };
+    let slope = number(element, "slope")?;
+    if slope <= 0.0 || slope % 6.0 != 0.0 {
+        return Err(format!("slope of {} dB/oct is not a multiple of 6", slope));
+    }
+    Ok(config::PassFilterParameters {
+        freq: number(element, "freq")?,
+        order: (slope / 6.0) as usize,
+        filter_type,
+    })
+}
+
+/// Convert one element of the export, adding the result to the settings of its channel.
+fn convert_element(
+    element: &Element,
+    channels: &mut HashMap<usize, ChannelSettings>,
+) -> Result<(), String> {
+    let name = element.attribute("name").unwrap_or("");
+    let (prefix, numbers) = match split_name(name) {
+        Some((prefix, numbers)) if numbers.first().map_or(false, |ch| *ch > 0) => (prefix, numbers),
+        _ => return Err("no channel number in the name".to_string()),
+    };
+    let settings = channels.entry(numbers[0] - 1).or_default();
+    let filter_name = name.to_lowercase();
+    match (element.name.as_str(), prefix) {
+        ("filter", _) if is_bypassed(element) => {
+            debug!("Skipping bypassed filter '{}'", name);
+        }
+        ("filter", "PEQ") => {
+            let parameters = convert_peq(element)?;
+            settings
+                .filters
+                .push((filter_name, config::Filter::Biquad { parameters }));
+        }
+        ("filter", "HPF") | ("filter", "LPF") => {
+            let parameters = convert_crossover(element)?;
+            let filter = if prefix == "HPF" {
+                config::Filter::Highpass { parameters }
+            } else {
+                config::Filter::Lowpass { parameters }
+            };
+            settings.filters.push((filter_name, filter));
+        }
+        ("item", "Gain") | ("item", "DGain") => settings.gain += number(element, "dec")?,
+        ("item", "Delay") => {
+            let delay = number(element, "dec")?;
+            if delay < 0.0 {
+                return Err("negative delay".to_string());
+            }
+            settings.delay = delay;
+        }
+        ("item", "Polarity") => settings.inverted = number(element, "dec")? != 0.0,
+        ("item", "Mute") => settings.mute = number(element, "dec")? != 0.0,
+        _ => return Err("unknown setting".to_string()),
+    }
+    Ok(())
+}
+
+/// Convert the contents of a miniDSP XML export to a config.
+/// PEQ bands, crossovers, gains, delays in ms, polarity and mute are converted per output channel,
+/// with the channels numbered from 1 in the export. Anything else is listed as unsupported.
+pub fn import_minidsp(xml: &str) -> Res<MinidspImport> {
+    let root = parse_xml(xml)?;
+    let mut channels: HashMap<usize, ChannelSettings> = HashMap::new();
+    let mut unsupported = Vec::new();
+    for element in root.children.iter() {
+        let description = match element.attribute("name") {
+            Some(name) => format!("<{} name=\"{}\">", element.name, name),
+            None => format!("<{}>", element.name),
+        };
+        if element.name != "filter" && element.name != "item" {
+            unsupported.push(format!("{}: unknown element", description));
+        } else if let Err(reason) = convert_element(element, &mut channels) {
+            unsupported.push(format!("{}: {}", description, reason));
+        }
+    }
+    let nbr_channels = match channels.keys().max() {
+        Some(max) => max + 1,
+        None => {
+            let msg = "The miniDSP export contains no supported settings";
+            return Err(config::ConfigError::new(msg).into());
+        }
+    };
+
+    let mut conf = config::default_config_with_channels(nbr_channels);
+    for channel in 0..nbr_channels {
+        let settings = channels.remove(&channel).unwrap_or_default();
+        let mut filters = settings.filters;
+        if settings.gain != 0.0 || settings.inverted || settings.mute {
+            let parameters = config::GainParameters {
+                gain: settings.gain,
+                inverted: settings.inverted,
+                mute: settings.mute,
+            };
+            filters.push((
+                format!("gain_{}", channel + 1),
+                config::Filter::Gain { parameters },
+            ));
+        }
+        if settings.delay > 0.0 {
+            let parameters = config::DelayParameters {
+                delay: settings.delay,
+                unit: config::TimeUnit::Milliseconds,
+                subsample: false,
+            };
+            filters.push((
+                format!("delay_{}", channel + 1),
+                config::Filter::Delay { parameters },
+            ));
+        }
+        let mut names = Vec::new();
+        for (name, filter) in filters {
+            match filters::validate_filter(conf.devices.samplerate, &filter) {
+                Ok(()) => {
+                    names.push(name.clone());
+                    conf.filters.insert(name, filter);
+                }
+                Err(err) => unsupported.push(format!("filter '{}': {}", name, err)),
+            }
+        }
+        if !names.is_empty() {
+            conf.pipeline.push(config::PipelineStep::Filter {
+                channel,
+                names,
+                gain_compensate: false,
+                mix: 1.0,
+            });
+        }
+    }
+    Ok(MinidspImport {
+        config: conf,
+        unsupported,
+    })
+}
+
+/// Read and convert a miniDSP XML export file.
+pub fn import_file(filename: &str) -> Res<MinidspImport> {
+    match fs::read_to_string(filename) {
+        Ok(contents) => import_minidsp(&contents),
+        Err(err) => {
+            let msg = format!(
+                "Could not read miniDSP export '{}'. Error: {}",
+                filename, err
+            );
+            Err(config::ConfigError::new(&msg).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config;
+    use crate::minidsp::{import_minidsp, parse_xml};
+
+    const EXPORT: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<!-- exported settings -->
+<setting version="1.2">
+  <filter name="PEQ_1_1">
+    <freq>1000</freq><q>2</q><boost>-3.5</boost><type>PK</type><bypass>0</bypass>
+  </filter>
+  <filter name="PEQ_1_2">
+    <freq>50</freq><q>1</q><boost>6</boost><type>PK</type><bypass>1</bypass>
+  </filter>
+  <filter name="PEQ_2_1">
+    <type>AP</type><bypass>0</bypass><dec>1.0, 0.5, 0.25, 0.1, -0.2</dec>
+  </filter>
+  <filter name="HPF_2">
+    <freq>80</freq><type>LR</type><slope>24</slope><bypass>0</bypass>
+  </filter>
+  <filter name="LPF_2">
+    <freq>2000</freq><type>XX</type><slope>12</slope><bypass>0</bypass>
+  </filter>
+  <item name="DGain_2_0" dec="-2"/>
+  <item name="Polarity_2" dec="1"/>
+  <item name="Delay_1" dec="1.5"/>
+  <item name="MasterVolume" dec="0"/>
+  <master_status>1</master_status>
+</setting>
+"#;
+
+    #[test]
+    fn parse_elements() {
+        let root =
+            parse_xml("<?xml version=\"1.0\"?><a x='1' y=\"&lt;2&gt;\"><b>t&amp;t</b><c/></a>")
+                .unwrap();
+        assert_eq!(root.name, "a");
+        assert_eq!(root.attribute("y"), Some("<2>"));
+        assert_eq!(root.child_text("b"), Some("t&t"));
+        assert_eq!(root.children.len(), 2);
+        assert!(parse_xml("<a><b></a>").is_err());
+        assert!(parse_xml("<a>").is_err());
+    }
+
+    #[test]
+    fn import_export() {
+        let import = import_minidsp(EXPORT).unwrap();
+        let conf = import.config;
+        assert_eq!(conf.pipeline.len(), 2);
+        assert_eq!(
+            conf.filters.get("peq_1_1"),
+            Some(&config::Filter::Biquad {
+                parameters: config::BiquadParameters::Peaking(config::PeakingWidth::Q {
+                    freq: 1000.0,
+                    q: 2.0,
+                    gain: -3.5
+                })
+            })
+        );
+        assert!(!conf.filters.contains_key("peq_1_2"));
+        assert_eq!(
+            conf.filters.get("peq_2_1"),
+            Some(&config::Filter::Biquad {
+                parameters: config::BiquadParameters::Free {
+                    a1: -0.1,
+                    a2: 0.2,
+                    b0: 1.0,
+                    b1: 0.5,
+                    b2: 0.25
+                }
+            })
+        );
+        assert_eq!(
+            conf.filters.get("gain_2"),
+            Some(&config::Filter::Gain {
+                parameters: config::GainParameters {
+                    gain: -2.0,
+                    inverted: true,
+                    mute: false
+                }
+            })
+        );
+        if let config::PipelineStep::Filter { channel, names, .. } = &conf.pipeline[1] {
+            assert_eq!(*channel, 1);
+            assert_eq!(
+                names,
+                &vec![
+                    "peq_2_1".to_string(),
+                    "hpf_2".to_string(),
+                    "gain_2".to_string()
+                ]
+            );
+        } else {
+            panic!("expected a filter step");
+        }
+        assert_eq!(import.unsupported.len(), 3);
+        assert!(import.unsupported[0].contains("LPF_2"));
+        assert!(import.unsupported[1].contains("MasterVolume"));
+        assert!(import.unsupported[2].contains("master_status"));
+    }
+
+    #[test]
+    fn import_nothing() {
+        assert!(import_minidsp("<setting><name>empty</name></setting>").is_err());
+        assert!(import_minidsp("not xml").is_err());
+    }
+}
@@ -9,6 +9,7 @@ pub struct Mixer {
     pub channels_in: usize,
     pub channels_out: usize,
     pub mapping: Vec<Vec<MixerSource>>,
+    pub attenuation: Vec<PrcFmt>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -17,16 +18,36 @@ pub struct MixerSource {
     pub gain: PrcFmt,
 }
 
+/// Scale the gains of each output so that the sum of their absolute values is at most one,
+/// meaning that the output can't exceed full scale as long as the inputs don't.
+/// Returns the applied attenuation of each output in dB.
+fn attenuate_mapping(mapping: &mut [Vec<MixerSource>]) -> Vec<PrcFmt> {
+    mapping
+        .iter_mut()
+        .map(|sources| {
+            let total_gain: PrcFmt = sources.iter().map(|src| src.gain.abs()).sum();
+            if total_gain > 1.0 {
+                for src in sources.iter_mut() {
+                    src.gain /= total_gain;
+                }
+                -20.0 * total_gain.log10()
+            } else {
+                0.0
+            }
+        })
+        .collect()
+}
+
 impl Mixer {
     /// Creates a Mixer from a config struct
     pub fn from_config(name: String, config: config::Mixer) -> Self {
         let ch_in = config.channels.r#in;
         let ch_out = config.channels.out;
         let mut mapping = vec![Vec::<MixerSource>::new(); ch_out];
-        for cfg_mapping in config.mapping {
+        for cfg_mapping in config.mapping().iter() {
             if !cfg_mapping.mute {
                 let dest = cfg_mapping.dest;
-                for cfg_src in cfg_mapping.sources {
+                for cfg_src in cfg_mapping.sources.iter() {
                     if !cfg_src.mute {
                         let mut gain: PrcFmt = 10.0;
                         gain = gain.powf(cfg_src.gain / 20.0);
@@ -42,11 +63,22 @@ impl Mixer {
                 }
             }
         }
+        let attenuation = if config.auto_attenuate {
+            let attenuation = attenuate_mapping(&mut mapping);
+            info!(
+                "Mixer '{}' attenuates the outputs by {:?} dB to avoid clipping",
+                name, attenuation
+            );
+            attenuation
+        } else {
+            vec![0.0; ch_out]
+        };
         Mixer {
             name,
             channels_in: ch_in,
             channels_out: ch_out,
             mapping,
+            attenuation,
         }
     }
 
@@ -54,9 +86,9 @@ impl Mixer {
         let ch_in = config.channels.r#in;
         let ch_out = config.channels.out;
         let mut mapping = vec![Vec::<MixerSource>::new(); ch_out];
-        for cfg_mapping in config.mapping {
+        for cfg_mapping in config.mapping().iter() {
             let dest = cfg_mapping.dest;
-            for cfg_src in cfg_mapping.sources {
+            for cfg_src in cfg_mapping.sources.iter() {
                 let mut gain: PrcFmt = 10.0;
                 gain = gain.powf(cfg_src.gain / 20.0);
                 if cfg_src.inverted {
@@ -69,6 +101,11 @@ impl Mixer {
                 mapping[dest].push(src);
             }
         }
+        self.attenuation = if config.auto_attenuate {
+            attenuate_mapping(&mut mapping)
+        } else {
+            vec![0.0; ch_out]
+        };
         self.channels_in = ch_in;
         self.channels_out = ch_out;
         self.mapping = mapping;
@@ -96,9 +133,12 @@ impl Mixer {
 
 /// Validate the mixer config, to give a helpful message intead of a panic.
 pub fn validate_mixer(mixer_config: &config::Mixer) -> Res<()> {
+    if mixer_config.mapping.is_none() && mixer_config.downmix.is_none() {
+        return Err(config::ConfigError::new("A mapping or a downmix must be given.").into());
+    }
     let chan_in = mixer_config.channels.r#in;
     let chan_out = mixer_config.channels.out;
-    for mapping in mixer_config.mapping.iter() {
+    for mapping in mixer_config.mapping().iter() {
         if mapping.dest >= chan_out {
             let msg = format!(
                 "Invalid destination channel {}, max is {}.",
@@ -121,11 +161,77 @@ pub fn validate_mixer(mixer_config: &config::Mixer) -> Res<()> {
     Ok(())
 }
 
+/// Build the mapping of a mixer that applies a standard downmix to stereo.
+/// A 7.1 input is first folded to 5.1 by adding each side channel to the back channel on the same side at -3 dB,
+/// and the recipe is then applied to the 5.1 channels. The LFE channel is left out, as in the standards.
+pub fn downmix_mapping(preset: &config::DownmixPreset) -> Res<Vec<config::MixerMapping>> {
+    let half_power = std::f64::consts::FRAC_1_SQRT_2 as PrcFmt;
+    // Surround channels of each side, with their gains
+    let (left_surround, right_surround) = match (preset.layout_in, preset.layout_out) {
+        (config::ChannelLayout::Surround51, config::ChannelLayout::Stereo) => {
+            (vec![(4, 1.0)], vec![(5, 1.0)])
+        }
+        (config::ChannelLayout::Surround71, config::ChannelLayout::Stereo) => (
+            vec![(4, half_power), (6, half_power)],
+            vec![(5, half_power), (7, half_power)],
+        ),
+        _ => {
+            let msg = format!(
+                "Unsupported downmix from {} to {}, only 5.1 and 7.1 to 2.0 are available",
+                preset.layout_in, preset.layout_out
+            );
+            return Err(config::ConfigError::new(&msg).into());
+        }
+    };
+    // Gains of the left and right surround into the left and right outputs
+    let (ls_to_left, rs_to_left, ls_to_right, rs_to_right) = match preset.recipe {
+        // ITU-R BS.775, each surround on its own side at -3 dB
+        config::DownmixRecipe::Itu => (half_power, 0.0, 0.0, half_power),
+        // Dolby Surround, mono surround at -6 dB with opposite polarity in the outputs
+        config::DownmixRecipe::Dolby => (-0.5, -0.5, 0.5, 0.5),
+        // Dolby Pro Logic II, surrounds mixed at different levels to keep them separable
+        config::DownmixRecipe::DolbyPl2 => (
+            -half_power * (0.75 as PrcFmt).sqrt(),
+            -half_power * 0.5,
+            half_power * 0.5,
+            half_power * (0.75 as PrcFmt).sqrt(),
+        ),
+    };
+    let source = |channel: usize, coefficient: PrcFmt| config::MixerSource {
+        channel,
+        gain: 20.0 * coefficient.abs().log10(),
+        inverted: coefficient < 0.0,
+        mute: false,
+    };
+    let mapping = [(0, ls_to_left, rs_to_left), (1, ls_to_right, rs_to_right)]
+        .iter()
+        .map(|(dest, from_ls, from_rs)| {
+            let mut sources = vec![source(*dest, 1.0), source(2, half_power)];
+            for (channel, gain) in left_surround.iter() {
+                if *from_ls != 0.0 {
+                    sources.push(source(*channel, from_ls * gain));
+                }
+            }
+            for (channel, gain) in right_surround.iter() {
+                if *from_rs != 0.0 {
+                    sources.push(source(*channel, from_rs * gain));
+                }
+            }
+            config::MixerMapping {
+                dest: *dest,
+                sources,
+                mute: false,
+            }
+        })
+        .collect();
+    Ok(mapping)
+}
+
 /// Get a vector showing which input channels are used
 pub fn get_used_input_channels(mixer_config: &config::Mixer) -> Vec<bool> {
     let chan_in = mixer_config.channels.r#in;
     let mut used_channels = vec![false; chan_in];
-    for mapping in mixer_config.mapping.iter() {
+    for mapping in mixer_config.mapping().iter() {
         if !mapping.mute {
             for source in mapping.sources.iter() {
                 if !source.mute {
@@ -139,9 +245,10 @@ pub fn get_used_input_channels(mixer_config: &config::Mixer) -> Vec<bool> {
 
 #[cfg(test)]
 mod tests {
-    use crate::config::{Mixer, MixerChannels, MixerMapping, MixerSource};
+    use crate::config::{DownmixPreset, Mixer, MixerChannels, MixerMapping, MixerSource};
     use crate::mixer;
     use crate::mixer::get_used_input_channels;
+    use crate::PrcFmt;
 
     #[test]
     fn check_all_used() {
@@ -192,7 +299,9 @@ mod tests {
         };
         let conf = Mixer {
             channels: chans,
-            mapping: vec![map0, map1, map2, map3],
+            mapping: Some(vec![map0, map1, map2, map3]),
+            auto_attenuate: false,
+            downmix: None,
         };
         let used = get_used_input_channels(&conf);
         assert_eq!(used, vec![true, true]);
@@ -247,7 +356,9 @@ mod tests {
         };
         let conf = Mixer {
             channels: chans,
-            mapping: vec![map0, map1, map2, map3],
+            mapping: Some(vec![map0, map1, map2, map3]),
+            auto_attenuate: false,
+            downmix: None,
         };
         let used = get_used_input_channels(&conf);
         assert_eq!(used, vec![false, true]);
@@ -302,7 +413,9 @@ mod tests {
         };
         let conf = Mixer {
             channels: chans,
-            mapping: vec![map0, map1, map2, map3],
+            mapping: Some(vec![map0, map1, map2, map3]),
+            auto_attenuate: false,
+            downmix: None,
         };
         let used = get_used_input_channels(&conf);
         assert_eq!(used, vec![false, true]);
@@ -357,7 +470,9 @@ mod tests {
         };
         let conf = Mixer {
             channels: chans,
-            mapping: vec![map0, map1, map2, map3],
+            mapping: Some(vec![map0, map1, map2, map3]),
+            auto_attenuate: false,
+            downmix: None,
         };
         let used = get_used_input_channels(&conf);
         assert_eq!(used, vec![false, true]);
@@ -412,7 +527,9 @@ mod tests {
         };
         let conf = Mixer {
             channels: chans,
-            mapping: vec![map0, map1, map2, map3],
+            mapping: Some(vec![map0, map1, map2, map3]),
+            auto_attenuate: false,
+            downmix: None,
         };
         let mix = mixer::Mixer::from_config("dummy".to_string(), conf);
         assert_eq!(mix.channels_in, 2);
@@ -494,7 +611,9 @@ mod tests {
         };
         let conf = Mixer {
             channels: chans,
-            mapping: vec![map0, map1, map2, map3],
+            mapping: Some(vec![map0, map1, map2, map3]),
+            auto_attenuate: false,
+            downmix: None,
         };
         let mix = mixer::Mixer::from_config("dummy".to_string(), conf);
         assert_eq!(mix.channels_in, 2);
@@ -515,4 +634,121 @@ mod tests {
 
         assert_eq!(mix.mapping, exp_map);
     }
+
+    #[test]
+    fn check_auto_attenuate() {
+        let chans = MixerChannels { r#in: 2, out: 2 };
+        let src0 = MixerSource {
+            channel: 0,
+            gain: 0.0,
+            inverted: false,
+            mute: false,
+        };
+        let src1 = MixerSource {
+            channel: 1,
+            gain: 0.0,
+            inverted: true,
+            mute: false,
+        };
+        let src2 = MixerSource {
+            channel: 1,
+            gain: -6.0,
+            inverted: false,
+            mute: false,
+        };
+        let map0 = MixerMapping {
+            dest: 0,
+            sources: vec![src0, src1],
+            mute: false,
+        };
+        let map1 = MixerMapping {
+            dest: 1,
+            sources: vec![src2],
+            mute: false,
+        };
+        let conf = Mixer {
+            channels: chans,
+            mapping: Some(vec![map0, map1]),
+            auto_attenuate: true,
+            downmix: None,
+        };
+        let mix = mixer::Mixer::from_config("dummy".to_string(), conf);
+        assert!((mix.attenuation[0] + 6.0206).abs() < 1.0e-3);
+        assert_eq!(mix.attenuation[1], 0.0);
+        assert!((mix.mapping[0][0].gain - 0.5).abs() < 1.0e-6);
+        assert!((mix.mapping[0][1].gain + 0.5).abs() < 1.0e-6);
+        assert!((mix.mapping[1][0].gain - 0.5012).abs() < 1.0e-4);
+    }
+
+    const H: PrcFmt = std::f64::consts::FRAC_1_SQRT_2 as PrcFmt;
+
+    fn downmix_gains(layout_in: &str, recipe: &str) -> Vec<Vec<(usize, PrcFmt)>> {
+        let yaml = format!(
+            "layout_in: {}\nlayout_out: 2.0\nrecipe: {}\n",
+            layout_in, recipe
+        );
+        let preset: DownmixPreset = serde_yaml::from_str(&yaml).unwrap();
+        let channels = preset.layout_in.channels();
+        let conf = Mixer {
+            channels: MixerChannels {
+                r#in: channels,
+                out: 2,
+            },
+            mapping: Some(mixer::downmix_mapping(&preset).unwrap()),
+            auto_attenuate: false,
+            downmix: None,
+        };
+        let mix = mixer::Mixer::from_config("downmix".to_string(), conf);
+        mix.mapping
+            .iter()
+            .map(|sources| sources.iter().map(|src| (src.channel, src.gain)).collect())
+            .collect()
+    }
+
+    fn assert_gains(gains: &[(usize, PrcFmt)], expected: &[(usize, PrcFmt)]) {
+        assert_eq!(gains.len(), expected.len());
+        for ((channel, gain), (exp_channel, exp_gain)) in gains.iter().zip(expected.iter()) {
+            assert_eq!(channel, exp_channel);
+            assert!((gain - exp_gain).abs() < 1.0e-4, "{} != {}", gain, exp_gain);
+        }
+    }
+
+    #[test]
+    fn downmix_itu() {
+        let gains = downmix_gains("5.1", "itu");
+        assert_gains(&gains[0], &[(0, 1.0), (2, H), (4, H)]);
+        assert_gains(&gains[1], &[(1, 1.0), (2, H), (5, H)]);
+        let gains = downmix_gains("7.1", "itu");
+        assert_gains(&gains[0], &[(0, 1.0), (2, H), (4, 0.5), (6, 0.5)]);
+    }
+
+    #[test]
+    fn downmix_dolby() {
+        let gains = downmix_gains("5.1", "dolby");
+        assert_gains(&gains[0], &[(0, 1.0), (2, H), (4, -0.5), (5, -0.5)]);
+        assert_gains(&gains[1], &[(1, 1.0), (2, H), (4, 0.5), (5, 0.5)]);
+        let gains = downmix_gains("5.1", "dolby_pl2");
+        assert_gains(&gains[0], &[(0, 1.0), (2, H), (4, -0.6124), (5, -0.3536)]);
+    }
+
+    #[test]
+    fn downmix_unsupported() {
+        let preset: DownmixPreset =
+            serde_yaml::from_str("layout_in: 2.0\nlayout_out: 5.1\nrecipe: itu\n").unwrap();
+        assert!(mixer::downmix_mapping(&preset).is_err());
+    }
+
+    #[test]
+    fn mapping_or_downmix_required() {
+        let conf: Mixer = serde_yaml::from_str("channels:\n  in: 2\n  out: 2\n").unwrap();
+        assert!(mixer::validate_mixer(&conf).is_err());
+        let conf: Mixer = serde_yaml::from_str(
+            "channels:\n  in: 2\n  out: 2\nmapping:\n  - dest: 0\n    sources:\n      - channel: 1\n        gain: 0\n",
+        )
+        .unwrap();
+        assert!(mixer::validate_mixer(&conf).is_ok());
+        let conf: Mixer =
+            serde_yaml::from_str("channels:\n  in: 2\n  out: 2\nmapping: []\n").unwrap();
+        assert!(mixer::validate_mixer(&conf).is_ok());
+    }
 }
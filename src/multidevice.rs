@@ -0,0 +1,1064 @@
+// Capture from several devices, combined into a single stream of chunks,
+// and playback to several devices, that each get a part of the channels.
+use crate::audiodevice::*;
+use crate::config;
+use crate::countertimer;
+use crate::filedevice::start_capture_monitor;
+use std::sync::mpsc;
+use std::sync::{Arc, Barrier, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use rubato::VecResampler;
+
+use crate::CaptureStatus;
+use crate::CommandMessage;
+use crate::PlaybackStatus;
+use crate::PrcFmt;
+use crate::Res;
+use crate::StatusMessage;
+
+/// Longest time to wait for a chunk from a device, before checking for commands again.
+const RECEIVE_TIMEOUT: Duration = Duration::from_millis(100);
+
+pub struct MultiCaptureDevice {
+    pub devices: Vec<Box<dyn CaptureDevice>>,
+    pub channels: Vec<usize>,
+    pub queuelimit: usize,
+    /// Resampler for the devices after the first, used to follow the clock of the first when rate adjust is enabled.
+    pub resampler_conf: Option<config::Resampler>,
+    pub samplerate: usize,
+    pub chunksize: usize,
+    pub adjust_period: f32,
+    /// Monitor file for the combined capture, written in the sample format of the first device.
+    pub monitor_file: Option<String>,
+    pub sample_format: config::SampleFormat,
+}
+
+pub struct MultiPlaybackDevice {
+    pub devices: Vec<Box<dyn PlaybackDevice>>,
+    pub channels: Vec<usize>,
+    pub queuelimit: usize,
+    /// Resampler for the devices after the first, used to follow their clocks when rate adjust is enabled.
+    pub resampler_conf: Option<config::Resampler>,
+    pub samplerate: usize,
+    pub chunksize: usize,
+}
+
+struct SubDevice {
+    audio: mpsc::Receiver<AudioMessage>,
+    command: mpsc::Sender<CommandMessage>,
+    status: Arc<RwLock<CaptureStatus>>,
+    handle: Box<thread::JoinHandle<()>>,
+    first_channel: usize,
+    channels: usize,
+}
+
+struct PlaybackSubDevice {
+    audio: mpsc::SyncSender<AudioMessage>,
+    status: Arc<RwLock<PlaybackStatus>>,
+    status_messages: mpsc::Receiver<StatusMessage>,
+    handle: Box<thread::JoinHandle<()>>,
+    resampler: Option<DeviceResampler>,
+}
+
+/// Async resampler for a device that has its own clock.
+/// The input is buffered, so that the resampler gets chunks of the size it needs.
+struct DeviceResampler {
+    resampler: Box<dyn VecResampler<PrcFmt>>,
+    buffer: Vec<Vec<PrcFmt>>,
+}
+
+impl DeviceResampler {
+    fn new(resampler: Box<dyn VecResampler<PrcFmt>>, channels: usize) -> Self {
+        DeviceResampler {
+            resampler,
+            buffer: vec![Vec::new(); channels],
+        }
+    }
+
+    /// Adjust the resampling ratio, in the same way as a capture device follows the playback clock.
+    fn set_speed(&mut self, speed: f64) {
+        if self.resampler.set_resample_ratio_relative(speed).is_err() {
+            debug!("Failed to set resampling speed to {}", speed);
+        }
+    }
+
+    /// Add a chunk to the buffer, and return the resampled chunks that are ready.
+    fn process(&mut self, chunk: AudioChunk) -> Vec<AudioChunk> {
+        append_chunk(&mut self.buffer, &chunk);
+        let mut chunks = Vec::new();
+        loop {
+            let needed = self.resampler.input_frames_next();
+            if self.buffer.iter().any(|buffer| buffer.len() < needed) {
+                break;
+            }
+            let input: Vec<Vec<PrcFmt>> = self
+                .buffer
+                .iter_mut()
+                .map(|buffer| buffer.drain(0..needed).collect())
+                .collect();
+            match self.resampler.process(&input, None) {
+                Ok(waveforms) => {
+                    let frames = waveforms.first().map(|w| w.len()).unwrap_or_default();
+                    chunks.push(AudioChunk::new(
+                        waveforms,
+                        chunk.maxval,
+                        chunk.minval,
+                        frames,
+                        frames,
+                    ));
+                }
+                Err(err) => {
+                    warn!("Resampling for device failed: {}", err);
+                    break;
+                }
+            }
+        }
+        chunks
+    }
+}
+
+/// Append the waveforms of a chunk to a buffer, with silence for the channels that are not included.
+fn append_chunk(buffer: &mut [Vec<PrcFmt>], chunk: &AudioChunk) {
+    for (buffer, waveform) in buffer.iter_mut().zip(chunk.waveforms.iter()) {
+        if waveform.is_empty() {
+            buffer.resize(buffer.len() + chunk.frames, 0.0);
+        } else {
+            buffer.extend_from_slice(waveform);
+        }
+    }
+}
+
+/// Buffer for a capture device after the first, that may run from a different clock.
+/// The first device sets the pace, and chunks of the same size as its chunks are taken from the buffer.
+/// With a resampler, the level of the buffer is kept at one chunk by adjusting the resampling ratio.
+struct CaptureBuffer {
+    resampler: Option<DeviceResampler>,
+    buffer: Vec<Vec<PrcFmt>>,
+    chunksize: usize,
+    samplerate: usize,
+    adjust_period: f32,
+    paused: bool,
+    ended: bool,
+    level_avg: countertimer::Averager,
+    timer: countertimer::Stopwatch,
+}
+
+impl CaptureBuffer {
+    fn new(
+        resampler: Option<DeviceResampler>,
+        channels: usize,
+        chunksize: usize,
+        samplerate: usize,
+        adjust_period: f32,
+    ) -> Self {
+        CaptureBuffer {
+            resampler,
+            buffer: vec![Vec::new(); channels],
+            chunksize,
+            samplerate,
+            adjust_period,
+            paused: false,
+            ended: false,
+            level_avg: countertimer::Averager::new(),
+            timer: countertimer::Stopwatch::new(),
+        }
+    }
+
+    fn frames(&self) -> usize {
+        self.buffer.first().map(|b| b.len()).unwrap_or_default()
+    }
+
+    /// Add a message from the device. A pause counts as a chunk of silence.
+    fn push(&mut self, message: AudioMessage) {
+        let chunk = match message {
+            AudioMessage::Audio(chunk) => {
+                self.paused = false;
+                chunk
+            }
+            AudioMessage::Pause => {
+                self.paused = true;
+                let waveforms = vec![vec![0.0; self.chunksize]; self.buffer.len()];
+                AudioChunk::new(waveforms, 0.0, 0.0, self.chunksize, self.chunksize)
+            }
+            AudioMessage::EndOfStream => {
+                self.ended = true;
+                return;
+            }
+        };
+        match self.resampler.as_mut() {
+            Some(resampler) => {
+                for resampled in resampler.process(chunk) {
+                    append_chunk(&mut self.buffer, &resampled);
+                }
+            }
+            None => append_chunk(&mut self.buffer, &chunk),
+        }
+    }
+
+    /// Take a number of frames from the buffer, leaving out the channels that are not used.
+    /// Returns the end of the stream once the device has ended and the buffer runs out.
+    fn take(&mut self, frames: usize, used_channels: &[bool]) -> AudioMessage {
+        if self.frames() < frames {
+            return AudioMessage::EndOfStream;
+        }
+        let mut maxval: PrcFmt = 0.0;
+        let mut minval: PrcFmt = 0.0;
+        let waveforms = self
+            .buffer
+            .iter_mut()
+            .enumerate()
+            .map(|(n, buffer)| {
+                let waveform: Vec<PrcFmt> = buffer.drain(0..frames).collect();
+                if !used_channels.get(n).copied().unwrap_or(true) {
+                    return Vec::new();
+                }
+                for value in waveform.iter() {
+                    maxval = maxval.max(*value);
+                    minval = minval.min(*value);
+                }
+                waveform
+            })
+            .collect();
+        self.level_avg.add_value(self.frames() as f64);
+        if self.paused {
+            return AudioMessage::Pause;
+        }
+        AudioMessage::Audio(AudioChunk::new(waveforms, maxval, minval, frames, frames))
+    }
+
+    /// Update the resampling ratio once per adjust period, from the average buffer level.
+    /// Returns the new relative speed, if it was updated.
+    fn adjust_speed(&mut self) -> Option<f64> {
+        let resampler = self.resampler.as_mut()?;
+        if !self
+            .timer
+            .larger_than_millis((1000.0 * self.adjust_period) as u64)
+        {
+            return None;
+        }
+        let av_level = self.level_avg.get_average()?;
+        self.timer.restart();
+        self.level_avg.restart();
+        let speed = calculate_speed(
+            av_level,
+            self.chunksize,
+            self.adjust_period,
+            self.samplerate as u32,
+        );
+        resampler.set_speed(speed);
+        Some(speed)
+    }
+}
+
+/// Combine one message from each device into a single message.
+/// Devices that are paused contribute silence, and the result is only a Pause if all devices are paused.
+fn combine_messages(
+    messages: Vec<AudioMessage>,
+    channels: &[usize],
+    used_channels: &[bool],
+) -> AudioMessage {
+    let mut chunks = Vec::with_capacity(messages.len());
+    for message in messages {
+        match message {
+            AudioMessage::Audio(chunk) => chunks.push(Some(chunk)),
+            AudioMessage::Pause => chunks.push(None),
+            AudioMessage::EndOfStream => return AudioMessage::EndOfStream,
+        }
+    }
+    let (frames, valid_frames) = match chunks.iter().flatten().next() {
+        Some(chunk) => (
+            chunk.frames,
+            chunks
+                .iter()
+                .flatten()
+                .map(|chunk| chunk.valid_frames)
+                .min()
+                .unwrap_or_default(),
+        ),
+        None => return AudioMessage::Pause,
+    };
+    let mut maxval: PrcFmt = 0.0;
+    let mut minval: PrcFmt = 0.0;
+    let mut waveforms = Vec::with_capacity(channels.iter().sum());
+    for (chunk, nbr_channels) in chunks.into_iter().zip(channels) {
+        match chunk {
+            Some(chunk) => {
+                maxval = maxval.max(chunk.maxval);
+                minval = minval.min(chunk.minval);
+                waveforms.extend(chunk.waveforms);
+            }
+            None => {
+                for _ in 0..*nbr_channels {
+                    let used = used_channels.get(waveforms.len()).copied().unwrap_or(true);
+                    if used {
+                        waveforms.push(vec![0.0; frames]);
+                    } else {
+                        waveforms.push(Vec::new());
+                    }
+                }
+            }
+        }
+    }
+    AudioMessage::Audio(AudioChunk::new(
+        waveforms,
+        maxval,
+        minval,
+        frames,
+        valid_frames,
+    ))
+}
+
+/// Copy the used channels and update interval to the status of each device,
+/// and collect the levels and rate of the devices into the combined status.
+fn sync_status(capture_status: &Arc<RwLock<CaptureStatus>>, devices: &[SubDevice]) {
+    let mut status = capture_status.write().unwrap();
+    let mut signal_rms = Vec::new();
+    let mut signal_peak = Vec::new();
+    for (n, device) in devices.iter().enumerate() {
+        let mut dev_status = device.status.write().unwrap();
+        dev_status.update_interval = status.update_interval;
+        dev_status.used_channels = (device.first_channel..device.first_channel + device.channels)
+            .map(|ch| status.used_channels.get(ch).copied().unwrap_or(true))
+            .collect();
+        signal_rms.extend(dev_status.signal_rms.iter().copied());
+        signal_peak.extend(dev_status.signal_peak.iter().copied());
+        if n == 0 {
+            status.measured_samplerate = dev_status.measured_samplerate;
+            status.signal_range = dev_status.signal_range;
+            status.rate_adjust = dev_status.rate_adjust;
+            status.state = dev_status.state;
+            status.status_updates = dev_status.status_updates;
+        }
+    }
+    status.signal_rms = signal_rms;
+    status.signal_peak = signal_peak;
+}
+
+/// Stop all devices and wait for their threads to exit.
+/// Pass the barrier in place of the devices that were not started,
+/// so that the devices that did start are not left waiting when a later device fails to start.
+fn release_barrier(barrier: &Arc<Barrier>, missing: usize) {
+    let helpers: Vec<thread::JoinHandle<()>> = (0..missing)
+        .map(|_| {
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+            })
+        })
+        .collect();
+    barrier.wait();
+    for helper in helpers {
+        helper.join().unwrap_or(());
+    }
+}
+
+fn stop_devices(devices: Vec<SubDevice>) {
+    let mut handles = Vec::new();
+    for device in devices {
+        // Dropping the receiver makes sure that no device is left waiting to send a chunk
+        drop(device.audio);
+        device.command.send(CommandMessage::Exit).unwrap_or(());
+        handles.push(device.handle);
+    }
+    for handle in handles {
+        if handle.join().is_err() {
+            error!("A capture thread panicked");
+        }
+    }
+}
+
+/// Split a message into one message for each device, each getting its part of the channels.
+fn split_message(message: &AudioMessage, channels: &[usize]) -> Vec<AudioMessage> {
+    match message {
+        AudioMessage::Audio(chunk) => {
+            let mut waveforms = chunk.waveforms.iter();
+            channels
+                .iter()
+                .map(|nbr_channels| {
+                    let device_waveforms =
+                        waveforms.by_ref().take(*nbr_channels).cloned().collect();
+                    AudioMessage::Audio(AudioChunk::new(
+                        device_waveforms,
+                        chunk.maxval,
+                        chunk.minval,
+                        chunk.frames,
+                        chunk.valid_frames,
+                    ))
+                })
+                .collect()
+        }
+        AudioMessage::Pause => channels.iter().map(|_| AudioMessage::Pause).collect(),
+        AudioMessage::EndOfStream => channels.iter().map(|_| AudioMessage::EndOfStream).collect(),
+    }
+}
+
+/// Copy the update interval to the status of each device,
+/// and collect the levels and clipping of the devices into the combined status.
+/// The buffer level is taken from the first device, which is the one that controls the rate adjust.
+fn sync_playback_status(
+    playback_status: &Arc<RwLock<PlaybackStatus>>,
+    devices: &[PlaybackSubDevice],
+) {
+    let mut status = playback_status.write().unwrap();
+    let mut signal_rms = Vec::new();
+    let mut signal_peak = Vec::new();
+    let mut clipped_samples = 0;
+    for (n, device) in devices.iter().enumerate() {
+        let mut dev_status = device.status.write().unwrap();
+        dev_status.update_interval = status.update_interval;
+        signal_rms.extend(dev_status.signal_rms.iter().copied());
+        signal_peak.extend(dev_status.signal_peak.iter().copied());
+        clipped_samples += dev_status.clipped_samples;
+        if n == 0 {
+            status.buffer_level = dev_status.buffer_level;
+        }
+        if dev_status.output_file.is_some() && status.output_file.is_none() {
+            status.output_file = dev_status.output_file.clone();
+        }
+    }
+    status.signal_rms = signal_rms;
+    status.signal_peak = signal_peak;
+    status.clipped_samples = clipped_samples;
+}
+
+/// Stop all playback devices and wait for their threads to exit.
+/// Returns the number of devices that reported that they are done.
+fn stop_playback_devices(devices: Vec<PlaybackSubDevice>) -> usize {
+    let mut stopped = Vec::new();
+    for device in devices {
+        device
+            .audio
+            .try_send(AudioMessage::EndOfStream)
+            .unwrap_or(());
+        stopped.push((device.handle, device.status_messages));
+    }
+    let mut nbr_done = 0;
+    for (handle, status_messages) in stopped {
+        if handle.join().is_err() {
+            error!("A playback thread panicked");
+        }
+        nbr_done += status_messages
+            .try_iter()
+            .filter(|message| matches!(message, StatusMessage::PlaybackDone))
+            .count();
+    }
+    nbr_done
+}
+
+/// Start a capture thread for each device, and a thread that combines their outputs
+impl CaptureDevice for MultiCaptureDevice {
+    fn start(
+        &mut self,
+        channel: mpsc::SyncSender<AudioMessage>,
+        barrier: Arc<Barrier>,
+        status_channel: mpsc::Sender<StatusMessage>,
+        command_channel: mpsc::Receiver<CommandMessage>,
+        capture_status: Arc<RwLock<CaptureStatus>>,
+    ) -> Res<Box<thread::JoinHandle<()>>> {
+        let nbr_devices = self.devices.len();
+        let device_barrier = Arc::new(Barrier::new(nbr_devices + 1));
+        let (tx_dev_status, rx_dev_status) = mpsc::channel();
+        let mut devices = Vec::with_capacity(nbr_devices);
+        let mut first_channel = 0;
+        for (device, channels) in self.devices.iter_mut().zip(self.channels.iter()) {
+            let (tx_audio, rx_audio) = mpsc::sync_channel(self.queuelimit);
+            let (tx_command, rx_command) = mpsc::channel();
+            let mut dev_status = capture_status.read().unwrap().clone();
+            dev_status.used_channels = (first_channel..first_channel + channels)
+                .map(|ch| dev_status.used_channels.get(ch).copied().unwrap_or(true))
+                .collect();
+            let dev_status = Arc::new(RwLock::new(dev_status));
+            let handle = match device.start(
+                tx_audio,
+                device_barrier.clone(),
+                tx_dev_status.clone(),
+                rx_command,
+                dev_status.clone(),
+            ) {
+                Ok(handle) => handle,
+                Err(err) => {
+                    release_barrier(&device_barrier, nbr_devices - devices.len());
+                    stop_devices(devices);
+                    return Err(err);
+                }
+            };
+            devices.push(SubDevice {
+                audio: rx_audio,
+                command: tx_command,
+                status: dev_status,
+                handle,
+                first_channel,
+                channels: *channels,
+            });
+            first_channel += channels;
+        }
+        // The first device sets the pace, the others are buffered and follow it by resampling
+        let mut buffers: Vec<CaptureBuffer> = self
+            .channels
+            .iter()
+            .skip(1)
+            .map(|channels| {
+                let resampler = self
+                    .resampler_conf
+                    .as_ref()
+                    .and_then(|conf| {
+                        get_resampler(
+                            conf,
+                            *channels,
+                            self.samplerate,
+                            self.samplerate,
+                            self.chunksize,
+                        )
+                    })
+                    .map(|resampler| DeviceResampler::new(resampler, *channels));
+                CaptureBuffer::new(
+                    resampler,
+                    *channels,
+                    self.chunksize,
+                    self.samplerate,
+                    self.adjust_period,
+                )
+            })
+            .collect();
+        let channels = self.channels.clone();
+        let chunksize = self.chunksize;
+        let mut monitor = start_capture_monitor(
+            &self.monitor_file,
+            channels.iter().sum(),
+            self.samplerate,
+            &self.sample_format,
+        );
+        let handle = thread::Builder::new()
+            .name("MultiCapture".to_string())
+            .spawn(move || {
+                // Wait until all devices have reported that they are ready, or failed to start
+                let mut start_error = None;
+                for _ in 0..nbr_devices {
+                    match rx_dev_status.recv() {
+                        Ok(StatusMessage::CaptureReady) => {}
+                        Ok(StatusMessage::CaptureError(message)) => {
+                            start_error = Some(message);
+                        }
+                        Ok(_) => {}
+                        Err(err) => {
+                            start_error = Some(err.to_string());
+                            break;
+                        }
+                    }
+                }
+                device_barrier.wait();
+                if let Some(message) = start_error {
+                    status_channel
+                        .send(StatusMessage::CaptureError(message))
+                        .unwrap_or(());
+                    barrier.wait();
+                    stop_devices(devices);
+                    return;
+                }
+                status_channel
+                    .send(StatusMessage::CaptureReady)
+                    .unwrap_or(());
+                barrier.wait();
+                debug!("starting combined capture loop");
+                let mut pending: Option<AudioMessage> = None;
+                loop {
+                    match command_channel.try_recv() {
+                        Ok(CommandMessage::Exit) => {
+                            debug!("Exit message received, stopping all capture devices");
+                            channel.send(AudioMessage::EndOfStream).unwrap_or(());
+                            status_channel
+                                .send(StatusMessage::CaptureDone)
+                                .unwrap_or(());
+                            break;
+                        }
+                        Ok(CommandMessage::SetSpeed { speed }) => {
+                            for device in devices.iter() {
+                                device
+                                    .command
+                                    .send(CommandMessage::SetSpeed { speed })
+                                    .unwrap_or(());
+                            }
+                        }
+                        Err(mpsc::TryRecvError::Empty) => {}
+                        Err(mpsc::TryRecvError::Disconnected) => {
+                            error!("Command channel was closed");
+                            break;
+                        }
+                    }
+                    // Pass on errors and format changes, the supervisor stops everything on these
+                    while let Ok(message) = rx_dev_status.try_recv() {
+                        match message {
+                            StatusMessage::CaptureError(_)
+                            | StatusMessage::CaptureFormatChange(_) => {
+                                status_channel.send(message).unwrap_or(());
+                            }
+                            _ => {}
+                        }
+                    }
+                    if pending.is_none() {
+                        match devices[0].audio.recv_timeout(RECEIVE_TIMEOUT) {
+                            Ok(message) => pending = Some(message),
+                            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                                pending = Some(AudioMessage::EndOfStream)
+                            }
+                        }
+                    }
+                    let frames = match &pending {
+                        Some(AudioMessage::Audio(chunk)) => chunk.frames,
+                        _ => chunksize,
+                    };
+                    // Wait for the other devices to have enough frames,
+                    // and buffer everything they have delivered so that their levels can be measured.
+                    let mut ready = true;
+                    for (device, buffer) in devices.iter().skip(1).zip(buffers.iter_mut()) {
+                        while let Ok(message) = device.audio.try_recv() {
+                            buffer.push(message);
+                        }
+                        while ready && !buffer.ended && buffer.frames() < frames {
+                            match device.audio.recv_timeout(RECEIVE_TIMEOUT) {
+                                Ok(message) => buffer.push(message),
+                                Err(mpsc::RecvTimeoutError::Timeout) => ready = false,
+                                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                                    buffer.push(AudioMessage::EndOfStream)
+                                }
+                            }
+                        }
+                    }
+                    if !ready {
+                        continue;
+                    }
+                    let used_channels = capture_status.read().unwrap().used_channels.clone();
+                    let mut messages = Vec::with_capacity(devices.len());
+                    messages.extend(pending.take());
+                    for (device, buffer) in devices.iter().skip(1).zip(buffers.iter_mut()) {
+                        let device_used: Vec<bool> = (device.first_channel
+                            ..device.first_channel + device.channels)
+                            .map(|ch| used_channels.get(ch).copied().unwrap_or(true))
+                            .collect();
+                        messages.push(buffer.take(frames, &device_used));
+                        if let Some(speed) = buffer.adjust_speed() {
+                            debug!(
+                                "Capture device starting at channel {}, relative speed: {:.6}",
+                                device.first_channel, speed
+                            );
+                        }
+                    }
+                    sync_status(&capture_status, &devices);
+                    let message = combine_messages(messages, &channels, &used_channels);
+                    if let (Some(monitor), AudioMessage::Audio(chunk)) =
+                        (monitor.as_mut(), &message)
+                    {
+                        monitor.write(chunk);
+                    }
+                    let done = matches!(message, AudioMessage::EndOfStream);
+                    if done {
+                        // Finish writing the monitor file before playback reports that it's done.
+                        drop(monitor.take());
+                    }
+                    if channel.send(message).is_err() || done {
+                        debug!("Combined capture stream ended");
+                        status_channel
+                            .send(StatusMessage::CaptureDone)
+                            .unwrap_or(());
+                        break;
+                    }
+                }
+                stop_devices(devices);
+            })
+            .unwrap();
+        Ok(Box::new(handle))
+    }
+}
+
+/// Start a playback thread for each device, and a thread that splits the chunks between them
+impl PlaybackDevice for MultiPlaybackDevice {
+    fn start(
+        &mut self,
+        channel: mpsc::Receiver<AudioMessage>,
+        barrier: Arc<Barrier>,
+        status_channel: mpsc::Sender<StatusMessage>,
+        playback_status: Arc<RwLock<PlaybackStatus>>,
+    ) -> Res<Box<thread::JoinHandle<()>>> {
+        let nbr_devices = self.devices.len();
+        let device_barrier = Arc::new(Barrier::new(nbr_devices + 1));
+        let mut devices = Vec::with_capacity(nbr_devices);
+        for (n, (device, channels)) in self
+            .devices
+            .iter_mut()
+            .zip(self.channels.iter())
+            .enumerate()
+        {
+            let (tx_audio, rx_audio) = mpsc::sync_channel(self.queuelimit);
+            let (tx_dev_status, rx_dev_status) = mpsc::channel();
+            let dev_status = Arc::new(RwLock::new(playback_status.read().unwrap().clone()));
+            let handle = match device.start(
+                rx_audio,
+                device_barrier.clone(),
+                tx_dev_status,
+                dev_status.clone(),
+            ) {
+                Ok(handle) => handle,
+                Err(err) => {
+                    release_barrier(&device_barrier, nbr_devices - devices.len());
+                    stop_playback_devices(devices);
+                    return Err(err);
+                }
+            };
+            // The first device is the clock master, the others follow it by resampling
+            let resampler = self
+                .resampler_conf
+                .as_ref()
+                .filter(|_| n > 0)
+                .and_then(|conf| {
+                    get_resampler(
+                        conf,
+                        *channels,
+                        self.samplerate,
+                        self.samplerate,
+                        self.chunksize,
+                    )
+                })
+                .map(|resampler| DeviceResampler::new(resampler, *channels));
+            devices.push(PlaybackSubDevice {
+                audio: tx_audio,
+                status: dev_status,
+                status_messages: rx_dev_status,
+                handle,
+                resampler,
+            });
+        }
+        let channels = self.channels.clone();
+        let handle = thread::Builder::new()
+            .name("MultiPlayback".to_string())
+            .spawn(move || {
+                // Wait until all devices have reported that they are ready, or failed to start
+                let mut start_error = None;
+                for device in devices.iter() {
+                    match device.status_messages.recv() {
+                        Ok(StatusMessage::PlaybackReady) => {}
+                        Ok(StatusMessage::PlaybackError(message)) => {
+                            start_error = Some(message);
+                        }
+                        Ok(_) => {}
+                        Err(err) => {
+                            start_error = Some(err.to_string());
+                        }
+                    }
+                }
+                device_barrier.wait();
+                if let Some(message) = start_error {
+                    status_channel
+                        .send(StatusMessage::PlaybackError(message))
+                        .unwrap_or(());
+                    barrier.wait();
+                    stop_playback_devices(devices);
+                    return;
+                }
+                status_channel
+                    .send(StatusMessage::PlaybackReady)
+                    .unwrap_or(());
+                barrier.wait();
+                debug!("starting split playback loop");
+                let mut nbr_done = 0;
+                let mut status_timer = countertimer::Stopwatch::new();
+                loop {
+                    let mut done = false;
+                    match channel.recv_timeout(RECEIVE_TIMEOUT) {
+                        Ok(message) => {
+                            done = matches!(message, AudioMessage::EndOfStream);
+                            for (device, dev_message) in
+                                devices.iter_mut().zip(split_message(&message, &channels))
+                            {
+                                // A device that fails to receive has stopped, and reports that itself
+                                match (device.resampler.as_mut(), dev_message) {
+                                    (Some(resampler), AudioMessage::Audio(chunk)) => {
+                                        for resampled in resampler.process(chunk) {
+                                            device
+                                                .audio
+                                                .send(AudioMessage::Audio(resampled))
+                                                .unwrap_or(());
+                                        }
+                                    }
+                                    (_, dev_message) => {
+                                        device.audio.send(dev_message).unwrap_or(());
+                                    }
+                                }
+                            }
+                            let interval = playback_status.read().unwrap().update_interval as u64;
+                            if status_timer.larger_than_millis(interval) {
+                                sync_playback_status(&playback_status, &devices);
+                                status_timer.restart();
+                            }
+                        }
+                        Err(mpsc::RecvTimeoutError::Timeout) => {}
+                        Err(mpsc::RecvTimeoutError::Disconnected) => {
+                            error!("Audio channel was closed");
+                            done = true;
+                        }
+                    }
+                    // Pass on errors and format changes, and rate adjustments from the first device.
+                    // The rate adjustments from the other devices go to their resamplers.
+                    for (n, device) in devices.iter_mut().enumerate() {
+                        while let Ok(message) = device.status_messages.try_recv() {
+                            match message {
+                                StatusMessage::PlaybackError(_)
+                                | StatusMessage::PlaybackFormatChange(_) => {
+                                    status_channel.send(message).unwrap_or(());
+                                }
+                                StatusMessage::SetSpeed(_) if n == 0 => {
+                                    status_channel.send(message).unwrap_or(());
+                                }
+                                StatusMessage::SetSpeed(speed) => {
+                                    if let Some(resampler) = device.resampler.as_mut() {
+                                        resampler.set_speed(speed);
+                                    }
+                                }
+                                StatusMessage::PlaybackDone => nbr_done += 1,
+                                _ => {}
+                            }
+                        }
+                    }
+                    if done {
+                        break;
+                    }
+                }
+                nbr_done += stop_playback_devices(devices);
+                if nbr_done > 0 {
+                    debug!("Split playback stream ended");
+                    status_channel
+                        .send(StatusMessage::PlaybackDone)
+                        .unwrap_or(());
+                }
+            })
+            .unwrap();
+        Ok(Box::new(handle))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audiodevice::{get_resampler, AudioChunk, AudioMessage, PlaybackDevice};
+    use crate::config::{ConfigError, Resampler};
+    use crate::multidevice::{
+        combine_messages, split_message, CaptureBuffer, DeviceResampler, MultiPlaybackDevice,
+    };
+    use crate::PlaybackStatus;
+    use crate::Res;
+    use crate::StatusMessage;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{mpsc, Arc, Barrier, RwLock};
+    use std::thread;
+
+    /// A playback device that either fails to start, or waits for the start barrier and then for the end of the stream.
+    struct TestPlayback {
+        fail: bool,
+        started: Arc<AtomicBool>,
+    }
+
+    impl PlaybackDevice for TestPlayback {
+        fn start(
+            &mut self,
+            channel: mpsc::Receiver<AudioMessage>,
+            barrier: Arc<Barrier>,
+            status_channel: mpsc::Sender<StatusMessage>,
+            _playback_status: Arc<RwLock<PlaybackStatus>>,
+        ) -> Res<Box<thread::JoinHandle<()>>> {
+            if self.fail {
+                return Err(ConfigError::new("Device not available").into());
+            }
+            let started = self.started.clone();
+            let handle = thread::spawn(move || {
+                status_channel
+                    .send(StatusMessage::PlaybackReady)
+                    .unwrap_or(());
+                barrier.wait();
+                started.store(true, Ordering::Relaxed);
+                while let Ok(AudioMessage::Audio(_)) = channel.recv() {}
+            });
+            Ok(Box::new(handle))
+        }
+    }
+
+    #[test]
+    fn combine_chunks() {
+        let first = AudioChunk::new(vec![vec![1.0, 2.0], vec![3.0, 4.0]], 4.0, 1.0, 2, 2);
+        let second = AudioChunk::new(vec![vec![-5.0, 6.0]], 6.0, -5.0, 2, 1);
+        let messages = vec![AudioMessage::Audio(first), AudioMessage::Audio(second)];
+        match combine_messages(messages, &[2, 1], &[true, true, true]) {
+            AudioMessage::Audio(chunk) => {
+                assert_eq!(
+                    chunk.waveforms,
+                    vec![vec![1.0, 2.0], vec![3.0, 4.0], vec![-5.0, 6.0]]
+                );
+                assert_eq!(chunk.channels, 3);
+                assert_eq!(chunk.valid_frames, 1);
+                assert_eq!(chunk.maxval, 6.0);
+                assert_eq!(chunk.minval, -5.0);
+            }
+            _ => panic!("Expected an audio chunk"),
+        }
+    }
+
+    #[test]
+    fn combine_with_paused() {
+        let first = AudioChunk::new(vec![vec![1.0, 2.0]], 2.0, 1.0, 2, 2);
+        let messages = vec![AudioMessage::Audio(first), AudioMessage::Pause];
+        match combine_messages(messages, &[1, 2], &[true, true, false]) {
+            AudioMessage::Audio(chunk) => {
+                assert_eq!(
+                    chunk.waveforms,
+                    vec![vec![1.0, 2.0], vec![0.0, 0.0], vec![]]
+                );
+            }
+            _ => panic!("Expected an audio chunk"),
+        }
+        let messages = vec![AudioMessage::Pause, AudioMessage::Pause];
+        assert!(matches!(
+            combine_messages(messages, &[1, 2], &[true, true, true]),
+            AudioMessage::Pause
+        ));
+        let messages = vec![AudioMessage::Pause, AudioMessage::EndOfStream];
+        assert!(matches!(
+            combine_messages(messages, &[1, 2], &[true, true, true]),
+            AudioMessage::EndOfStream
+        ));
+    }
+
+    #[test]
+    fn split_chunk() {
+        let chunk = AudioChunk::new(
+            vec![vec![1.0, 2.0], vec![], vec![-5.0, 6.0]],
+            6.0,
+            -5.0,
+            2,
+            1,
+        );
+        let messages = split_message(&AudioMessage::Audio(chunk), &[2, 1]);
+        assert_eq!(messages.len(), 2);
+        match &messages[0] {
+            AudioMessage::Audio(chunk) => {
+                assert_eq!(chunk.waveforms, vec![vec![1.0, 2.0], vec![]]);
+                assert_eq!(chunk.channels, 2);
+                assert_eq!(chunk.valid_frames, 1);
+            }
+            _ => panic!("Expected an audio chunk"),
+        }
+        match &messages[1] {
+            AudioMessage::Audio(chunk) => {
+                assert_eq!(chunk.waveforms, vec![vec![-5.0, 6.0]]);
+                assert_eq!(chunk.channels, 1);
+            }
+            _ => panic!("Expected an audio chunk"),
+        }
+        let messages = split_message(&AudioMessage::EndOfStream, &[2, 1]);
+        assert!(messages
+            .iter()
+            .all(|message| matches!(message, AudioMessage::EndOfStream)));
+    }
+
+    #[test]
+    fn resampled_chunks_keep_size() {
+        let resampler = get_resampler(&Resampler::BalancedAsync, 2, 44100, 44100, 64).unwrap();
+        let mut resampler = DeviceResampler::new(resampler, 2);
+        resampler.set_speed(1.001);
+        let mut nbr_chunks = 0;
+        for _ in 0..20 {
+            let chunk = AudioChunk::new(vec![vec![0.5; 64], vec![]], 0.5, 0.5, 64, 64);
+            for resampled in resampler.process(chunk) {
+                assert_eq!(resampled.frames, 64);
+                assert_eq!(resampled.waveforms.len(), 2);
+                assert!(resampled.waveforms.iter().all(|w| w.len() == 64));
+                nbr_chunks += 1;
+            }
+        }
+        assert!(nbr_chunks >= 18);
+    }
+
+    #[test]
+    fn capture_buffer_takes_chunks() {
+        let mut buffer = CaptureBuffer::new(None, 2, 4, 44100, 10.0);
+        let chunk = AudioChunk::new(vec![vec![1.0, 2.0, 3.0], vec![]], 3.0, 1.0, 3, 3);
+        buffer.push(AudioMessage::Audio(chunk));
+        assert_eq!(buffer.frames(), 3);
+        match buffer.take(2, &[true, true]) {
+            AudioMessage::Audio(chunk) => {
+                assert_eq!(chunk.frames, 2);
+                assert_eq!(chunk.waveforms, vec![vec![1.0, 2.0], vec![0.0, 0.0]]);
+                assert_eq!(chunk.maxval, 2.0);
+            }
+            _ => panic!("expected audio"),
+        }
+        // A pause adds a chunk of silence
+        buffer.push(AudioMessage::Pause);
+        assert_eq!(buffer.frames(), 5);
+        assert!(matches!(buffer.take(2, &[true, true]), AudioMessage::Pause));
+        buffer.push(AudioMessage::EndOfStream);
+        match buffer.take(3, &[true, false]) {
+            AudioMessage::Pause => {}
+            _ => panic!("expected pause"),
+        }
+        assert!(matches!(
+            buffer.take(1, &[true, true]),
+            AudioMessage::EndOfStream
+        ));
+        // Without a resampler, the speed isn't adjusted
+        assert!(buffer.adjust_speed().is_none());
+    }
+
+    #[test]
+    fn capture_buffer_slows_down_fast_device() {
+        let resampler = get_resampler(&Resampler::BalancedAsync, 1, 44100, 44100, 64).unwrap();
+        let resampler = DeviceResampler::new(resampler, 1);
+        let mut buffer = CaptureBuffer::new(Some(resampler), 1, 64, 44100, 0.0);
+        // The device has delivered more than the first device, the buffer level is above the target
+        for _ in 0..10 {
+            let chunk = AudioChunk::new(vec![vec![0.5; 64]], 0.5, 0.5, 64, 64);
+            buffer.push(AudioMessage::Audio(chunk));
+        }
+        assert!(buffer.frames() > 3 * 64);
+        assert!(matches!(buffer.take(64, &[true]), AudioMessage::Audio(_)));
+        let speed = buffer.adjust_speed().unwrap();
+        assert!(speed < 1.0);
+    }
+
+    #[test]
+    fn start_failure_releases_started_devices() {
+        let started = Arc::new(AtomicBool::new(false));
+        let mut device = MultiPlaybackDevice {
+            devices: vec![
+                Box::new(TestPlayback {
+                    fail: false,
+                    started: started.clone(),
+                }),
+                Box::new(TestPlayback {
+                    fail: true,
+                    started: Arc::new(AtomicBool::new(false)),
+                }),
+            ],
+            channels: vec![1, 1],
+            queuelimit: 4,
+            resampler_conf: None,
+            samplerate: 44100,
+            chunksize: 1024,
+        };
+        let status = Arc::new(RwLock::new(PlaybackStatus {
+            buffer_level: 0,
+            clipped_samples: 0,
+            clipping_active: false,
+            update_interval: 1000,
+            signal_rms: Vec::new(),
+            signal_peak: Vec::new(),
+            output_file: None,
+            channel_labels: Vec::new(),
+        }));
+        let (_tx_audio, rx_audio) = mpsc::sync_channel(4);
+        let (tx_status, _rx_status) = mpsc::channel();
+        let result = device.start(rx_audio, Arc::new(Barrier::new(1)), tx_status, status);
+        assert!(result.is_err());
+        // The first device has passed the barrier and been stopped
+        assert!(started.load(Ordering::Relaxed));
+    }
+}
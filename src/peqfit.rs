@@ -0,0 +1,244 @@
+use crate::biquad::BiquadCoefficients;
+use crate::config;
+use crate::PrcFmt;
+use crate::Res;
+
+/// Filters with a smaller peak deviation than this are not added.
+const MIN_FIT_GAIN: PrcFmt = 0.5;
+/// Largest boost or cut of a single peaking filter.
+const MAX_FIT_GAIN: PrcFmt = 20.0;
+const MIN_FIT_Q: PrcFmt = 0.3;
+const MAX_FIT_Q: PrcFmt = 10.0;
+/// Scalings of the initial gain and Q estimates that are tried when refining a filter.
+const GAIN_STEPS: [PrcFmt; 5] = [0.5, 0.75, 1.0, 1.1, 1.25];
+const Q_STEPS: [PrcFmt; 7] = [0.5, 0.71, 0.84, 1.0, 1.19, 1.41, 2.0];
+
+/// Result of fitting peaking filters to the difference between a measured response and a target.
+#[derive(Debug, PartialEq)]
+pub struct PeqFit {
+    /// Level difference between target and measurement in dB, not included in the filters.
+    pub level_offset: PrcFmt,
+    pub peaks: Vec<config::BiquadParameters>,
+    /// Largest remaining deviation from the target in dB, after applying the filters and the level offset.
+    pub max_error: PrcFmt,
+}
+
+/// Check that a curve is given as pairs of frequency and level, with increasing frequencies below nyquist.
+fn validate_curve(name: &str, curve: &[(PrcFmt, PrcFmt)], samplerate: usize) -> Res<()> {
+    let nyquist = samplerate as PrcFmt / 2.0;
+    if curve.len() < 2 {
+        let msg = format!("The {} curve must have at least 2 points", name);
+        return Err(config::ConfigError::new(&msg).into());
+    }
+    if curve
+        .iter()
+        .any(|(freq, level)| !(*freq > 0.0 && *freq < nyquist && level.is_finite()))
+    {
+        let msg = format!(
+            "The {} curve must have finite levels and frequencies between 0 and {} Hz",
+            name, nyquist
+        );
+        return Err(config::ConfigError::new(&msg).into());
+    }
+    if curve.windows(2).any(|pair| pair[1].0 <= pair[0].0) {
+        let msg = format!("The frequencies of the {} curve must be increasing", name);
+        return Err(config::ConfigError::new(&msg).into());
+    }
+    Ok(())
+}
+
+/// Level of a curve at a frequency, interpolated linearly on a logarithmic frequency axis.
+/// Outside the curve, the level at the nearest end is used.
+fn interpolate(curve: &[(PrcFmt, PrcFmt)], freq: PrcFmt) -> PrcFmt {
+    let upper = curve.iter().position(|(f, _)| *f >= freq);
+    match upper {
+        Some(0) => curve[0].1,
+        Some(idx) => {
+            let (f_lo, l_lo) = curve[idx - 1];
+            let (f_hi, l_hi) = curve[idx];
+            let fract = (freq / f_lo).ln() / (f_hi / f_lo).ln();
+            l_lo + fract * (l_hi - l_lo)
+        }
+        None => curve[curve.len() - 1].1,
+    }
+}
+
+fn peaking(freq: PrcFmt, q: PrcFmt, gain: PrcFmt) -> config::BiquadParameters {
+    config::BiquadParameters::Peaking(config::PeakingWidth::Q { freq, q, gain })
+}
+
+/// Magnitude response in dB of a biquad at the given frequencies.
+fn response_db(
+    parameters: config::BiquadParameters,
+    frequencies: &[PrcFmt],
+    samplerate: usize,
+) -> Vec<PrcFmt> {
+    let coeffs = BiquadCoefficients::from_config(samplerate, parameters);
+    frequencies
+        .iter()
+        .map(|freq| 20.0 * coeffs.response(*freq, samplerate).norm().log10())
+        .collect()
+}
+
+fn squared_error(residual: &[PrcFmt], response: &[PrcFmt]) -> PrcFmt {
+    residual
+        .iter()
+        .zip(response.iter())
+        .map(|(res, resp)| (res - resp).powi(2))
+        .sum()
+}
+
+/// Estimate the Q of a peak in the residual, from the width where it has fallen to half of the peak value.
+fn estimate_q(frequencies: &[PrcFmt], residual: &[PrcFmt], peak: usize) -> PrcFmt {
+    let half = residual[peak] / 2.0;
+    let within = |idx: &usize| residual[*idx] * half.signum() > half.abs();
+    let lower = (0..peak).rev().find(|idx| !within(idx)).unwrap_or(0);
+    let upper = (peak + 1..frequencies.len())
+        .find(|idx| !within(idx))
+        .unwrap_or(frequencies.len() - 1);
+    let octaves = (frequencies[upper] / frequencies[lower]).log2().max(0.05);
+    let width = (2.0 as PrcFmt).powf(octaves);
+    (width.sqrt() / (width - 1.0)).clamp(MIN_FIT_Q, MAX_FIT_Q)
+}
+
+/// Fit up to `max_filters` peaking filters that bring the measured response as close as possible to the target.
+/// Both curves are given as pairs of frequency in Hz and level in dB.
+/// The target is evaluated at the measured frequencies, and the mean difference is removed as a level offset.
+/// The filters are then added one at a time, each placed at the largest remaining deviation
+/// with its gain and Q refined to minimize the squared error.
+pub fn fit_peaking_eq(
+    measured: &[(PrcFmt, PrcFmt)],
+    target: &[(PrcFmt, PrcFmt)],
+    max_filters: usize,
+    samplerate: usize,
+) -> Res<PeqFit> {
+    validate_curve("measured", measured, samplerate)?;
+    validate_curve("target", target, samplerate)?;
+    let frequencies: Vec<PrcFmt> = measured.iter().map(|(freq, _)| *freq).collect();
+    let mut residual: Vec<PrcFmt> = measured
+        .iter()
+        .map(|(freq, level)| interpolate(target, *freq) - level)
+        .collect();
+    let level_offset = residual.iter().sum::<PrcFmt>() / residual.len() as PrcFmt;
+    for value in residual.iter_mut() {
+        *value -= level_offset;
+    }
+
+    let mut peaks = Vec::new();
+    while peaks.len() < max_filters {
+        let (peak, deviation) =
+            residual
+                .iter()
+                .enumerate()
+                .fold((0, 0.0), |(best, best_dev), (idx, value)| {
+                    if value.abs() > best_dev {
+                        (idx, value.abs())
+                    } else {
+                        (best, best_dev)
+                    }
+                });
+        if deviation < MIN_FIT_GAIN {
+            break;
+        }
+        let freq = frequencies[peak];
+        let gain = residual[peak].clamp(-MAX_FIT_GAIN, MAX_FIT_GAIN);
+        let q = estimate_q(&frequencies, &residual, peak);
+        let current_error: PrcFmt = residual.iter().map(|value| value.powi(2)).sum();
+        let mut best: Option<(PrcFmt, config::BiquadParameters, Vec<PrcFmt>)> = None;
+        for gain_step in GAIN_STEPS.iter() {
+            for q_step in Q_STEPS.iter() {
+                let candidate_gain = (gain * gain_step).clamp(-MAX_FIT_GAIN, MAX_FIT_GAIN);
+                let candidate_q = (q * q_step).clamp(MIN_FIT_Q, MAX_FIT_Q);
+                let candidate = peaking(freq, candidate_q, candidate_gain);
+                let response = response_db(candidate.clone(), &frequencies, samplerate);
+                let error = squared_error(&residual, &response);
+                if best
+                    .as_ref()
+                    .map_or(true, |(best_error, _, _)| error < *best_error)
+                {
+                    best = Some((error, candidate, response));
+                }
+            }
+        }
+        match best {
+            Some((error, candidate, response)) if error < current_error => {
+                debug!("Fitted peaking filter {:?}", candidate);
+                for (value, resp) in residual.iter_mut().zip(response.iter()) {
+                    *value -= resp;
+                }
+                peaks.push(candidate);
+            }
+            _ => break,
+        }
+    }
+    let max_error = residual
+        .iter()
+        .fold(0.0, |acc: PrcFmt, value| acc.max(value.abs()));
+    Ok(PeqFit {
+        level_offset,
+        peaks,
+        max_error,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config;
+    use crate::peqfit::{fit_peaking_eq, interpolate, peaking, response_db};
+    use crate::PrcFmt;
+
+    fn log_frequencies() -> Vec<PrcFmt> {
+        (0..200)
+            .map(|n| 20.0 * (1000.0 as PrcFmt).powf(n as PrcFmt / 200.0))
+            .collect()
+    }
+
+    #[test]
+    fn interpolate_log_frequency() {
+        let curve = vec![(100.0, 0.0), (400.0, 6.0)];
+        assert!((interpolate(&curve, 200.0) - 3.0).abs() < 1.0e-6);
+        assert_eq!(interpolate(&curve, 50.0), 0.0);
+        assert_eq!(interpolate(&curve, 1000.0), 6.0);
+    }
+
+    #[test]
+    fn fit_single_peak() {
+        // a room mode at 80 Hz, measured 3 dB above a flat target
+        let frequencies = log_frequencies();
+        let mode = response_db(peaking(80.0, 4.0, 8.0), &frequencies, 48000);
+        let measured: Vec<(PrcFmt, PrcFmt)> = frequencies
+            .iter()
+            .zip(mode.iter())
+            .map(|(freq, level)| (*freq, level + 3.0))
+            .collect();
+        let target = vec![(20.0, 3.0), (20000.0, 3.0)];
+        let fit = fit_peaking_eq(&measured, &target, 5, 48000).unwrap();
+        assert!(!fit.peaks.is_empty());
+        assert!(fit.max_error < 1.0, "max error {}", fit.max_error);
+        if let config::BiquadParameters::Peaking(config::PeakingWidth::Q { freq, gain, .. }) =
+            fit.peaks[0]
+        {
+            assert!((freq - 80.0).abs() < 3.0);
+            assert!(gain < -5.0);
+        } else {
+            panic!("expected a peaking filter");
+        }
+    }
+
+    #[test]
+    fn flat_needs_no_filters() {
+        let measured = vec![(20.0, -2.0), (1000.0, -2.0), (20000.0, -2.0)];
+        let target = vec![(20.0, 0.0), (20000.0, 0.0)];
+        let fit = fit_peaking_eq(&measured, &target, 5, 48000).unwrap();
+        assert!(fit.peaks.is_empty());
+        assert!((fit.level_offset - 2.0).abs() < 1.0e-6);
+    }
+
+    #[test]
+    fn invalid_curves() {
+        let target = vec![(20.0, 0.0), (20000.0, 0.0)];
+        assert!(fit_peaking_eq(&[(100.0, 0.0)], &target, 5, 48000).is_err());
+        assert!(fit_peaking_eq(&[(100.0, 0.0), (50.0, 0.0)], &target, 5, 48000).is_err());
+        assert!(fit_peaking_eq(&[(100.0, 0.0), (30000.0, 0.0)], &target, 5, 48000).is_err());
+    }
+}
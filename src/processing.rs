@@ -1,10 +1,200 @@
 use crate::audiodevice::*;
+use crate::basicfilters;
 use crate::config;
+use crate::countertimer;
 use crate::filters;
+use crate::spectrum;
+use crate::PrcFmt;
 use crate::ProcessingParameters;
+use crate::StatusMessage;
+use crate::VolumeRamp;
+use num_complex::Complex;
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::mpsc;
 use std::sync::{Arc, Barrier, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often to retry the pipeline after it has failed, outputting silence in between.
+const FAILED_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Shortest time between two warnings about slow processing of a chunk.
+const LOAD_WARNING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Length of the fade when pausing and resuming, to avoid a click.
+const PAUSE_FADE_MS: f32 = 50.0;
+
+/// A sine tone that is added to one output channel for a limited time.
+#[derive(Clone, Debug)]
+pub struct TestTone {
+    channel: usize,
+    amplitude: PrcFmt,
+    phase_step: PrcFmt,
+    phase: PrcFmt,
+    remaining_frames: usize,
+}
+
+impl TestTone {
+    pub fn new(channel: usize, freq: f32, level_db: f32, seconds: f32, samplerate: usize) -> Self {
+        TestTone {
+            channel,
+            amplitude: (10.0 as PrcFmt).powf(level_db as PrcFmt / 20.0),
+            phase_step: 2.0 * std::f64::consts::PI as PrcFmt * freq as PrcFmt
+                / samplerate as PrcFmt,
+            phase: 0.0,
+            remaining_frames: (seconds * samplerate as f32).round() as usize,
+        }
+    }
+
+    /// Add the next part of the tone to its channel of the chunk.
+    /// Returns true when the tone has finished.
+    pub fn add_to_chunk(&mut self, chunk: &mut AudioChunk) -> bool {
+        if let Some(waveform) = chunk.waveforms.get_mut(self.channel) {
+            if waveform.is_empty() {
+                // The channel is not used by the pipeline, fill it with silence first
+                *waveform = vec![0.0; chunk.frames];
+            }
+            for value in waveform.iter_mut().take(self.remaining_frames) {
+                *value += self.amplitude * self.phase.sin();
+                self.phase =
+                    (self.phase + self.phase_step) % (2.0 * std::f64::consts::PI as PrcFmt);
+            }
+        }
+        self.remaining_frames = self.remaining_frames.saturating_sub(chunk.frames);
+        self.remaining_frames == 0
+    }
+}
+
+/// Amplitude of the click sent when measuring the loopback latency.
+const LATENCY_CLICK_AMPLITUDE: PrcFmt = 0.5;
+
+/// Captured level above which the click is considered detected, about -26 dB.
+const LATENCY_DETECT_LEVEL: PrcFmt = 0.05;
+
+/// A measurement of the round-trip latency from the output back to the input,
+/// made by sending a click on a playback channel that is physically looped back to a capture channel.
+/// The frames are counted on both sides of the processing, so the latency includes
+/// the queued chunks as well as the device buffers and hardware.
+/// The click is only sent once the capture channel has been quiet for a while,
+/// so that the first loud sample after it can be taken as the returning click.
+#[derive(Clone, Debug)]
+pub struct LatencyMeasurement {
+    playback_channel: usize,
+    capture_channel: usize,
+    quiet_frames_needed: usize,
+    max_frames: usize,
+    quiet_frames: usize,
+    frames: usize,
+    click_frame: Option<usize>,
+    pub result: Option<usize>,
+}
+
+impl LatencyMeasurement {
+    /// Create a new measurement. The capture channel must be quiet for `quiet_frames` before the click is sent,
+    /// and the measurement is given up after `max_frames`.
+    pub fn new(
+        playback_channel: usize,
+        capture_channel: usize,
+        quiet_frames: usize,
+        max_frames: usize,
+    ) -> Self {
+        LatencyMeasurement {
+            playback_channel,
+            capture_channel,
+            quiet_frames_needed: quiet_frames,
+            max_frames,
+            quiet_frames: 0,
+            frames: 0,
+            click_frame: None,
+            result: None,
+        }
+    }
+
+    /// Look for the click in a captured chunk once it has been sent,
+    /// and otherwise keep track of how long the capture channel has been quiet.
+    pub fn detect_click(&mut self, chunk: &AudioChunk) {
+        if self.result.is_some() {
+            return;
+        }
+        let waveform = match chunk.waveforms.get(self.capture_channel) {
+            Some(waveform) => waveform,
+            None => return,
+        };
+        let last_loud = waveform
+            .iter()
+            .take(chunk.valid_frames)
+            .rposition(|value| value.abs() > LATENCY_DETECT_LEVEL);
+        match (self.click_frame, last_loud) {
+            (Some(click_frame), Some(_)) => {
+                let position = waveform
+                    .iter()
+                    .position(|value| value.abs() > LATENCY_DETECT_LEVEL)
+                    .unwrap_or_default();
+                self.result = Some(self.frames + position - click_frame);
+            }
+            (None, Some(position)) => {
+                self.quiet_frames = chunk.valid_frames - position - 1;
+            }
+            (_, None) => {
+                self.quiet_frames += chunk.valid_frames;
+            }
+        }
+    }
+
+    /// Add the click to the first processed chunk after the capture channel has been quiet long enough,
+    /// and count the frames of every chunk.
+    pub fn add_click(&mut self, chunk: &mut AudioChunk) {
+        if self.click_frame.is_none()
+            && self.quiet_frames >= self.quiet_frames_needed
+            && chunk.frames > 0
+        {
+            if let Some(waveform) = chunk.waveforms.get_mut(self.playback_channel) {
+                if waveform.is_empty() {
+                    *waveform = vec![0.0; chunk.frames];
+                }
+                waveform[0] += LATENCY_CLICK_AMPLITUDE;
+                self.click_frame = Some(self.frames);
+            }
+        }
+        self.frames += chunk.frames;
+    }
+
+    /// Check if the measurement is done, either with a result or by running out of time.
+    pub fn is_finished(&self) -> bool {
+        self.result.is_some() || self.frames >= self.max_frames
+    }
+}
+
+/// A request for a latency measurement.
+/// The result is sent on the reply channel of the request,
+/// and the id identifies the request while it waits to be taken by the processing thread.
+#[derive(Clone, Debug)]
+pub struct LatencyRequest {
+    pub id: usize,
+    pub measurement: LatencyMeasurement,
+    pub reply: mpsc::Sender<Option<usize>>,
+}
+
+/// A request for the response of a filter of the running pipeline.
+/// The processing thread evaluates it with the current coefficients of the filter,
+/// and sends the result on the reply channel of the request.
+#[derive(Clone, Debug)]
+pub struct FilterResponseRequest {
+    pub id: usize,
+    pub name: String,
+    pub frequencies: Vec<PrcFmt>,
+    pub reply: mpsc::Sender<Result<Vec<Complex<PrcFmt>>, String>>,
+}
+
+/// A request for the spectrum of a capture channel.
+/// The processing thread collects the samples of the channel as they are captured,
+/// and sends them on the reply channel of the request once there are enough.
+#[derive(Clone, Debug)]
+pub struct SpectrumRequest {
+    pub id: usize,
+    pub collector: spectrum::SpectrumCollector,
+    pub reply: mpsc::Sender<Vec<PrcFmt>>,
+}
 
 pub fn run_processing(
     conf_proc: config::Configuration,
@@ -13,17 +203,234 @@ pub fn run_processing(
     rx_cap: mpsc::Receiver<AudioMessage>,
     rx_pipeconf: mpsc::Receiver<(config::ConfigChange, config::Configuration)>,
     processing_status: Arc<RwLock<ProcessingParameters>>,
+    status_channel: mpsc::Sender<StatusMessage>,
 ) -> thread::JoinHandle<()> {
     thread::spawn(move || {
-        let mut pipeline = filters::Pipeline::from_config(conf_proc, processing_status.clone());
+        let mut dc_blocks: Vec<basicfilters::DcBlock> = if conf_proc.devices.dc_block {
+            debug!(
+                "Enable DC blocking at {} Hz",
+                conf_proc.devices.dc_block_freq
+            );
+            (0..conf_proc.devices.capture.channels())
+                .map(|_| {
+                    basicfilters::DcBlock::new(
+                        conf_proc.devices.samplerate,
+                        conf_proc.devices.dc_block_freq,
+                    )
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let mut agc = conf_proc.devices.agc.as_ref().map(|agc_conf| {
+            debug!("Enable AGC with target level {} dB", agc_conf.target_level);
+            basicfilters::Agc::from_config(
+                agc_conf,
+                conf_proc.devices.samplerate,
+                conf_proc.devices.chunksize,
+            )
+        });
+        processing_status.write().unwrap().agc_gain = agc.as_ref().map(|a| a.gain() as f32);
+        let mut mix_extra = conf_proc.devices.extra_channels == config::ExtraChannels::MixToFirst;
+        let mono_to_all = conf_proc.devices.mono_to_all;
+        let mut sum_surplus = conf_proc.devices.surplus_output == config::SurplusOutput::SumToLast;
+        let mut input_channels = config::get_pipeline_input_channels(&conf_proc);
+        let mut used_channels = config::get_used_capture_channels(&conf_proc);
+        let chunk_period = conf_proc.devices.chunksize as f32 / conf_proc.devices.samplerate as f32;
+        let mut output_channels = conf_proc.devices.playback.channels();
+        let mut failed_at: Option<Instant> = None;
+        let load_warning_level = conf_proc.devices.load_warning_level;
+        let mut load_warned_at: Option<Instant> = None;
+        let mut slow_chunks = 0;
+        let status_interval = conf_proc.devices.status_interval_ms as u64;
+        let mut status_timer = countertimer::Stopwatch::new();
+        let mut histogram = countertimer::ProcessingHistogram::new();
+        let mut test_tone: Option<TestTone> = None;
+        let mut latency_measurement: Option<LatencyRequest> = None;
+        let mut spectrum_requests: Vec<SpectrumRequest> = Vec::new();
+        let pause_fade_step =
+            basicfilters::get_mute_fade_step(Some(PAUSE_FADE_MS), conf_proc.devices.samplerate)
+                .unwrap_or(1.0);
+        let mut pause_gain: PrcFmt = if processing_status.read().unwrap().paused {
+            0.0
+        } else {
+            1.0
+        };
+        let mut pipeline = build_startup_pipeline(conf_proc, &processing_status);
+        processing_status
+            .write()
+            .unwrap()
+            .processing_histogram
+            .reset();
         debug!("build filters, waiting to start processing loop");
+        status_channel
+            .send(StatusMessage::ProcessingReady)
+            .unwrap_or(());
         barrier_proc.wait();
         debug!("Processing loop starts now!");
         loop {
             match rx_cap.recv() {
                 Ok(AudioMessage::Audio(mut chunk)) => {
                     //trace!("AudioMessage::Audio received");
-                    chunk = pipeline.process_chunk(chunk);
+                    let start = Instant::now();
+                    if let Some(request) = take_latency_request(&processing_status) {
+                        // A measurement that was already running is dropped, which tells its client
+                        latency_measurement = Some(request);
+                    }
+                    if let Some(request) = latency_measurement.as_mut() {
+                        request.measurement.detect_click(&chunk);
+                    }
+                    spectrum_requests.append(&mut take_spectrum_requests(&processing_status));
+                    for request in spectrum_requests.iter_mut() {
+                        request.collector.add_chunk(&chunk);
+                    }
+                    for request in drain_finished_spectrum_requests(&mut spectrum_requests) {
+                        request
+                            .reply
+                            .send(request.collector.into_samples())
+                            .unwrap_or(());
+                    }
+                    let (paused, flush) = {
+                        let status = processing_status.read().unwrap();
+                        (status.paused, status.flush_filters)
+                    };
+                    if flush {
+                        debug!("Clearing the state of all filters");
+                        processing_status.write().unwrap().flush_filters = false;
+                        pipeline.reset();
+                    }
+                    // Once the pause has faded out, process silence so that the filters are at rest when resuming
+                    let silent_input = paused && pause_gain == 0.0;
+                    if silent_input {
+                        chunk.set_silent();
+                    }
+                    if mix_extra {
+                        chunk.mix_unused_to_first(&used_channels);
+                    }
+                    for (waveform, dc_block) in chunk.waveforms.iter_mut().zip(dc_blocks.iter_mut())
+                    {
+                        if !waveform.is_empty() {
+                            dc_block.process_waveform(waveform);
+                        }
+                    }
+                    if let Some(agc) = agc.as_mut() {
+                        if !silent_input {
+                            agc.process_chunk(&mut chunk);
+                        }
+                    }
+                    if mono_to_all {
+                        chunk.duplicate_first(input_channels);
+                    }
+                    let frames = chunk.frames;
+                    let valid_frames = chunk.valid_frames;
+                    let volume_ramp = processing_status.read().unwrap().volume_ramp;
+                    let retry = failed_at.map_or(true, |t| t.elapsed() >= FAILED_RETRY_INTERVAL);
+                    let result = if retry {
+                        panic::catch_unwind(AssertUnwindSafe(|| pipeline.process_chunk(chunk)))
+                            .map_err(|payload| panic_message(&payload))
+                    } else {
+                        Err(String::new())
+                    };
+                    if volume_ramp.is_some() {
+                        clear_used_volume_ramp(&processing_status, volume_ramp);
+                    }
+                    match result {
+                        Ok(processed) => {
+                            if failed_at.take().is_some() {
+                                info!("Processing recovered");
+                            }
+                            chunk = processed;
+                        }
+                        Err(message) => {
+                            // Output silence instead of letting the panic take down the thread
+                            chunk = AudioChunk::new(
+                                vec![vec![0.0; frames]; output_channels],
+                                0.0,
+                                0.0,
+                                frames,
+                                valid_frames,
+                            );
+                            if failed_at.is_none() {
+                                error!("Processing failed, outputting silence: {}", message);
+                                if status_channel
+                                    .send(StatusMessage::ProcessingError(message))
+                                    .is_err()
+                                {
+                                    debug!("Supervisor has already stopped");
+                                }
+                            }
+                            if retry {
+                                failed_at = Some(Instant::now());
+                            }
+                        }
+                    }
+                    chunk.remove_surplus(output_channels, sum_surplus);
+                    if silent_input {
+                        chunk.set_silent();
+                    } else if paused || pause_gain < 1.0 {
+                        // Fade all channels from the same starting gain
+                        let start_gain = pause_gain;
+                        for waveform in chunk.waveforms.iter_mut().filter(|w| !w.is_empty()) {
+                            pause_gain = start_gain;
+                            basicfilters::apply_fade(
+                                waveform,
+                                &mut pause_gain,
+                                paused,
+                                pause_fade_step,
+                            );
+                        }
+                    }
+                    if let Some(tone) = take_test_tone(&processing_status) {
+                        test_tone = Some(tone);
+                    }
+                    if let Some(tone) = test_tone.as_mut() {
+                        if tone.add_to_chunk(&mut chunk) {
+                            debug!("Test tone finished");
+                            test_tone = None;
+                        }
+                    }
+                    if let Some(request) = latency_measurement.as_mut() {
+                        request.measurement.add_click(&mut chunk);
+                    }
+                    if latency_measurement
+                        .as_ref()
+                        .map_or(false, |request| request.measurement.is_finished())
+                    {
+                        if let Some(request) = latency_measurement.take() {
+                            request.reply.send(request.measurement.result).unwrap_or(());
+                        }
+                    }
+                    for request in take_filter_response_requests(&processing_status) {
+                        request
+                            .reply
+                            .send(pipeline.filter_response(&request.name, &request.frequencies))
+                            .unwrap_or(());
+                    }
+                    let elapsed = start.elapsed();
+                    let fraction = elapsed.as_secs_f32() / chunk_period;
+                    if fraction > load_warning_level {
+                        slow_chunks += 1;
+                        if load_warned_at.map_or(true, |t| t.elapsed() >= LOAD_WARNING_INTERVAL) {
+                            warn!(
+                                "Processing a chunk took {:.2} ms, {:.0}% of the chunk period of {:.2} ms. {} slow chunk(s) since the last warning, there is a risk of dropouts",
+                                1000.0 * elapsed.as_secs_f32(),
+                                100.0 * fraction,
+                                1000.0 * chunk_period,
+                                slow_chunks
+                            );
+                            load_warned_at = Some(Instant::now());
+                            slow_chunks = 0;
+                        }
+                    }
+                    histogram.add(fraction);
+                    if status_timer.larger_than_millis(status_interval) {
+                        let mut status = processing_status.write().unwrap();
+                        status.processing_histogram.merge(&histogram);
+                        histogram.reset();
+                        status.agc_gain = agc.as_ref().map(|agc| agc.gain() as f32);
+                        pipeline.publish_status(&mut status);
+                        status_timer.restart();
+                    }
                     let msg = AudioMessage::Audio(chunk);
                     if tx_pb.send(msg).is_err() {
                         info!("Playback thread has already stopped.");
@@ -57,9 +464,22 @@ pub fn run_processing(
             }
             if let Ok((diff, new_config)) = rx_pipeconf.try_recv() {
                 trace!("Message received on config channel");
+                histogram.reset();
+                processing_status
+                    .write()
+                    .unwrap()
+                    .processing_histogram
+                    .reset();
                 match diff {
                     config::ConfigChange::Pipeline | config::ConfigChange::MixerParameters => {
                         debug!("Rebuilding pipeline.");
+                        mix_extra =
+                            new_config.devices.extra_channels == config::ExtraChannels::MixToFirst;
+                        used_channels = config::get_used_capture_channels(&new_config);
+                        input_channels = config::get_pipeline_input_channels(&new_config);
+                        sum_surplus =
+                            new_config.devices.surplus_output == config::SurplusOutput::SumToLast;
+                        output_channels = new_config.devices.playback.channels();
                         let new_pipeline =
                             filters::Pipeline::from_config(new_config, processing_status.clone());
                         pipeline = new_pipeline;
@@ -71,7 +491,7 @@ pub fn run_processing(
                         );
                         pipeline.update_parameters(new_config, filters, mixers);
                     }
-                    config::ConfigChange::Devices => {
+                    config::ConfigChange::Devices | config::ConfigChange::ChannelCount => {
                         let msg = AudioMessage::EndOfStream;
                         tx_pb.send(msg).unwrap();
                         break;
@@ -82,3 +502,189 @@ pub fn run_processing(
         }
     })
 }
+
+/// Build the pipeline that processing starts with.
+/// With a `startup_volume`, the Volume and Loudness filters are created at that level,
+/// and then ramp to the current volume when processing starts.
+fn build_startup_pipeline(
+    conf: config::Configuration,
+    processing_status: &Arc<RwLock<ProcessingParameters>>,
+) -> filters::Pipeline {
+    // A ramp time given with an earlier volume change must not be used by the new filters
+    processing_status.write().unwrap().volume_ramp = None;
+    let startup_volume = match conf.devices.startup_volume {
+        Some(volume) => volume,
+        None => return filters::Pipeline::from_config(conf, processing_status.clone()),
+    };
+    let target_volume = {
+        let mut params = processing_status.write().unwrap();
+        let target_volume = params.volume;
+        params.volume = startup_volume - params.gain_offset;
+        target_volume
+    };
+    debug!(
+        "Ramping volume from startup level {} dB to {} dB",
+        startup_volume, target_volume
+    );
+    let pipeline = filters::Pipeline::from_config(conf, processing_status.clone());
+    processing_status.write().unwrap().volume = target_volume;
+    pipeline
+}
+
+/// Clear the ramp time given with a volume change, once the filters have processed a chunk with it.
+/// All filters have then seen the change, since the ramp is set together with the new volume.
+/// A ramp that was given after the chunk started is kept for the next chunk.
+fn clear_used_volume_ramp(
+    processing_status: &Arc<RwLock<ProcessingParameters>>,
+    used_ramp: Option<VolumeRamp>,
+) {
+    let mut params = processing_status.write().unwrap();
+    if params.volume_ramp == used_ramp {
+        params.volume_ramp = None;
+    }
+}
+
+/// Get the message from the payload of a caught panic.
+fn panic_message(payload: &Box<dyn std::any::Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown error".to_string()
+    }
+}
+
+/// Take a newly requested latency measurement from the processing status.
+fn take_latency_request(
+    processing_status: &Arc<RwLock<ProcessingParameters>>,
+) -> Option<LatencyRequest> {
+    let pending = processing_status
+        .read()
+        .unwrap()
+        .latency_measurement
+        .is_some();
+    if pending {
+        processing_status
+            .write()
+            .unwrap()
+            .latency_measurement
+            .take()
+    } else {
+        None
+    }
+}
+
+/// Take the new requests for filter responses from the processing status.
+fn take_filter_response_requests(
+    processing_status: &Arc<RwLock<ProcessingParameters>>,
+) -> Vec<FilterResponseRequest> {
+    let pending = !processing_status
+        .read()
+        .unwrap()
+        .filter_responses
+        .is_empty();
+    if pending {
+        std::mem::take(&mut processing_status.write().unwrap().filter_responses)
+    } else {
+        Vec::new()
+    }
+}
+
+/// Take the new requests for spectrums from the processing status.
+fn take_spectrum_requests(
+    processing_status: &Arc<RwLock<ProcessingParameters>>,
+) -> Vec<SpectrumRequest> {
+    let pending = !processing_status
+        .read()
+        .unwrap()
+        .spectrum_requests
+        .is_empty();
+    if pending {
+        std::mem::take(&mut processing_status.write().unwrap().spectrum_requests)
+    } else {
+        Vec::new()
+    }
+}
+
+/// Remove the spectrum requests that have collected all their samples, and return them.
+fn drain_finished_spectrum_requests(requests: &mut Vec<SpectrumRequest>) -> Vec<SpectrumRequest> {
+    let (finished, running) = std::mem::take(requests)
+        .into_iter()
+        .partition(|request| request.collector.is_finished());
+    *requests = running;
+    finished
+}
+
+/// Take a newly requested test tone from the processing status.
+fn take_test_tone(processing_status: &Arc<RwLock<ProcessingParameters>>) -> Option<TestTone> {
+    let pending = processing_status.read().unwrap().test_tone.is_some();
+    if pending {
+        processing_status.write().unwrap().test_tone.take()
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{panic_message, LatencyMeasurement};
+    use crate::audiodevice::AudioChunk;
+    use std::panic::{self, AssertUnwindSafe};
+
+    fn chunk_with_click(frames: usize, click: Option<usize>) -> AudioChunk {
+        let mut waveform = vec![0.0; frames];
+        if let Some(position) = click {
+            waveform[position] = 0.3;
+        }
+        AudioChunk::new(vec![waveform, vec![0.0; frames]], 0.3, 0.0, frames, frames)
+    }
+
+    #[test]
+    fn latency_click_after_quiet() {
+        let mut measurement = LatencyMeasurement::new(1, 0, 6, 100);
+        measurement.detect_click(&chunk_with_click(4, None));
+        let mut output = chunk_with_click(4, None);
+        measurement.add_click(&mut output);
+        assert_eq!(output.waveforms[1][0], 0.0);
+        measurement.detect_click(&chunk_with_click(4, None));
+        let mut output = chunk_with_click(4, None);
+        measurement.add_click(&mut output);
+        assert_eq!(output.waveforms[1][0], 0.5);
+        measurement.detect_click(&chunk_with_click(4, None));
+        measurement.add_click(&mut chunk_with_click(4, None));
+        measurement.detect_click(&chunk_with_click(4, Some(2)));
+        assert_eq!(measurement.result, Some(10));
+        assert!(measurement.is_finished());
+    }
+
+    #[test]
+    fn latency_no_click_while_loud() {
+        let mut measurement = LatencyMeasurement::new(1, 0, 6, 16);
+        for _ in 0..4 {
+            measurement.detect_click(&chunk_with_click(4, Some(3)));
+            let mut output = chunk_with_click(4, None);
+            measurement.add_click(&mut output);
+            assert_eq!(output.waveforms[1][0], 0.0);
+        }
+        assert_eq!(measurement.result, None);
+        assert!(measurement.is_finished());
+    }
+
+    #[test]
+    fn panic_to_error() {
+        let result: Result<(), String> =
+            panic::catch_unwind(AssertUnwindSafe(|| panic!("static message")))
+                .map_err(|payload| panic_message(&payload));
+        assert_eq!(result, Err("static message".to_string()));
+        let value = 3;
+        let result: Result<(), String> =
+            panic::catch_unwind(AssertUnwindSafe(|| panic!("formatted message {}", value)))
+                .map_err(|payload| panic_message(&payload));
+        assert_eq!(result, Err("formatted message 3".to_string()));
+        let result: Result<(), String> =
+            panic::catch_unwind(AssertUnwindSafe(|| panic::panic_any(42)))
+                .map_err(|payload| panic_message(&payload));
+        assert_eq!(result, Err("unknown error".to_string()));
+    }
+}
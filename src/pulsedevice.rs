@@ -6,8 +6,11 @@ use pulse::stream::Direction;
 use crate::audiodevice::*;
 use crate::config;
 use crate::config::SampleFormat;
-use crate::conversions::{buffer_to_chunk_rawbytes, chunk_to_buffer_rawbytes};
+use crate::conversions::{
+    apply_int_conversion, buffer_to_chunk_rawbytes, chunk_to_buffer_rawbytes,
+};
 use crate::countertimer;
+use crate::filedevice::start_capture_monitor;
 use rubato::VecResampler;
 use std::sync::mpsc;
 use std::sync::{Arc, Barrier, RwLock};
@@ -56,6 +59,7 @@ pub struct PulsePlaybackDevice {
     pub chunksize: usize,
     pub channels: usize,
     pub sample_format: SampleFormat,
+    pub int_conversion: config::IntConversion,
 }
 
 pub struct PulseCaptureDevice {
@@ -69,6 +73,7 @@ pub struct PulseCaptureDevice {
     pub sample_format: SampleFormat,
     pub silence_threshold: PrcFmt,
     pub silence_timeout: PrcFmt,
+    pub monitor_file: Option<String>,
 }
 
 /// Open a PulseAudio device
@@ -142,6 +147,7 @@ impl PlaybackDevice for PulsePlaybackDevice {
         let channels = self.channels;
         let store_bytes_per_sample = self.sample_format.bytes_per_sample();
         let sample_format = self.sample_format.clone();
+        let int_conversion = self.int_conversion;
         let handle = thread::Builder::new()
             .name("PulsePlayback".to_string())
             .spawn(move || {
@@ -169,7 +175,12 @@ impl PlaybackDevice for PulsePlaybackDevice {
                         let mut buffer = vec![0u8; chunksize * channels * store_bytes_per_sample];
                         loop {
                             match channel.recv() {
-                                Ok(AudioMessage::Audio(chunk)) => {
+                                Ok(AudioMessage::Audio(mut chunk)) => {
+                                    apply_int_conversion(
+                                        &mut chunk,
+                                        &sample_format,
+                                        int_conversion,
+                                    );
                                     conversion_result = chunk_to_buffer_rawbytes(
                                         &chunk,
                                         &mut buffer,
@@ -290,6 +301,7 @@ impl CaptureDevice for PulseCaptureDevice {
         let async_src = resampler_is_async(&resampler_conf);
         let silence_timeout = self.silence_timeout;
         let silence_threshold = self.silence_threshold;
+        let monitor_file = self.monitor_file.clone();
         let handle = thread::Builder::new()
             .name("PulseCapture".to_string())
             .spawn(move || {
@@ -313,6 +325,7 @@ impl CaptureDevice for PulseCaptureDevice {
                     true,
                 ) {
                     Ok(pulsedevice) => {
+                        let mut monitor = start_capture_monitor(&monitor_file, channels, capture_samplerate, &sample_format);
                         match status_channel.send(StatusMessage::CaptureReady) {
                             Ok(()) => {}
                             Err(_err) => {}
@@ -373,6 +386,9 @@ impl CaptureDevice for PulseCaptureDevice {
                             last_instant = Instant::now();
                             match read_res {
                                 Ok(()) => {
+                                    if let Some(monitor) = &mut monitor {
+                                        monitor.write_bytes(&buf[0..capture_bytes]);
+                                    }
                                     averager.add_value(capture_bytes);
                                     if averager.larger_than_millis(capture_status.read().unwrap().update_interval as u64) {
                                         let bytes_per_sec = averager.get_average();
@@ -384,7 +400,7 @@ impl CaptureDevice for PulseCaptureDevice {
                                             capture_status.read().unwrap().signal_rms,
                                         );
                                         let mut capt_stat = capture_status.write().unwrap();
-                                        capt_stat.measured_samplerate = measured_rate_f as usize;
+                                        capt_stat.update_measured_samplerate(measured_rate_f);
                                         capt_stat.signal_range = value_range as f32;
                                         capt_stat.rate_adjust = rate_adjust as f32;
                                         capt_stat.state = state;
@@ -0,0 +1,306 @@
+// Self test, running the processing pipeline on generated signals
+use crate::audiodevice::AudioChunk;
+use crate::config;
+use crate::countertimer;
+use crate::filters;
+use crate::PrcFmt;
+use crate::ProcessingParameters;
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+/// Duration of the generated test signals in seconds.
+const TEST_DURATION: f32 = 2.0;
+/// Frequency of the sine used for the level and load tests.
+const TEST_FREQUENCY: f64 = 1000.0;
+/// Amplitude of the generated test signals, -6 dBFS.
+const TEST_AMPLITUDE: PrcFmt = 0.5;
+/// Largest accepted latency added by the pipeline in milliseconds.
+const MAX_LATENCY_MS: f32 = 200.0;
+/// Largest accepted processing load in percent.
+const MAX_LOAD: f32 = 80.0;
+
+pub struct SelfTestReport {
+    pub samplerate: usize,
+    pub peak: PrcFmt,
+    pub clipped_samples: usize,
+    pub invalid_samples: usize,
+    pub latency_samples: Option<usize>,
+    pub load: f32,
+}
+
+impl SelfTestReport {
+    pub fn latency_ms(&self) -> Option<f32> {
+        self.latency_samples
+            .map(|samples| 1000.0 * samples as f32 / self.samplerate as f32)
+    }
+
+    pub fn latency_ok(&self) -> bool {
+        match self.latency_ms() {
+            Some(latency) => latency <= MAX_LATENCY_MS,
+            None => false,
+        }
+    }
+
+    pub fn passed(&self) -> bool {
+        self.invalid_samples == 0
+            && self.clipped_samples == 0
+            && self.latency_ok()
+            && self.load <= MAX_LOAD
+    }
+}
+
+fn pass_fail(ok: bool) -> &'static str {
+    if ok {
+        "pass"
+    } else {
+        "FAIL"
+    }
+}
+
+impl fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let peak_db = if self.peak > 0.0 {
+            20.0 * self.peak.log10()
+        } else {
+            -1000.0
+        };
+        writeln!(
+            f,
+            "NaN/Inf samples: {} [{}]",
+            self.invalid_samples,
+            pass_fail(self.invalid_samples == 0)
+        )?;
+        writeln!(
+            f,
+            "Peak level: {:.2} dBFS, clipped samples: {} [{}]",
+            peak_db,
+            self.clipped_samples,
+            pass_fail(self.clipped_samples == 0)
+        )?;
+        match self.latency_ms() {
+            Some(latency) => writeln!(
+                f,
+                "Added latency: {:.2} ms ({} samples) [{}]",
+                latency,
+                self.latency_samples.unwrap_or_default(),
+                pass_fail(self.latency_ok())
+            )?,
+            None => writeln!(f, "Added latency: no output from impulse [FAIL]")?,
+        }
+        writeln!(
+            f,
+            "Processing load: {:.1}% [{}]",
+            self.load,
+            pass_fail(self.load <= MAX_LOAD)
+        )?;
+        write!(
+            f,
+            "Self test {}",
+            if self.passed() { "passed" } else { "FAILED" }
+        )
+    }
+}
+
+/// Generate the chunks of a test signal by calling `generator` with the index of each frame.
+fn generate_chunks(
+    channels: usize,
+    chunksize: usize,
+    nbr_chunks: usize,
+    generator: impl Fn(usize) -> PrcFmt,
+) -> Vec<AudioChunk> {
+    (0..nbr_chunks)
+        .map(|n| {
+            let waveform: Vec<PrcFmt> = (0..chunksize)
+                .map(|i| generator(n * chunksize + i))
+                .collect();
+            let waveforms = vec![waveform; channels];
+            AudioChunk::new(
+                waveforms,
+                TEST_AMPLITUDE,
+                -TEST_AMPLITUDE,
+                chunksize,
+                chunksize,
+            )
+        })
+        .collect()
+}
+
+/// Processing parameters with neutral volume settings,
+/// so that offline processing doesn't depend on the command line or the live volume.
+fn neutral_parameters() -> Arc<RwLock<ProcessingParameters>> {
+    Arc::new(RwLock::new(ProcessingParameters {
+        volume: 0.0,
+        gain_offset: 0.0,
+        mute: false,
+        paused: false,
+        test_tone: None,
+        latency_measurement: None,
+        filter_responses: Vec::new(),
+        spectrum_requests: Vec::new(),
+        dynamic_eq_offsets: HashMap::new(),
+        loudness_boosts: HashMap::new(),
+        mixer_attenuations: HashMap::new(),
+        agc_gain: None,
+        processing_histogram: countertimer::ProcessingHistogram::new(),
+        profile_filters: false,
+        filter_times: HashMap::new(),
+        volume_ramp: None,
+        flush_filters: false,
+    }))
+}
+
+/// Run a short burst of generated audio through the pipeline and collect a report.
+pub fn run_selftest(conf: config::Configuration) -> SelfTestReport {
+    let samplerate = conf.devices.samplerate;
+    let chunksize = conf.devices.chunksize;
+    let channels = config::get_pipeline_input_channels(&conf);
+    let nbr_chunks = 1 + (TEST_DURATION * samplerate as f32) as usize / chunksize;
+    let params = neutral_parameters();
+
+    // Latency, measured as the position of the peak of the impulse response
+    let mut pipeline = filters::Pipeline::from_config(conf.clone(), params.clone());
+    let impulse = generate_chunks(channels, chunksize, nbr_chunks, |n| {
+        if n == 0 {
+            TEST_AMPLITUDE
+        } else {
+            0.0
+        }
+    });
+    let mut max_value: PrcFmt = 0.0;
+    let mut latency_samples = None;
+    for (n, chunk) in impulse.into_iter().enumerate() {
+        let output = pipeline.process_chunk(chunk);
+        for waveform in output.waveforms.iter() {
+            for (i, value) in waveform.iter().enumerate() {
+                if value.is_finite() && value.abs() > max_value {
+                    max_value = value.abs();
+                    latency_samples = Some(n * chunksize + i);
+                }
+            }
+        }
+    }
+
+    // Levels, invalid values and processing load, measured with a sine
+    let mut pipeline = filters::Pipeline::from_config(conf, params);
+    let omega = 2.0 * PI * TEST_FREQUENCY / samplerate as f64;
+    let sine = generate_chunks(channels, chunksize, nbr_chunks, |n| {
+        TEST_AMPLITUDE * (omega * n as f64).sin() as PrcFmt
+    });
+    let mut peak: PrcFmt = 0.0;
+    let mut clipped_samples = 0;
+    let mut invalid_samples = 0;
+    let start = Instant::now();
+    let outputs: Vec<AudioChunk> = sine
+        .into_iter()
+        .map(|chunk| pipeline.process_chunk(chunk))
+        .collect();
+    let elapsed = start.elapsed().as_secs_f32();
+    for output in outputs.iter() {
+        for value in output.waveforms.iter().flatten() {
+            if !value.is_finite() {
+                invalid_samples += 1;
+            } else {
+                if value.abs() > peak {
+                    peak = value.abs();
+                }
+                if value.abs() > 1.0 {
+                    clipped_samples += 1;
+                }
+            }
+        }
+    }
+    let processed_time = (nbr_chunks * chunksize) as f32 / samplerate as f32;
+    let load = 100.0 * elapsed / processed_time;
+
+    SelfTestReport {
+        samplerate,
+        peak,
+        clipped_samples,
+        invalid_samples,
+        latency_samples,
+        load,
+    }
+}
+
+/// Run a unit impulse through a new copy of the pipeline, on one input channel at a time,
+/// and return the first `length` samples of each output channel.
+/// The result is indexed as `[input][output]`, so that paths between channels can be told apart.
+pub fn impulse_response(conf: config::Configuration, length: usize) -> Vec<Vec<Vec<PrcFmt>>> {
+    let chunksize = conf.devices.chunksize;
+    let channels = config::get_pipeline_input_channels(&conf);
+    let nbr_chunks = (length + chunksize - 1) / chunksize;
+    (0..channels)
+        .map(|input| {
+            let mut pipeline = filters::Pipeline::from_config(conf.clone(), neutral_parameters());
+            let mut response: Vec<Vec<PrcFmt>> = Vec::new();
+            for n in 0..nbr_chunks {
+                let mut waveforms = vec![vec![0.0; chunksize]; channels];
+                if n == 0 {
+                    waveforms[input][0] = 1.0;
+                }
+                let chunk = AudioChunk::new(waveforms, 1.0, 0.0, chunksize, chunksize);
+                let output = pipeline.process_chunk(chunk);
+                response.resize(output.waveforms.len(), Vec::new());
+                for (channel, waveform) in response.iter_mut().zip(output.waveforms.iter()) {
+                    channel.extend_from_slice(waveform);
+                }
+            }
+            for channel in response.iter_mut() {
+                channel.resize(length, 0.0);
+            }
+            response
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config;
+    use crate::selftest::{impulse_response, run_selftest};
+
+    fn load_config(pipeline_yaml: &str) -> config::Configuration {
+        let yaml = format!(
+            "devices:\n  samplerate: 44100\n  chunksize: 1024\n  capture:\n    type: Stdin\n    channels: 2\n    format: S16LE\n  playback:\n    type: Stdout\n    channels: 2\n    format: S16LE\n{}",
+            pipeline_yaml
+        );
+        let mut conf: config::Configuration = serde_yaml::from_str(&yaml).unwrap();
+        config::validate_config(&mut conf, None).unwrap();
+        conf
+    }
+
+    #[test]
+    fn selftest_passthrough() {
+        let report = run_selftest(load_config(""));
+        assert_eq!(report.latency_samples, Some(0));
+        assert_eq!(report.clipped_samples, 0);
+        assert_eq!(report.invalid_samples, 0);
+        assert!((report.peak - 0.5).abs() < 1.0e-3);
+        assert!(report.passed());
+    }
+
+    #[test]
+    fn selftest_clipping() {
+        let report = run_selftest(load_config(
+            "filters:\n  boost:\n    type: Gain\n    parameters:\n      gain: 12.0\npipeline:\n  - type: Filter\n    channel: 0\n    names:\n      - boost\n",
+        ));
+        assert!(report.clipped_samples > 0);
+        assert!(!report.passed());
+        assert!(format!("{}", report).ends_with("Self test FAILED"));
+    }
+
+    #[test]
+    fn impulse_response_per_input() {
+        let conf = load_config(
+            "mixers:\n  crossfeed:\n    channels:\n      in: 2\n      out: 2\n    mapping:\n      - dest: 0\n        sources:\n          - channel: 0\n            gain: 0\n          - channel: 1\n            gain: -6.0206\n      - dest: 1\n        sources:\n          - channel: 1\n            gain: 0\npipeline:\n  - type: Mixer\n    name: crossfeed\n",
+        );
+        let response = impulse_response(conf, 4);
+        assert_eq!(response.len(), 2);
+        assert_eq!(response[0][0], vec![1.0, 0.0, 0.0, 0.0]);
+        assert_eq!(response[0][1], vec![0.0, 0.0, 0.0, 0.0]);
+        assert!((response[1][0][0] - 0.5).abs() < 1.0e-4);
+        assert_eq!(response[1][1], vec![1.0, 0.0, 0.0, 0.0]);
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config;
+use crate::Res;
+
+/// Named configs that can be saved and recalled via the websocket server.
+/// When a directory is given, each snapshot is also stored there as `<name>.yml`,
+/// and the snapshots found in the directory are loaded at startup.
+#[derive(Debug)]
+pub struct Snapshots {
+    dir: Option<PathBuf>,
+    configs: HashMap<String, config::Configuration>,
+}
+
+/// Check that a snapshot name is usable as a file name.
+fn validate_name(name: &str) -> Res<()> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        let msg = format!(
+            "Invalid snapshot name '{}', only letters, digits, '-' and '_' are allowed",
+            name
+        );
+        return Err(config::ConfigError::new(&msg).into());
+    }
+    Ok(())
+}
+
+/// Load the snapshots stored in a directory, skipping any files that can't be read.
+fn load_dir(dir: &Path) -> HashMap<String, config::Configuration> {
+    let mut configs = HashMap::new();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            debug!("No snapshots loaded from {:?}: {}", dir, err);
+            return configs;
+        }
+    };
+    for path in entries.flatten().map(|entry| entry.path()) {
+        if path.extension().map_or(true, |ext| ext != "yml") {
+            continue;
+        }
+        let name = match path.file_stem().and_then(|stem| stem.to_str()) {
+            Some(name) if validate_name(name).is_ok() => name.to_string(),
+            _ => continue,
+        };
+        match config::load_config(&path.to_string_lossy()) {
+            Ok(conf) => {
+                debug!("Loaded snapshot '{}' from {:?}", name, path);
+                configs.insert(name, conf);
+            }
+            Err(err) => warn!("Skipping snapshot file {:?}: {}", path, err),
+        }
+    }
+    configs
+}
+
+impl Snapshots {
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        let configs = dir.as_deref().map(load_dir).unwrap_or_default();
+        Snapshots { dir, configs }
+    }
+
+    /// Store a config under the given name, replacing any previous snapshot with that name.
+    pub fn save(&mut self, name: &str, conf: &config::Configuration) -> Res<()> {
+        validate_name(name)?;
+        if let Some(dir) = &self.dir {
+            let path = dir.join(format!("{}.yml", name));
+            let contents = serde_yaml::to_string(conf)?;
+            if let Err(err) = fs::create_dir_all(dir).and_then(|_| fs::write(&path, contents)) {
+                let msg = format!("Could not write snapshot file {:?}. Error: {}", path, err);
+                return Err(config::ConfigError::new(&msg).into());
+            }
+            debug!("Wrote snapshot '{}' to {:?}", name, path);
+        }
+        self.configs.insert(name.to_string(), conf.clone());
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&config::Configuration> {
+        self.configs.get(name)
+    }
+
+    /// The names of all snapshots, sorted.
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.configs.keys().cloned().collect();
+        names.sort();
+        names
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::snapshots::{validate_name, Snapshots};
+
+    #[test]
+    fn snapshot_names() {
+        assert!(validate_name("Living_room-2").is_ok());
+        assert!(validate_name("").is_err());
+        assert!(validate_name("../evil").is_err());
+        assert!(validate_name("with space").is_err());
+    }
+
+    #[test]
+    fn save_and_list() {
+        let conf: crate::config::Configuration = serde_yaml::from_str(
+            "devices:\n  samplerate: 44100\n  chunksize: 1024\n  capture:\n    type: Stdin\n    channels: 2\n    format: S16LE\n  playback:\n    type: Stdout\n    channels: 2\n    format: S16LE\n",
+        )
+        .unwrap();
+        let mut snapshots = Snapshots::new(None);
+        snapshots.save("night", &conf).unwrap();
+        snapshots.save("day", &conf).unwrap();
+        assert!(snapshots.save("a/b", &conf).is_err());
+        assert_eq!(
+            snapshots.names(),
+            vec!["day".to_string(), "night".to_string()]
+        );
+        assert_eq!(snapshots.get("day"), Some(&conf));
+        assert!(snapshots.get("evening").is_none());
+    }
+}
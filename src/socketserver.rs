@@ -2,25 +2,37 @@ use clap::crate_version;
 #[cfg(feature = "secure-websocket")]
 use native_tls::{Identity, TlsAcceptor, TlsStream};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
 #[cfg(feature = "secure-websocket")]
 use std::fs::File;
 #[cfg(feature = "secure-websocket")]
 use std::io::Read;
 use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::{Arc, Mutex, RwLock};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
 use tungstenite::accept;
 use tungstenite::Message;
 use tungstenite::WebSocket;
 
 use crate::config;
+use crate::countertimer;
+use crate::loudness;
+use crate::peqfit;
+use crate::processing;
+use crate::selftest;
+use crate::snapshots;
+use crate::spectrum;
 use crate::ExitRequest;
+use crate::PrcFmt;
 use crate::ProcessingState;
 use crate::Res;
 use crate::{
-    list_supported_devices, CaptureStatus, PlaybackStatus, ProcessingParameters, ProcessingStatus,
-    StopReason,
+    list_features, list_supported_devices, CaptureStatus, PlaybackStatus, ProcessingParameters,
+    ProcessingStatus, StopReason, VolumeRamp, MAX_VOLUME_DB, MIN_VOLUME_DB,
 };
 
 #[derive(Debug, Clone)]
@@ -30,11 +42,91 @@ pub struct SharedData {
     pub active_config: Arc<Mutex<Option<config::Configuration>>>,
     pub active_config_path: Arc<Mutex<Option<String>>>,
     pub new_config: Arc<Mutex<Option<config::Configuration>>>,
+    /// The pending config, or the active one if nothing is pending, as it was loaded and before validation.
+    pub raw_config: Arc<Mutex<Option<config::Configuration>>>,
     pub previous_config: Arc<Mutex<Option<config::Configuration>>>,
+    pub snapshots: Arc<Mutex<snapshots::Snapshots>>,
     pub capture_status: Arc<RwLock<CaptureStatus>>,
     pub playback_status: Arc<RwLock<PlaybackStatus>>,
     pub processing_status: Arc<RwLock<ProcessingParameters>>,
     pub status: Arc<RwLock<ProcessingStatus>>,
+    pub logger: Arc<Mutex<LogControl>>,
+}
+
+/// Log levels accepted by the SetLogLevel command.
+const LOG_LEVELS: [&str; 6] = ["off", "error", "warn", "info", "debug", "trace"];
+
+/// Handle for changing the logging at runtime.
+pub struct LogControl {
+    pub handle: flexi_logger::LoggerHandle,
+    pub logfile: Option<PathBuf>,
+}
+
+impl fmt::Debug for LogControl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LogControl")
+            .field("logfile", &self.logfile)
+            .finish()
+    }
+}
+
+impl LogControl {
+    /// Change the log level.
+    pub fn set_level(&mut self, level: &str) -> Res<()> {
+        if !LOG_LEVELS.contains(&level) {
+            let msg = format!("Invalid log level '{}'", level);
+            return Err(config::ConfigError::new(&msg).into());
+        }
+        let spec = flexi_logger::LogSpecification::parse(level)?;
+        self.handle.set_new_spec(spec);
+        info!("Log level changed to {}", level);
+        Ok(())
+    }
+
+    /// Move the current log file aside by adding a timestamp to its name, and continue in a new file.
+    /// Returns the new name of the old file.
+    pub fn rotate(&self) -> Res<PathBuf> {
+        let logfile = match &self.logfile {
+            Some(logfile) => logfile,
+            None => return Err(config::ConfigError::new("Not logging to a file").into()),
+        };
+        let time =
+            time::OffsetDateTime::now_local().unwrap_or_else(|_| time::OffsetDateTime::now_utc());
+        let now = format!(
+            "{:04}{:02}{:02}-{:02}{:02}{:02}",
+            time.year(),
+            time.month() as u8,
+            time.day(),
+            time.hour(),
+            time.minute(),
+            time.second()
+        );
+        let stem = logfile
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let newname = match logfile.extension() {
+            Some(ext) => format!("{}_{}.{}", stem, now, ext.to_string_lossy()),
+            None => format!("{}_{}", stem, now),
+        };
+        let rotated = logfile.with_file_name(newname);
+        self.handle.flush();
+        std::fs::rename(logfile, &rotated)?;
+        // Rebuild the file writer with the same settings as the logger, to start the new file.
+        // The logger was started with WriteMode::Async, which it uses for files without periodic flushing.
+        let writer = flexi_logger::writers::FileLogWriter::builder(
+            flexi_logger::FileSpec::try_from(logfile)?,
+        )
+        .write_mode(flexi_logger::WriteMode::AsyncWith {
+            bufsize: flexi_logger::DEFAULT_BUFFER_CAPACITY,
+            pool_capa: flexi_logger::DEFAULT_POOL_CAPA,
+            message_capa: flexi_logger::DEFAULT_MESSAGE_CAPA,
+            flush_interval: Duration::from_secs(0),
+        });
+        self.handle.reset_flw(&writer)?;
+        info!("Previous log file moved to {}", rotated.to_string_lossy());
+        Ok(rotated)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -45,6 +137,17 @@ pub struct ServerParameters<'a> {
     pub cert_file: Option<&'a str>,
     #[cfg(feature = "secure-websocket")]
     pub cert_pass: Option<&'a str>,
+    pub heartbeat: Option<HeartbeatParameters>,
+}
+
+/// Time in seconds that a client may take to answer a ping, when no timeout is given.
+pub const DEFAULT_PING_TIMEOUT: f32 = 10.0;
+
+/// Interval for sending pings to the clients, and how long to wait for an answer.
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatParameters {
+    pub interval: Duration,
+    pub timeout: Duration,
 }
 
 #[derive(Debug, PartialEq, Deserialize)]
@@ -52,38 +155,187 @@ enum WsCommand {
     SetConfigName(String),
     SetConfig(String),
     SetConfigJson(String),
+    #[cfg(feature = "url-config")]
+    LoadConfigFromUrl {
+        url: String,
+    },
     Reload,
     GetConfig,
     GetPreviousConfig,
+    GetNonDefaultConfig,
+    GetPendingChange,
+    SaveSnapshot {
+        name: String,
+    },
+    RecallSnapshot {
+        name: String,
+    },
+    ListSnapshots,
     ReadConfig(String),
     ReadConfigFile(String),
     ValidateConfig(String),
     GetConfigJson,
+    GetEffectiveConfig,
     GetConfigName,
     GetSignalRange,
     GetCaptureSignalRms,
     GetCaptureSignalPeak,
     GetPlaybackSignalRms,
     GetPlaybackSignalPeak,
+    GetChannelLabels,
     GetCaptureRate,
     GetUpdateInterval,
     SetUpdateInterval(usize),
     GetVolume,
-    SetVolume(f32),
+    SetVolume(VolumeValue),
+    AdjustVolume(f32),
+    GetGainOffset,
+    SetGainOffset {
+        db: f32,
+    },
+    ClearGainOffset,
     GetMute,
     SetMute(bool),
+    Pause,
+    Resume,
+    GetPaused,
+    FlushFilters,
+    InjectTone {
+        channel: usize,
+        freq: f32,
+        level_db: f32,
+        seconds: f32,
+    },
+    MeasureLoopbackLatency {
+        playback_channel: usize,
+        capture_channel: usize,
+    },
+    GetCaptureSpectrum {
+        channel: usize,
+        length: usize,
+        smoothing: Option<spectrum::OctaveSmoothing>,
+    },
+    SetPlaybackFormat(Option<String>),
+    SwapChannels {
+        a: usize,
+        b: usize,
+    },
+    InvertChannel {
+        channel: usize,
+    },
+    GetPipelineOrder,
+    SetPipelineOrder {
+        order: Vec<usize>,
+    },
     GetVersion,
+    GetVersionAndFeatures,
     GetState,
     GetStopReason,
+    GetRuntimeInfo,
     GetRateAdjust,
+    FreezeRateAdjust,
+    UnfreezeRateAdjust,
+    GetRateAdjustFrozen,
+    GetClockSource,
     GetClippedSamples,
+    GetClipCount,
+    GetClippingActive,
+    ResetClipCount,
+    GetOverloadReductions,
     GetBufferLevel,
+    GetPlaybackFile,
+    GetDynamicEqOffsets,
+    GetLoudnessBoost(String),
+    GetMixerAttenuations,
+    GetAgcGain,
+    GetFilterTimes,
+    GetProcessingHistogram,
+    ResetProcessingHistogram,
+    GetFilterNames,
+    GetFilterResponse {
+        name: String,
+        frequencies: Vec<f32>,
+    },
+    FitParametricEq {
+        measured: Vec<(f32, f32)>,
+        target: Vec<(f32, f32)>,
+        max_filters: usize,
+    },
+    GetPipelineImpulseResponse {
+        length: usize,
+    },
+    GetMixerNames,
+    StartMeterStream {
+        interval_ms: u64,
+        include: Vec<MeterValue>,
+    },
+    StopMeterStream,
     GetSupportedDeviceTypes,
+    SetLogLevel(String),
+    RotateLog,
     Exit,
     Stop,
     None,
 }
 
+/// A new config that has been set but not yet applied, compared to the active one.
+#[derive(Debug, PartialEq, Serialize)]
+struct PendingChange {
+    change: String,
+    differences: Vec<String>,
+}
+
+/// Uptime and restart counts, for monitoring the health of the process.
+#[derive(Debug, PartialEq, Serialize)]
+struct RuntimeInfo {
+    uptime: f64,
+    error_restarts: usize,
+    config_restarts: usize,
+    config_restart_reason: Option<String>,
+    state: ProcessingState,
+}
+
+/// Measured round-trip latency from a playback channel to a looped back capture channel.
+#[derive(Debug, PartialEq, Serialize)]
+struct LoopbackLatency {
+    samples: usize,
+    ms: f32,
+}
+
+/// Frequency response of a filter, with the magnitude in dB and the phase in degrees.
+#[derive(Debug, PartialEq, Serialize)]
+struct FilterResponse {
+    magnitude: Vec<f32>,
+    phase: Vec<f32>,
+}
+
+/// Peaking filters fitted to a measured response, with the names in the order they should be used.
+#[derive(Debug, PartialEq, Serialize)]
+struct FittedEq {
+    filters: HashMap<String, config::Filter>,
+    names: Vec<String>,
+    level_offset: f32,
+    max_error: f32,
+}
+
+/// A new volume, given either as a plain value in dB or together with a ramp time for this change only.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(untagged)]
+enum VolumeValue {
+    Plain(f32),
+    WithRamp { value: f32, ramp_ms: f32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+enum MeterValue {
+    #[serde(rename = "rms")]
+    Rms,
+    #[serde(rename = "peak")]
+    Peak,
+    #[serde(rename = "labels")]
+    Labels,
+}
+
 #[derive(Debug, PartialEq, Serialize)]
 enum WsResult {
     Ok,
@@ -98,6 +350,25 @@ enum WsReply {
     SetConfig {
         result: WsResult,
     },
+    #[cfg(feature = "url-config")]
+    LoadConfigFromUrl {
+        result: WsResult,
+    },
+    SwapChannels {
+        result: WsResult,
+        value: Option<config::Mixer>,
+    },
+    InvertChannel {
+        result: WsResult,
+        value: Option<config::Mixer>,
+    },
+    GetPipelineOrder {
+        result: WsResult,
+        value: Vec<String>,
+    },
+    SetPipelineOrder {
+        result: WsResult,
+    },
     SetConfigJson {
         result: WsResult,
     },
@@ -112,6 +383,24 @@ enum WsReply {
         result: WsResult,
         value: String,
     },
+    GetNonDefaultConfig {
+        result: WsResult,
+        value: Option<String>,
+    },
+    GetPendingChange {
+        result: WsResult,
+        value: Option<PendingChange>,
+    },
+    SaveSnapshot {
+        result: WsResult,
+    },
+    RecallSnapshot {
+        result: WsResult,
+    },
+    ListSnapshots {
+        result: WsResult,
+        value: Vec<String>,
+    },
     ReadConfig {
         result: WsResult,
         value: String,
@@ -128,6 +417,10 @@ enum WsReply {
         result: WsResult,
         value: String,
     },
+    GetEffectiveConfig {
+        result: WsResult,
+        value: String,
+    },
     GetConfigName {
         result: WsResult,
         value: String,
@@ -144,6 +437,10 @@ enum WsReply {
         result: WsResult,
         value: Vec<f32>,
     },
+    GetChannelLabels {
+        result: WsResult,
+        value: config::ChannelLabels,
+    },
     GetCaptureSignalRms {
         result: WsResult,
         value: Vec<f32>,
@@ -170,9 +467,50 @@ enum WsReply {
         result: WsResult,
         value: f32,
     },
+    AdjustVolume {
+        result: WsResult,
+        value: f32,
+    },
+    GetGainOffset {
+        result: WsResult,
+        value: f32,
+    },
+    SetGainOffset {
+        result: WsResult,
+    },
+    ClearGainOffset {
+        result: WsResult,
+    },
     SetMute {
         result: WsResult,
     },
+    Pause {
+        result: WsResult,
+    },
+    Resume {
+        result: WsResult,
+    },
+    GetPaused {
+        result: WsResult,
+        value: bool,
+    },
+    FlushFilters {
+        result: WsResult,
+    },
+    InjectTone {
+        result: WsResult,
+    },
+    MeasureLoopbackLatency {
+        result: WsResult,
+        value: Option<LoopbackLatency>,
+    },
+    GetCaptureSpectrum {
+        result: WsResult,
+        value: Option<spectrum::Spectrum>,
+    },
+    SetPlaybackFormat {
+        result: WsResult,
+    },
     GetMute {
         result: WsResult,
         value: bool,
@@ -181,6 +519,10 @@ enum WsReply {
         result: WsResult,
         value: String,
     },
+    GetVersionAndFeatures {
+        result: WsResult,
+        value: (String, Vec<String>),
+    },
     GetState {
         result: WsResult,
         value: ProcessingState,
@@ -189,10 +531,28 @@ enum WsReply {
         result: WsResult,
         value: StopReason,
     },
+    GetRuntimeInfo {
+        result: WsResult,
+        value: RuntimeInfo,
+    },
     GetRateAdjust {
         result: WsResult,
         value: f32,
     },
+    FreezeRateAdjust {
+        result: WsResult,
+    },
+    UnfreezeRateAdjust {
+        result: WsResult,
+    },
+    GetRateAdjustFrozen {
+        result: WsResult,
+        value: bool,
+    },
+    GetClockSource {
+        result: WsResult,
+        value: config::ClockSource,
+    },
     GetBufferLevel {
         result: WsResult,
         value: usize,
@@ -201,10 +561,102 @@ enum WsReply {
         result: WsResult,
         value: usize,
     },
+    GetClipCount {
+        result: WsResult,
+        value: usize,
+    },
+    GetClippingActive {
+        result: WsResult,
+        value: bool,
+    },
+    ResetClipCount {
+        result: WsResult,
+    },
+    GetOverloadReductions {
+        result: WsResult,
+        value: usize,
+    },
+    GetPlaybackFile {
+        result: WsResult,
+        value: Option<String>,
+    },
+    GetLoudnessBoost {
+        result: WsResult,
+        value: Option<loudness::LoudnessBoost>,
+    },
+    GetDynamicEqOffsets {
+        result: WsResult,
+        value: HashMap<String, f32>,
+    },
+    GetMixerAttenuations {
+        result: WsResult,
+        value: HashMap<String, Vec<f32>>,
+    },
+    GetAgcGain {
+        result: WsResult,
+        value: Option<f32>,
+    },
+    GetFilterTimes {
+        result: WsResult,
+        value: HashMap<String, countertimer::FilterTime>,
+    },
+    GetProcessingHistogram {
+        result: WsResult,
+        value: Vec<usize>,
+    },
+    ResetProcessingHistogram {
+        result: WsResult,
+    },
+    GetFilterNames {
+        result: WsResult,
+        value: HashMap<String, String>,
+    },
+    GetFilterResponse {
+        result: WsResult,
+        value: Option<FilterResponse>,
+    },
+    FitParametricEq {
+        result: WsResult,
+        value: Option<FittedEq>,
+    },
+    GetPipelineImpulseResponse {
+        result: WsResult,
+        value: Option<Vec<Vec<Vec<f32>>>>,
+    },
+    GetMixerNames {
+        result: WsResult,
+        value: Vec<String>,
+    },
+    StartMeterStream {
+        result: WsResult,
+    },
+    StopMeterStream {
+        result: WsResult,
+    },
+    MeterFrame {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        capture_rms: Option<Vec<f32>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        capture_peak: Option<Vec<f32>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        playback_rms: Option<Vec<f32>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        playback_peak: Option<Vec<f32>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        capture_labels: Option<Vec<String>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        playback_labels: Option<Vec<String>>,
+    },
     GetSupportedDeviceTypes {
         result: WsResult,
         value: (Vec<String>, Vec<String>),
     },
+    SetLogLevel {
+        result: WsResult,
+    },
+    RotateLog {
+        result: WsResult,
+    },
     Exit {
         result: WsResult,
     },
@@ -216,6 +668,520 @@ enum WsReply {
     },
 }
 
+/// Timeout for fetching a config from a URL.
+#[cfg(feature = "url-config")]
+const URL_CONFIG_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Fetch a config from an http or https URL. The certificates of https servers are verified.
+#[cfg(feature = "url-config")]
+fn fetch_config(url: &str) -> Res<config::Configuration> {
+    if !(url.starts_with("http://") || url.starts_with("https://")) {
+        let msg = format!("Unsupported URL '{}', only http and https can be used", url);
+        return Err(config::ConfigError::new(&msg).into());
+    }
+    let agent = ureq::AgentBuilder::new()
+        .timeout(URL_CONFIG_TIMEOUT)
+        .build();
+    let config_yml = agent.get(url).call()?.into_string()?;
+    let conf = serde_yaml::from_str(&config_yml)?;
+    Ok(conf)
+}
+
+/// Longest allowed duration of a test tone, in seconds.
+const MAX_TONE_SECONDS: f32 = 60.0;
+
+/// Check the parameters of a test tone against the active config.
+fn check_tone(
+    conf: &config::Configuration,
+    channel: usize,
+    freq: f32,
+    level_db: f32,
+    seconds: f32,
+) -> Res<()> {
+    let channels = conf.devices.playback.channels();
+    if channel >= channels {
+        let msg = format!(
+            "Invalid channel {}, the playback device has {} channels",
+            channel, channels
+        );
+        return Err(config::ConfigError::new(&msg).into());
+    }
+    if freq <= 0.0 || freq >= conf.devices.samplerate as f32 / 2.0 {
+        return Err(
+            config::ConfigError::new("Tone frequency must be > 0 and < samplerate/2").into(),
+        );
+    }
+    if level_db > 0.0 {
+        return Err(config::ConfigError::new("Tone level must be <= 0 dB").into());
+    }
+    if seconds <= 0.0 || seconds > MAX_TONE_SECONDS {
+        let msg = format!(
+            "Tone duration must be > 0 and <= {} seconds",
+            MAX_TONE_SECONDS
+        );
+        return Err(config::ConfigError::new(&msg).into());
+    }
+    Ok(())
+}
+
+/// Hand a test tone over to the processing thread, without waiting for it to play.
+fn inject_tone(
+    shared_data_inst: &SharedData,
+    channel: usize,
+    freq: f32,
+    level_db: f32,
+    seconds: f32,
+) -> Res<()> {
+    let samplerate = match shared_data_inst.active_config.lock().unwrap().as_ref() {
+        Some(conf) => {
+            check_tone(conf, channel, freq, level_db, seconds)?;
+            conf.devices.samplerate
+        }
+        None => return Err(config::ConfigError::new("No active config").into()),
+    };
+    // The processing thread picks up the tone from here, replacing any tone that is still playing
+    shared_data_inst
+        .processing_status
+        .write()
+        .unwrap()
+        .test_tone = Some(processing::TestTone::new(
+        channel, freq, level_db, seconds, samplerate,
+    ));
+    Ok(())
+}
+
+/// Longest time to wait for the click of a latency measurement to come back.
+const LOOPBACK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long the capture channel must be quiet before the click of a latency measurement is sent.
+const LOOPBACK_QUIET_TIME: Duration = Duration::from_millis(200);
+
+/// Longest time to wait for the processing thread to evaluate a filter response.
+const FILTER_RESPONSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Counter used to give each request to the processing thread its own id.
+static NEXT_REQUEST_ID: AtomicUsize = AtomicUsize::new(0);
+
+fn next_request_id() -> usize {
+    NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Evaluate the response of a filter of the running pipeline at the given frequencies.
+/// The processing thread evaluates the current coefficients of the filter,
+/// so that changes made while running are included.
+fn get_filter_response(
+    shared_data_inst: &SharedData,
+    name: &str,
+    frequencies: &[f32],
+) -> Res<FilterResponse> {
+    let samplerate = match shared_data_inst.active_config.lock().unwrap().as_ref() {
+        Some(conf) => {
+            if !conf.filters.contains_key(name) {
+                let msg = format!("No filter named '{}' in the active config", name);
+                return Err(config::ConfigError::new(&msg).into());
+            }
+            conf.devices.samplerate
+        }
+        None => return Err(config::ConfigError::new("No active config").into()),
+    };
+    let nyquist = samplerate as f32 / 2.0;
+    if let Some(freq) = frequencies
+        .iter()
+        .find(|freq| !(0.0..=nyquist).contains(*freq))
+    {
+        let msg = format!(
+            "Frequency {} Hz is outside the range 0 to {} Hz",
+            freq, nyquist
+        );
+        return Err(config::ConfigError::new(&msg).into());
+    }
+    let frequencies: Vec<PrcFmt> = frequencies.iter().map(|freq| *freq as PrcFmt).collect();
+    let (tx_reply, rx_reply) = mpsc::channel();
+    let id = next_request_id();
+    shared_data_inst
+        .processing_status
+        .write()
+        .unwrap()
+        .filter_responses
+        .push(processing::FilterResponseRequest {
+            id,
+            name: name.to_string(),
+            frequencies,
+            reply: tx_reply,
+        });
+    let response = match rx_reply.recv_timeout(FILTER_RESPONSE_TIMEOUT) {
+        Ok(Ok(response)) => response,
+        Ok(Err(msg)) => return Err(config::ConfigError::new(&msg).into()),
+        Err(_) => {
+            shared_data_inst
+                .processing_status
+                .write()
+                .unwrap()
+                .filter_responses
+                .retain(|request| request.id != id);
+            return Err(config::ConfigError::new("Processing is not running").into());
+        }
+    };
+    let (magnitude, phase) = response
+        .iter()
+        .map(|value| {
+            let (magn, angle) = value.to_polar();
+            // A zero in the response is reported as -1000 dB, like silence in the signal levels.
+            let magn_db = if magn == 0.0 {
+                -1000.0
+            } else {
+                20.0 * magn.log10() as f32
+            };
+            (magn_db, angle.to_degrees() as f32)
+        })
+        .unzip();
+    Ok(FilterResponse { magnitude, phase })
+}
+
+/// Fit peaking filters that correct a measured response towards a target, at the samplerate of the active config.
+fn fit_parametric_eq(
+    shared_data_inst: &SharedData,
+    measured: &[(f32, f32)],
+    target: &[(f32, f32)],
+    max_filters: usize,
+) -> Res<FittedEq> {
+    let samplerate = match shared_data_inst.active_config.lock().unwrap().as_ref() {
+        Some(conf) => conf.devices.samplerate,
+        None => return Err(config::ConfigError::new("No active config").into()),
+    };
+    let to_prcfmt = |curve: &[(f32, f32)]| -> Vec<(PrcFmt, PrcFmt)> {
+        curve
+            .iter()
+            .map(|(freq, level)| (*freq as PrcFmt, *level as PrcFmt))
+            .collect()
+    };
+    let fit = peqfit::fit_peaking_eq(
+        &to_prcfmt(measured),
+        &to_prcfmt(target),
+        max_filters,
+        samplerate,
+    )?;
+    let names: Vec<String> = (1..=fit.peaks.len())
+        .map(|n| format!("peq_fit_{}", n))
+        .collect();
+    let filters = names
+        .iter()
+        .cloned()
+        .zip(
+            fit.peaks
+                .into_iter()
+                .map(|parameters| config::Filter::Biquad { parameters }),
+        )
+        .collect();
+    Ok(FittedEq {
+        filters,
+        names,
+        level_offset: fit.level_offset as f32,
+        max_error: fit.max_error as f32,
+    })
+}
+
+/// Longest impulse response that can be requested, in seconds at the samplerate of the active config.
+const MAX_IMPULSE_RESPONSE_SECONDS: usize = 10;
+
+/// Largest number of values in an impulse response reply, counting all pairs of input and output channels.
+/// This limits the size of the reply, that is built as a single message.
+const MAX_IMPULSE_RESPONSE_VALUES: usize = 2_000_000;
+
+/// Get the longest impulse response that can be requested for a config,
+/// limited both in time and by the total number of values of the reply.
+fn max_impulse_response_length(conf: &config::Configuration) -> usize {
+    let nbr_paths = config::get_pipeline_input_channels(conf) * conf.devices.playback.channels();
+    (MAX_IMPULSE_RESPONSE_SECONDS * conf.devices.samplerate)
+        .min(MAX_IMPULSE_RESPONSE_VALUES / nbr_paths.max(1))
+}
+
+/// Run an impulse through a copy of the pipeline of the active config, without affecting the running processing.
+fn get_pipeline_impulse_response(
+    shared_data_inst: &SharedData,
+    length: usize,
+) -> Res<Vec<Vec<Vec<f32>>>> {
+    let conf = match shared_data_inst.active_config.lock().unwrap().as_ref() {
+        Some(conf) => conf.clone(),
+        None => return Err(config::ConfigError::new("No active config").into()),
+    };
+    let max_length = max_impulse_response_length(&conf);
+    if length == 0 || length > max_length {
+        let msg = format!(
+            "Invalid length {}, must be between 1 and {} samples",
+            length, max_length
+        );
+        return Err(config::ConfigError::new(&msg).into());
+    }
+    let response = selftest::impulse_response(conf, length)
+        .into_iter()
+        .map(|input| {
+            input
+                .into_iter()
+                .map(|channel| channel.into_iter().map(|value| value as f32).collect())
+                .collect()
+        })
+        .collect();
+    Ok(response)
+}
+
+/// Send a click on a playback channel, and measure the time until it is captured on a looped back capture channel.
+fn measure_loopback_latency(
+    shared_data_inst: &SharedData,
+    playback_channel: usize,
+    capture_channel: usize,
+) -> Res<LoopbackLatency> {
+    let samplerate = match shared_data_inst.active_config.lock().unwrap().as_ref() {
+        Some(conf) => {
+            let playback_channels = conf.devices.playback.channels();
+            if playback_channel >= playback_channels {
+                let msg = format!(
+                    "Invalid playback channel {}, the playback device has {} channels",
+                    playback_channel, playback_channels
+                );
+                return Err(config::ConfigError::new(&msg).into());
+            }
+            let converted = config::get_converted_capture_channels(conf);
+            if !converted.get(capture_channel).copied().unwrap_or(false) {
+                let msg = format!(
+                    "Invalid capture channel {}, it must be one of the {} channels of the capture device and used by the pipeline",
+                    capture_channel,
+                    converted.len()
+                );
+                return Err(config::ConfigError::new(&msg).into());
+            }
+            conf.devices.samplerate
+        }
+        None => return Err(config::ConfigError::new("No active config").into()),
+    };
+    let (tx_reply, rx_reply) = mpsc::channel();
+    let id = next_request_id();
+    shared_data_inst
+        .processing_status
+        .write()
+        .unwrap()
+        .latency_measurement = Some(processing::LatencyRequest {
+        id,
+        measurement: processing::LatencyMeasurement::new(
+            playback_channel,
+            capture_channel,
+            (LOOPBACK_QUIET_TIME.as_secs_f32() * samplerate as f32) as usize,
+            ((LOOPBACK_QUIET_TIME + LOOPBACK_TIMEOUT).as_secs_f32() * samplerate as f32) as usize,
+        ),
+        reply: tx_reply,
+    });
+    let result = rx_reply.recv_timeout(LOOPBACK_QUIET_TIME + LOOPBACK_TIMEOUT);
+    if let Err(mpsc::RecvTimeoutError::Timeout) = result {
+        // Withdraw the request if processing never took it
+        let mut params = shared_data_inst.processing_status.write().unwrap();
+        if params
+            .latency_measurement
+            .as_ref()
+            .map_or(false, |request| request.id == id)
+        {
+            params.latency_measurement = None;
+        }
+    }
+    match result {
+        Ok(Some(samples)) => Ok(LoopbackLatency {
+            samples,
+            ms: 1000.0 * samples as f32 / samplerate as f32,
+        }),
+        Err(mpsc::RecvTimeoutError::Disconnected) => Err(config::ConfigError::new(
+            "The measurement was interrupted, by another measurement or a restart of processing",
+        )
+        .into()),
+        Ok(None) | Err(mpsc::RecvTimeoutError::Timeout) => {
+            let msg = format!(
+                "No click detected on capture channel {} within {} seconds, is it looped back and otherwise quiet?",
+                capture_channel,
+                LOOPBACK_TIMEOUT.as_secs()
+            );
+            Err(config::ConfigError::new(&msg).into())
+        }
+    }
+}
+
+/// Time to wait for the processing thread to collect the samples of a spectrum,
+/// in addition to the duration of the samples.
+const SPECTRUM_TIMEOUT_MARGIN: Duration = Duration::from_secs(1);
+
+/// Calculate the spectrum of a capture channel, from samples collected by the processing thread.
+/// The samples are taken as they are captured, before any processing.
+fn get_capture_spectrum(
+    shared_data_inst: &SharedData,
+    channel: usize,
+    length: usize,
+    smoothing: Option<spectrum::OctaveSmoothing>,
+) -> Res<spectrum::Spectrum> {
+    spectrum::validate_length(length)?;
+    let samplerate = match shared_data_inst.active_config.lock().unwrap().as_ref() {
+        Some(conf) => {
+            let converted = config::get_converted_capture_channels(conf);
+            if !converted.get(channel).copied().unwrap_or(false) {
+                let msg = format!(
+                    "Invalid capture channel {}, it must be one of the {} channels of the capture device and used by the pipeline",
+                    channel,
+                    converted.len()
+                );
+                return Err(config::ConfigError::new(&msg).into());
+            }
+            conf.devices.samplerate
+        }
+        None => return Err(config::ConfigError::new("No active config").into()),
+    };
+    let (tx_reply, rx_reply) = mpsc::channel();
+    let id = next_request_id();
+    shared_data_inst
+        .processing_status
+        .write()
+        .unwrap()
+        .spectrum_requests
+        .push(processing::SpectrumRequest {
+            id,
+            collector: spectrum::SpectrumCollector::new(channel, length),
+            reply: tx_reply,
+        });
+    let timeout =
+        Duration::from_secs_f32(length as f32 / samplerate as f32) + SPECTRUM_TIMEOUT_MARGIN;
+    let samples = match rx_reply.recv_timeout(timeout) {
+        Ok(samples) => samples,
+        Err(_) => {
+            shared_data_inst
+                .processing_status
+                .write()
+                .unwrap()
+                .spectrum_requests
+                .retain(|request| request.id != id);
+            return Err(config::ConfigError::new("Processing is not running").into());
+        }
+    };
+    spectrum::calculate_spectrum(&samples, samplerate, smoothing)
+}
+
+/// Shortest interval between pushed metering frames.
+const MIN_METER_INTERVAL_MS: u64 = 20;
+
+/// Settings of an active stream of metering frames for a connection.
+struct MeterStream {
+    interval: Duration,
+    rms: bool,
+    peak: bool,
+    labels: bool,
+    last_sent: Instant,
+}
+
+impl MeterStream {
+    fn new(interval_ms: u64, include: &[MeterValue]) -> Self {
+        MeterStream {
+            interval: Duration::from_millis(interval_ms.max(MIN_METER_INTERVAL_MS)),
+            rms: include.contains(&MeterValue::Rms),
+            peak: include.contains(&MeterValue::Peak),
+            labels: include.contains(&MeterValue::Labels),
+            last_sent: Instant::now(),
+        }
+    }
+
+    /// Time left until the next frame is due.
+    fn time_to_next(&self) -> Duration {
+        self.interval.saturating_sub(self.last_sent.elapsed())
+    }
+
+    /// Build a frame from the current levels, if one is due.
+    fn next_frame(&mut self, shared_data_inst: &SharedData) -> Option<WsReply> {
+        if self.last_sent.elapsed() < self.interval {
+            return None;
+        }
+        self.last_sent = Instant::now();
+        let capstat = shared_data_inst.capture_status.read().unwrap();
+        let pbstat = shared_data_inst.playback_status.read().unwrap();
+        let capture_values = |values: &Vec<f32>| {
+            if capstat.meter_used_only {
+                mask_unused_channels(values, &capstat.used_channels)
+            } else {
+                values.clone()
+            }
+        };
+        Some(WsReply::MeterFrame {
+            capture_rms: self.rms.then(|| capture_values(&capstat.signal_rms)),
+            capture_peak: self.peak.then(|| capture_values(&capstat.signal_peak)),
+            playback_rms: self.rms.then(|| pbstat.signal_rms.clone()),
+            playback_peak: self.peak.then(|| pbstat.signal_peak.clone()),
+            capture_labels: self.labels.then(|| capstat.channel_labels.clone()),
+            playback_labels: self.labels.then(|| pbstat.channel_labels.clone()),
+        })
+    }
+}
+
+/// Keepalive state of a connection.
+/// A ping is sent when nothing has been received from the client for one interval.
+/// Any message from the client, including the pong, counts as an answer.
+struct Heartbeat {
+    parameters: HeartbeatParameters,
+    last_received: Instant,
+    ping_sent: Option<Instant>,
+}
+
+impl Heartbeat {
+    fn new(parameters: HeartbeatParameters) -> Self {
+        Heartbeat {
+            parameters,
+            last_received: Instant::now(),
+            ping_sent: None,
+        }
+    }
+
+    /// Time left until a ping is due, or until an unanswered ping times out.
+    fn time_to_next(&self) -> Duration {
+        match self.ping_sent {
+            Some(sent) => self.parameters.timeout.saturating_sub(sent.elapsed()),
+            None => self
+                .parameters
+                .interval
+                .saturating_sub(self.last_received.elapsed()),
+        }
+    }
+
+    fn received(&mut self) {
+        self.last_received = Instant::now();
+        self.ping_sent = None;
+    }
+
+    fn ping_due(&self) -> bool {
+        self.ping_sent.is_none() && self.last_received.elapsed() >= self.parameters.interval
+    }
+
+    fn timed_out(&self) -> bool {
+        self.ping_sent
+            .map_or(false, |sent| sent.elapsed() >= self.parameters.timeout)
+    }
+}
+
+/// Start or stop the meter stream of a connection.
+fn update_meter_stream(command: &WsCommand, meter_stream: &mut Option<MeterStream>) {
+    match command {
+        WsCommand::StartMeterStream {
+            interval_ms,
+            include,
+        } => {
+            *meter_stream = Some(MeterStream::new(*interval_ms, include));
+        }
+        WsCommand::StopMeterStream => {
+            *meter_stream = None;
+        }
+        _ => {}
+    }
+}
+
+fn plain_tcp_stream(websocket: &WebSocket<TcpStream>) -> &TcpStream {
+    websocket.get_ref()
+}
+
+#[cfg(feature = "secure-websocket")]
+fn tls_tcp_stream(websocket: &WebSocket<TlsStream<TcpStream>>) -> &TcpStream {
+    websocket.get_ref().get_ref()
+}
+
 fn parse_command(cmd: Message) -> Res<WsCommand> {
     match cmd {
         Message::Text(command_str) => {
@@ -226,6 +1192,21 @@ fn parse_command(cmd: Message) -> Res<WsCommand> {
     }
 }
 
+/// Replace the values of unused channels by -1000 dB, keeping the positions of the used ones.
+fn mask_unused_channels(values: &[f32], used_channels: &[bool]) -> Vec<f32> {
+    values
+        .iter()
+        .enumerate()
+        .map(|(n, value)| {
+            if used_channels.get(n).copied().unwrap_or(true) {
+                *value
+            } else {
+                -1000.0
+            }
+        })
+        .collect()
+}
+
 #[cfg(feature = "secure-websocket")]
 fn make_acceptor_with_cert(cert: &str, key: &str) -> Res<Arc<TlsAcceptor>> {
     let mut file = File::open(cert)?;
@@ -260,6 +1241,7 @@ pub fn start_server(parameters: ServerParameters, shared_data: SharedData) {
     debug!("Start websocket server on {}:{}", address, parameters.port);
     #[cfg(feature = "secure-websocket")]
     let acceptor = make_acceptor(&parameters.cert_file, &parameters.cert_pass);
+    let heartbeat = parameters.heartbeat;
 
     thread::spawn(move || {
         let ws_result = TcpListener::bind(format!("{}:{}", address, port));
@@ -273,17 +1255,17 @@ pub fn start_server(parameters: ServerParameters, shared_data: SharedData) {
                 thread::spawn(move || match acceptor_inst {
                     None => {
                         let websocket_res = accept_plain_stream(stream);
-                        handle_tcp(websocket_res, &shared_data_inst);
+                        handle_tcp(websocket_res, &shared_data_inst, heartbeat);
                     }
                     Some(acc) => {
                         let websocket_res = accept_secure_stream(acc, stream);
-                        handle_tls(websocket_res, &shared_data_inst);
+                        handle_tls(websocket_res, &shared_data_inst, heartbeat);
                     }
                 });
                 #[cfg(not(feature = "secure-websocket"))]
                 thread::spawn(move || {
                     let websocket_res = accept_plain_stream(stream);
-                    handle_tcp(websocket_res, &shared_data_inst);
+                    handle_tcp(websocket_res, &shared_data_inst, heartbeat);
                 });
             }
         } else if let Err(err) = ws_result {
@@ -293,53 +1275,110 @@ pub fn start_server(parameters: ServerParameters, shared_data: SharedData) {
 }
 
 macro_rules! make_handler {
-    ($t:ty, $n:ident) => {
-        fn $n(websocket_res: Res<WebSocket<$t>>, shared_data_inst: &SharedData) {
+    ($t:ty, $n:ident, $s:ident) => {
+        fn $n(
+            websocket_res: Res<WebSocket<$t>>,
+            shared_data_inst: &SharedData,
+            heartbeat_params: Option<HeartbeatParameters>,
+        ) {
             match websocket_res {
-                Ok(mut websocket) => loop {
-                    let msg_res = websocket.read_message();
-                    match msg_res {
-                        Ok(msg) => {
-                            trace!("received: {:?}", msg);
-                            let command = parse_command(msg);
-                            debug!("parsed command: {:?}", command);
-                            let reply = match command {
-                                Ok(cmd) => handle_command(cmd, &shared_data_inst),
-                                Err(err) => Some(WsReply::Invalid {
-                                    error: err.to_string(),
-                                }),
-                            };
-                            if let Some(rep) = reply {
-                                let write_result = websocket.write_message(Message::text(
-                                    serde_json::to_string(&rep).unwrap(),
-                                ));
-                                if let Err(err) = write_result {
+                Ok(mut websocket) => {
+                    let mut meter_stream: Option<MeterStream> = None;
+                    let mut heartbeat = heartbeat_params.map(Heartbeat::new);
+                    loop {
+                        // Wake up in time for the next metering frame or heartbeat check
+                        let timeout = meter_stream
+                            .as_ref()
+                            .map(|stream| stream.time_to_next())
+                            .into_iter()
+                            .chain(heartbeat.as_ref().map(|hb| hb.time_to_next()))
+                            .min()
+                            .map(|time| time.max(Duration::from_millis(1)));
+                        if let Err(err) = $s(&websocket).set_read_timeout(timeout) {
+                            warn!("Failed to set read timeout: {}", err);
+                        }
+                        let msg_res = websocket.read_message();
+                        match msg_res {
+                            Ok(msg) => {
+                                trace!("received: {:?}", msg);
+                                if let Some(hb) = heartbeat.as_mut() {
+                                    hb.received();
+                                }
+                                let command = parse_command(msg);
+                                debug!("parsed command: {:?}", command);
+                                let reply = match command {
+                                    Ok(cmd) => {
+                                        update_meter_stream(&cmd, &mut meter_stream);
+                                        handle_command(cmd, &shared_data_inst)
+                                    }
+                                    Err(err) => Some(WsReply::Invalid {
+                                        error: err.to_string(),
+                                    }),
+                                };
+                                if let Some(rep) = reply {
+                                    let write_result = websocket.write_message(Message::text(
+                                        serde_json::to_string(&rep).unwrap(),
+                                    ));
+                                    if let Err(err) = write_result {
+                                        warn!("Failed to write: {}", err);
+                                        break;
+                                    }
+                                } else {
+                                    debug!("Sending no reply");
+                                }
+                            }
+                            Err(tungstenite::error::Error::Io(err))
+                                if err.kind() == std::io::ErrorKind::WouldBlock
+                                    || err.kind() == std::io::ErrorKind::TimedOut => {}
+                            Err(tungstenite::error::Error::ConnectionClosed) => {
+                                debug!("Connection was closed");
+                                break;
+                            }
+                            Err(err) => {
+                                warn!("Lost connection: {}", err);
+                                break;
+                            }
+                        }
+                        if let Some(hb) = heartbeat.as_mut() {
+                            if hb.timed_out() {
+                                warn!("Client did not answer ping, closing connection");
+                                let _ = websocket.close(None);
+                                let _ = websocket.write_pending();
+                                break;
+                            }
+                            if hb.ping_due() {
+                                trace!("Sending ping");
+                                if let Err(err) = websocket.write_message(Message::Ping(Vec::new()))
+                                {
                                     warn!("Failed to write: {}", err);
                                     break;
                                 }
-                            } else {
-                                debug!("Sending no reply");
+                                hb.ping_sent = Some(Instant::now());
                             }
                         }
-                        Err(tungstenite::error::Error::ConnectionClosed) => {
-                            debug!("Connection was closed");
-                            break;
-                        }
-                        Err(err) => {
-                            warn!("Lost connection: {}", err);
-                            break;
+                        if let Some(frame) = meter_stream
+                            .as_mut()
+                            .and_then(|stream| stream.next_frame(&shared_data_inst))
+                        {
+                            let write_result = websocket.write_message(Message::text(
+                                serde_json::to_string(&frame).unwrap(),
+                            ));
+                            if let Err(err) = write_result {
+                                warn!("Failed to write: {}", err);
+                                break;
+                            }
                         }
                     }
-                },
+                }
                 Err(err) => warn!("Connection failed: {}", err),
             };
         }
     };
 }
 
-make_handler!(TcpStream, handle_tcp);
+make_handler!(TcpStream, handle_tcp, plain_tcp_stream);
 #[cfg(feature = "secure-websocket")]
-make_handler!(TlsStream<TcpStream>, handle_tls);
+make_handler!(TlsStream<TcpStream>, handle_tls, tls_tcp_stream);
 
 #[cfg(feature = "secure-websocket")]
 fn accept_secure_stream(
@@ -357,6 +1396,50 @@ fn accept_plain_stream(
     Ok(ws)
 }
 
+/// Get the type of a filter, as written in the "type" field of the config.
+fn filter_type_name(filter: &config::Filter) -> String {
+    serde_json::to_value(filter)
+        .ok()
+        .and_then(|value| value["type"].as_str().map(|name| name.to_string()))
+        .unwrap_or_default()
+}
+
+/// Validate a config received at runtime and queue it for a reload.
+/// The config is also stored as it was received, as the base for later changes.
+fn queue_new_config(shared_data_inst: &SharedData, raw_conf: config::Configuration) -> Res<()> {
+    let mut conf = raw_conf.clone();
+    config::validate_config(&mut conf, None)?;
+    *shared_data_inst.raw_config.lock().unwrap() = Some(raw_conf);
+    *shared_data_inst.new_config.lock().unwrap() = Some(conf);
+    shared_data_inst
+        .signal_reload
+        .store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Apply a change to the pending config, or to the active one if nothing is pending,
+/// and queue the result for a reload.
+/// The change is made to the config as it was loaded, which is then validated from scratch.
+fn modify_config<F, T>(shared_data_inst: &SharedData, change: F) -> Res<T>
+where
+    F: FnOnce(&mut config::Configuration) -> Res<T>,
+{
+    let mut raw_config = shared_data_inst.raw_config.lock().unwrap();
+    let mut raw_conf = match raw_config.clone() {
+        Some(conf) => conf,
+        None => return Err(config::ConfigError::new("No config loaded").into()),
+    };
+    let value = change(&mut raw_conf)?;
+    let mut conf = raw_conf.clone();
+    config::validate_config(&mut conf, None)?;
+    *raw_config = Some(raw_conf);
+    *shared_data_inst.new_config.lock().unwrap() = Some(conf);
+    shared_data_inst
+        .signal_reload
+        .store(true, Ordering::Relaxed);
+    Ok(value)
+}
+
 fn handle_command(command: WsCommand, shared_data_inst: &SharedData) -> Option<WsReply> {
     match command {
         WsCommand::Reload => {
@@ -383,9 +1466,14 @@ fn handle_command(command: WsCommand, shared_data_inst: &SharedData) -> Option<W
         }
         WsCommand::GetCaptureSignalRms => {
             let capstat = shared_data_inst.capture_status.read().unwrap();
+            let value = if capstat.meter_used_only {
+                mask_unused_channels(&capstat.signal_rms, &capstat.used_channels)
+            } else {
+                capstat.signal_rms.clone()
+            };
             Some(WsReply::GetCaptureSignalRms {
                 result: WsResult::Ok,
-                value: capstat.signal_rms.clone(),
+                value,
             })
         }
         WsCommand::GetPlaybackSignalRms => {
@@ -397,9 +1485,14 @@ fn handle_command(command: WsCommand, shared_data_inst: &SharedData) -> Option<W
         }
         WsCommand::GetCaptureSignalPeak => {
             let capstat = shared_data_inst.capture_status.read().unwrap();
+            let value = if capstat.meter_used_only {
+                mask_unused_channels(&capstat.signal_peak, &capstat.used_channels)
+            } else {
+                capstat.signal_peak.clone()
+            };
             Some(WsReply::GetCaptureSignalPeak {
                 result: WsResult::Ok,
-                value: capstat.signal_peak.clone(),
+                value,
             })
         }
         WsCommand::GetPlaybackSignalPeak => {
@@ -409,10 +1502,32 @@ fn handle_command(command: WsCommand, shared_data_inst: &SharedData) -> Option<W
                 value: pbstat.signal_peak.clone(),
             })
         }
+        WsCommand::GetChannelLabels => {
+            let capture = shared_data_inst
+                .capture_status
+                .read()
+                .unwrap()
+                .channel_labels
+                .clone();
+            let playback = shared_data_inst
+                .playback_status
+                .read()
+                .unwrap()
+                .channel_labels
+                .clone();
+            Some(WsReply::GetChannelLabels {
+                result: WsResult::Ok,
+                value: config::ChannelLabels { capture, playback },
+            })
+        }
         WsCommand::GetVersion => Some(WsReply::GetVersion {
             result: WsResult::Ok,
             value: crate_version!().to_string(),
         }),
+        WsCommand::GetVersionAndFeatures => Some(WsReply::GetVersionAndFeatures {
+            result: WsResult::Ok,
+            value: (crate_version!().to_string(), list_features()),
+        }),
         WsCommand::GetState => {
             let capstat = shared_data_inst.capture_status.read().unwrap();
             Some(WsReply::GetState {
@@ -420,6 +1535,13 @@ fn handle_command(command: WsCommand, shared_data_inst: &SharedData) -> Option<W
                 value: capstat.state,
             })
         }
+        WsCommand::GetOverloadReductions => {
+            let stat = shared_data_inst.status.read().unwrap();
+            Some(WsReply::GetOverloadReductions {
+                result: WsResult::Ok,
+                value: stat.overload_reductions,
+            })
+        }
         WsCommand::GetStopReason => {
             let stat = shared_data_inst.status.read().unwrap();
             let value = stat.stop_reason.clone();
@@ -428,6 +1550,20 @@ fn handle_command(command: WsCommand, shared_data_inst: &SharedData) -> Option<W
                 value,
             })
         }
+        WsCommand::GetRuntimeInfo => {
+            let stat = shared_data_inst.status.read().unwrap();
+            let capstat = shared_data_inst.capture_status.read().unwrap();
+            Some(WsReply::GetRuntimeInfo {
+                result: WsResult::Ok,
+                value: RuntimeInfo {
+                    uptime: stat.start_time.elapsed().as_secs_f64(),
+                    error_restarts: stat.error_restarts,
+                    config_restarts: stat.config_restarts,
+                    config_restart_reason: stat.config_restart_reason.clone(),
+                    state: capstat.state,
+                },
+            })
+        }
         WsCommand::GetRateAdjust => {
             let capstat = shared_data_inst.capture_status.read().unwrap();
             Some(WsReply::GetRateAdjust {
@@ -435,6 +1571,38 @@ fn handle_command(command: WsCommand, shared_data_inst: &SharedData) -> Option<W
                 value: capstat.rate_adjust,
             })
         }
+        WsCommand::FreezeRateAdjust => {
+            let mut capstat = shared_data_inst.capture_status.write().unwrap();
+            debug!("Freezing rate adjust at {}", capstat.rate_adjust);
+            capstat.rate_adjust_frozen = true;
+            Some(WsReply::FreezeRateAdjust {
+                result: WsResult::Ok,
+            })
+        }
+        WsCommand::UnfreezeRateAdjust => {
+            shared_data_inst
+                .capture_status
+                .write()
+                .unwrap()
+                .rate_adjust_frozen = false;
+            Some(WsReply::UnfreezeRateAdjust {
+                result: WsResult::Ok,
+            })
+        }
+        WsCommand::GetRateAdjustFrozen => {
+            let capstat = shared_data_inst.capture_status.read().unwrap();
+            Some(WsReply::GetRateAdjustFrozen {
+                result: WsResult::Ok,
+                value: capstat.rate_adjust_frozen,
+            })
+        }
+        WsCommand::GetClockSource => {
+            let capstat = shared_data_inst.capture_status.read().unwrap();
+            Some(WsReply::GetClockSource {
+                result: WsResult::Ok,
+                value: capstat.clock_source,
+            })
+        }
         WsCommand::GetClippedSamples => {
             let pbstat = shared_data_inst.playback_status.read().unwrap();
             Some(WsReply::GetClippedSamples {
@@ -442,6 +1610,32 @@ fn handle_command(command: WsCommand, shared_data_inst: &SharedData) -> Option<W
                 value: pbstat.clipped_samples,
             })
         }
+        WsCommand::GetClipCount => {
+            let pbstat = shared_data_inst.playback_status.read().unwrap();
+            Some(WsReply::GetClipCount {
+                result: WsResult::Ok,
+                value: pbstat.clipped_samples,
+            })
+        }
+        WsCommand::GetClippingActive => {
+            let pbstat = shared_data_inst.playback_status.read().unwrap();
+            Some(WsReply::GetClippingActive {
+                result: WsResult::Ok,
+                value: pbstat.clipping_active,
+            })
+        }
+        WsCommand::ResetClipCount => {
+            // The playback threads only add to the counter while holding the write lock,
+            // so clearing it under the same lock can't race with an update.
+            shared_data_inst
+                .playback_status
+                .write()
+                .unwrap()
+                .clipped_samples = 0;
+            Some(WsReply::ResetClipCount {
+                result: WsResult::Ok,
+            })
+        }
         WsCommand::GetBufferLevel => {
             let pbstat = shared_data_inst.playback_status.read().unwrap();
             Some(WsReply::GetBufferLevel {
@@ -449,6 +1643,155 @@ fn handle_command(command: WsCommand, shared_data_inst: &SharedData) -> Option<W
                 value: pbstat.buffer_level,
             })
         }
+        WsCommand::GetPlaybackFile => {
+            let pbstat = shared_data_inst.playback_status.read().unwrap();
+            Some(WsReply::GetPlaybackFile {
+                result: WsResult::Ok,
+                value: pbstat.output_file.clone(),
+            })
+        }
+        WsCommand::GetLoudnessBoost(name) => {
+            let procstat = shared_data_inst.processing_status.read().unwrap();
+            match procstat.loudness_boosts.get(&name) {
+                Some(boost) => Some(WsReply::GetLoudnessBoost {
+                    result: WsResult::Ok,
+                    value: Some(boost.clone()),
+                }),
+                None => Some(WsReply::GetLoudnessBoost {
+                    result: WsResult::Error,
+                    value: None,
+                }),
+            }
+        }
+        WsCommand::GetAgcGain => {
+            let procstat = shared_data_inst.processing_status.read().unwrap();
+            Some(WsReply::GetAgcGain {
+                result: WsResult::Ok,
+                value: procstat.agc_gain,
+            })
+        }
+        WsCommand::GetFilterTimes => {
+            let procstat = shared_data_inst.processing_status.read().unwrap();
+            Some(WsReply::GetFilterTimes {
+                result: WsResult::Ok,
+                value: procstat.filter_times.clone(),
+            })
+        }
+        WsCommand::GetMixerAttenuations => {
+            let procstat = shared_data_inst.processing_status.read().unwrap();
+            Some(WsReply::GetMixerAttenuations {
+                result: WsResult::Ok,
+                value: procstat.mixer_attenuations.clone(),
+            })
+        }
+        WsCommand::GetDynamicEqOffsets => {
+            let procstat = shared_data_inst.processing_status.read().unwrap();
+            Some(WsReply::GetDynamicEqOffsets {
+                result: WsResult::Ok,
+                value: procstat.dynamic_eq_offsets.clone(),
+            })
+        }
+        WsCommand::GetProcessingHistogram => {
+            let procstat = shared_data_inst.processing_status.read().unwrap();
+            Some(WsReply::GetProcessingHistogram {
+                result: WsResult::Ok,
+                value: procstat.processing_histogram.counts(),
+            })
+        }
+        WsCommand::ResetProcessingHistogram => {
+            shared_data_inst
+                .processing_status
+                .write()
+                .unwrap()
+                .processing_histogram
+                .reset();
+            Some(WsReply::ResetProcessingHistogram {
+                result: WsResult::Ok,
+            })
+        }
+        WsCommand::StartMeterStream { .. } => Some(WsReply::StartMeterStream {
+            result: WsResult::Ok,
+        }),
+        WsCommand::StopMeterStream => Some(WsReply::StopMeterStream {
+            result: WsResult::Ok,
+        }),
+        WsCommand::GetFilterNames => {
+            match shared_data_inst.active_config.lock().unwrap().as_ref() {
+                Some(conf) => Some(WsReply::GetFilterNames {
+                    result: WsResult::Ok,
+                    value: conf
+                        .filters
+                        .iter()
+                        .map(|(name, filter)| (name.clone(), filter_type_name(filter)))
+                        .collect(),
+                }),
+                None => Some(WsReply::GetFilterNames {
+                    result: WsResult::Error,
+                    value: HashMap::new(),
+                }),
+            }
+        }
+        WsCommand::FitParametricEq {
+            measured,
+            target,
+            max_filters,
+        } => match fit_parametric_eq(shared_data_inst, &measured, &target, max_filters) {
+            Ok(fitted) => Some(WsReply::FitParametricEq {
+                result: WsResult::Ok,
+                value: Some(fitted),
+            }),
+            Err(err) => {
+                error!("Could not fit parametric eq: {}", err);
+                Some(WsReply::FitParametricEq {
+                    result: WsResult::Error,
+                    value: None,
+                })
+            }
+        },
+        WsCommand::GetPipelineImpulseResponse { length } => {
+            match get_pipeline_impulse_response(shared_data_inst, length) {
+                Ok(response) => Some(WsReply::GetPipelineImpulseResponse {
+                    result: WsResult::Ok,
+                    value: Some(response),
+                }),
+                Err(err) => {
+                    error!("Could not get impulse response of the pipeline: {}", err);
+                    Some(WsReply::GetPipelineImpulseResponse {
+                        result: WsResult::Error,
+                        value: None,
+                    })
+                }
+            }
+        }
+        WsCommand::GetFilterResponse { name, frequencies } => {
+            match get_filter_response(shared_data_inst, &name, &frequencies) {
+                Ok(response) => Some(WsReply::GetFilterResponse {
+                    result: WsResult::Ok,
+                    value: Some(response),
+                }),
+                Err(err) => {
+                    error!("Could not get response of filter '{}': {}", name, err);
+                    Some(WsReply::GetFilterResponse {
+                        result: WsResult::Error,
+                        value: None,
+                    })
+                }
+            }
+        }
+        WsCommand::GetMixerNames => match shared_data_inst.active_config.lock().unwrap().as_ref() {
+            Some(conf) => {
+                let mut names: Vec<String> = conf.mixers.keys().cloned().collect();
+                names.sort();
+                Some(WsReply::GetMixerNames {
+                    result: WsResult::Ok,
+                    value: names,
+                })
+            }
+            None => Some(WsReply::GetMixerNames {
+                result: WsResult::Error,
+                value: Vec::new(),
+            }),
+        },
         WsCommand::GetUpdateInterval => {
             let capstat = shared_data_inst.capture_status.read().unwrap();
             Some(WsReply::GetUpdateInterval {
@@ -478,13 +1821,65 @@ fn handle_command(command: WsCommand, shared_data_inst: &SharedData) -> Option<W
                 value: procstat.volume,
             })
         }
-        WsCommand::SetVolume(nbr) => {
+        WsCommand::SetVolume(VolumeValue::Plain(nbr)) => {
             let mut procstat = shared_data_inst.processing_status.write().unwrap();
             procstat.volume = nbr;
+            procstat.volume_ramp = None;
+            Some(WsReply::SetVolume {
+                result: WsResult::Ok,
+            })
+        }
+        WsCommand::SetVolume(VolumeValue::WithRamp { value, ramp_ms }) => {
+            if !ramp_ms.is_finite() || ramp_ms < 0.0 {
+                error!("Invalid volume ramp time {} ms, must be >= 0", ramp_ms);
+                return Some(WsReply::SetVolume {
+                    result: WsResult::Error,
+                });
+            }
+            let mut procstat = shared_data_inst.processing_status.write().unwrap();
+            procstat.volume = value;
+            procstat.volume_ramp = Some(VolumeRamp {
+                target: value,
+                ramp_ms,
+            });
             Some(WsReply::SetVolume {
                 result: WsResult::Ok,
             })
         }
+        WsCommand::AdjustVolume(delta) => {
+            let mut procstat = shared_data_inst.processing_status.write().unwrap();
+            let volume = (procstat.volume + delta).clamp(MIN_VOLUME_DB, MAX_VOLUME_DB);
+            procstat.volume = volume;
+            procstat.volume_ramp = None;
+            Some(WsReply::AdjustVolume {
+                result: WsResult::Ok,
+                value: volume,
+            })
+        }
+        WsCommand::GetGainOffset => {
+            let procstat = shared_data_inst.processing_status.read().unwrap();
+            Some(WsReply::GetGainOffset {
+                result: WsResult::Ok,
+                value: procstat.gain_offset,
+            })
+        }
+        WsCommand::SetGainOffset { db } => {
+            let mut procstat = shared_data_inst.processing_status.write().unwrap();
+            procstat.gain_offset = db.clamp(MIN_VOLUME_DB, MAX_VOLUME_DB);
+            Some(WsReply::SetGainOffset {
+                result: WsResult::Ok,
+            })
+        }
+        WsCommand::ClearGainOffset => {
+            shared_data_inst
+                .processing_status
+                .write()
+                .unwrap()
+                .gain_offset = 0.0;
+            Some(WsReply::ClearGainOffset {
+                result: WsResult::Ok,
+            })
+        }
         WsCommand::GetMute => {
             let procstat = shared_data_inst.processing_status.read().unwrap();
             Some(WsReply::GetMute {
@@ -499,6 +1894,118 @@ fn handle_command(command: WsCommand, shared_data_inst: &SharedData) -> Option<W
                 result: WsResult::Ok,
             })
         }
+        WsCommand::Pause => {
+            shared_data_inst.processing_status.write().unwrap().paused = true;
+            Some(WsReply::Pause {
+                result: WsResult::Ok,
+            })
+        }
+        WsCommand::Resume => {
+            shared_data_inst.processing_status.write().unwrap().paused = false;
+            Some(WsReply::Resume {
+                result: WsResult::Ok,
+            })
+        }
+        WsCommand::InjectTone {
+            channel,
+            freq,
+            level_db,
+            seconds,
+        } => match inject_tone(shared_data_inst, channel, freq, level_db, seconds) {
+            Ok(()) => Some(WsReply::InjectTone {
+                result: WsResult::Ok,
+            }),
+            Err(err) => {
+                error!("Could not play test tone: {}", err);
+                Some(WsReply::InjectTone {
+                    result: WsResult::Error,
+                })
+            }
+        },
+        WsCommand::MeasureLoopbackLatency {
+            playback_channel,
+            capture_channel,
+        } => match measure_loopback_latency(shared_data_inst, playback_channel, capture_channel) {
+            Ok(latency) => Some(WsReply::MeasureLoopbackLatency {
+                result: WsResult::Ok,
+                value: Some(latency),
+            }),
+            Err(err) => {
+                error!("Could not measure loopback latency: {}", err);
+                Some(WsReply::MeasureLoopbackLatency {
+                    result: WsResult::Error,
+                    value: None,
+                })
+            }
+        },
+        WsCommand::GetCaptureSpectrum {
+            channel,
+            length,
+            smoothing,
+        } => match get_capture_spectrum(shared_data_inst, channel, length, smoothing) {
+            Ok(spectrum) => Some(WsReply::GetCaptureSpectrum {
+                result: WsResult::Ok,
+                value: Some(spectrum),
+            }),
+            Err(err) => {
+                error!(
+                    "Could not get spectrum of capture channel {}: {}",
+                    channel, err
+                );
+                Some(WsReply::GetCaptureSpectrum {
+                    result: WsResult::Error,
+                    value: None,
+                })
+            }
+        },
+        WsCommand::GetPaused => {
+            let procstat = shared_data_inst.processing_status.read().unwrap();
+            Some(WsReply::GetPaused {
+                result: WsResult::Ok,
+                value: procstat.paused,
+            })
+        }
+        WsCommand::FlushFilters => {
+            shared_data_inst
+                .processing_status
+                .write()
+                .unwrap()
+                .flush_filters = true;
+            Some(WsReply::FlushFilters {
+                result: WsResult::Ok,
+            })
+        }
+        WsCommand::SetPlaybackFormat(format_name) => {
+            let format = match format_name.as_deref().map(config::SampleFormat::from_name) {
+                Some(None) => {
+                    error!("Unknown sample format: {:?}", format_name);
+                    return Some(WsReply::SetPlaybackFormat {
+                        result: WsResult::Error,
+                    });
+                }
+                Some(format) => format,
+                None => None,
+            };
+            let previous_format = std::mem::replace(
+                &mut config::OVERRIDES.write().unwrap().playback_sample_format,
+                format,
+            );
+            // Validate the loaded config again with the new override, this restarts the devices.
+            // Clearing the override restores the format of the loaded config.
+            let raw_config = shared_data_inst.raw_config.lock().unwrap().clone();
+            if let Some(raw_conf) = raw_config {
+                if let Err(error) = queue_new_config(shared_data_inst, raw_conf) {
+                    error!("Error setting playback format: {}", error);
+                    config::OVERRIDES.write().unwrap().playback_sample_format = previous_format;
+                    return Some(WsReply::SetPlaybackFormat {
+                        result: WsResult::Error,
+                    });
+                }
+            }
+            Some(WsReply::SetPlaybackFormat {
+                result: WsResult::Ok,
+            })
+        }
         WsCommand::GetConfig => Some(WsReply::GetConfig {
             result: WsResult::Ok,
             value: serde_yaml::to_string(&*shared_data_inst.active_config.lock().unwrap()).unwrap(),
@@ -508,10 +2015,118 @@ fn handle_command(command: WsCommand, shared_data_inst: &SharedData) -> Option<W
             value: serde_yaml::to_string(&*shared_data_inst.previous_config.lock().unwrap())
                 .unwrap(),
         }),
+        WsCommand::GetNonDefaultConfig => {
+            let raw_config = shared_data_inst.raw_config.lock().unwrap().clone();
+            let result = match raw_config {
+                Some(conf) => config::get_non_default_config(&conf)
+                    .and_then(|value| serde_yaml::to_string(&value).map_err(|err| err.into())),
+                None => Err(config::ConfigError::new("No config loaded").into()),
+            };
+            match result {
+                Ok(value) => Some(WsReply::GetNonDefaultConfig {
+                    result: WsResult::Ok,
+                    value: Some(value),
+                }),
+                Err(err) => {
+                    error!("Could not compare config with defaults: {}", err);
+                    Some(WsReply::GetNonDefaultConfig {
+                        result: WsResult::Error,
+                        value: None,
+                    })
+                }
+            }
+        }
+        WsCommand::SaveSnapshot { name } => {
+            // Save the config as it was loaded, since recalling it applies overrides and tokens again
+            let raw_config = shared_data_inst.raw_config.lock().unwrap().clone();
+            let result = match raw_config {
+                Some(conf) => shared_data_inst
+                    .snapshots
+                    .lock()
+                    .unwrap()
+                    .save(&name, &conf),
+                None => Err(config::ConfigError::new("No config loaded").into()),
+            };
+            match result {
+                Ok(()) => Some(WsReply::SaveSnapshot {
+                    result: WsResult::Ok,
+                }),
+                Err(error) => {
+                    error!("Could not save snapshot '{}': {}", name, error);
+                    Some(WsReply::SaveSnapshot {
+                        result: WsResult::Error,
+                    })
+                }
+            }
+        }
+        WsCommand::RecallSnapshot { name } => {
+            let snapshot = shared_data_inst
+                .snapshots
+                .lock()
+                .unwrap()
+                .get(&name)
+                .cloned();
+            match snapshot {
+                Some(conf) => match queue_new_config(shared_data_inst, conf) {
+                    Ok(()) => Some(WsReply::RecallSnapshot {
+                        result: WsResult::Ok,
+                    }),
+                    Err(error) => {
+                        error!("Error recalling snapshot '{}': {}", name, error);
+                        Some(WsReply::RecallSnapshot {
+                            result: WsResult::Error,
+                        })
+                    }
+                },
+                None => {
+                    error!("No snapshot named '{}'", name);
+                    Some(WsReply::RecallSnapshot {
+                        result: WsResult::Error,
+                    })
+                }
+            }
+        }
+        WsCommand::ListSnapshots => Some(WsReply::ListSnapshots {
+            result: WsResult::Ok,
+            value: shared_data_inst.snapshots.lock().unwrap().names(),
+        }),
+        WsCommand::GetPendingChange => {
+            let active_config = shared_data_inst.active_config.lock().unwrap().clone();
+            let new_config = shared_data_inst.new_config.lock().unwrap().clone();
+            let pending = new_config.map(|new_conf| match active_config {
+                Some(active_conf) => PendingChange {
+                    change: config::config_diff(&active_conf, &new_conf)
+                        .name()
+                        .to_string(),
+                    differences: config::config_diff_summary(&active_conf, &new_conf),
+                },
+                // Nothing is running, the new config starts from scratch
+                None => PendingChange {
+                    change: config::ConfigChange::Devices.name().to_string(),
+                    differences: Vec::new(),
+                },
+            });
+            Some(WsReply::GetPendingChange {
+                result: WsResult::Ok,
+                value: pending,
+            })
+        }
         WsCommand::GetConfigJson => Some(WsReply::GetConfigJson {
             result: WsResult::Ok,
             value: serde_json::to_string(&*shared_data_inst.active_config.lock().unwrap()).unwrap(),
         }),
+        WsCommand::GetEffectiveConfig => Some(WsReply::GetEffectiveConfig {
+            result: WsResult::Ok,
+            value: serde_yaml::to_string(
+                &shared_data_inst
+                    .active_config
+                    .lock()
+                    .unwrap()
+                    .as_ref()
+                    .map(config::get_effective_config),
+            )
+            .unwrap(),
+        }),
         WsCommand::GetConfigName => Some(WsReply::GetConfigName {
             result: WsResult::Ok,
             value: shared_data_inst
@@ -538,16 +2153,10 @@ fn handle_command(command: WsCommand, shared_data_inst: &SharedData) -> Option<W
         },
         WsCommand::SetConfig(config_yml) => {
             match serde_yaml::from_str::<config::Configuration>(&config_yml) {
-                Ok(mut conf) => match config::validate_config(&mut conf, None) {
-                    Ok(()) => {
-                        *shared_data_inst.new_config.lock().unwrap() = Some(conf);
-                        shared_data_inst
-                            .signal_reload
-                            .store(true, Ordering::Relaxed);
-                        Some(WsReply::SetConfig {
-                            result: WsResult::Ok,
-                        })
-                    }
+                Ok(conf) => match queue_new_config(shared_data_inst, conf) {
+                    Ok(()) => Some(WsReply::SetConfig {
+                        result: WsResult::Ok,
+                    }),
                     Err(error) => {
                         error!("Error setting config: {}", error);
                         Some(WsReply::SetConfig {
@@ -565,16 +2174,10 @@ fn handle_command(command: WsCommand, shared_data_inst: &SharedData) -> Option<W
         }
         WsCommand::SetConfigJson(config_json) => {
             match serde_json::from_str::<config::Configuration>(&config_json) {
-                Ok(mut conf) => match config::validate_config(&mut conf, None) {
-                    Ok(()) => {
-                        *shared_data_inst.new_config.lock().unwrap() = Some(conf);
-                        shared_data_inst
-                            .signal_reload
-                            .store(true, Ordering::Relaxed);
-                        Some(WsReply::SetConfigJson {
-                            result: WsResult::Ok,
-                        })
-                    }
+                Ok(conf) => match queue_new_config(shared_data_inst, conf) {
+                    Ok(()) => Some(WsReply::SetConfigJson {
+                        result: WsResult::Ok,
+                    }),
                     Err(error) => {
                         error!("Error setting config: {}", error);
                         Some(WsReply::SetConfigJson {
@@ -590,6 +2193,82 @@ fn handle_command(command: WsCommand, shared_data_inst: &SharedData) -> Option<W
                 }
             }
         }
+        WsCommand::SwapChannels { a, b } => {
+            match modify_config(shared_data_inst, |conf| {
+                config::swap_output_channels(conf, a, b)
+            }) {
+                Ok(mixer) => Some(WsReply::SwapChannels {
+                    result: WsResult::Ok,
+                    value: Some(mixer),
+                }),
+                Err(error) => {
+                    error!("Error swapping channels: {}", error);
+                    Some(WsReply::SwapChannels {
+                        result: WsResult::Error,
+                        value: None,
+                    })
+                }
+            }
+        }
+        WsCommand::InvertChannel { channel } => {
+            match modify_config(shared_data_inst, |conf| {
+                config::invert_output_channel(conf, channel)
+            }) {
+                Ok(mixer) => Some(WsReply::InvertChannel {
+                    result: WsResult::Ok,
+                    value: Some(mixer),
+                }),
+                Err(error) => {
+                    error!("Error inverting channel: {}", error);
+                    Some(WsReply::InvertChannel {
+                        result: WsResult::Error,
+                        value: None,
+                    })
+                }
+            }
+        }
+        WsCommand::GetPipelineOrder => match shared_data_inst.raw_config.lock().unwrap().as_ref() {
+            Some(conf) => Some(WsReply::GetPipelineOrder {
+                result: WsResult::Ok,
+                value: config::effective_pipeline(conf)
+                    .iter()
+                    .map(|step| step.to_string())
+                    .collect(),
+            }),
+            None => Some(WsReply::GetPipelineOrder {
+                result: WsResult::Error,
+                value: Vec::new(),
+            }),
+        },
+        WsCommand::SetPipelineOrder { order } => {
+            match modify_config(shared_data_inst, |conf| {
+                config::reorder_pipeline(conf, &order)
+            }) {
+                Ok(()) => Some(WsReply::SetPipelineOrder {
+                    result: WsResult::Ok,
+                }),
+                Err(error) => {
+                    error!("Error reordering pipeline: {}", error);
+                    Some(WsReply::SetPipelineOrder {
+                        result: WsResult::Error,
+                    })
+                }
+            }
+        }
+        #[cfg(feature = "url-config")]
+        WsCommand::LoadConfigFromUrl { url } => {
+            match fetch_config(&url).and_then(|conf| queue_new_config(shared_data_inst, conf)) {
+                Ok(()) => Some(WsReply::LoadConfigFromUrl {
+                    result: WsResult::Ok,
+                }),
+                Err(error) => {
+                    error!("Error loading config from {}: {}", url, error);
+                    Some(WsReply::LoadConfigFromUrl {
+                        result: WsResult::Error,
+                    })
+                }
+            }
+        }
         WsCommand::ReadConfig(config_yml) => {
             match serde_yaml::from_str::<config::Configuration>(&config_yml) {
                 Ok(conf) => Some(WsReply::ReadConfig {
@@ -651,6 +2330,26 @@ fn handle_command(command: WsCommand, shared_data_inst: &SharedData) -> Option<W
                 result: WsResult::Ok,
             })
         }
+        WsCommand::SetLogLevel(level) => {
+            let result = match shared_data_inst.logger.lock().unwrap().set_level(&level) {
+                Ok(()) => WsResult::Ok,
+                Err(err) => {
+                    warn!("Unable to change log level: {}", err);
+                    WsResult::Error
+                }
+            };
+            Some(WsReply::SetLogLevel { result })
+        }
+        WsCommand::RotateLog => {
+            let result = match shared_data_inst.logger.lock().unwrap().rotate() {
+                Ok(_) => WsResult::Ok,
+                Err(err) => {
+                    warn!("Unable to rotate log file: {}", err);
+                    WsResult::Error
+                }
+            };
+            Some(WsReply::RotateLog { result })
+        }
         WsCommand::Exit => {
             shared_data_inst
                 .signal_exit
@@ -672,7 +2371,16 @@ fn handle_command(command: WsCommand, shared_data_inst: &SharedData) -> Option<W
 
 #[cfg(test)]
 mod tests {
-    use crate::socketserver::{parse_command, WsCommand};
+    use crate::config;
+    #[cfg(feature = "url-config")]
+    use crate::socketserver::fetch_config;
+    use crate::socketserver::{
+        filter_type_name, mask_unused_channels, max_impulse_response_length, parse_command,
+        Heartbeat, HeartbeatParameters, MeterStream, MeterValue, VolumeValue, WsCommand,
+        MIN_METER_INTERVAL_MS,
+    };
+    use crate::spectrum;
+    use std::time::{Duration, Instant};
     use tungstenite::Message;
 
     #[test]
@@ -689,5 +2397,361 @@ mod tests {
         let cmd = Message::text("{\"SetConfigName\": \"somefile\"}");
         let res = parse_command(cmd).unwrap();
         assert_eq!(res, WsCommand::SetConfigName("somefile".to_string()));
+        let cmd = Message::text("{\"SetVolume\": -12.5}");
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(res, WsCommand::SetVolume(VolumeValue::Plain(-12.5)));
+        let cmd = Message::text("{\"SetVolume\": {\"value\": -30.0, \"ramp_ms\": 8000.0}}");
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(
+            res,
+            WsCommand::SetVolume(VolumeValue::WithRamp {
+                value: -30.0,
+                ramp_ms: 8000.0
+            })
+        );
+        let cmd = Message::text("{\"AdjustVolume\": -1.5}");
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(res, WsCommand::AdjustVolume(-1.5));
+        let cmd = Message::text("{\"SetGainOffset\": {\"db\": -3.0}}");
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(res, WsCommand::SetGainOffset { db: -3.0 });
+        let cmd = Message::text("\"ClearGainOffset\"");
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(res, WsCommand::ClearGainOffset);
+        let cmd = Message::text("{\"SetPlaybackFormat\": \"S24LE3\"}");
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(
+            res,
+            WsCommand::SetPlaybackFormat(Some("S24LE3".to_string()))
+        );
+        let cmd = Message::text("{\"SetPlaybackFormat\": null}");
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(res, WsCommand::SetPlaybackFormat(None));
+        let cmd = Message::text(
+            "{\"StartMeterStream\": {\"interval_ms\": 100, \"include\": [\"rms\", \"peak\"]}}",
+        );
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(
+            res,
+            WsCommand::StartMeterStream {
+                interval_ms: 100,
+                include: vec![MeterValue::Rms, MeterValue::Peak]
+            }
+        );
+        let cmd = Message::text(
+            "{\"InjectTone\": {\"channel\": 1, \"freq\": 440.0, \"level_db\": -20.0, \"seconds\": 2.0}}",
+        );
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(
+            res,
+            WsCommand::InjectTone {
+                channel: 1,
+                freq: 440.0,
+                level_db: -20.0,
+                seconds: 2.0
+            }
+        );
+        let cmd = Message::text(
+            "{\"MeasureLoopbackLatency\": {\"playback_channel\": 0, \"capture_channel\": 1}}",
+        );
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(
+            res,
+            WsCommand::MeasureLoopbackLatency {
+                playback_channel: 0,
+                capture_channel: 1
+            }
+        );
+        let cmd = Message::text(
+            "{\"GetCaptureSpectrum\": {\"channel\": 1, \"length\": 8192, \"smoothing\": \"third\"}}",
+        );
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(
+            res,
+            WsCommand::GetCaptureSpectrum {
+                channel: 1,
+                length: 8192,
+                smoothing: Some(spectrum::OctaveSmoothing::Third)
+            }
+        );
+        let cmd = Message::text("{\"GetCaptureSpectrum\": {\"channel\": 0, \"length\": 4096}}");
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(
+            res,
+            WsCommand::GetCaptureSpectrum {
+                channel: 0,
+                length: 4096,
+                smoothing: None
+            }
+        );
+        let cmd = Message::text(
+            "{\"GetFilterResponse\": {\"name\": \"eq\", \"frequencies\": [100.0, 1000.0]}}",
+        );
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(
+            res,
+            WsCommand::GetFilterResponse {
+                name: "eq".to_string(),
+                frequencies: vec![100.0, 1000.0]
+            }
+        );
+        let cmd = Message::text(
+            "{\"FitParametricEq\": {\"measured\": [[20.0, 1.5], [100.0, -2.0]], \"target\": [[20.0, 0.0], [20000.0, 0.0]], \"max_filters\": 4}}",
+        );
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(
+            res,
+            WsCommand::FitParametricEq {
+                measured: vec![(20.0, 1.5), (100.0, -2.0)],
+                target: vec![(20.0, 0.0), (20000.0, 0.0)],
+                max_filters: 4
+            }
+        );
+        let cmd = Message::text("{\"SaveSnapshot\": {\"name\": \"night\"}}");
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(
+            res,
+            WsCommand::SaveSnapshot {
+                name: "night".to_string()
+            }
+        );
+        let cmd = Message::text("{\"RecallSnapshot\": {\"name\": \"night\"}}");
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(
+            res,
+            WsCommand::RecallSnapshot {
+                name: "night".to_string()
+            }
+        );
+        let cmd = Message::text("\"ListSnapshots\"");
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(res, WsCommand::ListSnapshots);
+        let cmd = Message::text("\"GetEffectiveConfig\"");
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(res, WsCommand::GetEffectiveConfig);
+        let cmd = Message::text("\"GetClippingActive\"");
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(res, WsCommand::GetClippingActive);
+        let cmd = Message::text("\"FlushFilters\"");
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(res, WsCommand::FlushFilters);
+        let cmd = Message::text("{\"GetPipelineImpulseResponse\": {\"length\": 4096}}");
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(res, WsCommand::GetPipelineImpulseResponse { length: 4096 });
+        let cmd = Message::text("\"GetNonDefaultConfig\"");
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(res, WsCommand::GetNonDefaultConfig);
+        let cmd = Message::text("\"FreezeRateAdjust\"");
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(res, WsCommand::FreezeRateAdjust);
+        let cmd = Message::text("\"UnfreezeRateAdjust\"");
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(res, WsCommand::UnfreezeRateAdjust);
+        let cmd = Message::text("{\"SwapChannels\": {\"a\": 0, \"b\": 1}}");
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(res, WsCommand::SwapChannels { a: 0, b: 1 });
+        let cmd = Message::text("{\"InvertChannel\": {\"channel\": 1}}");
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(res, WsCommand::InvertChannel { channel: 1 });
+        let cmd = Message::text("\"GetChannelLabels\"");
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(res, WsCommand::GetChannelLabels);
+        let cmd = Message::text("\"GetRuntimeInfo\"");
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(res, WsCommand::GetRuntimeInfo);
+        let cmd = Message::text("\"GetPipelineOrder\"");
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(res, WsCommand::GetPipelineOrder);
+        let cmd = Message::text("{\"SetPipelineOrder\": {\"order\": [1, 0, 2]}}");
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(
+            res,
+            WsCommand::SetPipelineOrder {
+                order: vec![1, 0, 2]
+            }
+        );
+        let cmd = Message::text("{\"GetLoudnessBoost\": \"loudness\"}");
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(res, WsCommand::GetLoudnessBoost("loudness".to_string()));
+        let cmd = Message::text("{\"SetLogLevel\": \"debug\"}");
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(res, WsCommand::SetLogLevel("debug".to_string()));
+        let cmd = Message::text("\"GetFilterTimes\"");
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(res, WsCommand::GetFilterTimes);
+        let cmd = Message::text("\"GetAgcGain\"");
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(res, WsCommand::GetAgcGain);
+        let cmd = Message::text("\"RotateLog\"");
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(res, WsCommand::RotateLog);
+        let cmd = Message::text("\"GetMixerAttenuations\"");
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(res, WsCommand::GetMixerAttenuations);
+        let cmd = Message::text("\"StopMeterStream\"");
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(res, WsCommand::StopMeterStream);
+    }
+
+    #[test]
+    fn meter_stream_interval() {
+        let stream = MeterStream::new(1, &[MeterValue::Peak]);
+        assert_eq!(
+            stream.interval,
+            Duration::from_millis(MIN_METER_INTERVAL_MS)
+        );
+        assert!(stream.peak);
+        assert!(!stream.rms);
+        assert!(!stream.labels);
+        let stream = MeterStream::new(100, &[MeterValue::Rms, MeterValue::Labels]);
+        assert!(stream.labels);
+    }
+
+    #[test]
+    fn heartbeat_ping_and_timeout() {
+        let mut heartbeat = Heartbeat::new(HeartbeatParameters {
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(2),
+        });
+        assert!(!heartbeat.ping_due());
+        assert!(heartbeat.time_to_next() > Duration::from_secs(4));
+        heartbeat.last_received = Instant::now() - Duration::from_secs(6);
+        assert!(heartbeat.ping_due());
+        heartbeat.ping_sent = Some(Instant::now() - Duration::from_secs(1));
+        assert!(!heartbeat.ping_due());
+        assert!(!heartbeat.timed_out());
+        assert!(heartbeat.time_to_next() <= Duration::from_secs(1));
+        heartbeat.ping_sent = Some(Instant::now() - Duration::from_secs(3));
+        assert!(heartbeat.timed_out());
+        heartbeat.received();
+        assert!(!heartbeat.timed_out());
+        assert!(!heartbeat.ping_due());
+    }
+
+    #[test]
+    fn channel_labels() {
+        let labels = vec!["FL".to_string(), "FR".to_string()];
+        assert_eq!(config::get_channel_labels(&labels, 2), labels);
+        assert_eq!(
+            config::get_channel_labels(&[], 3),
+            vec!["0".to_string(), "1".to_string(), "2".to_string()]
+        );
+        let mut conf = config::default_config();
+        conf.devices.channel_labels.playback = vec!["L".to_string()];
+        assert!(config::validate_config(&mut conf, None).is_err());
+        conf.devices.channel_labels.playback = vec!["L".to_string(), "R".to_string()];
+        assert!(config::validate_config(&mut conf, None).is_ok());
+    }
+
+    #[test]
+    fn mask_unused() {
+        let values = vec![-10.0, -20.0, -30.0];
+        let masked = mask_unused_channels(&values, &[true, false, true]);
+        assert_eq!(masked, vec![-10.0, -1000.0, -30.0]);
+    }
+
+    #[test]
+    fn pending_change_summary() {
+        let active = config::default_config();
+        let mut new = active.clone();
+        new.devices.chunksize = 2048;
+        new.filters.insert(
+            "gain".to_string(),
+            config::Filter::Gain {
+                parameters: config::GainParameters {
+                    gain: -3.0,
+                    inverted: false,
+                    mute: false,
+                },
+            },
+        );
+        assert_eq!(
+            config::config_diff_summary(&active, &new),
+            vec!["devices.chunksize".to_string(), "filters.gain".to_string()]
+        );
+        assert!(config::config_diff_summary(&active, &active).is_empty());
+    }
+
+    #[test]
+    fn get_filter_type_name() {
+        let filter = config::Filter::Gain {
+            parameters: config::GainParameters {
+                gain: -3.0,
+                inverted: false,
+                mute: false,
+            },
+        };
+        assert_eq!(filter_type_name(&filter), "Gain");
+    }
+
+    #[test]
+    fn swap_and_invert_channels() {
+        let mut conf = config::default_config();
+        let mixer = config::swap_output_channels(&mut conf, 0, 1).unwrap();
+        assert_eq!(mixer.mapping()[0].sources[0].channel, 1);
+        assert_eq!(mixer.mapping()[1].sources[0].channel, 0);
+        let mixer = config::invert_output_channel(&mut conf, 1).unwrap();
+        assert!(!mixer.mapping()[0].sources[0].inverted);
+        assert!(mixer.mapping()[1].sources[0].inverted);
+        assert_eq!(
+            conf.pipeline,
+            vec![config::PipelineStep::Mixer {
+                name: config::WIRING_MIXER.to_string()
+            }]
+        );
+        assert!(config::validate_config(&mut conf, None).is_ok());
+        assert!(config::swap_output_channels(&mut conf, 0, 2).is_err());
+        assert!(config::invert_output_channel(&mut conf, 2).is_err());
+    }
+
+    #[test]
+    fn reorder_pipeline() {
+        let mut conf = config::default_config();
+        let first = config::PipelineStep::Filter {
+            channel: 0,
+            names: vec!["a".to_string(), "b".to_string()],
+            gain_compensate: false,
+            mix: 1.0,
+        };
+        let second = config::PipelineStep::Mixer {
+            name: "mix".to_string(),
+        };
+        conf.pipeline = vec![first.clone(), second.clone()];
+        assert_eq!(first.to_string(), "Filter 0: a, b");
+        assert_eq!(second.to_string(), "Mixer mix");
+        config::reorder_pipeline(&mut conf, &[1, 0]).unwrap();
+        assert_eq!(conf.pipeline, vec![second, first]);
+        assert!(config::reorder_pipeline(&mut conf, &[0, 0]).is_err());
+        assert!(config::reorder_pipeline(&mut conf, &[0]).is_err());
+        assert!(config::reorder_pipeline(&mut conf, &[0, 1, 2]).is_err());
+    }
+
+    #[test]
+    fn impulse_response_length_limit() {
+        // Two inputs and two outputs are limited to 10 seconds
+        assert_eq!(
+            max_impulse_response_length(&config::default_config()),
+            441000
+        );
+        // Many channels are limited by the total size of the reply
+        assert_eq!(
+            max_impulse_response_length(&config::default_config_with_channels(16)),
+            7812
+        );
+    }
+
+    #[cfg(feature = "url-config")]
+    #[test]
+    fn fetch_config_rejects_other_schemes() {
+        let cmd =
+            Message::text("{\"LoadConfigFromUrl\": {\"url\": \"file:///etc/camilladsp.yml\"}}");
+        let res = parse_command(cmd).unwrap();
+        assert_eq!(
+            res,
+            WsCommand::LoadConfigFromUrl {
+                url: "file:///etc/camilladsp.yml".to_string()
+            }
+        );
+        assert!(fetch_config("file:///etc/camilladsp.yml").is_err());
     }
 }
@@ -0,0 +1,283 @@
+use crate::audiodevice::AudioChunk;
+use crate::config;
+use crate::PrcFmt;
+use crate::Res;
+use realfft::RealFftPlanner;
+use serde::{Deserialize, Serialize};
+
+/// Shortest and longest number of samples that a spectrum can be calculated from.
+pub const MIN_SPECTRUM_LENGTH: usize = 64;
+pub const MAX_SPECTRUM_LENGTH: usize = 131072;
+
+/// Center frequency that the smoothing bands are aligned to.
+const BAND_REFERENCE_FREQ: PrcFmt = 1000.0;
+
+/// Fractional-octave smoothing of a spectrum.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OctaveSmoothing {
+    #[serde(rename = "third")]
+    Third,
+    #[serde(rename = "sixth")]
+    Sixth,
+    #[serde(rename = "twelfth")]
+    Twelfth,
+}
+
+impl OctaveSmoothing {
+    /// Number of bands per octave.
+    fn bands_per_octave(&self) -> i32 {
+        match self {
+            OctaveSmoothing::Third => 3,
+            OctaveSmoothing::Sixth => 6,
+            OctaveSmoothing::Twelfth => 12,
+        }
+    }
+}
+
+/// Magnitude spectrum of a captured signal, in dB relative to a full scale sine.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Spectrum {
+    pub frequencies: Vec<f32>,
+    pub magnitude: Vec<f32>,
+}
+
+/// Collects the samples of a capture channel until there are enough to calculate a spectrum.
+#[derive(Clone, Debug)]
+pub struct SpectrumCollector {
+    channel: usize,
+    length: usize,
+    samples: Vec<PrcFmt>,
+}
+
+impl SpectrumCollector {
+    pub fn new(channel: usize, length: usize) -> Self {
+        SpectrumCollector {
+            channel,
+            length,
+            samples: Vec::with_capacity(length),
+        }
+    }
+
+    /// Add the valid frames of the channel in a captured chunk.
+    pub fn add_chunk(&mut self, chunk: &AudioChunk) {
+        if let Some(waveform) = chunk.waveforms.get(self.channel) {
+            let needed = self.length - self.samples.len();
+            self.samples
+                .extend(waveform.iter().take(chunk.valid_frames.min(needed)));
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.samples.len() >= self.length
+    }
+
+    pub fn into_samples(self) -> Vec<PrcFmt> {
+        self.samples
+    }
+}
+
+/// Calculate the magnitude spectrum of a signal, using a Hann window.
+/// A full scale sine gives a peak of 0 dB. Empty bins are reported as -1000 dB, like silence in the signal levels.
+/// Without smoothing, the value of every bin is returned, from DC to nyquist.
+/// With smoothing, the power of the bins is averaged over fractional-octave bands,
+/// and one value is returned for each band, at its center frequency.
+pub fn calculate_spectrum(
+    samples: &[PrcFmt],
+    samplerate: usize,
+    smoothing: Option<OctaveSmoothing>,
+) -> Res<Spectrum> {
+    let length = samples.len();
+    let mut planner = RealFftPlanner::<PrcFmt>::new();
+    let fft = planner.plan_fft_forward(length);
+    let mut input = fft.make_input_vec();
+    let mut window_sum = 0.0;
+    for (n, (value, sample)) in input.iter_mut().zip(samples.iter()).enumerate() {
+        let window = 0.5
+            - 0.5 * (2.0 * std::f64::consts::PI as PrcFmt * n as PrcFmt / length as PrcFmt).cos();
+        window_sum += window;
+        *value = sample * window;
+    }
+    let mut output = fft.make_output_vec();
+    fft.process(&mut input, &mut output)?;
+    let scale = 2.0 / window_sum;
+    let power: Vec<PrcFmt> = output
+        .iter()
+        .map(|value| (value.norm() * scale).powi(2))
+        .collect();
+    let bin_width = samplerate as PrcFmt / length as PrcFmt;
+    let (frequencies, power) = match smoothing {
+        Some(smoothing) => smooth_power(&power, bin_width, smoothing),
+        None => (
+            (0..power.len()).map(|n| n as PrcFmt * bin_width).collect(),
+            power,
+        ),
+    };
+    let magnitude = power
+        .iter()
+        .map(|value| {
+            if *value == 0.0 {
+                -1000.0
+            } else {
+                10.0 * value.log10() as f32
+            }
+        })
+        .collect();
+    Ok(Spectrum {
+        frequencies: frequencies.iter().map(|freq| *freq as f32).collect(),
+        magnitude,
+    })
+}
+
+/// Average the power of the bins within each fractional-octave band.
+/// The bands are centered on the standard base-2 frequencies around 1 kHz,
+/// from the lowest one at or above the first bin up to nyquist.
+/// A band that is narrower than the bin spacing takes the bin nearest to its center.
+fn smooth_power(
+    power: &[PrcFmt],
+    bin_width: PrcFmt,
+    smoothing: OctaveSmoothing,
+) -> (Vec<PrcFmt>, Vec<PrcFmt>) {
+    let bands_per_octave = smoothing.bands_per_octave();
+    let half_band = (2.0 as PrcFmt).powf(1.0 / (2.0 * bands_per_octave as PrcFmt));
+    let nyquist = (power.len() - 1) as PrcFmt * bin_width;
+    let first_band =
+        (bands_per_octave as PrcFmt * (bin_width / BAND_REFERENCE_FREQ).log2()).ceil() as i32;
+    let mut frequencies = Vec::new();
+    let mut smoothed = Vec::new();
+    for band in first_band.. {
+        let center =
+            BAND_REFERENCE_FREQ * (2.0 as PrcFmt).powf(band as PrcFmt / bands_per_octave as PrcFmt);
+        if center > nyquist {
+            break;
+        }
+        let first_bin = (center / half_band / bin_width).ceil() as usize;
+        let last_bin = ((center * half_band / bin_width).floor() as usize).min(power.len() - 1);
+        let value = if first_bin <= last_bin {
+            power[first_bin..=last_bin].iter().sum::<PrcFmt>()
+                / (last_bin - first_bin + 1) as PrcFmt
+        } else {
+            power[((center / bin_width).round() as usize).min(power.len() - 1)]
+        };
+        frequencies.push(center);
+        smoothed.push(value);
+    }
+    (frequencies, smoothed)
+}
+
+/// Check that a spectrum can be calculated from the given number of samples.
+pub fn validate_length(length: usize) -> Res<()> {
+    if !(MIN_SPECTRUM_LENGTH..=MAX_SPECTRUM_LENGTH).contains(&length) {
+        let msg = format!(
+            "Invalid length {}, must be between {} and {} samples",
+            length, MIN_SPECTRUM_LENGTH, MAX_SPECTRUM_LENGTH
+        );
+        return Err(config::ConfigError::new(&msg).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audiodevice::AudioChunk;
+    use crate::spectrum::{
+        calculate_spectrum, validate_length, OctaveSmoothing, SpectrumCollector,
+    };
+    use crate::PrcFmt;
+
+    fn sine(freq: PrcFmt, amplitude: PrcFmt, samplerate: usize, length: usize) -> Vec<PrcFmt> {
+        (0..length)
+            .map(|n| {
+                amplitude
+                    * (2.0 * std::f64::consts::PI as PrcFmt * freq * n as PrcFmt
+                        / samplerate as PrcFmt)
+                        .sin()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn full_scale_sine_at_zero_db() {
+        // 1 kHz falls exactly on bin 128 of a 6144 point spectrum at 48 kHz
+        let samples = sine(1000.0, 1.0, 48000, 6144);
+        let spectrum = calculate_spectrum(&samples, 48000, None).unwrap();
+        assert_eq!(spectrum.frequencies.len(), 3073);
+        assert_eq!(spectrum.frequencies[128], 1000.0);
+        assert!(spectrum.magnitude[128].abs() < 0.01);
+        let half = sine(1000.0, 0.5, 48000, 6144);
+        let spectrum = calculate_spectrum(&half, 48000, None).unwrap();
+        assert!((spectrum.magnitude[128] + 6.02).abs() < 0.01);
+        assert!(spectrum.magnitude[140] < -100.0);
+    }
+
+    #[test]
+    fn silence_reported_as_minus_1000() {
+        let spectrum =
+            calculate_spectrum(&[0.0; 256], 44100, Some(OctaveSmoothing::Third)).unwrap();
+        assert!(spectrum.magnitude.iter().all(|value| *value == -1000.0));
+    }
+
+    #[test]
+    fn smoothed_bands_log_spaced() {
+        let samples = sine(1000.0, 1.0, 48000, 6144);
+        for (smoothing, per_octave) in [
+            (OctaveSmoothing::Third, 3),
+            (OctaveSmoothing::Sixth, 6),
+            (OctaveSmoothing::Twelfth, 12),
+        ] {
+            let spectrum = calculate_spectrum(&samples, 48000, Some(smoothing)).unwrap();
+            let center = spectrum
+                .frequencies
+                .iter()
+                .position(|freq| *freq == 1000.0)
+                .unwrap();
+            // The band above the 1 kHz band is a fraction of an octave higher
+            let ratio = spectrum.frequencies[center + 1] / spectrum.frequencies[center];
+            assert!((ratio - 2.0f32.powf(1.0 / per_octave as f32)).abs() < 1e-4);
+            // First band is at the bin spacing of 7.8 Hz, the last one below nyquist
+            assert!(spectrum.frequencies[0] >= 7.8);
+            assert!(*spectrum.frequencies.last().unwrap() <= 24000.0);
+            // The tone is spread over the bins of its band
+            let band_max = spectrum
+                .magnitude
+                .iter()
+                .cloned()
+                .fold(f32::NEG_INFINITY, f32::max);
+            assert_eq!(spectrum.magnitude[center], band_max);
+            assert!(spectrum.magnitude[center] < 0.0);
+            assert!(spectrum.magnitude[center + 2] < -60.0);
+        }
+    }
+
+    #[test]
+    fn narrower_bands_have_more_points() {
+        let samples = sine(440.0, 0.5, 44100, 8192);
+        let third = calculate_spectrum(&samples, 44100, Some(OctaveSmoothing::Third)).unwrap();
+        let twelfth = calculate_spectrum(&samples, 44100, Some(OctaveSmoothing::Twelfth)).unwrap();
+        assert!(twelfth.frequencies.len() > 3 * third.frequencies.len());
+        assert_eq!(third.frequencies.len(), third.magnitude.len());
+    }
+
+    #[test]
+    fn collect_over_chunks() {
+        let mut collector = SpectrumCollector::new(1, 10);
+        let chunk = AudioChunk::new(vec![vec![0.0; 4], vec![1.0, 2.0, 3.0, 4.0]], 0.0, 0.0, 4, 3);
+        collector.add_chunk(&chunk);
+        collector.add_chunk(&chunk);
+        assert!(!collector.is_finished());
+        collector.add_chunk(&chunk);
+        collector.add_chunk(&chunk);
+        assert!(collector.is_finished());
+        assert_eq!(
+            collector.into_samples(),
+            vec![1.0, 2.0, 3.0, 1.0, 2.0, 3.0, 1.0, 2.0, 3.0, 1.0]
+        );
+    }
+
+    #[test]
+    fn length_limits() {
+        assert!(validate_length(63).is_err());
+        assert!(validate_length(64).is_ok());
+        assert!(validate_length(131072).is_ok());
+        assert!(validate_length(131073).is_err());
+    }
+}
@@ -1,8 +1,11 @@
 use crate::audiodevice::*;
 use crate::config;
 use crate::config::{ConfigError, SampleFormat};
-use crate::conversions::{buffer_to_chunk_rawbytes, chunk_to_buffer_rawbytes};
+use crate::conversions::{
+    apply_int_conversion, buffer_to_chunk_rawbytes, chunk_to_buffer_rawbytes,
+};
 use crate::countertimer;
+use crate::filedevice::start_capture_monitor;
 use crossbeam_channel::{bounded, unbounded, Receiver, Sender, TryRecvError, TrySendError};
 use rubato::VecResampler;
 use std::collections::VecDeque;
@@ -30,6 +33,7 @@ enum DeviceState {
 #[derive(Clone, Debug)]
 pub struct WasapiPlaybackDevice {
     pub devname: String,
+    pub device_match: config::DeviceMatch,
     pub exclusive: bool,
     pub samplerate: usize,
     pub chunksize: usize,
@@ -38,11 +42,13 @@ pub struct WasapiPlaybackDevice {
     pub target_level: usize,
     pub adjust_period: f32,
     pub enable_rate_adjust: bool,
+    pub int_conversion: config::IntConversion,
 }
 
 #[derive(Clone, Debug)]
 pub struct WasapiCaptureDevice {
     pub devname: String,
+    pub device_match: config::DeviceMatch,
     pub exclusive: bool,
     pub loopback: bool,
     pub samplerate: usize,
@@ -56,6 +62,7 @@ pub struct WasapiCaptureDevice {
     pub silence_timeout: PrcFmt,
     pub stop_on_rate_change: bool,
     pub rate_measure_interval: f32,
+    pub monitor_file: Option<String>,
 }
 
 #[derive(Clone, Debug)]
@@ -89,8 +96,89 @@ fn get_wave_format(
     }
 }
 
+/// List the Wasapi devices. The channels and samplerate are those of the shared mode mix format,
+/// and the sample formats are the ones supported in exclusive mode with that samplerate and number of channels.
+pub fn list_devices() -> Vec<DeviceInfo> {
+    let mut devices = Vec::new();
+    for (capture, direction) in [
+        (true, wasapi::Direction::Capture),
+        (false, wasapi::Direction::Render),
+    ] {
+        let collection = match wasapi::DeviceCollection::new(&direction) {
+            Ok(collection) => collection,
+            Err(err) => {
+                warn!("Unable to list Wasapi devices: {}", err);
+                continue;
+            }
+        };
+        let nbr_devices = collection.get_nbr_devices().unwrap_or(0);
+        for index in 0..nbr_devices {
+            let device = match collection.get_device_at_index(index) {
+                Ok(device) => device,
+                Err(_) => continue,
+            };
+            let name = match device.get_friendlyname() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            let mut info = DeviceInfo {
+                backend: "Wasapi".to_string(),
+                capture,
+                name,
+                description: None,
+                channels: Vec::new(),
+                samplerates: Vec::new(),
+                formats: Vec::new(),
+            };
+            if let Ok(audio_client) = device.get_iaudioclient() {
+                if let Ok(mix_format) = audio_client.get_mixformat() {
+                    let channels = mix_format.get_nchannels() as usize;
+                    let samplerate = mix_format.get_samplespersec() as usize;
+                    info.channels = vec![channels];
+                    info.samplerates = vec![samplerate];
+                    for sample_format in [
+                        SampleFormat::S16LE,
+                        SampleFormat::S24LE,
+                        SampleFormat::S24LE3,
+                        SampleFormat::S32LE,
+                        SampleFormat::FLOAT32LE,
+                    ] {
+                        let wave_format = get_wave_format(&sample_format, samplerate, channels);
+                        if let Ok(None) =
+                            audio_client.is_supported(&wave_format, &wasapi::ShareMode::Exclusive)
+                        {
+                            info.formats.push(sample_format);
+                        }
+                    }
+                }
+            }
+            devices.push(info);
+        }
+    }
+    devices
+}
+
+/// Get a device from a collection, matching the configured name against the friendly names of the devices.
+fn get_device_by_name(
+    collection: &wasapi::DeviceCollection,
+    devname: &str,
+    device_match: config::DeviceMatch,
+) -> Res<wasapi::Device> {
+    let name = if device_match == config::DeviceMatch::Exact {
+        devname.to_string()
+    } else {
+        let mut names = Vec::new();
+        for index in 0..collection.get_nbr_devices()? {
+            names.push(collection.get_device_at_index(index)?.get_friendlyname()?);
+        }
+        match_device_name(devname, device_match, &names)?
+    };
+    collection.get_device_with_name(&name)
+}
+
 fn open_playback(
     devname: &str,
+    device_match: config::DeviceMatch,
     samplerate: usize,
     channels: usize,
     sample_format: &SampleFormat,
@@ -110,7 +198,7 @@ fn open_playback(
         wasapi::get_default_device(&wasapi::Direction::Render)?
     } else {
         let collection = wasapi::DeviceCollection::new(&wasapi::Direction::Render)?;
-        collection.get_device_with_name(devname)?
+        get_device_by_name(&collection, devname, device_match)?
     };
     trace!("Found playback device {}", devname);
     let mut audio_client = device.get_iaudioclient()?;
@@ -156,6 +244,7 @@ fn open_playback(
 
 fn open_capture(
     devname: &str,
+    device_match: config::DeviceMatch,
     samplerate: usize,
     channels: usize,
     sample_format: &SampleFormat,
@@ -178,10 +267,10 @@ fn open_capture(
         wasapi::get_default_device(&wasapi::Direction::Render)?
     } else if !loopback {
         let collection = wasapi::DeviceCollection::new(&wasapi::Direction::Capture)?;
-        collection.get_device_with_name(devname)?
+        get_device_by_name(&collection, devname, device_match)?
     } else {
         let collection = wasapi::DeviceCollection::new(&wasapi::Direction::Render)?;
-        collection.get_device_with_name(devname)?
+        get_device_by_name(&collection, devname, device_match)?
     };
     trace!("Found capture device {}", devname);
     let mut audio_client = device.get_iaudioclient()?;
@@ -550,6 +639,7 @@ impl PlaybackDevice for WasapiPlaybackDevice {
         playback_status: Arc<RwLock<PlaybackStatus>>,
     ) -> Res<Box<thread::JoinHandle<()>>> {
         let devname = self.devname.clone();
+        let device_match = self.device_match;
         let exclusive = self.exclusive;
         let samplerate = self.samplerate;
         let chunksize = self.chunksize;
@@ -563,6 +653,7 @@ impl PlaybackDevice for WasapiPlaybackDevice {
         let adjust = self.adjust_period > 0.0 && self.enable_rate_adjust;
         let sample_format = self.sample_format.clone();
         let sample_format_dev = self.sample_format.clone();
+        let int_conversion = self.int_conversion;
         let handle = thread::Builder::new()
             .name("WasapiPlayback".to_string())
             .spawn(move || {
@@ -594,6 +685,7 @@ impl PlaybackDevice for WasapiPlaybackDevice {
                         let (_device, audio_client, render_client, handle, wave_format) =
                             match open_playback(
                                 &devname,
+                                device_match,
                                 samplerate,
                                 channels,
                                 &sample_format_dev,
@@ -677,7 +769,7 @@ impl PlaybackDevice for WasapiPlaybackDevice {
                         }
                     }
                     match channel.recv() {
-                        Ok(AudioMessage::Audio(chunk)) => {
+                        Ok(AudioMessage::Audio(mut chunk)) => {
                             buffer_avg.add_value(buffer_fill.load(Ordering::Relaxed) as f64);
                             if adjust && timer.larger_than_millis((1000.0 * adjust_period) as u64) {
                                 if let Some(av_delay) = buffer_avg.get_average() {
@@ -709,6 +801,7 @@ impl PlaybackDevice for WasapiPlaybackDevice {
                                     0u8;
                                     channels * chunk.frames * sample_format.bytes_per_sample()
                                 ];
+                            apply_int_conversion(&mut chunk, &sample_format, int_conversion);
                             conversion_result =
                                 chunk_to_buffer_rawbytes(&chunk, &mut buf, &sample_format);
                             match tx_dev.send(PlaybackDeviceMessage::Data(buf)) {
@@ -834,6 +927,7 @@ impl CaptureDevice for WasapiCaptureDevice {
         let exclusive = self.exclusive;
         let loopback = self.loopback;
         let devname = self.devname.clone();
+        let device_match = self.device_match;
         let samplerate = self.samplerate;
         let capture_samplerate = self.capture_samplerate;
         let chunksize = self.chunksize;
@@ -848,6 +942,7 @@ impl CaptureDevice for WasapiCaptureDevice {
         let silence_threshold = self.silence_threshold;
         let stop_on_rate_change = self.stop_on_rate_change;
         let rate_measure_interval = (1000.0 * self.rate_measure_interval) as u64;
+        let monitor_file = self.monitor_file.clone();
         let handle = thread::Builder::new()
             .name("WasapiCapture".to_string())
             .spawn(move || {
@@ -883,7 +978,7 @@ impl CaptureDevice for WasapiCaptureDevice {
                     .name("WasapiCaptureInner".to_string())
                     .spawn(move || {
                         let (_device, audio_client, capture_client, handle, wave_format) =
-                        match open_capture(&devname, capture_samplerate, channels, &sample_format_dev, exclusive, loopback) {
+                        match open_capture(&devname, device_match, capture_samplerate, channels, &sample_format_dev, exclusive, loopback) {
                             Ok((_device, audio_client, capture_client, handle, wave_format)) => {
                                 tx_state_dev.send(DeviceState::Ok).unwrap_or(());
                                 (_device, audio_client, capture_client, handle, wave_format)
@@ -938,6 +1033,7 @@ impl CaptureDevice for WasapiCaptureDevice {
                 // TODO check if this ever needs to be resized
                 let mut data_buffer = vec![0u8; 4 * blockalign * capture_frames];
                 let mut expected_chunk_nbr = 0;
+                let mut monitor = start_capture_monitor(&monitor_file, channels, capture_samplerate, &sample_format);
                 debug!("Capture device ready and waiting");
                 match status_channel.send(StatusMessage::CaptureReady) {
                     Ok(()) => {}
@@ -1034,6 +1130,9 @@ impl CaptureDevice for WasapiCaptureDevice {
                         for element in data_buffer.iter_mut().take(capture_bytes) {
                             *element = data_queue.pop_front().unwrap();
                         }
+                        if let Some(monitor) = &mut monitor {
+                            monitor.write_bytes(&data_buffer[0..capture_bytes]);
+                        }
                         let mut chunk = buffer_to_chunk_rawbytes(
                             &data_buffer[0..capture_bytes],
                             channels,
@@ -1052,7 +1151,7 @@ impl CaptureDevice for WasapiCaptureDevice {
                                 measured_rate_f
                             );
                             let mut capture_status = capture_status.write().unwrap();
-                            capture_status.measured_samplerate = measured_rate_f as usize;
+                            capture_status.update_measured_samplerate(measured_rate_f);
                             capture_status.signal_range = value_range as f32;
                             capture_status.rate_adjust = rate_adjust as f32;
                             capture_status.state = state;
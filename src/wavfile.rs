@@ -0,0 +1,309 @@
+// Writing of Broadcast Wave files for the File playback device.
+// The file gets a bext chunk describing how it was produced,
+// and the sizes in the header are filled in when the file is closed.
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+
+use crate::config::SampleFormat;
+use crate::Res;
+
+/// Size of the bext chunk without the coding history.
+const BEXT_FIXED_SIZE: usize = 602;
+
+/// KSDATAFORMAT_SUBTYPE_PCM, as stored in the file.
+const SUBTYPE_PCM: [u8; 16] = [
+    0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71,
+];
+
+/// Information about the processing that is stored in the bext chunk.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BwfMetadata {
+    pub version: String,
+    pub config_hash: String,
+}
+
+/// Writes raw bytes in the given sample format to a Broadcast Wave file.
+/// The file is finalized when the writer is dropped.
+pub struct BwfWriter {
+    file: File,
+    data_size_pos: u64,
+    data_bytes: u64,
+    msb_aligned: bool,
+    buffer: Vec<u8>,
+}
+
+/// Copy a string into a fixed size, zero padded, ascii field.
+fn ascii_field(text: &str, length: usize) -> Vec<u8> {
+    let mut field: Vec<u8> = text
+        .chars()
+        .filter(|c| c.is_ascii())
+        .map(|c| c as u8)
+        .take(length)
+        .collect();
+    field.resize(length, 0);
+    field
+}
+
+/// Build the fmt chunk, including its header.
+/// S24LE is stored as 24 valid bits in 32-bit containers, which needs the extensible format.
+/// The valid bits are then expected in the upper three bytes of each container.
+fn fmt_chunk(channels: usize, samplerate: usize, sample_format: &SampleFormat) -> Vec<u8> {
+    let bytes_per_sample = sample_format.bytes_per_sample();
+    let block_align = channels * bytes_per_sample;
+    let format_tag: u16 = match sample_format {
+        SampleFormat::FLOAT32LE | SampleFormat::FLOAT64LE => 3,
+        SampleFormat::S24LE => 0xFFFE,
+        _ => 1,
+    };
+    let mut chunk = Vec::new();
+    chunk.extend_from_slice(b"fmt ");
+    let size: u32 = if format_tag == 0xFFFE { 40 } else { 16 };
+    chunk.extend_from_slice(&size.to_le_bytes());
+    chunk.extend_from_slice(&format_tag.to_le_bytes());
+    chunk.extend_from_slice(&(channels as u16).to_le_bytes());
+    chunk.extend_from_slice(&(samplerate as u32).to_le_bytes());
+    chunk.extend_from_slice(&((samplerate * block_align) as u32).to_le_bytes());
+    chunk.extend_from_slice(&(block_align as u16).to_le_bytes());
+    chunk.extend_from_slice(&(8 * bytes_per_sample as u16).to_le_bytes());
+    if format_tag == 0xFFFE {
+        chunk.extend_from_slice(&22u16.to_le_bytes());
+        chunk.extend_from_slice(&(sample_format.bits_per_sample() as u16).to_le_bytes());
+        chunk.extend_from_slice(&0u32.to_le_bytes());
+        chunk.extend_from_slice(&SUBTYPE_PCM);
+    }
+    chunk
+}
+
+/// Build the bext chunk, including its header.
+fn bext_chunk(
+    channels: usize,
+    samplerate: usize,
+    sample_format: &SampleFormat,
+    metadata: &BwfMetadata,
+) -> Vec<u8> {
+    let description = format!(
+        "CamillaDSP {}, samplerate: {} Hz, channels: {}, format: {:?}, config hash: {}",
+        metadata.version, samplerate, channels, sample_format, metadata.config_hash
+    );
+    let mode = match channels {
+        1 => "mono",
+        2 => "stereo",
+        _ => "multichannel",
+    };
+    let mut coding_history = format!(
+        "A=PCM,F={},W={},M={},T=CamillaDSP {} config {}\r\n",
+        samplerate,
+        sample_format.bits_per_sample(),
+        mode,
+        metadata.version,
+        metadata.config_hash
+    );
+    if coding_history.len() % 2 == 1 {
+        coding_history.push('\0');
+    }
+    let time =
+        time::OffsetDateTime::now_local().unwrap_or_else(|_| time::OffsetDateTime::now_utc());
+    let date = format!(
+        "{:04}-{:02}-{:02}",
+        time.year(),
+        time.month() as u8,
+        time.day()
+    );
+    let clock = format!(
+        "{:02}:{:02}:{:02}",
+        time.hour(),
+        time.minute(),
+        time.second()
+    );
+    let size = BEXT_FIXED_SIZE + coding_history.len();
+    let mut chunk = Vec::with_capacity(size + 8);
+    chunk.extend_from_slice(b"bext");
+    chunk.extend_from_slice(&(size as u32).to_le_bytes());
+    chunk.extend(ascii_field(&description, 256));
+    chunk.extend(ascii_field("CamillaDSP", 32));
+    chunk.extend(ascii_field(&metadata.config_hash, 32));
+    chunk.extend(ascii_field(&date, 10));
+    chunk.extend(ascii_field(&clock, 8));
+    // Time reference, the output doesn't have a defined start time.
+    chunk.extend_from_slice(&0u64.to_le_bytes());
+    // Version 1, without loudness values.
+    chunk.extend_from_slice(&1u16.to_le_bytes());
+    // Empty UMID and reserved space.
+    chunk.resize(8 + BEXT_FIXED_SIZE, 0);
+    chunk.extend_from_slice(coding_history.as_bytes());
+    chunk
+}
+
+impl BwfWriter {
+    /// Create a new Broadcast Wave file.
+    pub fn create(
+        filename: &str,
+        channels: usize,
+        samplerate: usize,
+        sample_format: &SampleFormat,
+        metadata: &BwfMetadata,
+    ) -> Res<Self> {
+        let mut file = File::create(filename)?;
+        let bext = bext_chunk(channels, samplerate, sample_format, metadata);
+        let fmt = fmt_chunk(channels, samplerate, sample_format);
+        file.write_all(b"RIFF")?;
+        file.write_all(&0u32.to_le_bytes())?;
+        file.write_all(b"WAVE")?;
+        file.write_all(&bext)?;
+        file.write_all(&fmt)?;
+        file.write_all(b"data")?;
+        let data_size_pos = 12 + bext.len() as u64 + fmt.len() as u64 + 4;
+        file.write_all(&0u32.to_le_bytes())?;
+        Ok(BwfWriter {
+            file,
+            data_size_pos,
+            data_bytes: 0,
+            msb_aligned: *sample_format == SampleFormat::S24LE,
+            buffer: Vec::new(),
+        })
+    }
+
+    /// Pad the data to an even length, and write the final chunk sizes.
+    /// Sizes that don't fit in the header are stored as the largest possible value.
+    fn finish(&mut self) -> io::Result<()> {
+        if self.data_bytes % 2 == 1 {
+            self.file.write_all(&[0])?;
+        }
+        let riff_size = self.data_size_pos + 4 + self.data_bytes + self.data_bytes % 2 - 8;
+        let data_size = self.data_bytes.min(u32::MAX as u64) as u32;
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file
+            .write_all(&(riff_size.min(u32::MAX as u64) as u32).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(self.data_size_pos))?;
+        self.file.write_all(&data_size.to_le_bytes())?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()
+    }
+}
+
+impl Write for BwfWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.msb_aligned {
+            // S24LE samples are in the lower three bytes, move them to the upper ones.
+            // Only whole samples are converted, the caller writes the rest again.
+            let length = data.len() - data.len() % 4;
+            self.buffer.clear();
+            for sample in data[..length].chunks_exact(4) {
+                self.buffer
+                    .extend_from_slice(&[0, sample[0], sample[1], sample[2]]);
+            }
+            self.file.write_all(&self.buffer)?;
+            self.data_bytes += length as u64;
+            return Ok(length);
+        }
+        let written = self.file.write(data)?;
+        self.data_bytes += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for BwfWriter {
+    fn drop(&mut self) {
+        if let Err(err) = self.finish() {
+            error!("Unable to finalize wav file: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BwfMetadata, BwfWriter};
+    use crate::config::SampleFormat;
+    use crate::filters::read_wav;
+    use std::io::Write;
+
+    fn read_u32(data: &[u8], pos: usize) -> u32 {
+        u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+    }
+
+    #[test]
+    fn write_bwf_file() {
+        let filename = std::env::temp_dir().join("camilladsp_bwf_test.wav");
+        let filename = filename.to_str().unwrap();
+        let metadata = BwfMetadata {
+            version: "1.2.3".to_string(),
+            config_hash: "0123456789abcdef".to_string(),
+        };
+        {
+            let mut writer =
+                BwfWriter::create(filename, 2, 44100, &SampleFormat::S16LE, &metadata).unwrap();
+            writer.write_all(&[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        }
+        let data = std::fs::read(filename).unwrap();
+        std::fs::remove_file(filename).unwrap();
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(read_u32(&data, 4) as usize, data.len() - 8);
+        assert_eq!(&data[8..12], b"WAVE");
+        assert_eq!(&data[12..16], b"bext");
+        let bext_size = read_u32(&data, 16) as usize;
+        let description = String::from_utf8_lossy(&data[20..276]);
+        assert!(description.starts_with("CamillaDSP 1.2.3, samplerate: 44100 Hz, channels: 2"));
+        assert!(description.contains("0123456789abcdef"));
+        let history = String::from_utf8_lossy(&data[20 + 602..20 + bext_size]);
+        assert!(history.starts_with("A=PCM,F=44100,W=16,M=stereo"));
+        let fmt_pos = 20 + bext_size;
+        assert_eq!(&data[fmt_pos..fmt_pos + 4], b"fmt ");
+        assert_eq!(read_u32(&data, fmt_pos + 8), 0x0002_0001);
+        assert_eq!(read_u32(&data, fmt_pos + 12), 44100);
+        let data_pos = fmt_pos + 24;
+        assert_eq!(&data[data_pos..data_pos + 4], b"data");
+        assert_eq!(read_u32(&data, data_pos + 4), 8);
+        assert_eq!(&data[data_pos + 8..], &[1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn write_bwf_file_s24le() {
+        let filename = std::env::temp_dir().join("camilladsp_bwf_s24_test.wav");
+        let filename = filename.to_str().unwrap();
+        let metadata = BwfMetadata {
+            version: "1.2.3".to_string(),
+            config_hash: "0123456789abcdef".to_string(),
+        };
+        {
+            let mut writer =
+                BwfWriter::create(filename, 1, 44100, &SampleFormat::S24LE, &metadata).unwrap();
+            writer.write_all(&[1, 2, 3, 0, 4, 5, 0xF6, 0xFF]).unwrap();
+        }
+        let data = std::fs::read(filename).unwrap();
+        std::fs::remove_file(filename).unwrap();
+        let bext_size = read_u32(&data, 16) as usize;
+        let fmt_pos = 20 + bext_size;
+        // 24 valid bits in a 32 bit container
+        assert_eq!(read_u32(&data, fmt_pos + 20) & 0xFFFF_0000, 32 << 16);
+        assert_eq!(data[fmt_pos + 26], 24);
+        let data_pos = fmt_pos + 48;
+        assert_eq!(&data[data_pos..data_pos + 4], b"data");
+        assert_eq!(read_u32(&data, data_pos + 4), 8);
+        assert_eq!(&data[data_pos + 8..], &[0, 1, 2, 3, 0, 4, 5, 0xF6]);
+    }
+
+    #[test]
+    fn read_back_s24le() {
+        let filename = std::env::temp_dir().join("camilladsp_bwf_s24_read_test.wav");
+        let filename = filename.to_str().unwrap();
+        let metadata = BwfMetadata {
+            version: "1.2.3".to_string(),
+            config_hash: "0123456789abcdef".to_string(),
+        };
+        {
+            let mut writer =
+                BwfWriter::create(filename, 1, 44100, &SampleFormat::S24LE, &metadata).unwrap();
+            // 0.5 and -0.25 as 24-bit samples
+            writer
+                .write_all(&[0, 0, 0x40, 0, 0, 0, 0xE0, 0xFF])
+                .unwrap();
+        }
+        let values = read_wav(filename, 0).unwrap();
+        std::fs::remove_file(filename).unwrap();
+        assert_eq!(values, vec![0.5, -0.25]);
+    }
+}